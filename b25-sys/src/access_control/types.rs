@@ -1,25 +1,67 @@
 use std::fmt::{Display, Formatter};
 
+#[cfg(feature = "block00cbc")]
 pub type Block00CbcDec = tail_cbc::Decryptor<crate::access_control::block00_structure::Block00>;
 
+#[cfg(feature = "block00cbc")]
 pub struct EmmReceivingKeyPair {
     pub card_id: i64,
     pub key: u64,
 }
+#[cfg(feature = "block00cbc")]
 pub(crate) struct EmmExtendedKeys(pub(crate) i64, pub(crate) [u32; 16]);
 
+/// An EMM in an EMM section, addressed to a card.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EmmBody {
     pub card_id: i64,
     pub protocol: u8,
+    /// The encrypted part following the protocol number.
     pub info: Vec<u8>,
 }
 
+impl EmmBody {
+    /// Parses the EMM at the head of the body of an EMM section, and returns
+    /// it with the EMMs following it.
+    pub fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        // The card id, the associated_information_length and the protocol number
+        let len = 7 + *data.get(6)? as usize;
+        if len < 8 || data.len() < len {
+            return None;
+        }
+        let mut card_id = [0; 8];
+        card_id[2..].copy_from_slice(&data[..6]);
+        let body = Self {
+            card_id: i64::from_be_bytes(card_id),
+            protocol: data[7],
+            info: data[8..len].to_vec(),
+        };
+        Some((body, &data[len..]))
+    }
+
+    /// Returns the EMM as it is in the section, which is passed to the card.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.card_id.to_be_bytes()[2..].to_vec();
+        bytes.push((1 + self.info.len()) as u8);
+        bytes.push(self.protocol);
+        bytes.extend_from_slice(&self.info);
+        bytes
+    }
+}
+
 impl Display for EmmBody {
-    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "card {:012x}, protocol {:#04x}, {} bytes",
+            self.card_id,
+            self.protocol,
+            self.info.len()
+        )
     }
 }
 
+#[cfg(feature = "block00cbc")]
 pub struct EmmDecryptedBody {
     pub card_id: i64,
     pub protocol: u8,
@@ -28,3 +70,32 @@ pub struct EmmDecryptedBody {
     pub expiration_date: u16,
     pub info: Vec<u8>,
 }
+
+#[test]
+fn test_parse_emm() {
+    let mut section = vec![
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0x04, 0x03, 0xaa, 0xbb, 0xcc,
+    ];
+    section.extend_from_slice(&[0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x01, 0x07]);
+
+    let (emm, rest) = EmmBody::parse(&section).unwrap();
+    assert_eq!(emm.card_id, 0x0123_4567_89ab);
+    assert_eq!(emm.protocol, 0x03);
+    assert_eq!(emm.info, [0xaa, 0xbb, 0xcc]);
+    assert_eq!(emm.to_bytes(), section[..11]);
+    assert_eq!(emm.to_string(), "card 0123456789ab, protocol 0x03, 3 bytes");
+
+    let (emm, rest) = EmmBody::parse(rest).unwrap();
+    assert_eq!(emm.card_id, 0xfedc_ba98_7654);
+    assert!(emm.info.is_empty());
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn test_parse_broken_emm() {
+    // Shorter than its length
+    assert!(EmmBody::parse(&[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0x04, 0x03, 0xaa]).is_none());
+    // Without the protocol number
+    assert!(EmmBody::parse(&[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0x00]).is_none());
+    assert!(EmmBody::parse(&[0xff; 4]).is_none());
+}
@@ -0,0 +1,90 @@
+use crate::access_control::block00_mac::verify_mac;
+use crate::access_control::block00_structure::{expand_00, Block00};
+use crate::access_control::types::Block00CbcDec;
+use crate::{BCasCardError, CardStatus, CasCard, EcmResult};
+use log::{info, warn};
+use std::ops::Deref;
+use tail_cbc::cipher::{Key, KeyIvInit};
+use tail_cbc::UnalignedBytesDecryptMut;
+
+pub(crate) fn select_key_by_mac(payload: &mut [u8]) -> Option<Key<Block00>> {
+    let size = payload.len();
+
+    let mut temp = payload.to_vec();
+    let _protocol = payload[0];
+    let _broadcaster_group_id = payload[1];
+    let working_key_id = payload[2];
+    let encrypted_part = &mut payload[3..];
+
+    let mut ret = None;
+    for k in if working_key_id % 2 == 1 {
+        crate::KEY1.lock()
+    } else {
+        crate::KEY0.lock()
+    }
+    .unwrap()
+    .deref()
+    {
+        let expanded_key = expand_00(*k, 0);
+        let mut dec =
+            Block00CbcDec::new(&expanded_key, &0xfe27199919690911u64.to_be_bytes().into());
+        dec.decrypt_bytes_b2b_mut(encrypted_part, &mut temp[3..])
+            .expect("decryption failed");
+
+        //mac is the last 4 bytes of the payload
+        let (content, mac) = temp.split_at_mut(size - 4);
+
+        if verify_mac(mac, content, k.to_le_bytes().into()).is_ok() {
+            encrypted_part.copy_from_slice(&temp[3..]);
+            ret = Some(expanded_key);
+            break;
+        }
+    }
+    ret
+}
+
+/// A card emulated with the working keys given to `set_keys`.
+pub(crate) struct WorkingKeyCard;
+
+impl CasCard for WorkingKeyCard {
+    fn init(&mut self) -> Result<(), BCasCardError> {
+        Ok(())
+    }
+
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError> {
+        Ok(CardStatus {
+            system_key: [
+                0x36, 0x31, 0x04, 0x66, 0x4b, 0x17, 0xea, 0x5c, 0x32, 0xdf, 0x9c, 0xf5, 0xc4, 0xc3,
+                0x6c, 0x1b, 0xec, 0x99, 0x39, 0x21, 0x68, 0x9d, 0x4b, 0xb7, 0xb7, 0x4e, 0x40, 0x84,
+                0x0d, 0x2e, 0x7d, 0x98,
+            ],
+            init_cbc: [0xfe, 0x27, 0x19, 0x99, 0x19, 0x69, 0x09, 0x11],
+            card_id: 0xfe2719991969091,
+            card_status: 0,
+            ca_system_id: 5,
+        })
+    }
+
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError> {
+        let mut payload = ecm.to_vec();
+        let mut scramble_key = [0; 16];
+        if payload.len() >= 19 {
+            match select_key_by_mac(&mut payload) {
+                Some(key) => {
+                    #[cfg(debug_assertions)]
+                    info!("Selected Kw= {:?}", key);
+                    scramble_key.copy_from_slice(&payload[3..19]);
+                }
+                None => warn!("No valid key found"),
+            }
+        }
+        Ok(EcmResult {
+            scramble_key,
+            return_code: 0x0800,
+        })
+    }
+
+    fn proc_emm(&mut self, _emm: &[u8]) -> Result<(), BCasCardError> {
+        Ok(())
+    }
+}
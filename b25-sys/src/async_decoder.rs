@@ -0,0 +1,384 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use futures_io::{AsyncRead, AsyncWrite};
+use log::debug;
+
+use crate::{DecoderOptions, EmmStats, ProgramStats, StreamDecoder};
+
+/// The amount of the input queued for the worker before `poll_write` waits.
+const INPUT_CAP: usize = 1600000;
+/// The amount of the output queued for `poll_read` before the worker waits.
+const OUTPUT_CAP: usize = 1600000;
+/// The size of the buffer the worker reads the decoder output into.
+const READ_CHUNK: usize = 1 << 20;
+
+/// The count, the mean and the maximum of the latencies of a stage.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.total.div_f64(n as f64),
+        }
+    }
+}
+
+impl Display for LatencyStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} times, mean {:.3}ms, max {:.3}ms",
+            self.count,
+            self.mean().as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[derive(Default)]
+struct State {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// The last flush requested and the last flush done by the worker.
+    flush_requested: u64,
+    flush_done: u64,
+    closed: bool,
+    /// Set on drop, so that the worker exits without waiting for the reader
+    /// or decoding the rest of the input.
+    abandoned: bool,
+    /// Set when the worker has exited.
+    finished: bool,
+    /// The error of the worker, returned once, and whether it has failed.
+    error: Option<Error>,
+    failed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// Each cycle of putting a chunk into the decoder and getting its output.
+    latency: LatencyStats,
+}
+
+impl State {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake()
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake()
+        }
+    }
+
+    fn take_error(&mut self) -> Option<Error> {
+        match self.error.take() {
+            Some(e) => Some(e),
+            None if self.failed => {
+                Some(Error::new(ErrorKind::BrokenPipe, "The decoder has failed."))
+            }
+            None => None,
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notifies the worker of new input, a flush, the close or the room in
+    /// the output queue.
+    work: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// `StreamDecoder` driven by a worker thread, implementing `AsyncRead` and
+/// `AsyncWrite`.
+///
+/// The input written is queued for the worker, which puts it into the decoder
+/// and queues the output for `poll_read`. Both queues are bounded. The tasks
+/// are woken up when the output arrives or the input queue has room, so the
+/// decoding never blocks the executor thread. `poll_flush` waits until the
+/// worker has flushed the decoder, and the reader sees the end of the stream
+/// after `poll_close`.
+pub struct AsyncStreamDecoder {
+    decoder: Arc<StreamDecoder>,
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+    /// The flush `poll_flush` is waiting for.
+    pending_flush: Option<u64>,
+}
+
+impl AsyncStreamDecoder {
+    pub fn new(opt: DecoderOptions) -> Result<Self, Error> {
+        let decoder = Arc::new(StreamDecoder::new(opt)?);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            work: Condvar::new(),
+        });
+        let worker = {
+            let (decoder, shared) = (decoder.clone(), shared.clone());
+            std::thread::Builder::new()
+                .name("b25-decoder".to_string())
+                .spawn(move || work(&decoder, &shared))?
+        };
+        Ok(Self {
+            decoder,
+            shared,
+            worker: Some(worker),
+            pending_flush: None,
+        })
+    }
+
+    /// See `StreamDecoder::program_stats`.
+    pub fn program_stats(&self) -> Vec<ProgramStats> {
+        self.decoder.program_stats()
+    }
+
+    /// See `StreamDecoder::emm_stats`.
+    pub fn emm_stats(&self) -> Vec<EmmStats> {
+        self.decoder.emm_stats()
+    }
+
+    /// Returns the latencies of the cycles of the worker, each of which puts
+    /// a chunk of the input into the decoder and gets its output. Round trips
+    /// to the card are included.
+    pub fn latency(&self) -> LatencyStats {
+        self.shared.lock().latency
+    }
+
+    /// Stops the worker and returns the output not read yet and the input not
+    /// decoded yet, in this order. After a failure of the decoder, the input
+    /// begins with the chunk it has failed on. Only what libaribb25 holds
+    /// back inside, less than a packet, is lost.
+    pub fn into_unprocessed(mut self) -> (Vec<u8>, Vec<u8>) {
+        {
+            let mut state = self.shared.lock();
+            state.abandoned = true;
+            self.shared.work.notify_one();
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        let mut state = self.shared.lock();
+        (
+            std::mem::take(&mut state.output),
+            std::mem::take(&mut state.input),
+        )
+    }
+}
+
+/// Moves the output of the decoder to the queue, and returns the time spent
+/// waiting for the room in the queue.
+fn drain(mut decoder: &StreamDecoder, buf: &mut [u8], shared: &Shared) -> Result<Duration, Error> {
+    let mut waited = Duration::ZERO;
+    loop {
+        let n = decoder.read(buf)?;
+        if n == 0 {
+            return Ok(waited);
+        }
+        let mut state = shared.lock();
+        if state.output.len() >= OUTPUT_CAP && !state.abandoned {
+            let start = Instant::now();
+            while state.output.len() >= OUTPUT_CAP && !state.abandoned {
+                state = shared.work.wait(state).unwrap();
+            }
+            waited += start.elapsed();
+        }
+        state.output.extend_from_slice(&buf[..n]);
+        state.wake_reader();
+    }
+}
+
+fn work(mut decoder: &StreamDecoder, shared: &Shared) {
+    let mut buf = vec![0u8; READ_CHUNK];
+    let mut input = Vec::new();
+    loop {
+        let (flush, closed) = {
+            let mut state = shared.lock();
+            while state.input.is_empty()
+                && state.flush_requested == state.flush_done
+                && !state.closed
+                && !state.abandoned
+            {
+                state = shared.work.wait(state).unwrap();
+            }
+            if state.abandoned {
+                state.finished = true;
+                debug!("The decoder worker has exited.");
+                return;
+            }
+            std::mem::swap(&mut input, &mut state.input);
+            state.wake_writer();
+            (state.flush_requested, state.closed)
+        };
+
+        let start = Instant::now();
+        let result = decoder.write_all(&input);
+        if result.is_err() {
+            // Kept to be taken by `into_unprocessed`
+            let mut state = shared.lock();
+            input.append(&mut state.input);
+            std::mem::swap(&mut input, &mut state.input);
+        }
+        let result = result
+            .and_then(|_| drain(decoder, &mut buf, shared))
+            .and_then(|mut waited| {
+                if flush > shared.lock().flush_done || closed {
+                    decoder.flush()?;
+                    waited += drain(decoder, &mut buf, shared)?;
+                }
+                Ok(waited)
+            });
+        input.clear();
+
+        let mut state = shared.lock();
+        state.flush_done = flush;
+        match result {
+            Ok(waited) => state.latency.record(start.elapsed().saturating_sub(waited)),
+            Err(e) => {
+                state.error = Some(e);
+                state.failed = true;
+            }
+        }
+        if state.failed || (closed && state.input.is_empty()) {
+            state.finished = true;
+            state.wake_reader();
+            state.wake_writer();
+            debug!("The decoder worker has exited.");
+            return;
+        }
+        state.wake_writer();
+    }
+}
+
+impl AsyncWrite for AsyncStreamDecoder {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut state = self.shared.lock();
+        if let Some(e) = state.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.closed {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        let room = INPUT_CAP.saturating_sub(state.input.len());
+        if room == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        state.input.extend_from_slice(&buf[..n]);
+        self.shared.work.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let shared = self.shared.clone();
+        let mut state = shared.lock();
+        if let Some(e) = state.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.finished {
+            return Poll::Ready(Ok(()));
+        }
+        let target = match self.pending_flush {
+            Some(target) => target,
+            None => {
+                state.flush_requested += 1;
+                shared.work.notify_one();
+                self.pending_flush = Some(state.flush_requested);
+                state.flush_requested
+            }
+        };
+        if state.flush_done >= target {
+            self.pending_flush = None;
+            Poll::Ready(Ok(()))
+        } else {
+            state.write_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.shared.lock();
+        if !state.closed {
+            state.closed = true;
+            self.shared.work.notify_one();
+        }
+        if let Some(e) = state.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.finished {
+            Poll::Ready(Ok(()))
+        } else {
+            state.write_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncRead for AsyncStreamDecoder {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut state = self.shared.lock();
+        if !state.output.is_empty() {
+            let n = buf.len().min(state.output.len());
+            buf[..n].copy_from_slice(&state.output[..n]);
+            state.output.drain(..n);
+            self.shared.work.notify_one();
+            return Poll::Ready(Ok(n));
+        }
+        if let Some(e) = state.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.finished {
+            // The end of the stream
+            return Poll::Ready(Ok(0));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for AsyncStreamDecoder {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            state.abandoned = true;
+            state.input.clear();
+            state.output.clear();
+            self.shared.work.notify_one();
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -5,7 +5,7 @@
 #![allow(non_upper_case_globals)]
 #![allow(dead_code)]
 
-use crate::bindings::error::BCasCardError;
+use crate::bindings::error::{BCasCardError, DecoderError};
 use log::debug;
 use std::io::{Error, ErrorKind};
 
@@ -554,7 +554,10 @@ impl B_CAS_CARD {
             unsafe { init.unwrap()(self as *mut B_CAS_CARD as *mut ::std::os::raw::c_void) };
 
         if errno != 0 {
-            Err(Error::new(ErrorKind::Other, BCasCardError::from(errno)))
+            Err(Error::new(
+                ErrorKind::Other,
+                DecoderError::from(BCasCardError::from(errno)),
+            ))
         } else {
             Ok(())
         }
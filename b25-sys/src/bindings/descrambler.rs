@@ -0,0 +1,505 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::multi2::{Multi2, Parity, Simd};
+use crate::{BCasCardError, CardStatus, CasCard, EcmResult, PowerOnControl};
+
+pub(crate) const PACKET_SIZE: usize = 188;
+const TABLE_PAT: u8 = 0x00;
+const TABLE_PMT: u8 = 0x02;
+const TABLE_ECM: u8 = 0x82;
+const TAG_CA: u8 = 0x09;
+
+/// CRC-32/MPEG-2 of a section, which is zero over a whole valid section.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= (b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A section being assembled from the packets of a PID.
+#[derive(Default)]
+pub(crate) struct Section {
+    data: Vec<u8>,
+}
+
+impl Section {
+    fn needed(&self) -> Option<usize> {
+        (self.data.len() >= 3)
+            .then(|| 3 + (((self.data[1] as usize) << 8 | self.data[2] as usize) & 0x0fff))
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(self.needed(), Some(n) if self.data.len() >= n)
+    }
+
+    /// Appends the payload and returns the sections completed.
+    pub(crate) fn push(&mut self, mut payload: &[u8], start: bool, out: &mut Vec<Vec<u8>>) {
+        if start {
+            let pointer = payload[0] as usize;
+            if pointer + 1 > payload.len() {
+                self.data.clear();
+                return;
+            }
+            if !self.data.is_empty() {
+                self.fill(&payload[1..1 + pointer], out);
+            }
+            self.data.clear();
+            payload = &payload[1 + pointer..];
+            // The following sections may start in the same packet
+            while !payload.is_empty() && payload[0] != 0xff {
+                payload = self.fill(payload, out);
+            }
+        } else if !self.data.is_empty() {
+            self.fill(payload, out);
+        }
+    }
+
+    /// Appends as much of `payload` as the section needs, and returns the rest.
+    fn fill<'a>(&mut self, payload: &'a [u8], out: &mut Vec<Vec<u8>>) -> &'a [u8] {
+        let mut payload = payload;
+        while !self.is_complete() && !payload.is_empty() {
+            let want = self.needed().unwrap_or(3) - self.data.len();
+            let n = want.min(payload.len());
+            self.data.extend_from_slice(&payload[..n]);
+            payload = &payload[n..];
+        }
+        if self.is_complete() {
+            let section = std::mem::take(&mut self.data);
+            if section.len() >= 12 && section[1] & 0x80 != 0 && crc32(&section) == 0 {
+                out.push(section);
+            }
+        }
+        payload
+    }
+}
+
+/// The number of the answers of the card kept for `Descrambler`.
+const ECM_KEYS_CAP: usize = 16;
+
+/// The last answers of the card to the ECMs libaribb25 has sent it, shared
+/// with `Descrambler`, so that each ECM goes to the card once.
+#[derive(Clone, Default)]
+pub(crate) struct EcmKeys(Arc<Mutex<VecDeque<EcmAnswer>>>);
+
+/// The body of an ECM and the answer of the card to it.
+type EcmAnswer = (Vec<u8>, EcmResult);
+
+impl EcmKeys {
+    /// Wraps the card given to libaribb25, to record its answers.
+    pub(crate) fn tap(&self, card: Box<dyn CasCard>) -> Box<dyn CasCard> {
+        Box::new(KeyTap {
+            card,
+            keys: self.clone(),
+        })
+    }
+
+    fn get(&self, ecm: &[u8]) -> Option<EcmResult> {
+        let keys = self.0.lock().unwrap();
+        keys.iter()
+            .rev()
+            .find(|(body, _)| body == ecm)
+            .map(|(_, result)| *result)
+    }
+
+    fn insert(&self, ecm: &[u8], result: EcmResult) {
+        let mut keys = self.0.lock().unwrap();
+        if keys.len() == ECM_KEYS_CAP {
+            keys.pop_front();
+        }
+        keys.push_back((ecm.to_vec(), result));
+    }
+}
+
+struct KeyTap {
+    card: Box<dyn CasCard>,
+    keys: EcmKeys,
+}
+
+impl CasCard for KeyTap {
+    fn init(&mut self) -> Result<(), BCasCardError> {
+        self.card.init()
+    }
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError> {
+        self.card.init_status()
+    }
+    fn card_ids(&mut self) -> Result<Vec<i64>, BCasCardError> {
+        self.card.card_ids()
+    }
+    fn power_on_control(&mut self) -> Result<Vec<PowerOnControl>, BCasCardError> {
+        self.card.power_on_control()
+    }
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError> {
+        let result = self.card.proc_ecm(ecm)?;
+        self.keys.insert(ecm, result);
+        Ok(result)
+    }
+    fn proc_emm(&mut self, emm: &[u8]) -> Result<(), BCasCardError> {
+        self.card.proc_emm(emm)
+    }
+}
+
+/// The scramble keys of an ECM PID.
+struct Ecm {
+    /// The body of the last ECM in the stream.
+    last: Vec<u8>,
+    /// Whether the card has answered `last`.
+    answered: bool,
+    multi2: Multi2,
+}
+
+/// Descrambles the packets with MULTI2 in Rust before they are put into
+/// libaribb25.
+///
+/// The ECMs are sent to the card by libaribb25 alone, and the scramble keys
+/// are taken from its answers in `EcmKeys`. The packets descrambled have their
+/// scrambling control cleared, so that libaribb25 passes them through. The
+/// packets which cannot be descrambled, such as those from an ECM until
+/// libaribb25 has sent it to the card, are left to libaribb25.
+pub(crate) struct Descrambler {
+    /// The descrambler with the system key of the card and no scramble keys.
+    base: Multi2,
+    /// The bytes of an incomplete packet at the end of the last input.
+    carry: Vec<u8>,
+    pmt_pids: Vec<u16>,
+    /// The ECM PID of each elementary stream.
+    ecm_pids: HashMap<u16, u16>,
+    ecms: HashMap<u16, Ecm>,
+    sections: HashMap<u16, Section>,
+    keys: EcmKeys,
+}
+
+impl Descrambler {
+    /// Creates a descrambler with the keys of the card, and the answers to the
+    /// ECMs recorded in `keys`.
+    pub(crate) fn new(status: &CardStatus, round: i32, simd: Simd, keys: EcmKeys) -> Self {
+        let mut base = Multi2::new(&status.system_key, &status.init_cbc);
+        base.set_round(round as usize);
+        base.set_simd(simd);
+        debug!("MULTI2 in Rust with {:?}", base.simd());
+        Self {
+            base,
+            carry: Vec::new(),
+            pmt_pids: Vec::new(),
+            ecm_pids: HashMap::new(),
+            ecms: HashMap::new(),
+            sections: HashMap::new(),
+            keys,
+        }
+    }
+
+    /// Descrambles the complete packets of `buf`, and returns them with the
+    /// bytes out of sync as they are. An incomplete packet at the end is kept
+    /// until the next call.
+    pub(crate) fn process(&mut self, buf: &[u8]) -> Vec<u8> {
+        self.update_keys();
+        let mut data = std::mem::take(&mut self.carry);
+        data.extend_from_slice(buf);
+        let mut pos = 0;
+        while pos < data.len() {
+            if data[pos] != 0x47 {
+                pos += 1;
+                continue;
+            }
+            if data.len() - pos < PACKET_SIZE {
+                self.carry = data.split_off(pos);
+                break;
+            }
+            self.packet(&mut data[pos..pos + PACKET_SIZE]);
+            pos += PACKET_SIZE;
+        }
+        data
+    }
+
+    /// Returns the incomplete packet kept, at the end of the stream.
+    pub(crate) fn take_carry(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.carry)
+    }
+
+    /// Sets the scramble keys of the ECMs libaribb25 has sent to the card
+    /// since the last call.
+    fn update_keys(&mut self) {
+        for (pid, ecm) in self.ecms.iter_mut() {
+            if ecm.answered || ecm.last.is_empty() {
+                continue;
+            }
+            if let Some(result) = self.keys.get(&ecm.last) {
+                ecm.answered = true;
+                if result.is_ok() {
+                    ecm.multi2.set_scramble_key(&result.scramble_key);
+                } else {
+                    debug!(
+                        "The card refused the ECM of PID {:#06x} ({:#06x}).",
+                        pid, result.return_code
+                    );
+                }
+            }
+        }
+    }
+
+    fn packet(&mut self, packet: &mut [u8]) {
+        let pid = u16::from_be_bytes([packet[1], packet[2]]) & 0x1fff;
+        let start = packet[1] & 0x40 != 0;
+        let control = packet[3] >> 6;
+        let offset = match (packet[3] >> 4) & 0x03 {
+            0x01 => 4,
+            0x03 => 5 + packet[4] as usize,
+            _ => return,
+        };
+        if offset >= PACKET_SIZE {
+            return;
+        }
+
+        if let Some(parity) = Parity::from_scrambling_control(control) {
+            let multi2 = self
+                .ecm_pids
+                .get(&pid)
+                .and_then(|ecm_pid| self.ecms.get(ecm_pid))
+                .map(|ecm| &ecm.multi2)
+                .filter(|multi2| multi2.has_scramble_key());
+            if let Some(multi2) = multi2 {
+                multi2.decrypt(parity, &mut packet[offset..]);
+                packet[3] &= 0x3f;
+            }
+            return;
+        }
+
+        if pid == 0 || self.pmt_pids.contains(&pid) || self.ecms.contains_key(&pid) {
+            let mut sections = Vec::new();
+            self.sections
+                .entry(pid)
+                .or_default()
+                .push(&packet[offset..], start, &mut sections);
+            for section in sections {
+                self.section(pid, &section);
+            }
+        }
+    }
+
+    fn section(&mut self, pid: u16, section: &[u8]) {
+        // Without the CRC
+        let body = &section[8..section.len() - 4];
+        match section[0] {
+            TABLE_PAT if pid == 0 => {
+                self.pmt_pids = body
+                    .chunks_exact(4)
+                    .filter(|entry| entry[..2] != [0, 0])
+                    .map(|entry| u16::from_be_bytes([entry[2], entry[3]]) & 0x1fff)
+                    .collect();
+            }
+            TABLE_PMT if self.pmt_pids.contains(&pid) => self.pmt(body),
+            TABLE_ECM if self.ecms.contains_key(&pid) => self.ecm(pid, body),
+            _ => {}
+        }
+    }
+
+    fn pmt(&mut self, body: &[u8]) {
+        if body.len() < 4 {
+            return;
+        }
+        let info_len = (u16::from_be_bytes([body[2], body[3]]) & 0x0fff) as usize;
+        let program_ecm = ca_pid(body.get(4..4 + info_len).unwrap_or_default());
+        let mut es = body.get(4 + info_len..).unwrap_or_default();
+        while es.len() >= 5 {
+            let pid = u16::from_be_bytes([es[1], es[2]]) & 0x1fff;
+            let len = (u16::from_be_bytes([es[3], es[4]]) & 0x0fff) as usize;
+            let descriptors = es.get(5..5 + len).unwrap_or_default();
+            if let Some(ecm_pid) = ca_pid(descriptors).or(program_ecm) {
+                self.ecm_pids.insert(pid, ecm_pid);
+                self.ecms.entry(ecm_pid).or_insert_with(|| Ecm {
+                    last: Vec::new(),
+                    answered: false,
+                    multi2: self.base.clone(),
+                });
+            }
+            es = es.get(5 + len..).unwrap_or_default();
+        }
+    }
+
+    fn ecm(&mut self, pid: u16, body: &[u8]) {
+        let ecm = self.ecms.get_mut(&pid).unwrap();
+        if ecm.last == body {
+            return;
+        }
+        // The following packets are left to libaribb25 until it has sent the
+        // ECM to the card
+        ecm.last = body.to_vec();
+        ecm.answered = false;
+        ecm.multi2.clear_scramble_key();
+    }
+}
+
+/// Returns the PID of the first CA descriptor in the descriptors.
+pub(crate) fn ca_pid(mut descriptors: &[u8]) -> Option<u16> {
+    while descriptors.len() >= 2 {
+        let len = descriptors[1] as usize;
+        let descriptor = descriptors.get(2..2 + len)?;
+        if descriptors[0] == TAG_CA && len >= 4 {
+            return Some(u16::from_be_bytes([descriptor[2], descriptor[3]]) & 0x1fff);
+        }
+        descriptors = &descriptors[2 + len..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MockCard, ECM_PID, STATUS};
+
+    /// The PAT, the PMT and the ECM at the head of `testing::scrambled_stream`.
+    const HEAD: usize = 3 * PACKET_SIZE;
+
+    fn descrambler(keys: &EcmKeys) -> Descrambler {
+        Descrambler::new(&STATUS, 4, Simd::detect(), keys.clone())
+    }
+
+    /// Descrambles the head of the stream, sends its ECM to the card through
+    /// the tap as libaribb25 would, and descrambles the rest.
+    fn run(descrambler: &mut Descrambler, tap: &mut dyn CasCard, stream: &[u8]) -> Vec<u8> {
+        let mut out = descrambler.process(&stream[..HEAD]);
+        let _ = tap.proc_ecm(&testing::ecm_body());
+        out.extend(descrambler.process(&stream[HEAD..]));
+        out
+    }
+
+    #[test]
+    fn test_descramble() {
+        let fixture = testing::scrambled_stream(64, 4);
+        let card = MockCard::default();
+        let log = card.log.clone();
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(card));
+        let out = run(&mut descrambler(&keys), &mut *tap, &fixture.scrambled);
+        assert_eq!(out, fixture.clear);
+        // Only the ECM sent by libaribb25 reaches the card
+        assert_eq!(log.lock().unwrap().ecms, vec![testing::ecm_body()]);
+    }
+
+    #[test]
+    fn test_before_answer() {
+        let fixture = testing::scrambled_stream(64, 4);
+        let keys = EcmKeys::default();
+        let mut descrambler = descrambler(&keys);
+        // Left to libaribb25 until it has sent the ECM to the card
+        assert_eq!(descrambler.process(&fixture.scrambled), fixture.scrambled);
+        keys.tap(Box::new(MockCard::default()))
+            .proc_ecm(&testing::ecm_body())
+            .unwrap();
+        assert_eq!(
+            descrambler.process(&fixture.scrambled[HEAD..]),
+            fixture.clear[HEAD..]
+        );
+    }
+
+    #[test]
+    fn test_split_input() {
+        let fixture = testing::scrambled_stream(64, 4);
+        // The bytes out of sync are passed through
+        let mut input = vec![0x00, 0x12, 0x34];
+        input.extend_from_slice(&fixture.scrambled);
+        let mut expected = input[..3].to_vec();
+        expected.extend_from_slice(&fixture.clear);
+
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(MockCard::default()));
+        let mut descrambler = descrambler(&keys);
+        let (head, tail) = input.split_at(3 + HEAD);
+        let mut out = Vec::new();
+        for chunk in head.chunks(100) {
+            out.extend(descrambler.process(chunk));
+        }
+        tap.proc_ecm(&testing::ecm_body()).unwrap();
+        for chunk in tail.chunks(100) {
+            out.extend(descrambler.process(chunk));
+        }
+        assert!(descrambler.take_carry().is_empty());
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_incomplete_packet() {
+        let fixture = testing::scrambled_stream(8, 4);
+        let len = fixture.scrambled.len() - 50;
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(MockCard::default()));
+        let mut descrambler = descrambler(&keys);
+        let out = run(&mut descrambler, &mut *tap, &fixture.scrambled[..len]);
+        assert_eq!(out, fixture.clear[..len - 138]);
+        // The last packet is kept as it is
+        assert_eq!(descrambler.take_carry(), fixture.scrambled[len - 138..len]);
+        assert!(descrambler.take_carry().is_empty());
+    }
+
+    #[test]
+    fn test_new_ecm() {
+        let fixture = testing::scrambled_stream(16, 4);
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(MockCard::default()));
+        let mut descrambler = descrambler(&keys);
+        run(&mut descrambler, &mut *tap, &fixture.scrambled);
+
+        // The keys of the last ECM are not used after another one
+        let mut body = testing::ecm_body();
+        body[20] = 0;
+        let ecm = testing::section(0x82, 0x0001, &body);
+        let mut input = testing::psi_packet(ECM_PID, 1, &ecm);
+        input.extend_from_slice(&fixture.scrambled[HEAD..]);
+        assert_eq!(descrambler.process(&input), input);
+    }
+
+    #[test]
+    fn test_unpurchased_ecm() {
+        let fixture = testing::scrambled_stream(16, 4);
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(MockCard {
+            return_code: 0x8901,
+            ..Default::default()
+        }));
+        let out = run(&mut descrambler(&keys), &mut *tap, &fixture.scrambled);
+        // Left to libaribb25
+        assert_eq!(out, fixture.scrambled);
+    }
+
+    #[test]
+    fn test_card_error() {
+        let fixture = testing::scrambled_stream(16, 4);
+        let card = MockCard {
+            ecm_error: Some(BCasCardError::BCAS_CARD_ERROR_TRANSMIT_FAILED),
+            ..Default::default()
+        };
+        let log = card.log.clone();
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(card));
+        let out = run(&mut descrambler(&keys), &mut *tap, &fixture.scrambled);
+        assert_eq!(out, fixture.scrambled);
+        assert_eq!(log.lock().unwrap().ecms.len(), 1);
+    }
+
+    #[test]
+    fn test_round() {
+        // The keys are right, but not the number of rounds
+        let fixture = testing::scrambled_stream(4, 32);
+        let keys = EcmKeys::default();
+        let mut tap = keys.tap(Box::new(MockCard::default()));
+        let out = run(&mut descrambler(&keys), &mut *tap, &fixture.scrambled);
+        assert_ne!(out, fixture.clear);
+
+        let mut descrambler = Descrambler::new(&STATUS, 32, Simd::Scalar, keys.clone());
+        assert_eq!(
+            run(&mut descrambler, &mut *tap, &fixture.scrambled),
+            fixture.clear
+        );
+    }
+}
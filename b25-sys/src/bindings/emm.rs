@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use log::{debug, info, warn};
+
+use crate::access_control::types::EmmBody;
+use crate::bindings::descrambler::{ca_pid, Section, PACKET_SIZE};
+use crate::{CasCard, EmmStats};
+
+const PID_CAT: u16 = 0x0001;
+const TABLE_CAT: u8 = 0x01;
+const TABLE_EMM: u8 = 0x84;
+
+/// The EMMs addressed to a card id.
+struct Target {
+    stats: EmmStats,
+    /// The last EMM passed to the card, which is repeated in the stream.
+    last: Vec<u8>,
+}
+
+/// Passes the EMMs addressed to the card to it.
+///
+/// The EMM PID is found in the CAT, and the EMMs in its sections are parsed
+/// and filtered by the ids of the card. An EMM is passed once, although the
+/// broadcaster sends it again and again.
+pub(crate) struct EmmProcessor {
+    targets: Vec<Target>,
+    /// The bytes of an incomplete packet at the end of the last input.
+    carry: Vec<u8>,
+    emm_pid: Option<u16>,
+    sections: HashMap<u16, Section>,
+}
+
+impl EmmProcessor {
+    pub(crate) fn new(card_ids: &[i64]) -> Self {
+        Self {
+            targets: card_ids
+                .iter()
+                .map(|&card_id| Target {
+                    stats: EmmStats {
+                        card_id,
+                        ..Default::default()
+                    },
+                    last: Vec::new(),
+                })
+                .collect(),
+            carry: Vec::new(),
+            emm_pid: None,
+            sections: HashMap::new(),
+        }
+    }
+
+    /// Looks for the EMMs in the complete packets of `buf`, and keeps an
+    /// incomplete packet at the end until the next call.
+    pub(crate) fn process(&mut self, buf: &[u8], card: &mut dyn CasCard) {
+        let mut data = std::mem::take(&mut self.carry);
+        data.extend_from_slice(buf);
+        let mut pos = 0;
+        while pos < data.len() {
+            if data[pos] != 0x47 {
+                pos += 1;
+                continue;
+            }
+            if data.len() - pos < PACKET_SIZE {
+                self.carry = data.split_off(pos);
+                break;
+            }
+            self.packet(&data[pos..pos + PACKET_SIZE], card);
+            pos += PACKET_SIZE;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<EmmStats> {
+        self.targets
+            .iter()
+            .map(|target| target.stats.clone())
+            .collect()
+    }
+
+    fn packet(&mut self, packet: &[u8], card: &mut dyn CasCard) {
+        let pid = u16::from_be_bytes([packet[1], packet[2]]) & 0x1fff;
+        if (pid != PID_CAT && Some(pid) != self.emm_pid) || packet[3] >> 6 != 0 {
+            return;
+        }
+        let offset = match (packet[3] >> 4) & 0x03 {
+            0x01 => 4,
+            0x03 => 5 + packet[4] as usize,
+            _ => return,
+        };
+        if offset >= PACKET_SIZE {
+            return;
+        }
+
+        let mut sections = Vec::new();
+        self.sections.entry(pid).or_default().push(
+            &packet[offset..],
+            packet[1] & 0x40 != 0,
+            &mut sections,
+        );
+        for section in sections {
+            // Without the CRC
+            let body = &section[8..section.len() - 4];
+            match section[0] {
+                TABLE_CAT if pid == PID_CAT => {
+                    let emm_pid = ca_pid(body);
+                    if emm_pid != self.emm_pid {
+                        debug!("EMM PID: {:?}", emm_pid);
+                        self.emm_pid = emm_pid;
+                    }
+                }
+                TABLE_EMM if pid != PID_CAT => self.section(body, card),
+                _ => {}
+            }
+        }
+    }
+
+    fn section(&mut self, mut body: &[u8], card: &mut dyn CasCard) {
+        while let Some((emm, rest)) = EmmBody::parse(body) {
+            body = rest;
+            let target = match self
+                .targets
+                .iter_mut()
+                .find(|target| target.stats.card_id == emm.card_id)
+            {
+                Some(target) => target,
+                None => continue,
+            };
+            target.stats.received += 1;
+            let bytes = emm.to_bytes();
+            if target.last == bytes {
+                continue;
+            }
+            match card.proc_emm(&bytes) {
+                Ok(()) => {
+                    info!("EMM applied: {}", emm);
+                    target.stats.applied += 1;
+                    target.stats.last_protocol = Some(emm.protocol);
+                    target.last = bytes;
+                }
+                Err(e) => {
+                    // Passed again when it is repeated
+                    warn!("EMM failed: {} ({})", emm, e);
+                    target.stats.failed += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MockCard, STATUS};
+    use crate::BCasCardError;
+
+    const EMM_PID: u16 = 0x0040;
+
+    fn cat() -> Vec<u8> {
+        let [pid_hi, pid_lo] = EMM_PID.to_be_bytes();
+        let cat = testing::section(
+            TABLE_CAT,
+            0xffff,
+            &[0x09, 0x04, 0x00, 0x05, 0xe0 | pid_hi, pid_lo],
+        );
+        testing::psi_packet(PID_CAT, 0, &cat)
+    }
+
+    fn emm(card_id: i64, update: u8) -> EmmBody {
+        EmmBody {
+            card_id,
+            protocol: 0x03,
+            info: vec![0x01, 0x00, update, 0x5a, 0x5a],
+        }
+    }
+
+    fn emm_packet(emms: &[EmmBody]) -> Vec<u8> {
+        let body: Vec<u8> = emms.iter().flat_map(|emm| emm.to_bytes()).collect();
+        testing::psi_packet(EMM_PID, 0, &testing::section(TABLE_EMM, 0x0001, &body))
+    }
+
+    #[test]
+    fn test_emm() {
+        let ours = emm(STATUS.card_id, 1);
+        let other = emm(0x0fed_cba9_8765, 1);
+        let packet = emm_packet(&[other, ours.clone()]);
+        // Ignored before the CAT
+        let mut stream = packet.clone();
+        stream.extend(cat());
+        // Repeated by the broadcaster
+        stream.extend_from_slice(&packet);
+        stream.extend_from_slice(&packet);
+        let updated = emm(STATUS.card_id, 2);
+        stream.extend(emm_packet(std::slice::from_ref(&updated)));
+
+        let mut card = MockCard::default();
+        let mut processor = EmmProcessor::new(&[STATUS.card_id]);
+        for chunk in stream.chunks(100) {
+            processor.process(chunk, &mut card);
+        }
+        assert_eq!(
+            card.log.lock().unwrap().emms,
+            [ours.to_bytes(), updated.to_bytes()]
+        );
+        assert_eq!(
+            processor.stats(),
+            [EmmStats {
+                card_id: STATUS.card_id,
+                received: 3,
+                applied: 2,
+                failed: 0,
+                last_protocol: Some(0x03),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_emm_error() {
+        let mut stream = cat();
+        let packet = emm_packet(&[emm(STATUS.card_id, 1)]);
+        stream.extend_from_slice(&packet);
+        stream.extend_from_slice(&packet);
+
+        let mut card = MockCard {
+            emm_error: Some(BCasCardError::BCAS_CARD_ERROR_TRANSMIT_FAILED),
+            ..Default::default()
+        };
+        let mut processor = EmmProcessor::new(&[STATUS.card_id]);
+        processor.process(&stream, &mut card);
+        // Passed again, as the card has not accepted it
+        assert_eq!(card.log.lock().unwrap().emms.len(), 2);
+        let stats = &processor.stats()[0];
+        assert_eq!((stats.received, stats.applied, stats.failed), (2, 0, 2));
+        assert_eq!(stats.last_protocol, None);
+    }
+}
@@ -7,29 +7,63 @@ use std::fmt::{Display, Formatter};
 
 #[derive(Debug, Clone)]
 pub enum AribB25DecoderError {
-    ARIB_STD_B25_ERROR_INVALID_PARAM = -1,
-    ARIB_STD_B25_ERROR_NO_ENOUGH_MEMORY = -2,
-    ARIB_STD_B25_ERROR_NON_TS_INPUT_STREAM = -3,
-    ARIB_STD_B25_ERROR_NO_PAT_IN_HEAD_16M = -4,
-    ARIB_STD_B25_ERROR_NO_PMT_IN_HEAD_32M = -5,
-    ARIB_STD_B25_ERROR_NO_ECM_IN_HEAD_32M = -6,
-    ARIB_STD_B25_ERROR_EMPTY_B_CAS_CARD = -7,
-    ARIB_STD_B25_ERROR_INVALID_B_CAS_STATUS = -8,
-    ARIB_STD_B25_ERROR_ECM_PROC_FAILURE = -9,
-    ARIB_STD_B25_ERROR_DECRYPT_FAILURE = -10,
-    ARIB_STD_B25_ERROR_PAT_PARSE_FAILURE = -11,
-    ARIB_STD_B25_ERROR_PMT_PARSE_FAILURE = -12,
-    ARIB_STD_B25_ERROR_ECM_PARSE_FAILURE = -13,
-    ARIB_STD_B25_ERROR_CAT_PARSE_FAILURE = -14,
-    ARIB_STD_B25_ERROR_EMM_PARSE_FAILURE = -15,
-    ARIB_STD_B25_ERROR_EMM_PROC_FAILURE = -16,
-
-    ARIB_STD_B25_WARN_UNPURCHASED_ECM = 1,
-    ARIB_STD_B25_WARN_TS_SECTION_ID_MISSMATCH = 2,
-    ARIB_STD_B25_WARN_BROKEN_TS_SECTION = 3,
-    ARIB_STD_B25_WARN_PAT_NOT_COMPLETE = 4,
-    ARIB_STD_B25_WARN_PMT_NOT_COMPLETE = 5,
-    ARIB_STD_B25_WARN_ECM_NOT_COMPLETE = 6,
+    ARIB_STD_B25_ERROR_INVALID_PARAM,
+    ARIB_STD_B25_ERROR_NO_ENOUGH_MEMORY,
+    ARIB_STD_B25_ERROR_NON_TS_INPUT_STREAM,
+    ARIB_STD_B25_ERROR_NO_PAT_IN_HEAD_16M,
+    ARIB_STD_B25_ERROR_NO_PMT_IN_HEAD_32M,
+    ARIB_STD_B25_ERROR_NO_ECM_IN_HEAD_32M,
+    ARIB_STD_B25_ERROR_EMPTY_B_CAS_CARD,
+    ARIB_STD_B25_ERROR_INVALID_B_CAS_STATUS,
+    ARIB_STD_B25_ERROR_ECM_PROC_FAILURE,
+    ARIB_STD_B25_ERROR_DECRYPT_FAILURE,
+    ARIB_STD_B25_ERROR_PAT_PARSE_FAILURE,
+    ARIB_STD_B25_ERROR_PMT_PARSE_FAILURE,
+    ARIB_STD_B25_ERROR_ECM_PARSE_FAILURE,
+    ARIB_STD_B25_ERROR_CAT_PARSE_FAILURE,
+    ARIB_STD_B25_ERROR_EMM_PARSE_FAILURE,
+    ARIB_STD_B25_ERROR_EMM_PROC_FAILURE,
+
+    ARIB_STD_B25_WARN_UNPURCHASED_ECM,
+    ARIB_STD_B25_WARN_TS_SECTION_ID_MISSMATCH,
+    ARIB_STD_B25_WARN_BROKEN_TS_SECTION,
+    ARIB_STD_B25_WARN_PAT_NOT_COMPLETE,
+    ARIB_STD_B25_WARN_PMT_NOT_COMPLETE,
+    ARIB_STD_B25_WARN_ECM_NOT_COMPLETE,
+    /// A code the decoder is not known to return.
+    Unknown(i32),
+}
+
+impl AribB25DecoderError {
+    /// Returns the code of libaribb25.
+    pub fn code(&self) -> i32 {
+        type E = AribB25DecoderError;
+        match *self {
+            E::ARIB_STD_B25_ERROR_INVALID_PARAM => -1,
+            E::ARIB_STD_B25_ERROR_NO_ENOUGH_MEMORY => -2,
+            E::ARIB_STD_B25_ERROR_NON_TS_INPUT_STREAM => -3,
+            E::ARIB_STD_B25_ERROR_NO_PAT_IN_HEAD_16M => -4,
+            E::ARIB_STD_B25_ERROR_NO_PMT_IN_HEAD_32M => -5,
+            E::ARIB_STD_B25_ERROR_NO_ECM_IN_HEAD_32M => -6,
+            E::ARIB_STD_B25_ERROR_EMPTY_B_CAS_CARD => -7,
+            E::ARIB_STD_B25_ERROR_INVALID_B_CAS_STATUS => -8,
+            E::ARIB_STD_B25_ERROR_ECM_PROC_FAILURE => -9,
+            E::ARIB_STD_B25_ERROR_DECRYPT_FAILURE => -10,
+            E::ARIB_STD_B25_ERROR_PAT_PARSE_FAILURE => -11,
+            E::ARIB_STD_B25_ERROR_PMT_PARSE_FAILURE => -12,
+            E::ARIB_STD_B25_ERROR_ECM_PARSE_FAILURE => -13,
+            E::ARIB_STD_B25_ERROR_CAT_PARSE_FAILURE => -14,
+            E::ARIB_STD_B25_ERROR_EMM_PARSE_FAILURE => -15,
+            E::ARIB_STD_B25_ERROR_EMM_PROC_FAILURE => -16,
+            E::ARIB_STD_B25_WARN_UNPURCHASED_ECM => 1,
+            E::ARIB_STD_B25_WARN_TS_SECTION_ID_MISSMATCH => 2,
+            E::ARIB_STD_B25_WARN_BROKEN_TS_SECTION => 3,
+            E::ARIB_STD_B25_WARN_PAT_NOT_COMPLETE => 4,
+            E::ARIB_STD_B25_WARN_PMT_NOT_COMPLETE => 5,
+            E::ARIB_STD_B25_WARN_ECM_NOT_COMPLETE => 6,
+            E::Unknown(code) => code,
+        }
+    }
 }
 
 impl From<i32> for AribB25DecoderError {
@@ -79,7 +113,7 @@ impl From<i32> for AribB25DecoderError {
         } else if e == 6 {
             AribB25DecoderError::ARIB_STD_B25_WARN_ECM_NOT_COMPLETE
         } else {
-            panic!("unknown error code: {}", e)
+            AribB25DecoderError::Unknown(e)
         }
     }
 }
@@ -151,6 +185,7 @@ impl Display for AribB25DecoderError {
             E::ARIB_STD_B25_WARN_ECM_NOT_COMPLETE => {
                 write!(f, "ARIB_STD_B25_WARN_ECM_NOT_COMPLETE")
             }
+            E::Unknown(code) => write!(f, "Unknown decoder error ({})", code),
         }
     }
 }
@@ -158,14 +193,149 @@ impl Display for AribB25DecoderError {
 impl std::error::Error for AribB25DecoderError {}
 impl std::error::Error for BCasCardError {}
 
+/// An error of the decoder, classified by what has failed.
+#[derive(Debug, Clone)]
+pub enum DecoderError {
+    /// A section could not be parsed or a packet could not be decrypted.
+    /// The decoder skips it and continues with the following packets.
+    Recoverable(AribB25DecoderError),
+    /// The input is not a stream the decoder can process.
+    Stream(AribB25DecoderError),
+    /// The B-CAS card is missing or failed to process an ECM/EMM.
+    Card(AribB25DecoderError),
+    /// The card reader could not be opened or talked to.
+    CardReader(BCasCardError),
+    /// An invalid parameter, an allocation failure or an unknown error inside
+    /// the decoder.
+    Internal(AribB25DecoderError),
+}
+
+impl DecoderError {
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, DecoderError::Recoverable(_))
+    }
+
+    pub fn is_card_fault(&self) -> bool {
+        matches!(self, DecoderError::Card(_) | DecoderError::CardReader(_))
+    }
+
+    pub fn is_stream_fault(&self) -> bool {
+        matches!(self, DecoderError::Recoverable(_) | DecoderError::Stream(_))
+    }
+
+    /// Returns the `DecoderError` carried by an `io::Error` of `StreamDecoder`.
+    pub fn from_io(e: &std::io::Error) -> Option<&Self> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl From<AribB25DecoderError> for DecoderError {
+    fn from(e: AribB25DecoderError) -> Self {
+        type E = AribB25DecoderError;
+        match e {
+            E::ARIB_STD_B25_ERROR_DECRYPT_FAILURE
+            | E::ARIB_STD_B25_ERROR_PAT_PARSE_FAILURE
+            | E::ARIB_STD_B25_ERROR_PMT_PARSE_FAILURE
+            | E::ARIB_STD_B25_ERROR_ECM_PARSE_FAILURE
+            | E::ARIB_STD_B25_ERROR_CAT_PARSE_FAILURE
+            | E::ARIB_STD_B25_ERROR_EMM_PARSE_FAILURE => DecoderError::Recoverable(e),
+            E::ARIB_STD_B25_ERROR_NON_TS_INPUT_STREAM
+            | E::ARIB_STD_B25_ERROR_NO_PAT_IN_HEAD_16M
+            | E::ARIB_STD_B25_ERROR_NO_PMT_IN_HEAD_32M
+            | E::ARIB_STD_B25_ERROR_NO_ECM_IN_HEAD_32M => DecoderError::Stream(e),
+            E::ARIB_STD_B25_ERROR_EMPTY_B_CAS_CARD
+            | E::ARIB_STD_B25_ERROR_INVALID_B_CAS_STATUS
+            | E::ARIB_STD_B25_ERROR_ECM_PROC_FAILURE
+            | E::ARIB_STD_B25_ERROR_EMM_PROC_FAILURE => DecoderError::Card(e),
+            // Including the codes the decoder is not known to return
+            _ => DecoderError::Internal(e),
+        }
+    }
+}
+
+impl From<BCasCardError> for DecoderError {
+    fn from(e: BCasCardError) -> Self {
+        DecoderError::CardReader(e)
+    }
+}
+
+impl Display for DecoderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecoderError::Recoverable(e) => write!(f, "Recoverable stream error ({})", e),
+            DecoderError::Stream(e) => write!(f, "Stream error ({})", e),
+            DecoderError::Card(e) => write!(f, "Card error ({})", e),
+            DecoderError::CardReader(e) => write!(f, "Card reader error ({})", e),
+            DecoderError::Internal(e) => write!(f, "Decoder error ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for DecoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecoderError::CardReader(e) => Some(e),
+            DecoderError::Recoverable(e)
+            | DecoderError::Stream(e)
+            | DecoderError::Card(e)
+            | DecoderError::Internal(e) => Some(e),
+        }
+    }
+}
+
+/// A warning the decoder reports while it keeps processing the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoderWarning {
+    /// The card refused an ECM because the program is not purchased.
+    UnpurchasedEcm,
+    SectionIdMismatch,
+    BrokenSection,
+    /// The PAT has not been received yet, as usual at the start of a stream.
+    PatNotComplete,
+    PmtNotComplete,
+    EcmNotComplete,
+}
+
+impl DecoderWarning {
+    pub(crate) fn from_code(code: i32) -> Option<Self> {
+        match code {
+            1 => Some(DecoderWarning::UnpurchasedEcm),
+            2 => Some(DecoderWarning::SectionIdMismatch),
+            3 => Some(DecoderWarning::BrokenSection),
+            4 => Some(DecoderWarning::PatNotComplete),
+            5 => Some(DecoderWarning::PmtNotComplete),
+            6 => Some(DecoderWarning::EcmNotComplete),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum BCasCardError {
-    BCAS_CARD_ERROR_INVALID_PARAMETER = -1,
-    BCAS_CARD_ERROR_NOT_INITIALIZED = -2,
-    BCAS_CARD_ERROR_NO_SMART_CARD_READER = -3,
-    BCAS_CARD_ERROR_ALL_READERS_CONNECTION_FAILED = -4,
-    BCAS_CARD_ERROR_NO_ENOUGH_MEMORY = -5,
-    BCAS_CARD_ERROR_TRANSMIT_FAILED = -6,
+    BCAS_CARD_ERROR_INVALID_PARAMETER,
+    BCAS_CARD_ERROR_NOT_INITIALIZED,
+    BCAS_CARD_ERROR_NO_SMART_CARD_READER,
+    BCAS_CARD_ERROR_ALL_READERS_CONNECTION_FAILED,
+    BCAS_CARD_ERROR_NO_ENOUGH_MEMORY,
+    BCAS_CARD_ERROR_TRANSMIT_FAILED,
+    /// A code the card reader is not known to return.
+    Unknown(i32),
+}
+
+impl BCasCardError {
+    /// Returns the code of the card reader of libaribb25.
+    pub fn code(&self) -> i32 {
+        type E = BCasCardError;
+        match *self {
+            E::BCAS_CARD_ERROR_INVALID_PARAMETER => -1,
+            E::BCAS_CARD_ERROR_NOT_INITIALIZED => -2,
+            E::BCAS_CARD_ERROR_NO_SMART_CARD_READER => -3,
+            E::BCAS_CARD_ERROR_ALL_READERS_CONNECTION_FAILED => -4,
+            E::BCAS_CARD_ERROR_NO_ENOUGH_MEMORY => -5,
+            E::BCAS_CARD_ERROR_TRANSMIT_FAILED => -6,
+            E::Unknown(code) => code,
+        }
+    }
 }
 
 impl From<i32> for BCasCardError {
@@ -183,7 +353,7 @@ impl From<i32> for BCasCardError {
         } else if e == -6 {
             BCasCardError::BCAS_CARD_ERROR_TRANSMIT_FAILED
         } else {
-            panic!("unknown error code: {}", e)
+            BCasCardError::Unknown(e)
         }
     }
 }
@@ -210,6 +380,27 @@ impl Display for BCasCardError {
             E::BCAS_CARD_ERROR_TRANSMIT_FAILED => {
                 write!(f, "BCAS_CARD_ERROR_TRANSMIT_FAILED")
             }
+            E::Unknown(code) => write!(f, "Unknown card reader error ({})", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_error_codes() {
+        for code in (-16..=6).filter(|&code| code != 0) {
+            assert_eq!(AribB25DecoderError::from(code).code(), code);
         }
+        // An unknown code is an internal error of the decoder
+        let e = AribB25DecoderError::from(-100);
+        assert_eq!(e.code(), -100);
+        assert_eq!(e.to_string(), "Unknown decoder error (-100)");
+        assert!(matches!(
+            DecoderError::from(e),
+            DecoderError::Internal(AribB25DecoderError::Unknown(-100))
+        ));
     }
 }
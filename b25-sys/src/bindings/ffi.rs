@@ -1,190 +1,275 @@
-use std::ptr::null_mut;
+use std::os::raw::{c_int, c_void};
+use std::ptr::{null_mut, NonNull};
 
-use crate::access_control::select_key_by_mac;
-use log::{info, warn};
+use log::debug;
 
 use crate::bindings::arib_std_b25::{
-    B_CAS_CARD, B_CAS_ECM_RESULT, B_CAS_ID, B_CAS_INIT_STATUS, B_CAS_PWR_ON_CTRL,
-    B_CAS_PWR_ON_CTRL_INFO,
+    create_b_cas_card, B_CAS_CARD, B_CAS_ECM_RESULT, B_CAS_ID, B_CAS_INIT_STATUS,
+    B_CAS_PWR_ON_CTRL, B_CAS_PWR_ON_CTRL_INFO,
 };
+use crate::{BCasCardError, CardStatus, CasCard, EcmResult, PowerOnControl};
 
-// B_CAS_CARD_PRIVATE_DATA is defined only inside b_cas_card.c and not exposed in
-// any public header, so bindgen cannot generate it. In the block00cbc path, the
-// Rust side constructs B_CAS_CARD and manages private_data on its own, so we
-// define the corresponding struct here.
-#[repr(C)]
-struct B_CAS_CARD_PRIVATE_DATA {
-    mng: i32,
-    card: i32,
-    pool: *mut ::std::os::raw::c_void,
-    reader: *const ::std::os::raw::c_char,
-    sbuf: *mut ::std::os::raw::c_void,
-    rbuf: *mut ::std::os::raw::c_void,
-    stat: B_CAS_INIT_STATUS,
-    id: B_CAS_ID,
-    id_max: i32,
-    pwc: B_CAS_PWR_ON_CTRL_INFO,
-    pwc_max: i32,
-    acas: i32,
+// The private data of a `B_CAS_CARD` calling a `CasCard`.
+struct Adapter {
+    card: Box<dyn CasCard>,
+    // libaribb25 keeps the pointers to them until the next call
+    ids: Vec<i64>,
+    pwc: Vec<B_CAS_PWR_ON_CTRL>,
+}
+
+unsafe fn adapter<'a>(bcas: *mut c_void) -> &'a mut Adapter {
+    &mut *((*(bcas as *mut B_CAS_CARD)).private_data as *mut Adapter)
 }
 
 // Overrides the functions of the struct `B_CAS_CARD`
 
-#[no_mangle]
-unsafe extern "C" fn release(bcas: *mut ::std::os::raw::c_void) {
-    //free private data manually
-    let _ =
-        Box::from_raw((*(bcas as *mut B_CAS_CARD)).private_data as *mut B_CAS_CARD_PRIVATE_DATA);
+unsafe extern "C" fn release(bcas: *mut c_void) {
+    let bcas = bcas as *mut B_CAS_CARD;
+    if !(*bcas).private_data.is_null() {
+        drop(Box::from_raw((*bcas).private_data as *mut Adapter));
+        (*bcas).private_data = null_mut();
+    }
+}
+
+unsafe extern "C" fn init(bcas: *mut c_void) -> c_int {
+    match adapter(bcas).card.init() {
+        Ok(()) => 0,
+        Err(e) => e.code(),
+    }
 }
 
-const DEFAULT_NAME: &[u8] = b"b25-sys\0";
-impl Default for B_CAS_CARD_PRIVATE_DATA {
-    fn default() -> Self {
-        B_CAS_CARD_PRIVATE_DATA {
-            mng: 0,
-            card: 0,
-            pool: null_mut(),
-            reader: DEFAULT_NAME.as_ptr() as *const ::std::os::raw::c_char,
-            sbuf: null_mut(),
-            rbuf: null_mut(),
-            stat: B_CAS_INIT_STATUS {
-                system_key: [
-                    0x36, 0x31, 0x04, 0x66, 0x4b, 0x17, 0xea, 0x5c, 0x32, 0xdf, 0x9c, 0xf5, 0xc4,
-                    0xc3, 0x6c, 0x1b, 0xec, 0x99, 0x39, 0x21, 0x68, 0x9d, 0x4b, 0xb7, 0xb7, 0x4e,
-                    0x40, 0x84, 0x0d, 0x2e, 0x7d, 0x98,
-                ],
-                init_cbc: [0xfe, 0x27, 0x19, 0x99, 0x19, 0x69, 0x09, 0x11],
-                bcas_card_id: 0xfe2719991969091,
-                card_status: 0,
-                ca_system_id: 5,
-            },
-            id: B_CAS_ID {
-                data: &mut [0i64; 1] as *mut _,
-                count: 1,
-            },
-            id_max: 0,
-            pwc: B_CAS_PWR_ON_CTRL_INFO {
-                data: &mut B_CAS_PWR_ON_CTRL {
-                    s_yy: 0,
-                    s_mm: 0,
-                    s_dd: 0,
-                    l_yy: 0,
-                    l_mm: 0,
-                    l_dd: 0,
-                    hold_time: 0,
-                    broadcaster_group_id: 0,
-                    network_id: 0,
-                    transport_id: 0,
-                },
-                count: 0,
-            },
-            pwc_max: 0,
-            acas: -1,
+unsafe extern "C" fn get_init_status(bcas: *mut c_void, stat: *mut B_CAS_INIT_STATUS) -> c_int {
+    match adapter(bcas).card.init_status() {
+        Ok(status) => {
+            *stat = B_CAS_INIT_STATUS {
+                system_key: status.system_key,
+                init_cbc: status.init_cbc,
+                bcas_card_id: status.card_id,
+                card_status: status.card_status,
+                ca_system_id: status.ca_system_id,
+            };
+            0
         }
+        Err(e) => e.code(),
     }
 }
-#[no_mangle]
-unsafe extern "C" fn init(bcas: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
-    (*(bcas as *mut B_CAS_CARD)).private_data =
-        Box::into_raw(Box::new(B_CAS_CARD_PRIVATE_DATA::default())) as *mut _;
-    0
+
+unsafe extern "C" fn get_id(bcas: *mut c_void, dst: *mut B_CAS_ID) -> c_int {
+    let adapter = adapter(bcas);
+    match adapter.card.card_ids() {
+        Ok(ids) => {
+            adapter.ids = ids;
+            (*dst).data = adapter.ids.as_mut_ptr();
+            (*dst).count = adapter.ids.len() as i32;
+            0
+        }
+        Err(e) => e.code(),
+    }
 }
 
-#[no_mangle]
-unsafe extern "C" fn get_init_status(
-    bcas: *mut ::std::os::raw::c_void,
-    stat: *mut B_CAS_INIT_STATUS,
-) -> ::std::os::raw::c_int {
-    let prv = (*(bcas as *mut B_CAS_CARD)).private_data as *mut B_CAS_CARD_PRIVATE_DATA;
-    *stat = (*prv).stat; //Copy the status
-    0
+unsafe extern "C" fn get_pwr_on_ctrl(bcas: *mut c_void, dst: *mut B_CAS_PWR_ON_CTRL_INFO) -> c_int {
+    let adapter = adapter(bcas);
+    match adapter.card.power_on_control() {
+        Ok(pwc) => {
+            adapter.pwc = pwc
+                .iter()
+                .map(|p| B_CAS_PWR_ON_CTRL {
+                    s_yy: p.start_year,
+                    s_mm: p.start_month,
+                    s_dd: p.start_day,
+                    l_yy: p.limit_year,
+                    l_mm: p.limit_month,
+                    l_dd: p.limit_day,
+                    hold_time: p.hold_time,
+                    broadcaster_group_id: p.broadcaster_group_id,
+                    network_id: p.network_id,
+                    transport_id: p.transport_id,
+                })
+                .collect();
+            (*dst).data = adapter.pwc.as_mut_ptr();
+            (*dst).count = adapter.pwc.len() as i32;
+            0
+        }
+        Err(e) => e.code(),
+    }
 }
 
-#[no_mangle]
 unsafe extern "C" fn proc_ecm(
-    _bcas: *mut ::std::os::raw::c_void,
+    bcas: *mut c_void,
     dst: *mut B_CAS_ECM_RESULT,
     src: *mut u8,
-    len: ::std::os::raw::c_int,
-) -> ::std::os::raw::c_int {
-    let mut payload = {
-        let recv = &*std::ptr::slice_from_raw_parts(src, len as usize);
-        recv.to_vec()
-    };
-
-    let ks = {
-        let size = payload.len();
-        if size < 19 {
-            Err(())
-        } else {
-            match select_key_by_mac(&mut payload) {
-                Some(key) => {
-                    #[cfg(debug_assertions)]
-                    info!("Selected Kw= {:?}", key);
-                    Ok(&payload[3..19])
-                }
-                None => {
-                    warn!("No valid key found");
-                    Err(())
-                }
-            }
+    len: c_int,
+) -> c_int {
+    let ecm = std::slice::from_raw_parts(src, len as usize);
+    match adapter(bcas).card.proc_ecm(ecm) {
+        Ok(result) => {
+            (*dst).scramble_key = result.scramble_key;
+            (*dst).return_code = result.return_code;
+            0
         }
-    };
+        Err(e) => e.code(),
+    }
+}
 
-    if let Ok(result) = ks {
-        std::ptr::copy_nonoverlapping(
-            result.as_ptr(),
-            (*dst).scramble_key.as_mut_ptr(),
-            result.len(),
-        );
+unsafe extern "C" fn proc_emm(bcas: *mut c_void, src: *mut u8, len: c_int) -> c_int {
+    let emm = std::slice::from_raw_parts(src, len as usize);
+    match adapter(bcas).card.proc_emm(emm) {
+        Ok(()) => 0,
+        Err(e) => e.code(),
     }
-    (*dst).return_code = 0x0800;
-    0
 }
 
-#[no_mangle]
-unsafe extern "C" fn get_id(
-    bcas: *mut ::std::os::raw::c_void,
-    dst: *mut B_CAS_ID,
-) -> ::std::os::raw::c_int {
-    let prv = (*(bcas as *mut B_CAS_CARD)).private_data as *mut B_CAS_CARD_PRIVATE_DATA;
-    (*dst).data = (*prv).id.data;
-    (*dst).count = (*prv).id.count;
-    0
+/// Wraps the card into a `B_CAS_CARD` for libaribb25, which releases the card
+/// when it is dropped.
+pub(crate) fn into_b_cas_card(card: Box<dyn CasCard>) -> Box<B_CAS_CARD> {
+    let adapter = Box::new(Adapter {
+        card,
+        ids: Vec::new(),
+        pwc: Vec::new(),
+    });
+    Box::new(B_CAS_CARD {
+        private_data: Box::into_raw(adapter) as *mut c_void,
+        release: Some(release),
+        init: Some(init),
+        get_init_status: Some(get_init_status),
+        get_id: Some(get_id),
+        get_pwr_on_ctrl: Some(get_pwr_on_ctrl),
+        proc_ecm: Some(proc_ecm),
+        proc_emm: Some(proc_emm),
+        // ACAS support is not planned for recisdb at this time
+        set_acas_mode: None,
+    })
 }
 
-unsafe extern "C" fn get_pwr_on_ctrl(
-    bcas: *mut ::std::os::raw::c_void,
-    dst: *mut B_CAS_PWR_ON_CTRL_INFO,
-) -> ::std::os::raw::c_int {
-    let prv = (*(bcas as *mut B_CAS_CARD)).private_data as *mut B_CAS_CARD_PRIVATE_DATA;
-    (*dst).data = (*prv).pwc.data;
-    (*dst).count = (*prv).pwc.count;
-    0
+/// Returns the card wrapped by `into_b_cas_card`.
+pub(crate) fn cas_card(bcas: &mut B_CAS_CARD) -> &mut dyn CasCard {
+    unsafe { &mut *adapter(bcas as *mut B_CAS_CARD as *mut c_void).card }
 }
 
-#[no_mangle]
-unsafe extern "C" fn proc_emm(
-    bcas: *mut ::std::os::raw::c_void,
-    src: *mut u8,
-    len: ::std::os::raw::c_int,
-) -> ::std::os::raw::c_int {
-    0
+fn check(code: c_int) -> Result<(), BCasCardError> {
+    if code < 0 {
+        Err(BCasCardError::from(code))
+    } else {
+        Ok(())
+    }
 }
 
-impl Default for B_CAS_CARD {
-    fn default() -> Self {
-        B_CAS_CARD {
-            private_data: null_mut(),
-            release: Some(release),
-            init: Some(init),
-            get_init_status: Some(get_init_status),
-            get_id: Some(get_id),
-            get_pwr_on_ctrl: Some(get_pwr_on_ctrl),
-            proc_ecm: Some(proc_ecm),
-            proc_emm: Some(proc_emm),
-            // ACAS support is not planned for recisdb at this time
-            set_acas_mode: None,
+/// The card in a PC/SC reader, accessed by libaribb25.
+pub struct PcscCard(NonNull<B_CAS_CARD>);
+
+// The card is owned by `PcscCard` alone.
+unsafe impl Send for PcscCard {}
+
+impl PcscCard {
+    pub fn new() -> Result<Self, BCasCardError> {
+        NonNull::new(unsafe { create_b_cas_card() })
+            .map(PcscCard)
+            .ok_or(BCasCardError::BCAS_CARD_ERROR_NO_ENOUGH_MEMORY)
+    }
+
+    fn this(&self) -> *mut c_void {
+        self.0.as_ptr() as *mut c_void
+    }
+
+    fn vtable(&self) -> &B_CAS_CARD {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl CasCard for PcscCard {
+    fn init(&mut self) -> Result<(), BCasCardError> {
+        check(unsafe { self.vtable().init.unwrap()(self.this()) })
+    }
+
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError> {
+        let mut stat = B_CAS_INIT_STATUS {
+            system_key: [0; 32],
+            init_cbc: [0; 8],
+            bcas_card_id: 0,
+            card_status: 0,
+            ca_system_id: 0,
+        };
+        check(unsafe { self.vtable().get_init_status.unwrap()(self.this(), &mut stat) })?;
+        Ok(CardStatus {
+            system_key: stat.system_key,
+            init_cbc: stat.init_cbc,
+            card_id: stat.bcas_card_id,
+            card_status: stat.card_status,
+            ca_system_id: stat.ca_system_id,
+        })
+    }
+
+    fn card_ids(&mut self) -> Result<Vec<i64>, BCasCardError> {
+        let mut id = B_CAS_ID {
+            data: null_mut(),
+            count: 0,
+        };
+        unsafe {
+            check(self.vtable().get_id.unwrap()(self.this(), &mut id))?;
+            if id.data.is_null() {
+                return Ok(Vec::new());
+            }
+            Ok(std::slice::from_raw_parts(id.data, id.count as usize).to_vec())
+        }
+    }
+
+    fn power_on_control(&mut self) -> Result<Vec<PowerOnControl>, BCasCardError> {
+        let mut info = B_CAS_PWR_ON_CTRL_INFO {
+            data: null_mut(),
+            count: 0,
+        };
+        unsafe {
+            check(self.vtable().get_pwr_on_ctrl.unwrap()(
+                self.this(),
+                &mut info,
+            ))?;
+            if info.data.is_null() {
+                return Ok(Vec::new());
+            }
+            Ok(std::slice::from_raw_parts(info.data, info.count as usize)
+                .iter()
+                .map(|p| PowerOnControl {
+                    start_year: p.s_yy,
+                    start_month: p.s_mm,
+                    start_day: p.s_dd,
+                    limit_year: p.l_yy,
+                    limit_month: p.l_mm,
+                    limit_day: p.l_dd,
+                    hold_time: p.hold_time,
+                    broadcaster_group_id: p.broadcaster_group_id,
+                    network_id: p.network_id,
+                    transport_id: p.transport_id,
+                })
+                .collect())
         }
     }
+
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError> {
+        let mut res = B_CAS_ECM_RESULT {
+            scramble_key: [0; 16],
+            return_code: 0,
+        };
+        // libaribb25 does not write to the source
+        let src = ecm.as_ptr() as *mut u8;
+        check(unsafe {
+            self.vtable().proc_ecm.unwrap()(self.this(), &mut res, src, ecm.len() as c_int)
+        })?;
+        Ok(EcmResult {
+            scramble_key: res.scramble_key,
+            return_code: res.return_code,
+        })
+    }
+
+    fn proc_emm(&mut self, emm: &[u8]) -> Result<(), BCasCardError> {
+        let src = emm.as_ptr() as *mut u8;
+        check(unsafe { self.vtable().proc_emm.unwrap()(self.this(), src, emm.len() as c_int) })
+    }
+}
+
+impl Drop for PcscCard {
+    fn drop(&mut self) {
+        debug!("Releasing the PC/SC card...");
+        // libaribb25 frees the struct itself
+        unsafe { self.vtable().release.unwrap()(self.this()) }
+    }
 }
@@ -4,15 +4,27 @@ use std::io::{Error, ErrorKind, Read, Write};
 use std::ptr::null_mut;
 use std::ptr::NonNull;
 
-use crate::bindings::arib_std_b25::{ARIB_STD_B25, ARIB_STD_B25_BUFFER, B_CAS_CARD};
-use crate::bindings::error::AribB25DecoderError;
+use crate::bindings::arib_std_b25::{
+    ARIB_STD_B25, ARIB_STD_B25_BUFFER, ARIB_STD_B25_PROGRAM_INFO, B_CAS_CARD,
+};
+use crate::bindings::descrambler::{Descrambler, EcmKeys};
+use crate::bindings::emm::EmmProcessor;
+use crate::bindings::error::{AribB25DecoderError, DecoderError, DecoderWarning};
+use crate::multi2::Simd;
+use crate::CasCard;
+use crate::EmmStats;
+use crate::ProgramStats;
+use crate::WarningCallback;
 
 mod arib_std_b25;
-mod error;
+pub(crate) mod descrambler;
+mod emm;
+pub(crate) mod error;
 
-#[cfg(feature = "block00cbc")]
 mod ffi;
 
+pub use self::ffi::PcscCard;
+
 extern "C" {
     #[cfg(feature = "prioritized_card_reader")]
     pub(crate) fn override_card_reader_name_pattern(
@@ -26,6 +38,8 @@ pin_project! {
         pub dec: NonNull<ARIB_STD_B25>,
         #[pin]
         cas: Option<Box<B_CAS_CARD>>,
+        descrambler: Option<Descrambler>,
+        emm: Option<EmmProcessor>,
         // Buffer for excess decoded data that could not fit in the caller's
         // buf during a previous read() call.
         // The C-side get() returns all accumulated decoder output at once and
@@ -34,11 +48,14 @@ pin_project! {
         // subsequent read() calls to avoid data loss.
         pending_data: Vec<u8>,
         pending_offset: usize,
+        pub on_warning: Option<WarningCallback>,
     }
     impl PinnedDrop for InnerDecoder {
         fn drop(this: Pin<&mut Self>) {
-            //Release the decoder instance
-            if let Some(cas) = this.get_mut().cas.take() {
+            let this = this.get_mut();
+            //Release the decoder instance, before the card it refers to
+            unsafe { this.dec.as_mut().release() };
+            if let Some(cas) = this.cas.take() {
                 drop(cas)
             }
 
@@ -47,59 +64,123 @@ pin_project! {
     }
 }
 
-impl InnerDecoder {
-    #[allow(unused_variables)]
-    pub(crate) unsafe fn new(key: bool) -> Result<Self, Error> {
-        let dec = arib_std_b25::create_arib_std_b25();
-
-        #[cfg(feature = "block00cbc")]
-        if key {
-            return Self::new_with_key(dec);
-        }
-        Self::new_without_key(dec)
-    }
+// The decoder and the card are owned by `InnerDecoder` alone, and libaribb25
+// keeps no thread-local state, so they can be used from another thread.
+unsafe impl Send for InnerDecoder {}
 
-    #[cfg(feature = "block00cbc")]
-    unsafe fn new_with_key(dec: *mut ARIB_STD_B25) -> Result<Self, Error> {
-        let mut cas = B_CAS_CARD::default();
-        //Allocate private data inside B_CAS_CARD
+impl InnerDecoder {
+    /// Creates a decoder with the card. The answers of the card to the ECMs
+    /// are recorded in `keys`, if any, for `use_rust_multi2`.
+    pub(crate) unsafe fn new(
+        card: Box<dyn CasCard>,
+        keys: Option<&EcmKeys>,
+    ) -> Result<Self, Error> {
+        let card = match keys {
+            Some(keys) => keys.tap(card),
+            None => card,
+        };
+        let mut cas = ffi::into_b_cas_card(card);
+        // Initialize the CAS card, before the decoder which would be leaked
+        // on failure
         cas.initialize()?;
-        let ret = Self {
+        let dec = arib_std_b25::create_arib_std_b25();
+        (*dec).set_b_cas_card(&cas);
+        Ok(Self {
             dec: NonNull::new(dec).unwrap(),
-            cas: Some(Box::new(cas)),
+            cas: Some(cas),
+            descrambler: None,
+            emm: None,
             pending_data: Vec::new(),
             pending_offset: 0,
-        };
-        ret.dec.as_ref().set_b_cas_card(ret.cas.as_ref().unwrap());
-        Ok(ret)
+            on_warning: None,
+        })
     }
-    unsafe fn new_without_key(dec: *mut ARIB_STD_B25) -> Result<Self, Error> {
-        let cas = arib_std_b25::create_b_cas_card();
-        if cas.is_null() {
-            Err(Error::new(
-                ErrorKind::Other,
-                AribB25DecoderError::ARIB_STD_B25_ERROR_EMPTY_B_CAS_CARD,
-            ))
-        } else {
-            // Initialize the CAS card
-            (*cas).initialize()?;
-            (*dec).set_b_cas_card(&*cas);
-            Ok(Self {
-                dec: NonNull::new(dec).unwrap(),
-                cas: None,
-                pending_data: Vec::new(),
-                pending_offset: 0,
-            })
+}
+
+impl InnerDecoder {
+    fn card(&mut self) -> &mut dyn CasCard {
+        ffi::cas_card(self.cas.as_mut().unwrap())
+    }
+
+    /// Descrambles the packets with MULTI2 in Rust before libaribb25, which
+    /// then descrambles only those left scrambled. The scramble keys are those
+    /// recorded in `keys` given to `new`.
+    pub(crate) fn use_rust_multi2(
+        &mut self,
+        round: i32,
+        simd: Simd,
+        keys: EcmKeys,
+    ) -> Result<(), Error> {
+        let status = self
+            .card()
+            .init_status()
+            .map_err(|e| Error::new(ErrorKind::NotConnected, DecoderError::from(e)))?;
+        self.descrambler = Some(Descrambler::new(&status, round, simd, keys));
+        Ok(())
+    }
+
+    /// Passes the EMMs addressed to the card to it.
+    pub(crate) fn use_rust_emm(&mut self) -> Result<(), Error> {
+        let ids = self
+            .card()
+            .card_ids()
+            .map_err(|e| Error::new(ErrorKind::NotConnected, DecoderError::from(e)))?;
+        self.emm = Some(EmmProcessor::new(&ids));
+        Ok(())
+    }
+
+    pub(crate) fn emm_stats(&self) -> Vec<EmmStats> {
+        self.emm.as_ref().map(|emm| emm.stats()).unwrap_or_default()
+    }
+
+    /// Passes a warning code of libaribb25 to the callback, if any.
+    fn report_warning(&mut self, code: i32) {
+        if let (Some(callback), Some(warning)) =
+            (self.on_warning.as_mut(), DecoderWarning::from_code(code))
+        {
+            callback(warning)
         }
     }
+
+    /// Collects the statistics of the programs known to the decoder.
+    pub(crate) fn program_stats(&self) -> Vec<ProgramStats> {
+        let dec = unsafe { self.dec.as_ref() };
+        (0..dec.get_program_count())
+            .filter_map(|idx| {
+                let mut info = ARIB_STD_B25_PROGRAM_INFO {
+                    program_number: 0,
+                    ecm_unpurchased_count: 0,
+                    last_ecm_error_code: 0,
+                    padding: 0,
+                    total_packet_count: 0,
+                    undecrypted_packet_count: 0,
+                };
+                if dec.get_program_info(&mut info, idx) != 0 {
+                    return None;
+                }
+                Some(ProgramStats {
+                    program_number: info.program_number as u16,
+                    total_packets: info.total_packet_count as u64,
+                    undecrypted_packets: info.undecrypted_packet_count as u64,
+                    unpurchased_ecm_count: info.ecm_unpurchased_count as u32,
+                    last_ecm_error: Some(info.last_ecm_error_code).filter(|&code| code != 0),
+                })
+            })
+            .collect()
+    }
 }
 
 impl Write for InnerDecoder {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let descrambled = self.descrambler.as_mut().map(|d| d.process(buf));
+        let data = descrambled.as_deref().unwrap_or(buf);
+        if let Some(emm) = self.emm.as_mut() {
+            emm.process(data, ffi::cas_card(self.cas.as_mut().unwrap()));
+        }
         let code = unsafe {
             let buffer_struct = ARIB_STD_B25_BUFFER {
-                data: std::mem::transmute::<*const u8, *mut u8>(buf.as_ptr()),
-                size: buf.len() as u32,
+                data: std::mem::transmute::<*const u8, *mut u8>(data.as_ptr()),
+                size: data.len() as u32,
             };
             self.dec.as_ref().put(&buffer_struct)
         };
@@ -111,18 +192,31 @@ impl Write for InnerDecoder {
                 // if greater than 0, it means that the decoder emitted some warnings.
                 // if less than 0, it means that the decoder emitted some errors.
                 if code > 0 {
+                    self.report_warning(code);
                     // suppress warning (The NOT_COMPLETE error is generated at the time of initial reception because of the specification)
                     // warn!("{}", err);
                     Ok(buf.len())
                 } else {
                     error!("{}", err);
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, err))
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        DecoderError::from(err),
+                    ))
                 }
             }
         }
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(carry) = self.descrambler.as_mut().map(|d| d.take_carry()) {
+            if !carry.is_empty() {
+                let buffer_struct = ARIB_STD_B25_BUFFER {
+                    data: carry.as_ptr() as *mut u8,
+                    size: carry.len() as u32,
+                };
+                unsafe { self.dec.as_ref().put(&buffer_struct) };
+            }
+        }
         let code = unsafe { self.dec.as_ref().flush() };
 
         match code {
@@ -132,11 +226,15 @@ impl Write for InnerDecoder {
                 // if greater than 0, it means that the decoder emitted some warnings.
                 // if less than 0, it means that the decoder emitted some errors.
                 if code > 0 {
+                    self.report_warning(code);
                     warn!("{}", err);
                     Ok(())
                 } else {
                     error!("{}", err);
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, err))
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        DecoderError::from(err),
+                    ))
                 }
             }
         }
@@ -219,11 +317,15 @@ impl Read for InnerDecoder {
                 // if greater than 0, it means that the decoder emitted some warnings.
                 // if less than 0, it means that the decoder emitted some errors.
                 if code > 0 {
+                    self.report_warning(code);
                     warn!("{}", err);
                     Ok(sz)
                 } else {
                     error!("{}", err);
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, err))
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        DecoderError::from(err),
+                    ))
                 }
             }
         }
@@ -0,0 +1,95 @@
+use crate::BCasCardError;
+
+/// The status of a card, read when it is initialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardStatus {
+    pub system_key: [u8; 32],
+    pub init_cbc: [u8; 8],
+    pub card_id: i64,
+    pub card_status: i32,
+    pub ca_system_id: i32,
+}
+
+/// The answer of a card to an ECM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcmResult {
+    /// The odd scramble key followed by the even one.
+    pub scramble_key: [u8; 16],
+    pub return_code: u32,
+}
+
+impl EcmResult {
+    /// Returns whether the card has decrypted the ECM, that is, the program
+    /// is purchased.
+    pub fn is_ok(&self) -> bool {
+        matches!(self.return_code, 0x0800 | 0x0400 | 0x0200)
+    }
+}
+
+/// A period in which the card asks to be powered on to receive its EMMs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PowerOnControl {
+    pub start_year: i32,
+    pub start_month: i32,
+    pub start_day: i32,
+    pub limit_year: i32,
+    pub limit_month: i32,
+    pub limit_day: i32,
+    /// In hours.
+    pub hold_time: i32,
+    pub broadcaster_group_id: i32,
+    pub network_id: i32,
+    pub transport_id: i32,
+}
+
+/// A B-CAS card used by the decoder.
+///
+/// `PcscCard`, the card in a PC/SC reader accessed by libaribb25, is the
+/// default. Any other implementation, such as a remote reader or a mock, can
+/// be set to `DecoderOptions::card`. The errors are those of the card reader
+/// of libaribb25, to which they are passed.
+pub trait CasCard: Send {
+    /// Connects to the card. Called once before the others.
+    fn init(&mut self) -> Result<(), BCasCardError>;
+
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError>;
+
+    /// Returns the ids of the card, the one in the status by default.
+    fn card_ids(&mut self) -> Result<Vec<i64>, BCasCardError> {
+        Ok(vec![self.init_status()?.card_id])
+    }
+
+    /// Returns the periods in which the card is to be powered on, none by
+    /// default.
+    fn power_on_control(&mut self) -> Result<Vec<PowerOnControl>, BCasCardError> {
+        Ok(Vec::new())
+    }
+
+    /// Decrypts the body of an ECM section, which follows the header of 8
+    /// bytes and excludes the CRC.
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError>;
+
+    /// Passes an EMM addressed to the card, from its card id to the end.
+    fn proc_emm(&mut self, emm: &[u8]) -> Result<(), BCasCardError>;
+}
+
+impl<T: CasCard + ?Sized> CasCard for Box<T> {
+    fn init(&mut self) -> Result<(), BCasCardError> {
+        (**self).init()
+    }
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError> {
+        (**self).init_status()
+    }
+    fn card_ids(&mut self) -> Result<Vec<i64>, BCasCardError> {
+        (**self).card_ids()
+    }
+    fn power_on_control(&mut self) -> Result<Vec<PowerOnControl>, BCasCardError> {
+        (**self).power_on_control()
+    }
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError> {
+        (**self).proc_ecm(ecm)
+    }
+    fn proc_emm(&mut self, emm: &[u8]) -> Result<(), BCasCardError> {
+        (**self).proc_emm(emm)
+    }
+}
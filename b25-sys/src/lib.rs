@@ -1,13 +1,35 @@
 use log::info;
-use std::cell::Cell;
-use std::io::{Error, Read, Write};
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 
+use crate::bindings::descrambler::EcmKeys;
 use crate::bindings::InnerDecoder;
 
-#[cfg(feature = "block00cbc")]
+pub use crate::access_control::types::EmmBody;
+pub use crate::bindings::error::{
+    AribB25DecoderError, BCasCardError, DecoderError, DecoderWarning,
+};
+pub use crate::bindings::PcscCard;
+pub use crate::card::{CardStatus, CasCard, EcmResult, PowerOnControl};
+
 mod access_control;
+#[cfg(feature = "async")]
+mod async_decoder;
 mod bindings;
+mod card;
+mod multi2;
+#[cfg(feature = "remote")]
+mod remote;
+#[cfg(test)]
+mod testing;
+
+#[cfg(feature = "async")]
+pub use crate::async_decoder::{AsyncStreamDecoder, LatencyStats};
+pub use crate::multi2::{Multi2, Parity, Simd};
+#[cfg(feature = "remote")]
+pub use crate::remote::{CardServer, RemoteCard, RemoteMetrics};
 
 static KEY0: Mutex<Vec<u64>> = Mutex::new(Vec::new());
 static KEY1: Mutex<Vec<u64>> = Mutex::new(Vec::new());
@@ -26,27 +48,95 @@ pub fn set_card_reader_name(name: &str) -> bool {
 }
 
 /// Decode ARIB-STD-B25 stream with libaribb25. Both `Read` and `Write` are implemented.
+/// The decoder is `Send` and `Sync`, so that it can be moved to another thread.
 pub struct StreamDecoder {
-    received: Cell<usize>,
-    sent: Cell<usize>,
+    received: AtomicUsize,
+    sent: AtomicUsize,
     inner: Mutex<InnerDecoder>,
 }
 impl Drop for StreamDecoder {
     fn drop(&mut self) {
         info!(
             "Decoder: {}B received, and {}B converted.",
-            self.received.get(),
-            self.sent.get()
+            self.received.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed)
         );
     }
 }
 
+/// Decryption statistics of a program, as counted by libaribb25.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramStats {
+    pub program_number: u16,
+    /// The number of the packets of the program.
+    pub total_packets: u64,
+    /// The number of the packets left scrambled.
+    pub undecrypted_packets: u64,
+    /// The number of the ECMs that the card refused as not purchased.
+    pub unpurchased_ecm_count: u32,
+    /// The return code of the card for the last failed ECM, if any.
+    pub last_ecm_error: Option<i32>,
+}
+
+/// The EMMs addressed to a card id, as counted by the decoder.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EmmStats {
+    pub card_id: i64,
+    /// The number of the EMMs in the stream, including the repeated ones.
+    pub received: u64,
+    /// The number of the different EMMs the card has accepted.
+    pub applied: u64,
+    pub failed: u64,
+    /// The protocol number of the last EMM applied, if any.
+    pub last_protocol: Option<u8>,
+}
+
+impl Display for EmmStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "EMM for card {:012x}: {} received, {} applied, {} failed",
+            self.card_id, self.received, self.applied, self.failed
+        )?;
+        if let Some(protocol) = self.last_protocol {
+            write!(f, ", last protocol {:#04x}", protocol)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the warnings of the decoder, such as unpurchased ECMs and broken
+/// sections, which are otherwise dropped.
+pub type WarningCallback = Box<dyn FnMut(DecoderWarning) + Send>;
+
+/// The implementation of MULTI2 descrambling the packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Multi2Backend {
+    /// The C code of libaribb25.
+    #[default]
+    Libaribb25,
+    /// `Multi2` in Rust, with the instruction set detected at runtime.
+    /// The scramble keys are those the card answers to the ECMs sent by
+    /// libaribb25, which descrambles the packets until then. The SIMD of
+    /// libaribb25 is disabled, as it only sees the packets left scrambled.
+    Rust,
+}
+
 pub struct DecoderOptions {
     pub enable_working_key: bool,
     pub round: i32,
     pub strip: bool,
+    /// Pass the EMMs addressed to the card to it, as counted by
+    /// `StreamDecoder::emm_stats`.
     pub emm: bool,
+    /// Use SIMD in MULTI2. With `Multi2Backend::Rust`, `false` selects the
+    /// scalar code.
     pub simd: bool,
+    pub multi2: Multi2Backend,
+    pub on_warning: Option<WarningCallback>,
+    /// The card to use instead of `PcscCard`, or the working keys if
+    /// `enable_working_key` is set.
+    pub card: Option<Box<dyn CasCard>>,
 }
 
 impl Default for DecoderOptions {
@@ -57,46 +147,111 @@ impl Default for DecoderOptions {
             strip: true,
             emm: false,
             simd: true,
+            multi2: Multi2Backend::default(),
+            on_warning: None,
+            card: None,
         }
     }
 }
 
+#[allow(unused_variables)]
+fn default_card(working_key: bool) -> Result<Box<dyn CasCard>, Error> {
+    #[cfg(feature = "block00cbc")]
+    if working_key {
+        return Ok(Box::new(crate::access_control::WorkingKeyCard));
+    }
+    match PcscCard::new() {
+        Ok(card) => Ok(Box::new(card)),
+        Err(e) => Err(Error::new(ErrorKind::Other, DecoderError::from(e))),
+    }
+}
+
 impl StreamDecoder {
     pub fn new(opt: DecoderOptions) -> Result<Self, Error> {
         let inner = unsafe {
-            let inner = InnerDecoder::new(opt.enable_working_key)?;
+            let card = match opt.card {
+                Some(card) => card,
+                None => default_card(opt.enable_working_key)?,
+            };
+            let rust = opt.multi2 == Multi2Backend::Rust;
+            // The Rust MULTI2 takes the scramble keys from the ECMs libaribb25
+            // sends to the card
+            let keys = if rust { Some(EcmKeys::default()) } else { None };
+            let mut inner = InnerDecoder::new(card, keys.as_ref())?;
+            inner.on_warning = opt.on_warning;
             // Set options to the decoder
             inner.dec.as_ref().set_multi2_round(opt.round);
             inner.dec.as_ref().set_strip(if opt.strip { 1 } else { 0 });
-            inner.dec.as_ref().set_emm_proc(if opt.emm { 1 } else { 0 });
+            // The EMMs are passed by `EmmProcessor` instead
+            inner.dec.as_ref().set_emm_proc(0);
+            if opt.emm {
+                inner.use_rust_emm()?;
+            }
             inner
                 .dec
                 .as_ref()
-                .set_simd_mode(if opt.simd { 3 } else { 0 });
+                .set_simd_mode(if opt.simd && !rust { 3 } else { 0 });
+            if let Some(keys) = keys {
+                let simd = if opt.simd {
+                    Simd::detect()
+                } else {
+                    Simd::Scalar
+                };
+                inner.use_rust_multi2(opt.round, simd, keys)?;
+            }
             inner
         };
 
         Ok(Self {
-            received: Cell::new(0),
-            sent: Cell::new(0),
+            received: AtomicUsize::new(0),
+            sent: AtomicUsize::new(0),
             inner: Mutex::new(inner),
         })
     }
+
+    /// Returns the decryption statistics of each program seen so far, in the
+    /// order of the PAT.
+    pub fn program_stats(&self) -> Vec<ProgramStats> {
+        self.inner.lock().unwrap().program_stats()
+    }
+
+    /// Returns the EMMs seen for each id of the card, if `DecoderOptions::emm`
+    /// is set.
+    pub fn emm_stats(&self) -> Vec<EmmStats> {
+        self.inner.lock().unwrap().emm_stats()
+    }
 }
 
 impl Read for StreamDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for StreamDecoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// The decoder can be shared among threads, as `&File` can.
+impl Read for &StreamDecoder {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         self.inner.lock().unwrap().read(buf).map(|value| {
-            self.sent.set(self.sent.get() + value);
+            self.sent.fetch_add(value, Ordering::Relaxed);
             value
         })
     }
 }
 
-impl Write for StreamDecoder {
+impl Write for &StreamDecoder {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.inner.lock().unwrap().write(buf).map(|value| {
-            self.received.set(self.received.get() + value);
+            self.received.fetch_add(value, Ordering::Relaxed);
             value
         })
     }
@@ -105,3 +260,136 @@ impl Write for StreamDecoder {
         self.inner.lock().unwrap().flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MockCard};
+    use std::sync::Arc;
+
+    fn decoder(card: MockCard, multi2: Multi2Backend) -> StreamDecoder {
+        let opt = DecoderOptions {
+            strip: false,
+            multi2,
+            card: Some(Box::new(card)),
+            ..Default::default()
+        };
+        match StreamDecoder::new(opt) {
+            Ok(dec) => dec,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Reads until the decoder has nothing more, `chunk` bytes at most at once.
+    fn read_all(dec: &mut StreamDecoder, chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = vec![0; chunk];
+        loop {
+            match dec.read(&mut buf).unwrap() {
+                0 => return out,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        for multi2 in [Multi2Backend::Libaribb25, Multi2Backend::Rust] {
+            let fixture = testing::scrambled_stream(256, 4);
+            let card = MockCard::default();
+            let log = card.log.clone();
+            let mut dec = decoder(card, multi2);
+            dec.write_all(&fixture.scrambled).unwrap();
+            dec.flush().unwrap();
+            assert_eq!(read_all(&mut dec, 1 << 16), fixture.clear, "{:?}", multi2);
+            assert_eq!(log.lock().unwrap().ecms.len(), 1);
+
+            let stats = dec.program_stats();
+            assert_eq!(stats.len(), 1);
+            assert_eq!(stats[0].program_number, 1);
+            assert_eq!(stats[0].undecrypted_packets, 0);
+        }
+    }
+
+    #[test]
+    fn test_pending_data() {
+        let fixture = testing::scrambled_stream(256, 4);
+        let mut dec = decoder(MockCard::default(), Multi2Backend::Libaribb25);
+        dec.write_all(&fixture.scrambled).unwrap();
+        dec.flush().unwrap();
+        // The output of libaribb25 is kept over the reads smaller than it
+        let mut buf = [0; 100];
+        assert_eq!(dec.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf[..], fixture.clear[..100]);
+        let mut out = buf.to_vec();
+        out.extend(read_all(&mut dec, 1000));
+        assert_eq!(out, fixture.clear);
+    }
+
+    #[test]
+    fn test_flush() {
+        for multi2 in [Multi2Backend::Libaribb25, Multi2Backend::Rust] {
+            let fixture = testing::scrambled_stream(256, 4);
+            let mut dec = decoder(MockCard::default(), multi2);
+            // Written in the middle of a packet, and read before the flush
+            let (head, tail) = fixture.scrambled.split_at(100 * 188 + 50);
+            dec.write_all(head).unwrap();
+            let mut out = read_all(&mut dec, 1 << 16);
+            dec.write_all(tail).unwrap();
+            out.extend(read_all(&mut dec, 1 << 16));
+            dec.flush().unwrap();
+            out.extend(read_all(&mut dec, 1 << 16));
+            assert_eq!(out, fixture.clear, "{:?}", multi2);
+        }
+    }
+
+    #[test]
+    fn test_unpurchased_ecm() {
+        let fixture = testing::scrambled_stream(256, 4);
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        let opt = DecoderOptions {
+            strip: false,
+            card: Some(Box::new(MockCard {
+                return_code: 0x8901,
+                ..Default::default()
+            })),
+            on_warning: Some(Box::new(move |w| sink.lock().unwrap().push(w))),
+            ..Default::default()
+        };
+        let mut dec = StreamDecoder::new(opt).unwrap();
+        dec.write_all(&fixture.scrambled).unwrap();
+        dec.flush().unwrap();
+        read_all(&mut dec, 1 << 16);
+
+        assert!(warnings
+            .lock()
+            .unwrap()
+            .contains(&DecoderWarning::UnpurchasedEcm));
+        let stats = dec.program_stats();
+        assert!(stats[0].unpurchased_ecm_count > 0);
+        assert!(stats[0].undecrypted_packets > 0);
+    }
+
+    #[test]
+    fn test_card_init_error() {
+        let card = MockCard {
+            init_error: Some(BCasCardError::BCAS_CARD_ERROR_NO_SMART_CARD_READER),
+            ..Default::default()
+        };
+        let opt = DecoderOptions {
+            card: Some(Box::new(card)),
+            ..Default::default()
+        };
+        let e = match StreamDecoder::new(opt) {
+            Ok(_) => panic!("the card should fail"),
+            Err(e) => e,
+        };
+        assert!(matches!(
+            e.get_ref().and_then(|e| e.downcast_ref::<DecoderError>()),
+            Some(DecoderError::CardReader(
+                BCasCardError::BCAS_CARD_ERROR_NO_SMART_CARD_READER
+            ))
+        ));
+    }
+}
@@ -0,0 +1,509 @@
+//! MULTI2 block cipher in CBC mode with the OFB residual as used by
+//! ARIB STD-B25, in Rust.
+//!
+//! The decryption of the blocks is independent of each other in CBC mode, so
+//! several blocks are decrypted at once in the lanes of SSE2, AVX2 or NEON
+//! registers, as detected at runtime.
+
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+
+/// The instruction set used to decrypt the blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Simd {
+    Scalar,
+    Sse2,
+    Avx2,
+    Neon,
+}
+
+impl Simd {
+    /// Returns the fastest instruction set the CPU supports.
+    pub fn detect() -> Self {
+        [Simd::Avx2, Simd::Sse2, Simd::Neon]
+            .into_iter()
+            .find(|simd| simd.is_supported())
+            .unwrap_or(Simd::Scalar)
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Simd::Scalar => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Simd::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Simd::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Simd::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+/// Which of the two scramble keys decrypts the packet, given by the
+/// `transport_scrambling_control` bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    Even,
+    Odd,
+}
+
+impl Parity {
+    /// Returns the parity of `transport_scrambling_control`, or `None` if the
+    /// packet is not scrambled.
+    pub fn from_scrambling_control(control: u8) -> Option<Self> {
+        match control & 0x03 {
+            0x02 => Some(Parity::Even),
+            0x03 => Some(Parity::Odd),
+            _ => None,
+        }
+    }
+}
+
+/// Operations on the lanes of a register, each of which holds a half of a
+/// different block.
+pub(crate) trait Lanes: Copy {
+    const LANES: usize;
+
+    /// Loads `LANES` words from `src`.
+    unsafe fn load(src: &[u32]) -> Self;
+    /// Stores `LANES` words to `dst`.
+    unsafe fn store(self, dst: &mut [u32]);
+    fn splat(v: u32) -> Self;
+    fn add(self, other: Self) -> Self;
+    fn sub(self, other: Self) -> Self;
+    fn xor(self, other: Self) -> Self;
+    fn or(self, other: Self) -> Self;
+    fn rotl(self, n: u32) -> Self;
+}
+
+impl Lanes for u32 {
+    const LANES: usize = 1;
+
+    #[inline(always)]
+    unsafe fn load(src: &[u32]) -> Self {
+        src[0]
+    }
+    #[inline(always)]
+    unsafe fn store(self, dst: &mut [u32]) {
+        dst[0] = self
+    }
+    #[inline(always)]
+    fn splat(v: u32) -> Self {
+        v
+    }
+    #[inline(always)]
+    fn add(self, other: Self) -> Self {
+        self.wrapping_add(other)
+    }
+    #[inline(always)]
+    fn sub(self, other: Self) -> Self {
+        self.wrapping_sub(other)
+    }
+    #[inline(always)]
+    fn xor(self, other: Self) -> Self {
+        self ^ other
+    }
+    #[inline(always)]
+    fn or(self, other: Self) -> Self {
+        self | other
+    }
+    #[inline(always)]
+    fn rotl(self, n: u32) -> Self {
+        self.rotate_left(n)
+    }
+}
+
+#[inline(always)]
+fn pi1<L: Lanes>(l: L, r: L) -> (L, L) {
+    (l, r.xor(l))
+}
+
+#[inline(always)]
+fn pi2<L: Lanes>(l: L, r: L, k1: L) -> (L, L) {
+    let t0 = r.add(k1);
+    let t1 = t0.rotl(1).add(t0).sub(L::splat(1));
+    let t2 = t1.rotl(4).xor(t1);
+    (l.xor(t2), r)
+}
+
+#[inline(always)]
+fn pi3<L: Lanes>(l: L, r: L, k2: L, k3: L) -> (L, L) {
+    let t0 = l.add(k2);
+    let t1 = t0.rotl(2).add(t0).add(L::splat(1));
+    let t2 = t1.rotl(8).xor(t1);
+    let t3 = t2.add(k3);
+    let t4 = t3.rotl(1).sub(t3);
+    let t5 = t4.rotl(16).xor(t4.or(l));
+    (l, r.xor(t5))
+}
+
+#[inline(always)]
+fn pi4<L: Lanes>(l: L, r: L, k4: L) -> (L, L) {
+    let t0 = r.add(k4);
+    let t1 = t0.rotl(2).add(t0).add(L::splat(1));
+    (l.xor(t1), r)
+}
+
+#[inline(always)]
+fn encrypt_lanes<L: Lanes>(mut l: L, mut r: L, w: &[u32; 8], round: usize) -> (L, L) {
+    let k = w.map(L::splat);
+    for _ in 0..round {
+        (l, r) = pi1(l, r);
+        (l, r) = pi2(l, r, k[0]);
+        (l, r) = pi3(l, r, k[1], k[2]);
+        (l, r) = pi4(l, r, k[3]);
+        (l, r) = pi1(l, r);
+        (l, r) = pi2(l, r, k[4]);
+        (l, r) = pi3(l, r, k[5], k[6]);
+        (l, r) = pi4(l, r, k[7]);
+    }
+    (l, r)
+}
+
+#[inline(always)]
+fn decrypt_lanes<L: Lanes>(mut l: L, mut r: L, w: &[u32; 8], round: usize) -> (L, L) {
+    let k = w.map(L::splat);
+    for _ in 0..round {
+        (l, r) = pi4(l, r, k[7]);
+        (l, r) = pi3(l, r, k[5], k[6]);
+        (l, r) = pi2(l, r, k[4]);
+        (l, r) = pi1(l, r);
+        (l, r) = pi4(l, r, k[3]);
+        (l, r) = pi3(l, r, k[1], k[2]);
+        (l, r) = pi2(l, r, k[0]);
+        (l, r) = pi1(l, r);
+    }
+    (l, r)
+}
+
+/// Derives the work keys from the system key and a scramble key.
+fn schedule(system_key: &[u32; 8], (l, r): (u32, u32)) -> [u32; 8] {
+    let s = system_key;
+    let mut w = [0u32; 8];
+    let (l, r) = pi1(l, r);
+    let (l, r) = pi2(l, r, s[0]);
+    w[0] = l;
+    let (l, r) = pi3(l, r, s[1], s[2]);
+    w[1] = r;
+    let (l, r) = pi4(l, r, s[3]);
+    w[2] = l;
+    let (l, r) = pi1(l, r);
+    w[3] = r;
+    let (l, r) = pi2(l, r, s[4]);
+    w[4] = l;
+    let (l, r) = pi3(l, r, s[5], s[6]);
+    w[5] = r;
+    let (l, r) = pi4(l, r, s[7]);
+    w[6] = l;
+    let (_, r) = pi1(l, r);
+    w[7] = r;
+    w
+}
+
+fn read_block(block: &[u8]) -> (u32, u32) {
+    (
+        u32::from_be_bytes(block[..4].try_into().unwrap()),
+        u32::from_be_bytes(block[4..8].try_into().unwrap()),
+    )
+}
+
+fn write_block(block: &mut [u8], (l, r): (u32, u32)) {
+    block[..4].copy_from_slice(&l.to_be_bytes());
+    block[4..8].copy_from_slice(&r.to_be_bytes());
+}
+
+/// Decrypts the whole blocks of `data` in CBC mode, `L::LANES` blocks at
+/// once, and returns the number of the bytes decrypted. `cbc` is the
+/// ciphertext of the previous block, and is updated to the last block.
+#[inline(always)]
+unsafe fn decrypt_cbc<L: Lanes>(
+    data: &mut [u8],
+    w: &[u32; 8],
+    round: usize,
+    cbc: &mut (u32, u32),
+) -> usize {
+    let n = L::LANES;
+    let (mut ls, mut rs) = ([0u32; 8], [0u32; 8]);
+    let mut done = 0;
+    for chunk in data.chunks_exact_mut(8 * n) {
+        for (i, block) in chunk.chunks_exact(8).enumerate() {
+            (ls[i], rs[i]) = read_block(block);
+        }
+        let (l, r) = decrypt_lanes(L::load(&ls), L::load(&rs), w, round);
+        let (mut pl, mut pr) = ([0u32; 8], [0u32; 8]);
+        l.store(&mut pl);
+        r.store(&mut pr);
+        for (i, block) in chunk.chunks_exact_mut(8).enumerate() {
+            write_block(block, (pl[i] ^ cbc.0, pr[i] ^ cbc.1));
+            *cbc = (ls[i], rs[i]);
+        }
+        done += 8 * n;
+    }
+    done
+}
+
+/// A MULTI2 descrambler with the system key and the initial CBC value of a
+/// card, and the pair of scramble keys of the latest ECM.
+#[derive(Debug, Clone)]
+pub struct Multi2 {
+    system_key: [u32; 8],
+    cbc_init: (u32, u32),
+    round: usize,
+    /// The work keys of the odd and the even scramble keys.
+    odd: Option<[u32; 8]>,
+    even: Option<[u32; 8]>,
+    simd: Simd,
+}
+
+impl Multi2 {
+    /// Creates a descrambler of 4 rounds (32 rounds of the Feistel network),
+    /// using the fastest instruction set the CPU supports.
+    pub fn new(system_key: &[u8; 32], init_cbc: &[u8; 8]) -> Self {
+        let mut key = [0u32; 8];
+        for (k, bytes) in key.iter_mut().zip(system_key.chunks_exact(4)) {
+            *k = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        Self {
+            system_key: key,
+            cbc_init: read_block(init_cbc),
+            round: 4,
+            odd: None,
+            even: None,
+            simd: Simd::detect(),
+        }
+    }
+
+    /// Selects the instruction set. The scalar code is used instead if the
+    /// CPU does not support it.
+    pub fn set_simd(&mut self, simd: Simd) {
+        self.simd = if simd.is_supported() {
+            simd
+        } else {
+            Simd::Scalar
+        };
+    }
+
+    pub fn simd(&self) -> Simd {
+        self.simd
+    }
+
+    pub fn set_round(&mut self, round: usize) {
+        self.round = round;
+    }
+
+    /// Sets the scramble keys returned by the card for an ECM, the odd key
+    /// followed by the even key.
+    pub fn set_scramble_key(&mut self, key: &[u8; 16]) {
+        self.odd = Some(schedule(&self.system_key, read_block(&key[..8])));
+        self.even = Some(schedule(&self.system_key, read_block(&key[8..])));
+    }
+
+    pub fn clear_scramble_key(&mut self) {
+        self.odd = None;
+        self.even = None;
+    }
+
+    pub fn has_scramble_key(&self) -> bool {
+        self.odd.is_some()
+    }
+
+    fn work_key(&self, parity: Parity) -> Option<&[u32; 8]> {
+        match parity {
+            Parity::Odd => self.odd.as_ref(),
+            Parity::Even => self.even.as_ref(),
+        }
+    }
+
+    /// Decrypts the payload of a packet in place. Returns `false` if the
+    /// scramble key is not set.
+    pub fn decrypt(&self, parity: Parity, data: &mut [u8]) -> bool {
+        let w = match self.work_key(parity) {
+            Some(w) => w,
+            None => return false,
+        };
+        let mut cbc = self.cbc_init;
+        let done = unsafe {
+            match self.simd {
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                Simd::Avx2 => x86::decrypt_avx2(data, w, self.round, &mut cbc),
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                Simd::Sse2 => x86::decrypt_sse2(data, w, self.round, &mut cbc),
+                #[cfg(target_arch = "aarch64")]
+                Simd::Neon => neon::decrypt_neon(data, w, self.round, &mut cbc),
+                _ => 0,
+            }
+        };
+        let whole = (data.len() - done) / 8 * 8;
+        let (blocks, residual) = data[done..].split_at_mut(whole);
+        unsafe { decrypt_cbc::<u32>(blocks, w, self.round, &mut cbc) };
+        self.ofb(w, cbc, residual);
+        true
+    }
+
+    /// Encrypts the payload of a packet in place. Returns `false` if the
+    /// scramble key is not set.
+    pub fn encrypt(&self, parity: Parity, data: &mut [u8]) -> bool {
+        let w = match self.work_key(parity) {
+            Some(w) => w,
+            None => return false,
+        };
+        let mut cbc = self.cbc_init;
+        let whole = data.len() / 8 * 8;
+        let (blocks, residual) = data.split_at_mut(whole);
+        for block in blocks.chunks_exact_mut(8) {
+            let (l, r) = read_block(block);
+            cbc = encrypt_lanes(l ^ cbc.0, r ^ cbc.1, w, self.round);
+            write_block(block, cbc);
+        }
+        self.ofb(w, cbc, residual);
+        true
+    }
+
+    /// XORs the residual of less than a block with the encrypted last block.
+    fn ofb(&self, w: &[u32; 8], cbc: (u32, u32), residual: &mut [u8]) {
+        if residual.is_empty() {
+            return;
+        }
+        let mut stream = [0u8; 8];
+        write_block(&mut stream, encrypt_lanes(cbc.0, cbc.1, w, self.round));
+        for (b, s) in residual.iter_mut().zip(stream) {
+            *b ^= s;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSTEM_KEY: [u8; 32] = [
+        0x36, 0x31, 0x04, 0x66, 0x4b, 0x17, 0xea, 0x5c, 0x32, 0xdf, 0x9c, 0xf5, 0xc4, 0xc3, 0x6c,
+        0x1b, 0xec, 0x99, 0x39, 0x21, 0x68, 0x9d, 0x4b, 0xb7, 0xb7, 0x4e, 0x40, 0x84, 0x0d, 0x2e,
+        0x7d, 0x98,
+    ];
+    const INIT_CBC: [u8; 8] = [0xfe, 0x27, 0x19, 0x99, 0x19, 0x69, 0x09, 0x11];
+    const SCRAMBLE_KEY: [u8; 16] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32,
+        0x10,
+    ];
+
+    /// A payload of 184 bytes with a residual of 7 bytes after the last block.
+    fn payload(seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..184)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn multi2(simd: Simd) -> Multi2 {
+        let mut m = Multi2::new(&SYSTEM_KEY, &INIT_CBC);
+        m.set_simd(simd);
+        m.set_scramble_key(&SCRAMBLE_KEY);
+        m
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let m = multi2(Simd::Scalar);
+        for (len, parity) in [(184, Parity::Even), (176, Parity::Odd), (5, Parity::Odd)] {
+            let plain = payload(len as u32)[..len].to_vec();
+            let mut data = plain.clone();
+            assert!(m.encrypt(parity, &mut data));
+            assert_ne!(data, plain);
+            assert!(m.decrypt(parity, &mut data));
+            assert_eq!(data, plain);
+        }
+
+        let mut m = m;
+        m.clear_scramble_key();
+        assert!(!m.decrypt(Parity::Even, &mut payload(0)));
+    }
+
+    #[test]
+    fn test_simd_matches_scalar() {
+        let scalar = multi2(Simd::Scalar);
+        for simd in [Simd::Sse2, Simd::Avx2, Simd::Neon] {
+            if !simd.is_supported() {
+                continue;
+            }
+            let m = multi2(simd);
+            assert_eq!(m.simd(), simd);
+            for (seed, parity) in [(1, Parity::Even), (2, Parity::Odd)] {
+                let mut expected = payload(seed);
+                scalar.decrypt(parity, &mut expected);
+                let mut data = payload(seed);
+                m.decrypt(parity, &mut data);
+                assert_eq!(data, expected, "{:?}", simd);
+            }
+        }
+    }
+
+    /// Compares with `multi2.c` of libaribb25 linked to this crate.
+    mod libaribb25 {
+        use super::*;
+        use std::os::raw::{c_int, c_void};
+
+        #[repr(C)]
+        struct MULTI2 {
+            private_data: *mut c_void,
+            release: unsafe extern "C" fn(m2: *mut c_void),
+            add_ref: unsafe extern "C" fn(m2: *mut c_void) -> c_int,
+            set_round: unsafe extern "C" fn(m2: *mut c_void, val: i32) -> c_int,
+            set_system_key: unsafe extern "C" fn(m2: *mut c_void, val: *mut u8) -> c_int,
+            set_init_cbc: unsafe extern "C" fn(m2: *mut c_void, val: *mut u8) -> c_int,
+            set_scramble_key: unsafe extern "C" fn(m2: *mut c_void, val: *mut u8) -> c_int,
+            clear_scramble_key: unsafe extern "C" fn(m2: *mut c_void) -> c_int,
+            encrypt:
+                unsafe extern "C" fn(m2: *mut c_void, t: i32, buf: *mut u8, size: i32) -> c_int,
+            decrypt:
+                unsafe extern "C" fn(m2: *mut c_void, t: i32, buf: *mut u8, size: i32) -> c_int,
+        }
+
+        extern "C" {
+            fn create_multi2() -> *mut MULTI2;
+        }
+
+        #[test]
+        fn test_bit_exact() {
+            unsafe {
+                let c = create_multi2();
+                assert!(!c.is_null());
+                let this = c as *mut c_void;
+                ((*c).set_round)(this, 4);
+                ((*c).set_system_key)(this, SYSTEM_KEY.as_ptr() as *mut u8);
+                ((*c).set_init_cbc)(this, INIT_CBC.as_ptr() as *mut u8);
+                ((*c).set_scramble_key)(this, SCRAMBLE_KEY.as_ptr() as *mut u8);
+
+                for simd in [Simd::Scalar, Simd::Sse2, Simd::Avx2, Simd::Neon] {
+                    if !simd.is_supported() {
+                        continue;
+                    }
+                    let m = multi2(simd);
+                    for (seed, parity, control) in [(3, Parity::Even, 2), (4, Parity::Odd, 3)] {
+                        for len in [184, 183, 8, 3] {
+                            let mut expected = payload(seed)[..len].to_vec();
+                            ((*c).decrypt)(this, control, expected.as_mut_ptr(), len as i32);
+                            let mut data = payload(seed)[..len].to_vec();
+                            m.decrypt(parity, &mut data);
+                            assert_eq!(data, expected, "{:?} {}", simd, len);
+
+                            let mut expected = payload(seed)[..len].to_vec();
+                            ((*c).encrypt)(this, control, expected.as_mut_ptr(), len as i32);
+                            let mut data = payload(seed)[..len].to_vec();
+                            m.encrypt(parity, &mut data);
+                            assert_eq!(data, expected, "{:?} {}", simd, len);
+                        }
+                    }
+                }
+                ((*c).release)(this);
+            }
+        }
+    }
+}
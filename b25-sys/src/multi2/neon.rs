@@ -0,0 +1,58 @@
+use std::arch::aarch64::*;
+
+use super::{decrypt_cbc, Lanes};
+
+#[derive(Clone, Copy)]
+pub(super) struct Neon(uint32x4_t);
+
+impl Lanes for Neon {
+    const LANES: usize = 4;
+
+    #[inline(always)]
+    unsafe fn load(src: &[u32]) -> Self {
+        Neon(vld1q_u32(src[..4].as_ptr()))
+    }
+    #[inline(always)]
+    unsafe fn store(self, dst: &mut [u32]) {
+        vst1q_u32(dst[..4].as_mut_ptr(), self.0)
+    }
+    #[inline(always)]
+    fn splat(v: u32) -> Self {
+        unsafe { Neon(vdupq_n_u32(v)) }
+    }
+    #[inline(always)]
+    fn add(self, other: Self) -> Self {
+        unsafe { Neon(vaddq_u32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn sub(self, other: Self) -> Self {
+        unsafe { Neon(vsubq_u32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn xor(self, other: Self) -> Self {
+        unsafe { Neon(veorq_u32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn or(self, other: Self) -> Self {
+        unsafe { Neon(vorrq_u32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn rotl(self, n: u32) -> Self {
+        unsafe {
+            // A negative count shifts to the right
+            let left = vshlq_u32(self.0, vdupq_n_s32(n as i32));
+            let right = vshlq_u32(self.0, vdupq_n_s32(n as i32 - 32));
+            Neon(vorrq_u32(left, right))
+        }
+    }
+}
+
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn decrypt_neon(
+    data: &mut [u8],
+    w: &[u32; 8],
+    round: usize,
+    cbc: &mut (u32, u32),
+) -> usize {
+    decrypt_cbc::<Neon>(data, w, round, cbc)
+}
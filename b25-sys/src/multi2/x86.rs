@@ -0,0 +1,114 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use super::{decrypt_cbc, Lanes};
+
+#[derive(Clone, Copy)]
+pub(super) struct Sse2(__m128i);
+
+impl Lanes for Sse2 {
+    const LANES: usize = 4;
+
+    #[inline(always)]
+    unsafe fn load(src: &[u32]) -> Self {
+        Sse2(_mm_loadu_si128(src[..4].as_ptr() as *const __m128i))
+    }
+    #[inline(always)]
+    unsafe fn store(self, dst: &mut [u32]) {
+        _mm_storeu_si128(dst[..4].as_mut_ptr() as *mut __m128i, self.0)
+    }
+    #[inline(always)]
+    fn splat(v: u32) -> Self {
+        unsafe { Sse2(_mm_set1_epi32(v as i32)) }
+    }
+    #[inline(always)]
+    fn add(self, other: Self) -> Self {
+        unsafe { Sse2(_mm_add_epi32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn sub(self, other: Self) -> Self {
+        unsafe { Sse2(_mm_sub_epi32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn xor(self, other: Self) -> Self {
+        unsafe { Sse2(_mm_xor_si128(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn or(self, other: Self) -> Self {
+        unsafe { Sse2(_mm_or_si128(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn rotl(self, n: u32) -> Self {
+        unsafe {
+            let left = _mm_sll_epi32(self.0, _mm_cvtsi32_si128(n as i32));
+            let right = _mm_srl_epi32(self.0, _mm_cvtsi32_si128(32 - n as i32));
+            Sse2(_mm_or_si128(left, right))
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct Avx2(__m256i);
+
+impl Lanes for Avx2 {
+    const LANES: usize = 8;
+
+    #[inline(always)]
+    unsafe fn load(src: &[u32]) -> Self {
+        Avx2(_mm256_loadu_si256(src[..8].as_ptr() as *const __m256i))
+    }
+    #[inline(always)]
+    unsafe fn store(self, dst: &mut [u32]) {
+        _mm256_storeu_si256(dst[..8].as_mut_ptr() as *mut __m256i, self.0)
+    }
+    #[inline(always)]
+    fn splat(v: u32) -> Self {
+        unsafe { Avx2(_mm256_set1_epi32(v as i32)) }
+    }
+    #[inline(always)]
+    fn add(self, other: Self) -> Self {
+        unsafe { Avx2(_mm256_add_epi32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn sub(self, other: Self) -> Self {
+        unsafe { Avx2(_mm256_sub_epi32(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn xor(self, other: Self) -> Self {
+        unsafe { Avx2(_mm256_xor_si256(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn or(self, other: Self) -> Self {
+        unsafe { Avx2(_mm256_or_si256(self.0, other.0)) }
+    }
+    #[inline(always)]
+    fn rotl(self, n: u32) -> Self {
+        unsafe {
+            let left = _mm256_sll_epi32(self.0, _mm_cvtsi32_si128(n as i32));
+            let right = _mm256_srl_epi32(self.0, _mm_cvtsi32_si128(32 - n as i32));
+            Avx2(_mm256_or_si256(left, right))
+        }
+    }
+}
+
+#[target_feature(enable = "sse2")]
+pub(super) unsafe fn decrypt_sse2(
+    data: &mut [u8],
+    w: &[u32; 8],
+    round: usize,
+    cbc: &mut (u32, u32),
+) -> usize {
+    decrypt_cbc::<Sse2>(data, w, round, cbc)
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn decrypt_avx2(
+    data: &mut [u8],
+    w: &[u32; 8],
+    round: usize,
+    cbc: &mut (u32, u32),
+) -> usize {
+    decrypt_cbc::<Avx2>(data, w, round, cbc)
+}
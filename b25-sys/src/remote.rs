@@ -0,0 +1,468 @@
+//! A B-CAS card on another host, reached over TCP.
+//!
+//! `CardServer` exposes a card, typically `PcscCard`, to the network, and
+//! `RemoteCard` is the `CasCard` forwarding the requests of the decoder to it.
+//! The client is authenticated with a token shared by both sides, which also
+//! authenticates each message. The messages are not encrypted, so the server
+//! is to be used on a trusted network or through a tunnel.
+
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::remote::protocol::{
+    auth_message, encode_response, get_ecm_result, get_ids, get_nothing, get_power_on_control,
+    get_status, put_ecm_result, put_ids, put_nothing, put_power_on_control, put_status, read_frame,
+    verify_auth, write_frame, Request, Session, NONCE_LEN,
+};
+use crate::{BCasCardError, CardStatus, CasCard, EcmResult, PowerOnControl};
+
+mod protocol;
+
+/// The counts and the latencies of the requests to a card.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RemoteMetrics {
+    pub requests: u64,
+    /// The requests which have not been answered by the card.
+    pub failures: u64,
+    pub reconnects: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl RemoteMetrics {
+    fn record(&mut self, latency: Duration) {
+        self.requests += 1;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        match self.requests {
+            0 => Duration::ZERO,
+            n => self.total_latency.div_f64(n as f64),
+        }
+    }
+}
+
+impl Display for RemoteMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} requests, {} failures, {} reconnects, mean {:.3}ms, max {:.3}ms",
+            self.requests,
+            self.failures,
+            self.reconnects,
+            self.mean_latency().as_secs_f64() * 1000.0,
+            self.max_latency.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+type Decode<T> = fn(&[u8]) -> Result<Result<T, BCasCardError>, Error>;
+
+/// A card served by `CardServer` on another host.
+///
+/// A request failed on the connection is retried on a new one, waiting
+/// longer after each attempt. The connection is made again on the next
+/// request even if all the attempts have failed, so that the decoding
+/// resumes when the server comes back.
+pub struct RemoteCard {
+    addr: String,
+    token: Vec<u8>,
+    stream: Option<(TcpStream, Session)>,
+    connected: bool,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    status: Option<CardStatus>,
+    metrics: Arc<Mutex<RemoteMetrics>>,
+}
+
+impl RemoteCard {
+    /// Creates a card served at `addr`, such as `"192.0.2.1:40774"`. The
+    /// connection is made on `init`.
+    pub fn new(addr: &str, token: &[u8]) -> Self {
+        Self {
+            addr: addr.to_string(),
+            token: token.to_vec(),
+            stream: None,
+            connected: false,
+            timeout: Duration::from_secs(5),
+            retries: 3,
+            backoff: Duration::from_millis(200),
+            status: None,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Sets the timeout of connecting and of each response.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of the retries of a request, and the wait before the
+    /// first one, doubled for each of the others.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Returns the metrics, updated as the requests are made.
+    pub fn metrics(&self) -> Arc<Mutex<RemoteMetrics>> {
+        self.metrics.clone()
+    }
+
+    fn connect(&self) -> Result<(TcpStream, Session), Error> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(ErrorKind::AddrNotAvailable)?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+
+        let nonce = read_frame(&mut stream)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        let mut client_nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut client_nonce)?;
+        write_frame(
+            &mut stream,
+            &auth_message(&self.token, &nonce, &client_nonce),
+        )?;
+        match read_frame(&mut stream)?.as_slice() {
+            [0] => {
+                let session = Session::new(&self.token, &nonce, &client_nonce, false);
+                Ok((stream, session))
+            }
+            _ => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "The card server refused the token.",
+            )),
+        }
+    }
+
+    fn exchange(&mut self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.stream.is_none() {
+            let stream = self.connect()?;
+            if self.connected {
+                self.metrics.lock().unwrap().reconnects += 1;
+                info!("Reconnected to the card server {}.", self.addr);
+            }
+            self.connected = true;
+            self.stream = Some(stream);
+        }
+        let (stream, session) = self.stream.as_mut().unwrap();
+        session.write(stream, msg)?;
+        session.read(stream)
+    }
+
+    fn call<T>(&mut self, req: Request, decode: Decode<T>) -> Result<T, BCasCardError> {
+        let msg = req.encode();
+        let start = Instant::now();
+        let mut delay = self.backoff;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            let e = match self.exchange(&msg).and_then(|res| decode(&res)) {
+                Ok(result) => {
+                    self.metrics.lock().unwrap().record(start.elapsed());
+                    return result;
+                }
+                Err(e) => e,
+            };
+            warn!("The card server {} failed ({}).", self.addr, e);
+            if let Some((stream, _)) = self.stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            if e.kind() == ErrorKind::PermissionDenied {
+                break;
+            }
+        }
+        self.metrics.lock().unwrap().failures += 1;
+        Err(BCasCardError::BCAS_CARD_ERROR_TRANSMIT_FAILED)
+    }
+}
+
+impl CasCard for RemoteCard {
+    fn init(&mut self) -> Result<(), BCasCardError> {
+        match self.call(Request::InitStatus, get_status) {
+            Ok(status) => {
+                self.status = Some(status);
+                Ok(())
+            }
+            Err(BCasCardError::BCAS_CARD_ERROR_TRANSMIT_FAILED) => {
+                Err(BCasCardError::BCAS_CARD_ERROR_ALL_READERS_CONNECTION_FAILED)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError> {
+        match self.status {
+            Some(status) => Ok(status),
+            None => self.call(Request::InitStatus, get_status),
+        }
+    }
+
+    fn card_ids(&mut self) -> Result<Vec<i64>, BCasCardError> {
+        self.call(Request::CardIds, get_ids)
+    }
+
+    fn power_on_control(&mut self) -> Result<Vec<PowerOnControl>, BCasCardError> {
+        self.call(Request::PowerOnControl, get_power_on_control)
+    }
+
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError> {
+        self.call(Request::Ecm(ecm.to_vec()), get_ecm_result)
+    }
+
+    fn proc_emm(&mut self, emm: &[u8]) -> Result<(), BCasCardError> {
+        self.call(Request::Emm(emm.to_vec()), get_nothing)
+    }
+}
+
+impl Drop for RemoteCard {
+    fn drop(&mut self) {
+        info!(
+            "Card server {}: {}",
+            self.addr,
+            self.metrics.lock().unwrap()
+        );
+    }
+}
+
+/// Serves a card to `RemoteCard`s over TCP.
+#[derive(Clone)]
+pub struct CardServer {
+    card: Arc<Mutex<Box<dyn CasCard>>>,
+    token: Arc<Vec<u8>>,
+    metrics: Arc<Mutex<RemoteMetrics>>,
+}
+
+impl CardServer {
+    /// Initializes the card to be served to the clients with the token.
+    pub fn new(mut card: Box<dyn CasCard>, token: &[u8]) -> Result<Self, BCasCardError> {
+        card.init()?;
+        Ok(Self {
+            card: Arc::new(Mutex::new(card)),
+            token: Arc::new(token.to_vec()),
+            metrics: Default::default(),
+        })
+    }
+
+    /// Returns the metrics of the requests to the card from all the clients.
+    pub fn metrics(&self) -> Arc<Mutex<RemoteMetrics>> {
+        self.metrics.clone()
+    }
+
+    /// Accepts the clients, each served on its own thread.
+    pub fn serve(&self, listener: TcpListener) -> Result<(), Error> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a client ({}).", e);
+                    continue;
+                }
+            };
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    warn!("Failed to get the address of a client ({}).", e);
+                    continue;
+                }
+            };
+            info!("{} connected.", peer);
+            let server = self.clone();
+            std::thread::spawn(move || {
+                match server.handle(stream) {
+                    Ok(()) => info!("{} disconnected.", peer),
+                    Err(e) => warn!("{} disconnected ({}).", peer, e),
+                }
+                info!("Card: {}", server.metrics.lock().unwrap());
+            });
+        }
+        Ok(())
+    }
+
+    /// Authenticates the client and answers its requests until it
+    /// disconnects.
+    pub fn handle(&self, mut stream: TcpStream) -> Result<(), Error> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)?;
+        write_frame(&mut stream, &nonce)?;
+        let msg = read_frame(&mut stream)?;
+        let mut session = match verify_auth(&self.token, &nonce, &msg) {
+            Some(client_nonce) => Session::new(&self.token, &nonce, client_nonce, true),
+            None => {
+                write_frame(&mut stream, &[1])?;
+                return Err(Error::new(ErrorKind::PermissionDenied, "Wrong token."));
+            }
+        };
+        write_frame(&mut stream, &[0])?;
+        stream.set_read_timeout(None)?;
+
+        loop {
+            let msg = match session.read(&mut stream) {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let req = Request::decode(&msg)?;
+            debug!("{:?}", req);
+            let start = Instant::now();
+            let res = {
+                let mut card = self.card.lock().unwrap();
+                match req {
+                    Request::InitStatus => encode_response(card.init_status(), put_status),
+                    Request::CardIds => encode_response(card.card_ids(), put_ids),
+                    Request::PowerOnControl => {
+                        encode_response(card.power_on_control(), put_power_on_control)
+                    }
+                    Request::Ecm(ecm) => encode_response(card.proc_ecm(&ecm), put_ecm_result),
+                    Request::Emm(emm) => encode_response(card.proc_emm(&emm), put_nothing),
+                }
+            };
+            {
+                let mut metrics = self.metrics.lock().unwrap();
+                metrics.record(start.elapsed());
+                if res[..4] != [0; 4] {
+                    metrics.failures += 1;
+                }
+            }
+            session.write(&mut stream, &res)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockCard, STATUS};
+    use std::sync::mpsc;
+
+    const TOKEN: &[u8] = b"secret";
+
+    fn serve(card: MockCard) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = CardServer::new(Box::new(card), TOKEN).unwrap();
+        std::thread::spawn(move || server.serve(listener));
+        addr
+    }
+
+    #[test]
+    fn test_remote_card() {
+        let mut card = RemoteCard::new(
+            &serve(MockCard {
+                emm_error: Some(BCasCardError::BCAS_CARD_ERROR_INVALID_PARAMETER),
+                ..MockCard::default()
+            }),
+            TOKEN,
+        );
+        card.init().unwrap();
+        assert_eq!(card.init_status().unwrap(), STATUS);
+        assert_eq!(card.card_ids().unwrap(), [STATUS.card_id]);
+        assert!(card.power_on_control().unwrap().is_empty());
+
+        let ecm: Vec<u8> = (0..40).collect();
+        let result = card.proc_ecm(&ecm).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(result.scramble_key[..], ecm[..16]);
+
+        // The error of the card is passed as it is
+        assert!(matches!(
+            card.proc_emm(&[0; 10]),
+            Err(BCasCardError::BCAS_CARD_ERROR_INVALID_PARAMETER)
+        ));
+
+        let metrics = *card.metrics().lock().unwrap();
+        assert_eq!(metrics.requests, 5);
+        assert_eq!(metrics.failures, 0);
+    }
+
+    #[test]
+    fn test_wrong_token() {
+        let mut card = RemoteCard::new(&serve(MockCard::default()), b"wrong");
+        assert!(matches!(
+            card.init(),
+            Err(BCasCardError::BCAS_CARD_ERROR_ALL_READERS_CONNECTION_FAILED)
+        ));
+        assert_eq!(card.metrics().lock().unwrap().failures, 1);
+    }
+
+    #[test]
+    fn test_session() {
+        let nonces = ([1; NONCE_LEN], [2; NONCE_LEN]);
+        let mut client = Session::new(TOKEN, &nonces.0, &nonces.1, false);
+        let mut server = Session::new(TOKEN, &nonces.0, &nonces.1, true);
+        let mut wire = Vec::new();
+        client.write(&mut wire, b"request").unwrap();
+        assert_eq!(server.read(&mut &wire[..]).unwrap(), b"request");
+        // Replayed
+        assert!(server.read(&mut &wire[..]).is_err());
+
+        let mut wire = Vec::new();
+        server.write(&mut wire, b"response").unwrap();
+        // Altered
+        let mut altered = wire.clone();
+        altered[5] ^= 1;
+        assert!(client.read(&mut &altered[..]).is_err());
+        // Sent back by the server to itself
+        assert!(Session::new(TOKEN, &nonces.0, &nonces.1, true)
+            .read(&mut &wire[..])
+            .is_err());
+    }
+
+    #[test]
+    fn test_unknown_error() {
+        // A code unknown to the client is passed instead of failing
+        let msg = protocol::encode_response(Err(BCasCardError::from(-100)), protocol::put_nothing);
+        assert!(matches!(
+            protocol::get_nothing(&msg).unwrap(),
+            Err(BCasCardError::Unknown(-100))
+        ));
+        assert_eq!(BCasCardError::Unknown(-100).code(), -100);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = CardServer::new(Box::new(MockCard::default()), TOKEN).unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let stream = stream.unwrap();
+                tx.send(stream.try_clone().unwrap()).unwrap();
+                let server = server.clone();
+                std::thread::spawn(move || server.handle(stream));
+            }
+        });
+
+        let mut card = RemoteCard::new(&addr, TOKEN).with_retries(3, Duration::from_millis(10));
+        card.init().unwrap();
+        // The server drops the connection
+        rx.recv().unwrap().shutdown(Shutdown::Both).unwrap();
+        assert!(card.proc_ecm(&[1; 16]).unwrap().is_ok());
+
+        let metrics = *card.metrics().lock().unwrap();
+        assert_eq!(metrics.reconnects, 1);
+        assert_eq!(metrics.requests, 2);
+    }
+}
@@ -0,0 +1,336 @@
+//! The messages between `RemoteCard` and `CardServer`.
+//!
+//! Each message is a frame of a 32-bit big-endian length followed by the body.
+//! On a connection, the server sends a random nonce, and the client answers
+//! with a nonce of its own and the HMAC-SHA256 of both keyed by the shared
+//! token. The server replies with a status, after which the client sends
+//! requests and the server answers each of them in turn. A response starts
+//! with the 32-bit return code of the card, zero or a negative
+//! `BCasCardError`.
+//!
+//! The requests and the responses end with the HMAC-SHA256 of their body and
+//! of their number on the connection, keyed by a session key derived from the
+//! token and the nonces, so that they cannot be altered, replayed or
+//! reordered. They are not encrypted: the scramble keys answered to the ECMs
+//! can be read by anyone on the network.
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{BCasCardError, CardStatus, EcmResult, PowerOnControl};
+
+pub(crate) const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+/// Sections are at most 4096 bytes long.
+const MAX_FRAME: usize = 8192;
+
+const OP_INIT_STATUS: u8 = 1;
+const OP_CARD_IDS: u8 = 2;
+const OP_POWER_ON_CONTROL: u8 = 3;
+const OP_ECM: u8 = 4;
+const OP_EMM: u8 = 5;
+
+pub(crate) fn write_frame(w: &mut impl Write, body: &[u8]) -> Result<(), Error> {
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+pub(crate) fn read_frame(r: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(Error::new(ErrorKind::InvalidData, "The frame is too long."));
+    }
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn hmac(key: &[u8], label: &[u8], server_nonce: &[u8], client_nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(label);
+    mac.update(server_nonce);
+    mac.update(client_nonce);
+    mac
+}
+
+/// Returns the nonce of the client followed by the tag proving the token.
+pub(crate) fn auth_message(token: &[u8], server_nonce: &[u8], client_nonce: &[u8]) -> Vec<u8> {
+    let tag = hmac(token, b"recisdb card-server", server_nonce, client_nonce).finalize();
+    [client_nonce, &tag.into_bytes()[..]].concat()
+}
+
+/// Returns the nonce of the client if the message proves the token.
+pub(crate) fn verify_auth<'a>(
+    token: &[u8],
+    server_nonce: &[u8],
+    msg: &'a [u8],
+) -> Option<&'a [u8]> {
+    if msg.len() != NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (client_nonce, tag) = msg.split_at(NONCE_LEN);
+    hmac(token, b"recisdb card-server", server_nonce, client_nonce)
+        .verify_slice(tag)
+        .ok()
+        .map(|_| client_nonce)
+}
+
+/// The authenticated messages of a connection, after the handshake.
+pub(crate) struct Session {
+    key: Vec<u8>,
+    /// The direction of the messages sent, 0 for the client and 1 for the
+    /// server.
+    side: u8,
+    sent: u64,
+    received: u64,
+}
+
+impl Session {
+    pub(crate) fn new(
+        token: &[u8],
+        server_nonce: &[u8],
+        client_nonce: &[u8],
+        server: bool,
+    ) -> Self {
+        let key = hmac(token, b"recisdb session", server_nonce, client_nonce)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        Self {
+            key,
+            side: server as u8,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn mac(&self, side: u8, seq: u64, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&[side]);
+        mac.update(&seq.to_be_bytes());
+        mac.update(body);
+        mac
+    }
+
+    pub(crate) fn write(&mut self, w: &mut impl Write, body: &[u8]) -> Result<(), Error> {
+        let tag = self.mac(self.side, self.sent, body).finalize().into_bytes();
+        self.sent += 1;
+        write_frame(w, &[body, &tag[..]].concat())
+    }
+
+    pub(crate) fn read(&mut self, r: &mut impl Read) -> Result<Vec<u8>, Error> {
+        let mut msg = read_frame(r)?;
+        if msg.len() < TAG_LEN {
+            return Err(invalid());
+        }
+        let tag = msg.split_off(msg.len() - TAG_LEN);
+        if self
+            .mac(self.side ^ 1, self.received, &msg)
+            .verify_slice(&tag)
+            .is_err()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The message is not authenticated.",
+            ));
+        }
+        self.received += 1;
+        Ok(msg)
+    }
+}
+
+fn invalid() -> Error {
+    Error::new(ErrorKind::InvalidData, "Malformed message.")
+}
+
+/// Reads the fields of a message in order.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(invalid());
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    InitStatus,
+    CardIds,
+    PowerOnControl,
+    Ecm(Vec<u8>),
+    Emm(Vec<u8>),
+}
+
+impl Request {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        match self {
+            Request::InitStatus => vec![OP_INIT_STATUS],
+            Request::CardIds => vec![OP_CARD_IDS],
+            Request::PowerOnControl => vec![OP_POWER_ON_CONTROL],
+            Request::Ecm(body) => [&[OP_ECM], &body[..]].concat(),
+            Request::Emm(body) => [&[OP_EMM], &body[..]].concat(),
+        }
+    }
+
+    pub(crate) fn decode(msg: &[u8]) -> Result<Self, Error> {
+        match msg.split_first() {
+            Some((&OP_INIT_STATUS, _)) => Ok(Request::InitStatus),
+            Some((&OP_CARD_IDS, _)) => Ok(Request::CardIds),
+            Some((&OP_POWER_ON_CONTROL, _)) => Ok(Request::PowerOnControl),
+            Some((&OP_ECM, body)) => Ok(Request::Ecm(body.to_vec())),
+            Some((&OP_EMM, body)) => Ok(Request::Emm(body.to_vec())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Encodes the result of a request with `payload` writing its value.
+pub(crate) fn encode_response<T>(
+    result: Result<T, BCasCardError>,
+    payload: impl FnOnce(T, &mut Vec<u8>),
+) -> Vec<u8> {
+    match result {
+        Ok(value) => {
+            let mut msg = 0i32.to_be_bytes().to_vec();
+            payload(value, &mut msg);
+            msg
+        }
+        Err(e) => e.code().to_be_bytes().to_vec(),
+    }
+}
+
+/// The result of a request, the error of the card or the fields of the value.
+fn decode_response(msg: &[u8]) -> Result<Result<Fields<'_>, BCasCardError>, Error> {
+    let mut fields = Fields(msg);
+    match fields.i32()? {
+        0 => Ok(Ok(fields)),
+        code if code < 0 => Ok(Err(BCasCardError::from(code))),
+        _ => Err(invalid()),
+    }
+}
+
+pub(crate) fn put_status(status: CardStatus, msg: &mut Vec<u8>) {
+    msg.extend_from_slice(&status.system_key);
+    msg.extend_from_slice(&status.init_cbc);
+    msg.extend_from_slice(&status.card_id.to_be_bytes());
+    msg.extend_from_slice(&status.card_status.to_be_bytes());
+    msg.extend_from_slice(&status.ca_system_id.to_be_bytes());
+}
+
+pub(crate) fn get_status(msg: &[u8]) -> Result<Result<CardStatus, BCasCardError>, Error> {
+    Ok(match decode_response(msg)? {
+        Ok(mut f) => Ok(CardStatus {
+            system_key: f.take(32)?.try_into().unwrap(),
+            init_cbc: f.take(8)?.try_into().unwrap(),
+            card_id: f.i64()?,
+            card_status: f.i32()?,
+            ca_system_id: f.i32()?,
+        }),
+        Err(e) => Err(e),
+    })
+}
+
+pub(crate) fn put_ids(ids: Vec<i64>, msg: &mut Vec<u8>) {
+    msg.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+    for id in ids {
+        msg.extend_from_slice(&id.to_be_bytes());
+    }
+}
+
+pub(crate) fn get_ids(msg: &[u8]) -> Result<Result<Vec<i64>, BCasCardError>, Error> {
+    Ok(match decode_response(msg)? {
+        Ok(mut f) => Ok((0..f.u32()?).map(|_| f.i64()).collect::<Result<_, _>>()?),
+        Err(e) => Err(e),
+    })
+}
+
+pub(crate) fn put_power_on_control(pwc: Vec<PowerOnControl>, msg: &mut Vec<u8>) {
+    msg.extend_from_slice(&(pwc.len() as u32).to_be_bytes());
+    for p in pwc {
+        for v in [
+            p.start_year,
+            p.start_month,
+            p.start_day,
+            p.limit_year,
+            p.limit_month,
+            p.limit_day,
+            p.hold_time,
+            p.broadcaster_group_id,
+            p.network_id,
+            p.transport_id,
+        ] {
+            msg.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+}
+
+pub(crate) fn get_power_on_control(
+    msg: &[u8],
+) -> Result<Result<Vec<PowerOnControl>, BCasCardError>, Error> {
+    let mut f = match decode_response(msg)? {
+        Ok(f) => f,
+        Err(e) => return Ok(Err(e)),
+    };
+    let mut pwc = Vec::new();
+    for _ in 0..f.u32()? {
+        pwc.push(PowerOnControl {
+            start_year: f.i32()?,
+            start_month: f.i32()?,
+            start_day: f.i32()?,
+            limit_year: f.i32()?,
+            limit_month: f.i32()?,
+            limit_day: f.i32()?,
+            hold_time: f.i32()?,
+            broadcaster_group_id: f.i32()?,
+            network_id: f.i32()?,
+            transport_id: f.i32()?,
+        });
+    }
+    Ok(Ok(pwc))
+}
+
+pub(crate) fn put_ecm_result(result: EcmResult, msg: &mut Vec<u8>) {
+    msg.extend_from_slice(&result.scramble_key);
+    msg.extend_from_slice(&result.return_code.to_be_bytes());
+}
+
+pub(crate) fn get_ecm_result(msg: &[u8]) -> Result<Result<EcmResult, BCasCardError>, Error> {
+    Ok(match decode_response(msg)? {
+        Ok(mut f) => Ok(EcmResult {
+            scramble_key: f.take(16)?.try_into().unwrap(),
+            return_code: f.u32()?,
+        }),
+        Err(e) => Err(e),
+    })
+}
+
+pub(crate) fn put_nothing(_: (), _: &mut Vec<u8>) {}
+
+pub(crate) fn get_nothing(msg: &[u8]) -> Result<Result<(), BCasCardError>, Error> {
+    Ok(decode_response(msg)?.map(|_| ()))
+}
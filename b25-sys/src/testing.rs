@@ -0,0 +1,221 @@
+//! A mock card and synthetic scrambled streams, to test the decoder without a
+//! card reader.
+
+use std::sync::{Arc, Mutex};
+
+use crate::bindings::descrambler::crc32;
+use crate::{BCasCardError, CardStatus, CasCard, EcmResult, Multi2, Parity};
+
+pub(crate) const PMT_PID: u16 = 0x01f0;
+pub(crate) const ECM_PID: u16 = 0x01f1;
+pub(crate) const VIDEO_PID: u16 = 0x0100;
+const CA_SYSTEM_ID: u16 = 0x0005;
+
+pub(crate) const STATUS: CardStatus = CardStatus {
+    system_key: [
+        0x36, 0x31, 0x04, 0x66, 0x4b, 0x17, 0xea, 0x5c, 0x32, 0xdf, 0x9c, 0xf5, 0xc4, 0xc3, 0x6c,
+        0x1b, 0xec, 0x99, 0x39, 0x21, 0x68, 0x9d, 0x4b, 0xb7, 0xb7, 0x4e, 0x40, 0x84, 0x0d, 0x2e,
+        0x7d, 0x98,
+    ],
+    init_cbc: [0xfe, 0x27, 0x19, 0x99, 0x19, 0x69, 0x09, 0x11],
+    card_id: 0x0123_4567_89ab,
+    card_status: 0,
+    ca_system_id: CA_SYSTEM_ID as i32,
+};
+
+/// The scramble keys carried by the ECMs, the odd one followed by the even one.
+pub(crate) const SCRAMBLE_KEY: [u8; 16] = [
+    0xf5, 0x8e, 0x32, 0x9a, 0x0c, 0x61, 0xd7, 0x44, 0x18, 0xbb, 0x70, 0x2e, 0xa3, 0x56, 0xc9, 0x05,
+];
+
+/// The requests a `MockCard` has received.
+#[derive(Debug, Default)]
+pub(crate) struct CardLog {
+    pub ecms: Vec<Vec<u8>>,
+    pub emms: Vec<Vec<u8>>,
+}
+
+/// A card with the keys of `STATUS`, which takes the scramble keys from the
+/// first 16 bytes of the ECMs.
+pub(crate) struct MockCard {
+    pub init_error: Option<BCasCardError>,
+    pub ecm_error: Option<BCasCardError>,
+    pub emm_error: Option<BCasCardError>,
+    /// The return code of the ECMs, 0x0800 (purchased) by default.
+    pub return_code: u32,
+    /// Kept by the test, as the card is moved into the decoder.
+    pub log: Arc<Mutex<CardLog>>,
+}
+
+impl Default for MockCard {
+    fn default() -> Self {
+        Self {
+            init_error: None,
+            ecm_error: None,
+            emm_error: None,
+            return_code: 0x0800,
+            log: Arc::default(),
+        }
+    }
+}
+
+impl CasCard for MockCard {
+    fn init(&mut self) -> Result<(), BCasCardError> {
+        match self.init_error.clone() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn init_status(&mut self) -> Result<CardStatus, BCasCardError> {
+        Ok(STATUS)
+    }
+
+    fn proc_ecm(&mut self, ecm: &[u8]) -> Result<EcmResult, BCasCardError> {
+        self.log.lock().unwrap().ecms.push(ecm.to_vec());
+        if let Some(e) = self.ecm_error.clone() {
+            return Err(e);
+        }
+        let mut scramble_key = [0; 16];
+        scramble_key.copy_from_slice(
+            ecm.get(..16)
+                .ok_or(BCasCardError::BCAS_CARD_ERROR_INVALID_PARAMETER)?,
+        );
+        Ok(EcmResult {
+            scramble_key,
+            return_code: self.return_code,
+        })
+    }
+
+    fn proc_emm(&mut self, emm: &[u8]) -> Result<(), BCasCardError> {
+        self.log.lock().unwrap().emms.push(emm.to_vec());
+        match self.emm_error.clone() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A scrambled stream and the stream expected from it once descrambled.
+pub(crate) struct Fixture {
+    pub scrambled: Vec<u8>,
+    pub clear: Vec<u8>,
+}
+
+/// Builds a section with the syntax indicator and the CRC.
+pub(crate) fn section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+    let len = 5 + body.len() + 4;
+    let mut section = vec![table_id, 0xb0 | (len >> 8) as u8, len as u8];
+    section.extend_from_slice(&id.to_be_bytes());
+    section.extend_from_slice(&[0xc1, 0x00, 0x00]);
+    section.extend_from_slice(body);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// Puts a section of up to 183 bytes into a packet.
+pub(crate) fn psi_packet(pid: u16, cc: u8, section: &[u8]) -> Vec<u8> {
+    let mut packet = vec![
+        0x47,
+        0x40 | (pid >> 8) as u8,
+        pid as u8,
+        0x10 | (cc & 0x0f),
+        0x00,
+    ];
+    packet.extend_from_slice(section);
+    packet.resize(188, 0xff);
+    packet
+}
+
+/// The body of the ECM of `scrambled_stream`, starting with the scramble keys
+/// answered by `MockCard`.
+pub(crate) fn ecm_body() -> Vec<u8> {
+    let mut body = SCRAMBLE_KEY.to_vec();
+    body.extend_from_slice(&[0x5a; 14]);
+    body
+}
+
+/// Builds a stream with the PAT, the PMT and the ECM of a program, followed by
+/// `packets` packets of its video scrambled in `round` rounds, the first half
+/// with the even key and the rest with the odd one. Every third packet has an
+/// adaptation field, so that its payload ends with a residual block.
+pub(crate) fn scrambled_stream(packets: usize, round: usize) -> Fixture {
+    let pat = section(
+        0x00,
+        0x0001,
+        &[0x00, 0x01, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8],
+    );
+    let [ca_hi, ca_lo] = CA_SYSTEM_ID.to_be_bytes();
+    let [ecm_hi, ecm_lo] = ECM_PID.to_be_bytes();
+    let [video_hi, video_lo] = VIDEO_PID.to_be_bytes();
+    let pmt = section(
+        0x02,
+        0x0001,
+        &[
+            0xe0 | video_hi,
+            video_lo,
+            // A CA descriptor for the whole program
+            0xf0,
+            0x06,
+            0x09,
+            0x04,
+            ca_hi,
+            ca_lo,
+            0xe0 | ecm_hi,
+            ecm_lo,
+            // The video without descriptors
+            0x02,
+            0xe0 | video_hi,
+            video_lo,
+            0xf0,
+            0x00,
+        ],
+    );
+    let ecm = section(0x82, 0x0001, &ecm_body());
+
+    let mut fixture = Fixture {
+        scrambled: Vec::new(),
+        clear: Vec::new(),
+    };
+    for packet in [
+        psi_packet(0x0000, 0, &pat),
+        psi_packet(PMT_PID, 0, &pmt),
+        psi_packet(ECM_PID, 0, &ecm),
+    ] {
+        fixture.scrambled.extend_from_slice(&packet);
+        fixture.clear.extend_from_slice(&packet);
+    }
+
+    let mut multi2 = Multi2::new(&STATUS.system_key, &STATUS.init_cbc);
+    multi2.set_round(round);
+    multi2.set_scramble_key(&SCRAMBLE_KEY);
+    let mut seed = 0x2545_f491u32;
+    for i in 0..packets {
+        let mut packet = vec![0x47, video_hi, video_lo];
+        if i % 3 == 0 {
+            packet.extend_from_slice(&[0x30 | (i & 0x0f) as u8, 0x02, 0x00, 0xff]);
+        } else {
+            packet.push(0x10 | (i & 0x0f) as u8);
+        }
+        let offset = packet.len();
+        while packet.len() < 188 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            packet.push((seed >> 16) as u8);
+        }
+        fixture.clear.extend_from_slice(&packet);
+
+        let parity = if i < packets / 2 {
+            Parity::Even
+        } else {
+            Parity::Odd
+        };
+        multi2.encrypt(parity, &mut packet[offset..]);
+        packet[3] |= match parity {
+            Parity::Even => 0x80,
+            Parity::Odd => 0xc0,
+        };
+        fixture.scrambled.extend_from_slice(&packet);
+    }
+    fixture
+}
@@ -1,20 +1,34 @@
 use futures_time::time::Duration;
+use futures_util::{AsyncBufRead, AsyncBufReadExt, FutureExt};
 use std::future::Future;
 use std::io::Write;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use log::{error, info, warn};
 
-use b25_sys::DecoderOptions;
+use b25_sys::{CardServer, DecoderOptions, Multi2Backend, PcscCard};
 
 use crate::channels::representation::TsFilter;
 use crate::channels::{Channel, ChannelType};
 use crate::commands::utils::parse_keys;
 use crate::context::{Cli, Commands};
-use crate::io::AsyncInOutTriple;
-use crate::tuner::{Tunable, UnTunedTuner};
+use crate::io::{AsyncInOutTriple, DecodeThresholds, OutputCounter, PacketStage, Pipeline};
+use crate::ts::packet::{Framer, TS_PACKET_SIZE};
+use crate::ts::{
+    BroadcastClock, CaptionExtractor, CaptionFormat, ClockReader, EpgCollector, EventGate,
+    Inspector, PacketFilter, PacketMonitor, PartialTs, PcrAnalyzer, ServiceFilter,
+    ServiceSelection, SplitTrigger,
+};
+use crate::tuner::{Tunable, UnTunedTuner, Voltage};
 
 pub(crate) mod utils;
 
+/// Writes out what a command has collected from the stream, once the stream
+/// has ended or timed out.
+pub(crate) type Finish = Box<dyn FnOnce() -> std::io::Result<()>>;
+
 /// The behavior the user requested are returned.
 /// If an error occurred during preparation, the program bails out with expect().
 pub(crate) fn process_command(
@@ -23,6 +37,7 @@ pub(crate) fn process_command(
     impl Future<Output = std::io::Result<u64>>,
     Option<Duration>,
     Option<(u64, std::sync::mpsc::Receiver<u64>)>,
+    Option<Finish>,
 ) {
     const INPUT_BUF_DEFAULT: usize = 200000;
     let buf_sz = std::env::var("RECISDB_INPUT_BUF_BYTES")
@@ -53,7 +68,7 @@ pub(crate) fn process_command(
             );
 
             // Open tuner and tune to channel
-            let tuned = match UnTunedTuner::new(device, 0)
+            let mut tuned = match UnTunedTuner::new(device, buf_sz)
                 .map_err(|e| utils::error_handler::handle_opening_error(e.into()))
                 .unwrap()
                 .tune(channel, lnb)
@@ -64,8 +79,33 @@ pub(crate) fn process_command(
 
             // ctrlc::set_handler(|| std::process::exit(0)).expect("Error setting Ctrl-C handler");
 
+            // The stream is read to report the drift from the broadcast time
+            let mut clock = ClockReader::new(BroadcastClock::default(), None);
+            let mut framer = Framer::new();
+            let (mut packets, mut out) = (Vec::new(), Vec::new());
+
             loop {
-                print!("\r{:.2}dB", tuned.signal_quality());
+                while let Some(Ok(data)) = tuned.fill_buf().now_or_never() {
+                    if data.is_empty() {
+                        break;
+                    }
+                    let n = data.len();
+                    framer.feed(data, &mut packets);
+                    tuned.consume_unpin(n);
+                    for packet in packets.chunks_exact(TS_PACKET_SIZE) {
+                        clock.process(packet, &mut out);
+                        out.clear();
+                    }
+                    packets.clear();
+                }
+                // Tell when the device has stopped delivering the stream
+                let stalled = tuned
+                    .last_arrival()
+                    .map(|at| at.elapsed().as_secs())
+                    .filter(|&secs| secs >= 2)
+                    .map(|secs| format!(" (no data for {}s)", secs))
+                    .unwrap_or_default();
+                print!("\r{:.2}dB{:<20}", tuned.signal_quality(), stalled);
                 std::io::stdout().flush().unwrap();
                 std::thread::sleep(Duration::from_secs_f64(1.0).into())
             }
@@ -76,12 +116,36 @@ pub(crate) fn process_command(
             card,
             tsid,
             time,
+            broadcast_clock,
             no_decode: disable_decode,
             lnb,
+            arrival_index,
             key0,
             key1,
             no_simd,
+            rust_multi2,
+            card_server,
+            card_token_file,
             no_strip,
+            emm,
+            max_undecrypted,
+            max_undecrypted_ratio,
+            max_unpurchased_ecm,
+            sid,
+            partial_ts,
+            captions,
+            caption_format,
+            event_id,
+            margin_before,
+            margin_after,
+            drop_null,
+            pids,
+            exclude_pids,
+            drop_scrambled,
+            report_json,
+            output_format,
+            split_on,
+            split_schedule,
             output,
             exit_on_card_error,
         } => {
@@ -119,34 +183,120 @@ pub(crate) fn process_command(
             }
 
             // in, out, dec
-            let (input, _) = utils::get_src(device, Some(channel), None, lnb, buf_sz)
-                .map_err(|e| {
-                    error!("Failed to open input source: {}", e);
-                    std::process::exit(1);
-                })
-                .unwrap();
-            let output = utils::get_output(output)
-                .map_err(|e| {
-                    error!("Failed to open output: {}", e.kind());
+            let (input, _) =
+                utils::get_src(device, Some(channel), None, lnb, arrival_index, buf_sz)
+                    .map_err(|e| {
+                        error!("Failed to open input source: {}", e);
+                        std::process::exit(1);
+                    })
+                    .unwrap();
+            // The broadcast time is shared by the stages and the output
+            let clock = BroadcastClock::default();
+            let sid = keep_eit(sid, &split_on, event_id);
+            let single_service = sid.as_ref().and_then(ServiceSelection::single_id);
+            let counter = OutputCounter::default();
+            let output = if split_on.is_empty() && split_schedule.is_none() {
+                utils::get_output(output, output_format, &counter)
+            } else {
+                utils::get_split_output(
+                    output,
+                    output_format,
+                    split_on,
+                    split_schedule,
+                    single_service,
+                    broadcast_clock.then(|| clock.clone()),
+                    &counter,
+                )
+            }
+            .map_err(|e| {
+                error!("Failed to open output: {}", e.kind());
+                std::process::exit(1);
+            })
+            .unwrap();
+            let enable_working_key = !disable_decode && parse_keys(key0, key1);
+            let remote_card = card_server.map(|addr| match card_token_file.as_deref() {
+                Some(token_file) => utils::remote_card(addr, token_file),
+                None => {
+                    error!("--card-server requires --card-token-file.");
                     std::process::exit(1);
-                })
-                .unwrap();
+                }
+            });
+            // Called again to rebuild the decoder when it fails
+            let options = move || DecoderOptions {
+                enable_working_key,
+                simd: !no_simd,
+                multi2: if rust_multi2 {
+                    Multi2Backend::Rust
+                } else {
+                    Multi2Backend::Libaribb25
+                },
+                strip: !no_strip,
+                emm,
+                on_warning: Some(Box::new(utils::log_decoder_warning)),
+                card: remote_card.as_ref().map(|card| card()),
+                ..DecoderOptions::default()
+            };
             let dec = if disable_decode {
                 info!("Decode: Disabled");
                 None
             } else {
                 info!("Decode: Enabled");
-                Some(DecoderOptions {
-                    enable_working_key: parse_keys(key0, key1),
-                    simd: !no_simd,
-                    strip: !no_strip,
-                    ..DecoderOptions::default()
-                })
+                Some(options())
             };
 
-            let (body, _) = AsyncInOutTriple::new(input, output, dec, !exit_on_card_error);
+            let mut pipeline = Pipeline::default();
+            pipeline.before_decoder(PacketMonitor::new(report_json.map(PathBuf::from)));
+            // The ClockReader measures the duration if the broadcast time is used
+            if broadcast_clock {
+                info!("Clock: Broadcast time");
+            }
+            let stop_after = rec_duration.filter(|_| broadcast_clock).map(Into::into);
+            pipeline.before_decoder(ClockReader::new(clock.clone(), stop_after));
+            if let Some(event_id) = event_id {
+                let service_id = match sid.as_ref().and_then(ServiceSelection::single_id) {
+                    Some(service_id) => service_id,
+                    None => {
+                        error!("--event-id requires a single service ID in --sid.");
+                        std::process::exit(1);
+                    }
+                };
+                info!("Event: {:#06x} of service {}", event_id, service_id);
+                // After the decoder, which needs the PAT, the PMT and the ECM
+                // sent before the event starts
+                pipeline.after_decoder_ending(EventGate::new(
+                    service_id,
+                    event_id,
+                    std::time::Duration::from_secs_f64(margin_before.unwrap_or(0.0)),
+                    std::time::Duration::from_secs_f64(margin_after.unwrap_or(0.0)),
+                    broadcast_clock.then_some(clock),
+                ));
+            }
+            if let Some(selection) = sid {
+                add_service_stages(&mut pipeline, selection, partial_ts);
+            }
+            if let Some(path) = captions.map(PathBuf::from) {
+                let format = caption_format.unwrap_or_else(|| CaptionFormat::from_path(&path));
+                pipeline.after_decoder(CaptionExtractor::new(single_service, format, path));
+            }
+            if let Some(filter) = PacketFilter::new(drop_null, pids, exclude_pids, drop_scrambled) {
+                pipeline.after_decoder(filter);
+            }
+
+            let (body, _) =
+                AsyncInOutTriple::new(input, output, dec, !exit_on_card_error, pipeline);
+            let body = body
+                .with_thresholds(DecodeThresholds {
+                    max_undecrypted,
+                    max_undecrypted_ratio,
+                    max_unpurchased_ecm,
+                })
+                .with_recovery(Box::new(options))
+                .with_output_counter(counter)
+                // Ended by the run rather than by a timeout, so that the
+                // decoder is flushed and its statistics are checked
+                .with_duration(rec_duration.filter(|_| !broadcast_clock).map(Into::into));
             info!("Recording...");
-            (body, rec_duration, None)
+            (body, None, None, None)
         }
         Commands::Decode {
             source,
@@ -154,7 +304,26 @@ pub(crate) fn process_command(
             key0,
             key1,
             no_simd,
+            rust_multi2,
+            card_server,
+            card_token_file,
             no_strip,
+            emm,
+            max_undecrypted,
+            max_undecrypted_ratio,
+            max_unpurchased_ecm,
+            sid,
+            partial_ts,
+            captions,
+            caption_format,
+            drop_null,
+            pids,
+            exclude_pids,
+            drop_scrambled,
+            report_json,
+            output_format,
+            split_on,
+            split_schedule,
             output,
         } => {
             // Card reader
@@ -167,28 +336,199 @@ pub(crate) fn process_command(
             }
 
             // in, out, dec
-            let (input, input_sz) = utils::get_src(None, None, source, None, buf_sz)
+            let (input, input_sz) = utils::get_src(None, None, source, None, None, buf_sz)
                 .map_err(|e| {
                     error!("Failed to open input source: {}", e);
                     std::process::exit(1);
                 })
                 .unwrap();
-            let output = utils::get_output(output)
-                .map_err(|e| {
-                    error!("Failed to open output: {}", e.kind());
-                    std::process::exit(1);
-                })
-                .unwrap();
+            let sid = keep_eit(sid, &split_on, None);
+            let single_service = sid.as_ref().and_then(ServiceSelection::single_id);
+            let counter = OutputCounter::default();
+            let output = if split_on.is_empty() && split_schedule.is_none() {
+                utils::get_output(output, output_format, &counter)
+            } else {
+                utils::get_split_output(
+                    output,
+                    output_format,
+                    split_on,
+                    split_schedule,
+                    single_service,
+                    None,
+                    &counter,
+                )
+            }
+            .map_err(|e| {
+                error!("Failed to open output: {}", e.kind());
+                std::process::exit(1);
+            })
+            .unwrap();
             let dec = Some(DecoderOptions {
                 enable_working_key: parse_keys(key0, key1),
                 simd: !no_simd,
+                multi2: if rust_multi2 {
+                    Multi2Backend::Rust
+                } else {
+                    Multi2Backend::Libaribb25
+                },
                 strip: !no_strip,
+                emm,
+                on_warning: Some(Box::new(utils::log_decoder_warning)),
+                card: card_server.map(|addr| match card_token_file.as_deref() {
+                    Some(token_file) => utils::remote_card(addr, token_file)(),
+                    None => {
+                        error!("--card-server requires --card-token-file.");
+                        std::process::exit(1);
+                    }
+                }),
                 ..DecoderOptions::default()
             });
 
-            let (body, progress) = AsyncInOutTriple::new(input, output, dec, false);
+            let mut pipeline = Pipeline::default();
+            pipeline.before_decoder(PacketMonitor::new(report_json.map(PathBuf::from)));
+            if let Some(selection) = sid {
+                add_service_stages(&mut pipeline, selection, partial_ts);
+            }
+            if let Some(path) = captions.map(PathBuf::from) {
+                let format = caption_format.unwrap_or_else(|| CaptionFormat::from_path(&path));
+                pipeline.after_decoder(CaptionExtractor::new(single_service, format, path));
+            }
+            if let Some(filter) = PacketFilter::new(drop_null, pids, exclude_pids, drop_scrambled) {
+                pipeline.after_decoder(filter);
+            }
+
+            let (body, progress) = AsyncInOutTriple::new(input, output, dec, false, pipeline);
+            let body = body
+                .with_thresholds(DecodeThresholds {
+                    max_undecrypted,
+                    max_undecrypted_ratio,
+                    max_unpurchased_ecm,
+                })
+                .with_output_counter(counter);
             info!("Decoding...");
-            (body, None, input_sz.map(|sz| (sz, progress)))
+            (body, None, input_sz.map(|sz| (sz, progress)), None)
+        }
+        Commands::Epg {
+            device,
+            channel,
+            tsid,
+            lnb,
+            source,
+            time,
+            format,
+            output,
+        } => {
+            let (input, timeout, input_sz) =
+                open_source(device, channel, tsid, lnb, source, time, buf_sz);
+            let output = output.filter(|path| path != "-").map(PathBuf::from);
+
+            let collector = Arc::new(Mutex::new(EpgCollector::new(format, output)));
+            let mut pipeline = Pipeline::default();
+            pipeline.before_decoder(collector.clone());
+
+            let (body, progress) =
+                AsyncInOutTriple::new(input, Box::new(std::io::sink()), None, false, pipeline);
+            info!("Collecting EPG...");
+            let finish: Finish = Box::new(move || collector.lock().unwrap().finish());
+            (
+                body,
+                timeout,
+                input_sz.map(|sz| (sz, progress)),
+                Some(finish),
+            )
+        }
+        Commands::Analyze {
+            device,
+            channel,
+            tsid,
+            lnb,
+            source,
+            time,
+            interval,
+            format,
+            output,
+        } => {
+            if !interval.is_finite() || interval <= 0.0 {
+                error!("The interval must be positive.");
+                std::process::exit(1);
+            }
+            let (input, timeout, input_sz) =
+                open_source(device, channel, tsid, lnb, source, time, buf_sz);
+            let output = output.filter(|path| path != "-").map(PathBuf::from);
+
+            let analyzer = Arc::new(Mutex::new(PcrAnalyzer::new(interval, format, output)));
+            let mut pipeline = Pipeline::default();
+            pipeline.before_decoder(analyzer.clone());
+
+            let (body, progress) =
+                AsyncInOutTriple::new(input, Box::new(std::io::sink()), None, false, pipeline);
+            info!("Analyzing...");
+            let finish: Finish = Box::new(move || analyzer.lock().unwrap().finish());
+            (
+                body,
+                timeout,
+                input_sz.map(|sz| (sz, progress)),
+                Some(finish),
+            )
+        }
+        Commands::Inspect {
+            device,
+            channel,
+            tsid,
+            lnb,
+            source,
+            time,
+            format,
+            output,
+        } => {
+            let (input, timeout, input_sz) =
+                open_source(device, channel, tsid, lnb, source, time, buf_sz);
+            let output = output.filter(|path| path != "-").map(PathBuf::from);
+
+            let inspector = Arc::new(Mutex::new(Inspector::new(format, output)));
+            let mut pipeline = Pipeline::default();
+            pipeline.before_decoder(inspector.clone());
+
+            let (body, progress) =
+                AsyncInOutTriple::new(input, Box::new(std::io::sink()), None, false, pipeline);
+            info!("Inspecting...");
+            let finish: Finish = Box::new(move || inspector.lock().unwrap().finish());
+            (
+                body,
+                timeout,
+                input_sz.map(|sz| (sz, progress)),
+                Some(finish),
+            )
+        }
+        Commands::CardServer { listen, token_file } => {
+            let token = utils::read_token(&token_file);
+            let card = match PcscCard::new() {
+                Ok(card) => card,
+                Err(e) => {
+                    error!("Failed to create the card. ({})", e);
+                    std::process::exit(1);
+                }
+            };
+            let server = match CardServer::new(Box::new(card), &token) {
+                Ok(server) => server,
+                Err(e) => {
+                    error!("Failed to initialize the card. ({})", e);
+                    error!("Make sure that the B-CAS card is certainly connected.");
+                    std::process::exit(1);
+                }
+            };
+            let listener = match TcpListener::bind(&listen) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to listen on {}. ({})", listen, e);
+                    std::process::exit(1);
+                }
+            };
+            info!("Serving the card on {}", listen);
+            if let Err(e) = server.serve(listener) {
+                error!("The card server has stopped. ({})", e);
+            }
+            std::process::exit(1)
         }
         #[cfg(windows)]
         Commands::Enumerate { device, space } => {
@@ -207,3 +547,166 @@ pub(crate) fn process_command(
         }
     }
 }
+
+/// Opens the tuner or the file read by the commands that look into the
+/// stream. Returns the input, how long to read the tuner and the size of the
+/// file.
+fn open_source(
+    device: Option<String>,
+    channel: Option<String>,
+    tsid: Option<u32>,
+    lnb: Option<Voltage>,
+    source: Option<String>,
+    time: f64,
+    buf_sz: usize,
+) -> (Box<dyn AsyncBufRead + Unpin>, Option<Duration>, Option<u64>) {
+    let channel = channel.map(|ch| Channel::new(ch, tsid));
+    if let Some(channel) = &channel {
+        if let ChannelType::Undefined = channel.ch_type {
+            error!("The specified channel is invalid.");
+            std::process::exit(1);
+        }
+        if let Some(device) = &device {
+            info!("Tuner: {}", device);
+        }
+        info!(
+            "Channel: {} / {}",
+            channel.get_raw_ch_name(),
+            channel.ch_type
+        );
+    }
+    let timeout = channel.is_some().then(|| {
+        info!("Duration: {} seconds", time);
+        Duration::from_secs_f64(time)
+    });
+
+    let (input, input_sz) = utils::get_src(device, channel, source, lnb, None, buf_sz)
+        .map_err(|e| {
+            error!("Failed to open input source: {}", e);
+            std::process::exit(1);
+        })
+        .unwrap();
+    (input, timeout, input_sz)
+}
+
+/// Extracts the services before the decoder, which keeps their ECMs and the
+/// EMMs, and converts the stream to a partial TS after it.
+fn add_service_stages(pipeline: &mut Pipeline, selection: ServiceSelection, partial_ts: bool) {
+    info!("Services: {}", selection);
+    pipeline.before_decoder(ServiceFilter::new(selection));
+    // After the decoder, as the decoder needs the CAT that `PartialTs` removes
+    if partial_ts {
+        pipeline.after_decoder(PartialTs::new());
+    }
+}
+
+/// Keeps the EIT in the extracted services when the events are followed in
+/// its present/following after the decoder, to split on them or to record
+/// one of them.
+fn keep_eit(
+    sid: Option<ServiceSelection>,
+    split_on: &[SplitTrigger],
+    event_id: Option<u16>,
+) -> Option<ServiceSelection> {
+    sid.map(|mut selection| {
+        if split_on.contains(&SplitTrigger::Event) && !selection.epg {
+            info!("The EIT is kept to split on the events.");
+            selection.epg = true;
+        }
+        if event_id.is_some() && !selection.epg {
+            info!("The EIT is kept to follow the event.");
+            selection.epg = true;
+        }
+        selection
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::Packet;
+    use crate::ts::psi;
+    use futures_util::AsyncReadExt;
+
+    fn es(pid: u16) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10]);
+        p
+    }
+
+    fn section(pid: u16, table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        psi::packetize(
+            pid,
+            &psi::build_section(table_id, id, 0, body),
+            &mut 0,
+            &mut out,
+        );
+        out
+    }
+
+    fn pids(out: &[u8]) -> Vec<u16> {
+        out.chunks(TS_PACKET_SIZE)
+            .map(|p| Packet::new(p).pid())
+            .collect()
+    }
+
+    #[test]
+    fn test_emm_with_service_stages() {
+        // The service 0x400 scrambled with the ECMs on 0x901, the EMMs on 0x902
+        // and another service on 0x121
+        let ca = |pid: u16| [0x09, 4, 0x00, 0x05, 0xE0 | (pid >> 8) as u8, pid as u8];
+        let mut pmt = vec![0xE1, 0x11, 0xF0, 6];
+        pmt.extend_from_slice(&ca(0x901));
+        pmt.extend_from_slice(&[0x02, 0xE1, 0x11, 0xF0, 0]);
+        let input = [
+            section(
+                0x0000,
+                psi::TABLE_PAT,
+                0x7FE0,
+                &[0x04, 0x00, 0xE1, 0xF0, 0x04, 0x01, 0xE1, 0xF1],
+            ),
+            section(0x1F0, psi::TABLE_PMT, 0x400, &pmt),
+            section(0x0001, psi::TABLE_CAT, 0xFFFF, &ca(0x902)),
+            es(0x902),
+            es(0x901),
+            es(0x111),
+            es(0x121),
+        ]
+        .concat();
+
+        for partial_ts in [false, true] {
+            let mut pipeline = Pipeline::default();
+            add_service_stages(&mut pipeline, "1024".parse().unwrap(), partial_ts);
+            let source = futures_util::io::Cursor::new(input.clone());
+            let sink = Arc::new(Mutex::new(Vec::new()));
+            struct Sink(Arc<Mutex<Vec<u8>>>);
+            impl Write for Sink {
+                fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                    self.0.lock().unwrap().extend_from_slice(buf);
+                    Ok(buf.len())
+                }
+                fn flush(&mut self) -> std::io::Result<()> {
+                    Ok(())
+                }
+            }
+            let (mut i, mut o, _) = pipeline.attach(Box::new(source), Box::new(Sink(sink.clone())));
+
+            // The decoder is given the CAT and the EMMs
+            let mut decoder_input = Vec::new();
+            futures_executor::block_on(i.read_to_end(&mut decoder_input)).unwrap();
+            assert_eq!(
+                pids(&decoder_input),
+                vec![0x0000, 0x1F0, 0x0001, 0x902, 0x901, 0x111],
+                "{}",
+                partial_ts
+            );
+
+            o.write_all(&decoder_input).unwrap();
+            drop(o);
+            let output = pids(&sink.lock().unwrap());
+            assert_eq!(output.contains(&0x0001), !partial_ts);
+            assert!(output.contains(&0x111));
+        }
+    }
+}
@@ -3,11 +3,17 @@ use std::io::Write;
 use std::path::Path;
 use std::{fs, io};
 
+use b25_sys::{CasCard, DecoderWarning, RemoteCard};
 use futures_util::io::{AllowStdIo, BufReader};
 use futures_util::AsyncBufRead;
-use log::{error, info};
+use log::{debug, error, info, warn};
 
 use crate::channels;
+use crate::io::OutputCounter;
+use crate::ts::{
+    BroadcastClock, FileTemplate, M2tsWriter, OutputFormat, Schedule, SplitTrigger, SplitWriter,
+    DEFAULT_SPLIT_TEMPLATE,
+};
 use crate::tuner::{Tunable, UnTunedTuner, Voltage};
 
 pub(crate) mod error_handler {
@@ -99,16 +105,24 @@ pub(crate) fn get_src(
     channel: Option<channels::Channel>,
     source: Option<String>,
     lnb: Option<Voltage>,
+    arrival_index: Option<String>,
     buf_sz: usize,
 ) -> Result<(Box<dyn AsyncBufRead + Unpin>, Option<u64>), Box<dyn Error>> {
     match (device, channel, source) {
         (Some(device), Some(channel), None) => {
-            let inner = UnTunedTuner::new(device, buf_sz)
+            let mut inner = UnTunedTuner::new(device, buf_sz)
                 .map_err(|e| error_handler::handle_opening_error(e.into()))
                 .unwrap()
                 .tune(channel, lnb)
                 .map_err(|e| error_handler::handle_tuning_error(e))
                 .unwrap();
+            if let Some(path) = arrival_index {
+                let index = io::BufWriter::new(fs::File::create(&path)?);
+                match inner.set_arrival_index(Box::new(index)) {
+                    Ok(()) => info!("Arrival index: {}", path),
+                    Err(e) => warn!("Cannot write the arrival index: {}", e),
+                }
+            }
             Ok((Box::new(inner) as Box<dyn AsyncBufRead + Unpin>, None))
         }
         (None, None, Some(src)) => {
@@ -133,13 +147,76 @@ pub(crate) fn get_src(
             let input = BufReader::with_capacity(20000, AllowStdIo::new(fs::File::open(src)?));
             Ok((Box::new(input) as Box<dyn AsyncBufRead + Unpin>, src_sz))
         }
-        _ => unreachable!("Either device & channel or source must be specified."),
+        _ => Err("Either the device and the channel or the source must be specified.".into()),
     }
 }
 
-pub(crate) fn get_output(path: Option<String>) -> Result<Box<dyn Write>, io::Error> {
+/// Open the output, whose bytes are counted by `counter`.
+pub(crate) fn get_output(
+    path: Option<String>,
+    format: OutputFormat,
+    counter: &OutputCounter,
+) -> Result<Box<dyn Write + Send>, io::Error> {
+    let output = counter.wrap(open_output(path, format)?);
+    Ok(match format {
+        OutputFormat::Ts => output,
+        OutputFormat::M2ts => Box::new(M2tsWriter::new(output)),
+    })
+}
+
+/// Open the output split into files named after the template in `path`.
+pub(crate) fn get_split_output(
+    path: Option<String>,
+    format: OutputFormat,
+    triggers: Vec<SplitTrigger>,
+    schedule: Option<Schedule>,
+    service_id: Option<u16>,
+    clock: Option<BroadcastClock>,
+    counter: &OutputCounter,
+) -> Result<Box<dyn Write + Send>, io::Error> {
+    let invalid = |message: String| {
+        error!("{}", message);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    };
+    let path = match path {
+        Some(path) if path != "-" => path,
+        _ => {
+            return Err(invalid(
+                "A split recording cannot be written to stdout.".to_string(),
+            ))
+        }
+    };
+    // A directory is given a default template
+    let template = if Path::new(&path).is_dir()
+        || path.ends_with('/')
+        || (cfg!(windows) && path.ends_with('\\'))
+    {
+        Path::new(&path)
+            .join(DEFAULT_SPLIT_TEMPLATE)
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        path
+    };
+    let template: FileTemplate = template.parse().map_err(invalid)?;
+    info!("Split: {}", template);
+    Ok(Box::new(SplitWriter::new(
+        template,
+        format,
+        triggers,
+        schedule,
+        service_id,
+        clock,
+        counter.clone(),
+    )))
+}
+
+fn open_output(
+    path: Option<String>,
+    format: OutputFormat,
+) -> Result<Box<dyn Write + Send>, io::Error> {
     match path {
-        Some(s) if s == "-" => Ok(Box::new(std::io::stdout().lock()) as Box<dyn Write>),
+        Some(s) if s == "-" => Ok(Box::new(std::io::stdout()) as Box<dyn Write + Send>),
         Some(s) if s == "/dev/null" => Ok(Box::new(fs::File::create(s)?)),
         Some(path) => {
             let p = Path::new(&path);
@@ -176,14 +253,16 @@ pub(crate) fn get_output(path: Option<String>) -> Result<Box<dyn Write>, io::Err
                 }
             }
             // If the path is a directory, we will create a new file with the UNIX epoch time as the filename in this directory.
+            // The extension is that of the output format.
             let filename_time_now = std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs();
             Ok(Box::new(fs::File::create(format!(
-                "{}/{}.m2ts",
+                "{}/{}.{}",
                 path_buf.to_str().unwrap(),
-                filename_time_now
+                filename_time_now,
+                format.extension()
             ))?))
         }
         None => Err(std::io::Error::new(
@@ -193,6 +272,20 @@ pub(crate) fn get_output(path: Option<String>) -> Result<Box<dyn Write>, io::Err
     }
 }
 
+/// Logs the warnings of the decoder that would otherwise go unnoticed.
+pub(crate) fn log_decoder_warning(warning: DecoderWarning) {
+    match warning {
+        DecoderWarning::UnpurchasedEcm => {
+            warn!("The card refused an ECM. The program is not purchased.")
+        }
+        DecoderWarning::BrokenSection | DecoderWarning::SectionIdMismatch => {
+            debug!("Decoder: {:?}", warning)
+        }
+        // Usual until the first PAT, PMT and ECM arrive
+        _ => {}
+    }
+}
+
 pub(crate) fn parse_keys(key0: Option<Vec<String>>, key1: Option<Vec<String>>) -> bool {
     //Parse and store keys and if configuration is valid, return true.
     match (key0, key1) {
@@ -212,3 +305,60 @@ pub(crate) fn parse_keys(key0: Option<Vec<String>>, key1: Option<Vec<String>>) -
         _ => panic!("Specify both of the keys."),
     }
 }
+
+/// Reads the token shared with the card server, without the trailing newline.
+pub(crate) fn read_token(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(mut token) => {
+            while token.last().map_or(false, |b| b.is_ascii_whitespace()) {
+                token.pop();
+            }
+            if token.is_empty() {
+                error!("The token file {} is empty.", path);
+                std::process::exit(1);
+            }
+            token
+        }
+        Err(e) => {
+            error!("Failed to read the token file {}. ({})", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The card served by `recisdb card-server` at `addr`, connected on each call.
+/// The token is read once, as a failure to read it ends the process.
+pub(crate) fn remote_card(addr: String, token_file: &str) -> impl Fn() -> Box<dyn CasCard> {
+    info!("Card server: {}", addr);
+    let token = read_token(token_file);
+    move || Box::new(RemoteCard::new(&addr, &token)) as Box<dyn CasCard>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_directory() {
+        let dir = std::env::temp_dir().join(format!("recisdb-test-output-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // The file created in the directory is named after the format
+        for format in [OutputFormat::Ts, OutputFormat::M2ts] {
+            let sub = dir.join(format.extension());
+            fs::create_dir_all(&sub).unwrap();
+            drop(open_output(Some(sub.to_str().unwrap().to_string()), format).unwrap());
+            let names: Vec<_> = fs::read_dir(&sub)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            assert_eq!(names.len(), 1);
+            assert_eq!(
+                names[0].extension().unwrap().to_str(),
+                Some(format.extension())
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 use clap::{ArgGroup, Parser, Subcommand};
 use clap_num::maybe_hex;
 
+use crate::ts::{
+    AnalysisFormat, CaptionFormat, EpgFormat, InspectFormat, OutputFormat, PidList, Schedule,
+    ServiceSelection, SplitTrigger,
+};
 use crate::tuner::Voltage;
 
 #[derive(Debug, Parser)]
@@ -101,7 +105,16 @@ pub(crate) enum Commands {
         #[clap(short, long, value_name = "seconds")]
         time: Option<f64>,
 
-        /// Exit if the decoding fails while processing.
+        /// Use the broadcast time in the TOT/TDT instead of the host clock.{n}
+        /// The duration of `--time` is measured, and the start and the end
+        /// of `--event-id` are compared, on the broadcast time.
+        /// The drift of the host clock is reported either way.
+        #[clap(long = "broadcast-clock")]
+        broadcast_clock: bool,
+
+        /// Exit if the decoding fails while processing.{n}
+        /// Otherwise the stream is written undecoded until the card and the
+        /// decoder are reinitialized, and the undecoded ranges are logged.
         #[clap(short = 'e', long)]
         exit_on_card_error: bool,
 
@@ -112,16 +125,61 @@ pub(crate) enum Commands {
         /// Disable SIMD in MULTI2 processing.
         #[clap(long = "no-simd")]
         no_simd: bool,
+        /// Descramble with MULTI2 in Rust instead of libaribb25.{n}
+        /// SSE2, AVX2 or NEON is chosen by the CPU at runtime, so that no
+        /// SIGILL occurs. '--no-simd' selects the scalar code.
+        #[clap(long = "rust-multi2")]
+        rust_multi2: bool,
+        /// Use the B-CAS card served by `recisdb card-server` at the address.{n}
+        /// The connection is retried when it is lost.
+        /// The messages are authenticated with the token but not encrypted,
+        /// so the scramble keys can be read on the network; use a trusted
+        /// network or a tunnel.
+        #[clap(
+            long = "card-server",
+            value_name = "HOST:PORT",
+            requires = "card_token_file",
+            conflicts_with = "key"
+        )]
+        card_server: Option<String>,
+        /// The file containing the token shared with the card server.
+        #[clap(long = "card-token-file", value_name = "PATH")]
+        card_token_file: Option<String>,
         /// Disable null packet stripping.{n}
         /// If this flag is specified, the decoder won't discard meaningless packets automatically.
         #[clap(long = "no-strip")]
         no_strip: bool,
+        /// Pass the EMMs addressed to the card to it.{n}
+        /// The EMMs received and applied are counted for each card id, and
+        /// printed at the end.
+        #[clap(long = "emm")]
+        emm: bool,
+
+        /// Fail the run if a program has more undecrypted packets than this.{n}
+        /// The decryption statistics of each program are printed at the end.
+        #[clap(long = "max-undecrypted", value_name = "PACKETS")]
+        max_undecrypted: Option<u64>,
+        /// Fail the run if the ratio of the undecrypted packets of a program
+        /// is above this (0.0 - 1.0).
+        #[clap(long = "max-undecrypted-ratio", value_name = "RATIO", value_parser = parse_ratio)]
+        max_undecrypted_ratio: Option<f64>,
+        /// Fail the run if the card refused more ECMs of a program than this
+        /// as not purchased.
+        #[clap(long = "max-unpurchased-ecm", value_name = "COUNT")]
+        max_unpurchased_ecm: Option<u32>,
 
         /// LNB voltage.
         /// If none, the LNB voltage is assumed unset.{n}
         #[clap(value_enum, long = "lnb")]
         lnb: Option<Voltage>,
 
+        /// Write the arrival time of the tuner data to a sidecar index.{n}
+        /// Each line holds the byte offset in the tuner stream,
+        /// the arrival time in nanoseconds and the length of a chunk.{n}
+        /// Useful for correlating dropouts with signal logs (Linux only).
+        #[clap(long = "arrival-index", value_name = "FILE")]
+        arrival_index: Option<String>,
+
         /// The first working key (only available w/ "crypto" feature).{n}
         /// The first working key is a 64-bit hexadecimal number.{n}
         /// If the first working key is not specified, this subcommand
@@ -135,6 +193,104 @@ pub(crate) enum Commands {
         #[clap(long = "key1")]
         key1: Option<Vec<String>>,
 
+        /// Extract services, as `--sid` of recpt1 does.{n}
+        /// A comma-separated list of service IDs and the keywords
+        /// `hd`, `sd1`, `sd2`, `sd3`, `1seg`, `all` and `epg`.{n}
+        /// The PAT is rewritten to list the selected services only.
+        /// The NIT, SDT and TOT are always kept, and the EIT is kept
+        /// when `epg` is given.
+        #[clap(long, value_name = "SID_LIST")]
+        sid: Option<ServiceSelection>,
+        /// Write the services extracted by `--sid` as a partial TS
+        /// defined in ARIB STD-B21.{n}
+        /// The NIT entry is removed from the PAT, and a Selection
+        /// Information Table (SIT) is sent in place of the NIT and the SDT.
+        /// The NIT, SDT, TOT and CAT are removed.
+        #[clap(long = "partial-ts", requires = "sid")]
+        partial_ts: bool,
+
+        /// Extract the captions of a service into a file.{n}
+        /// The service is the one given to `--sid` if it is a single
+        /// service ID, or the first service in the PAT otherwise.
+        /// DRCS characters are replaced with '〓'.
+        #[clap(long, value_name = "FILE")]
+        captions: Option<String>,
+        /// The format of the captions.{n}
+        /// If not specified, the format is chosen by the extension
+        /// of the file, and SubRip is used for unknown extensions.
+        #[clap(value_enum, long = "caption-format", requires = "captions")]
+        caption_format: Option<CaptionFormat>,
+
+        /// Record an event of the service given to `--sid`.{n}
+        /// The recording starts when the event starts and stops when it
+        /// ends, following the EIT present/following of the service,
+        /// so that delays and extensions are recorded as well.{n}
+        /// The event ID is a decimal or hexadecimal number.
+        #[clap(long = "event-id", value_name = "EVENT_ID", value_parser=maybe_hex::<u16>, requires = "sid")]
+        event_id: Option<u16>,
+        /// Start recording the event earlier than the start time
+        /// in the EIT by the specified seconds, even if the previous
+        /// program is still on air.{n}
+        /// Up to 86400 seconds.
+        #[clap(long = "margin-before", value_name = "seconds", value_parser = parse_margin, requires = "event_id")]
+        margin_before: Option<f64>,
+        /// Keep recording after the end of the event for the specified seconds.{n}
+        /// Up to 86400 seconds.
+        #[clap(long = "margin-after", value_name = "seconds", value_parser = parse_margin, requires = "event_id")]
+        margin_after: Option<f64>,
+
+        /// Drop null packets (PID 0x1FFF) from the output.
+        #[clap(long = "drop-null")]
+        drop_null: bool,
+        /// Keep only the packets of the specified PIDs in the output.{n}
+        /// A comma-separated list of PIDs in decimal or hexadecimal.
+        #[clap(long, value_name = "PID_LIST", conflicts_with = "exclude_pids")]
+        pids: Option<PidList>,
+        /// Drop the packets of the specified PIDs from the output.{n}
+        /// A comma-separated list of PIDs in decimal or hexadecimal.
+        #[clap(long = "exclude-pids", value_name = "PID_LIST")]
+        exclude_pids: Option<PidList>,
+        /// Drop the packets that are still scrambled after decoding.
+        #[clap(long = "drop-scrambled")]
+        drop_scrambled: bool,
+
+        /// Write the per-PID packet report to a file in JSON.{n}
+        /// The report of drops, transport errors and scrambled packets
+        /// is always logged at the end.
+        #[clap(long = "report-json", value_name = "FILE")]
+        report_json: Option<String>,
+
+        /// The format of the output.{n}
+        /// `m2ts` writes BDAV MPEG-2 TS, whose 192-byte packets carry
+        /// an arrival time stamp derived from the PCR.
+        #[clap(value_enum, long = "output-format", default_value = "ts")]
+        output_format: OutputFormat,
+
+        /// Start a new file when the present event in the EIT changes
+        /// (`event`), or when the version or the ES composition of the PMT
+        /// changes (`pmt`).{n}
+        /// The service followed is the one given to `--sid` if it is a
+        /// single service ID, or the first service in the PAT otherwise.{n}
+        /// When splitting, the output is a template of the file names,
+        /// formatted with strftime after `{n}`, `{sid}`, `{event_id}` and
+        /// `{ext}` are replaced with the sequence number of the file, the
+        /// service ID, the event ID and the extension. If the output is a
+        /// directory, the files are named `%Y%m%d-%H%M%S_{n}.{ext}` in it.
+        /// Each file starts with the PAT and the PMT. Splitting on `event`
+        /// keeps the EIT in the services extracted with `--sid`.
+        #[clap(
+            value_enum,
+            long = "split-on",
+            value_name = "TRIGGER",
+            value_delimiter = ','
+        )]
+        split_on: Vec<SplitTrigger>,
+        /// Start a new file on a cron-like schedule.{n}
+        /// The five fields are minute, hour, day of month, month and day
+        /// of week, e.g. "0 * * * *" for every hour.
+        #[clap(long = "split-schedule", value_name = "CRON")]
+        split_schedule: Option<Schedule>,
+
         /// The location of the output.{n}
         /// The location is a string that is specified as an
         /// absolute path.{n}
@@ -156,6 +312,7 @@ pub(crate) enum Commands {
         /// The source file name.{n}
         /// The source file name is a string that is specified as a
         /// file name.{n}
+        /// TS with 188-byte, 192-byte (M2TS) and 204-byte packets is accepted.{n}
         /// If '--device' is specified, this parameter is ignored.
         #[clap(short = 'i', long = "input", value_name = "file", required = true)]
         source: Option<String>,
@@ -163,10 +320,48 @@ pub(crate) enum Commands {
         /// Disable SIMD in MULTI2 processing.
         #[clap(long = "no-simd")]
         no_simd: bool,
+        /// Descramble with MULTI2 in Rust instead of libaribb25.{n}
+        /// SSE2, AVX2 or NEON is chosen by the CPU at runtime, so that no
+        /// SIGILL occurs. '--no-simd' selects the scalar code.
+        #[clap(long = "rust-multi2")]
+        rust_multi2: bool,
+        /// Use the B-CAS card served by `recisdb card-server` at the address.{n}
+        /// The connection is retried when it is lost.
+        /// The messages are authenticated with the token but not encrypted,
+        /// so the scramble keys can be read on the network; use a trusted
+        /// network or a tunnel.
+        #[clap(
+            long = "card-server",
+            value_name = "HOST:PORT",
+            requires = "card_token_file",
+            conflicts_with = "key"
+        )]
+        card_server: Option<String>,
+        /// The file containing the token shared with the card server.
+        #[clap(long = "card-token-file", value_name = "PATH")]
+        card_token_file: Option<String>,
         /// Disable null packet stripping.{n}
         /// If this flag is specified, the decoder won't discard meaningless packets automatically.
         #[clap(long = "no-strip")]
         no_strip: bool,
+        /// Pass the EMMs addressed to the card to it.{n}
+        /// The EMMs received and applied are counted for each card id, and
+        /// printed at the end.
+        #[clap(long = "emm")]
+        emm: bool,
+
+        /// Fail the run if a program has more undecrypted packets than this.{n}
+        /// The decryption statistics of each program are printed at the end.
+        #[clap(long = "max-undecrypted", value_name = "PACKETS")]
+        max_undecrypted: Option<u64>,
+        /// Fail the run if the ratio of the undecrypted packets of a program
+        /// is above this (0.0 - 1.0).
+        #[clap(long = "max-undecrypted-ratio", value_name = "RATIO", value_parser = parse_ratio)]
+        max_undecrypted_ratio: Option<f64>,
+        /// Fail the run if the card refused more ECMs of a program than this
+        /// as not purchased.
+        #[clap(long = "max-unpurchased-ecm", value_name = "COUNT")]
+        max_unpurchased_ecm: Option<u32>,
 
         /// The card reader name.
         #[clap(long)]
@@ -185,6 +380,86 @@ pub(crate) enum Commands {
         #[clap(long = "key1")]
         key1: Option<Vec<String>>,
 
+        /// Extract services, as `--sid` of recpt1 does.{n}
+        /// A comma-separated list of service IDs and the keywords
+        /// `hd`, `sd1`, `sd2`, `sd3`, `1seg`, `all` and `epg`.{n}
+        /// The PAT is rewritten to list the selected services only.
+        /// The NIT, SDT and TOT are always kept, and the EIT is kept
+        /// when `epg` is given.
+        #[clap(long, value_name = "SID_LIST")]
+        sid: Option<ServiceSelection>,
+        /// Write the services extracted by `--sid` as a partial TS
+        /// defined in ARIB STD-B21.{n}
+        /// The NIT entry is removed from the PAT, and a Selection
+        /// Information Table (SIT) is sent in place of the NIT and the SDT.
+        /// The NIT, SDT, TOT and CAT are removed.
+        #[clap(long = "partial-ts", requires = "sid")]
+        partial_ts: bool,
+
+        /// Extract the captions of a service into a file.{n}
+        /// The service is the one given to `--sid` if it is a single
+        /// service ID, or the first service in the PAT otherwise.
+        /// DRCS characters are replaced with '〓'.
+        #[clap(long, value_name = "FILE")]
+        captions: Option<String>,
+        /// The format of the captions.{n}
+        /// If not specified, the format is chosen by the extension
+        /// of the file, and SubRip is used for unknown extensions.
+        #[clap(value_enum, long = "caption-format", requires = "captions")]
+        caption_format: Option<CaptionFormat>,
+
+        /// Drop null packets (PID 0x1FFF) from the output.
+        #[clap(long = "drop-null")]
+        drop_null: bool,
+        /// Keep only the packets of the specified PIDs in the output.{n}
+        /// A comma-separated list of PIDs in decimal or hexadecimal.
+        #[clap(long, value_name = "PID_LIST", conflicts_with = "exclude_pids")]
+        pids: Option<PidList>,
+        /// Drop the packets of the specified PIDs from the output.{n}
+        /// A comma-separated list of PIDs in decimal or hexadecimal.
+        #[clap(long = "exclude-pids", value_name = "PID_LIST")]
+        exclude_pids: Option<PidList>,
+        /// Drop the packets that are still scrambled after decoding.
+        #[clap(long = "drop-scrambled")]
+        drop_scrambled: bool,
+
+        /// Write the per-PID packet report to a file in JSON.{n}
+        /// The report of drops, transport errors and scrambled packets
+        /// is always logged at the end.
+        #[clap(long = "report-json", value_name = "FILE")]
+        report_json: Option<String>,
+
+        /// The format of the output.{n}
+        /// `m2ts` writes BDAV MPEG-2 TS, whose 192-byte packets carry
+        /// an arrival time stamp derived from the PCR.
+        #[clap(value_enum, long = "output-format", default_value = "ts")]
+        output_format: OutputFormat,
+
+        /// Start a new file when the present event in the EIT changes
+        /// (`event`), or when the version or the ES composition of the PMT
+        /// changes (`pmt`).{n}
+        /// The service followed is the one given to `--sid` if it is a
+        /// single service ID, or the first service in the PAT otherwise.{n}
+        /// When splitting, the output is a template of the file names,
+        /// formatted with strftime after `{n}`, `{sid}`, `{event_id}` and
+        /// `{ext}` are replaced with the sequence number of the file, the
+        /// service ID, the event ID and the extension. If the output is a
+        /// directory, the files are named `%Y%m%d-%H%M%S_{n}.{ext}` in it.
+        /// Each file starts with the PAT and the PMT. Splitting on `event`
+        /// keeps the EIT in the services extracted with `--sid`.
+        #[clap(
+            value_enum,
+            long = "split-on",
+            value_name = "TRIGGER",
+            value_delimiter = ','
+        )]
+        split_on: Vec<SplitTrigger>,
+        /// Start a new file on a cron-like schedule.{n}
+        /// The five fields are minute, hour, day of month, month and day
+        /// of week, e.g. "0 * * * *" for every hour.
+        #[clap(long = "split-schedule", value_name = "CRON")]
+        split_schedule: Option<Schedule>,
+
         /// The location of the output.{n}
         /// The location is a string that is specified as an
         /// absolute path.{n}
@@ -195,6 +470,190 @@ pub(crate) enum Commands {
         #[clap(required = true)]
         output: Option<String>,
     },
+    /// Export the program guide in the EIT.{n}
+    /// This subcommand collects the events of the EIT from a tuner or a file
+    /// and writes them out in JSON or XMLTV.{n}
+    /// Strings in ARIB STD-B24 8-unit code are converted to UTF-8.
+    #[clap(group(
+    ArgGroup::new("src")
+    .args(& ["device", "source"])
+    .required(true)
+    ))]
+    Epg {
+        /// The device name.{n}
+        /// This is the name of the device as specified in the
+        /// `/dev/` directory.{n}
+        /// To use this option, you must specify the `-c` option.{n}
+        /// When the device is a BonDriver-based device,
+        /// the name of the DLL comes here.{n}
+        /// When the device is a Unix chardev-based device,
+        /// the canonical path of the device comes here.
+        #[clap(short = 'i', long, value_name = "CANONICAL_PATH", requires = "channel")]
+        device: Option<String>,
+
+        /// The channel name.{n}
+        /// The channel name is a string that is defined in the
+        /// `channels` module.
+        #[clap(short, long, conflicts_with = "source")]
+        channel: Option<String>,
+
+        /// Override the transport stream ID(TSID) to obtain the stream (especially in ISDB-S w/ V4L-DVB).
+        #[clap(long, value_parser=maybe_hex::<u32>)]
+        tsid: Option<u32>,
+
+        /// LNB voltage.
+        /// If none, the LNB voltage is assumed unset.{n}
+        #[clap(value_enum, long = "lnb")]
+        lnb: Option<Voltage>,
+
+        /// The source file name.{n}
+        /// The events are collected from the whole file.
+        #[clap(long = "input", value_name = "file")]
+        source: Option<String>,
+
+        /// The duration of the collection from a tuner in seconds.{n}
+        /// The EIT schedule of all services takes a few minutes to go round.
+        #[clap(short, long, value_name = "seconds", default_value = "120")]
+        time: f64,
+
+        /// The output format.
+        #[clap(value_enum, long, default_value = "json")]
+        format: EpgFormat,
+
+        /// The location of the output.{n}
+        /// If '-' is specified, the program guide will be written to stdout.
+        #[clap(required = true)]
+        output: Option<String>,
+    },
+    /// Analyze the bitrate and the PCR of the stream.{n}
+    /// This subcommand measures the multiplex rate and the bitrate of each
+    /// service over time on the clock of the PCR, and reports the interval,
+    /// jitter and discontinuities of the PCR on each PID.
+    #[clap(group(
+    ArgGroup::new("src")
+    .args(& ["device", "source"])
+    .required(true)
+    ))]
+    Analyze {
+        /// The device name.{n}
+        /// This is the name of the device as specified in the
+        /// `/dev/` directory.{n}
+        /// To use this option, you must specify the `-c` option.{n}
+        /// When the device is a BonDriver-based device,
+        /// the name of the DLL comes here.{n}
+        /// When the device is a Unix chardev-based device,
+        /// the canonical path of the device comes here.
+        #[clap(short = 'i', long, value_name = "CANONICAL_PATH", requires = "channel")]
+        device: Option<String>,
+
+        /// The channel name.{n}
+        /// The channel name is a string that is defined in the
+        /// `channels` module.
+        #[clap(short, long, conflicts_with = "source")]
+        channel: Option<String>,
+
+        /// Override the transport stream ID(TSID) to obtain the stream (especially in ISDB-S w/ V4L-DVB).
+        #[clap(long, value_parser=maybe_hex::<u32>)]
+        tsid: Option<u32>,
+
+        /// LNB voltage.
+        /// If none, the LNB voltage is assumed unset.{n}
+        #[clap(value_enum, long = "lnb")]
+        lnb: Option<Voltage>,
+
+        /// The source file name.{n}
+        /// The whole file is analyzed.
+        #[clap(long = "input", value_name = "file")]
+        source: Option<String>,
+
+        /// The duration of the analysis of a tuner in seconds.
+        #[clap(short, long, value_name = "seconds", default_value = "30")]
+        time: f64,
+
+        /// The length of each sample of the bitrate in seconds.
+        #[clap(long, value_name = "seconds", default_value = "1")]
+        interval: f64,
+
+        /// The output format.{n}
+        /// The CSV holds the bitrates over time only.
+        #[clap(value_enum, long, default_value = "text")]
+        format: AnalysisFormat,
+
+        /// The location of the output.{n}
+        /// If not specified or '-' is specified, the results will be written to stdout.
+        output: Option<String>,
+    },
+
+    /// Inspect the PSI/SI of the stream.{n}
+    /// This subcommand prints the PAT, the PMT of each service with its
+    /// stream types, ES PIDs and CA descriptors, the service names in the SDT,
+    /// the network in the NIT and the EMM PIDs in the CAT, and tells which
+    /// PIDs are scrambled.
+    #[clap(group(
+    ArgGroup::new("src")
+    .args(& ["device", "source"])
+    .required(true)
+    ))]
+    Inspect {
+        /// The device name.{n}
+        /// This is the name of the device as specified in the
+        /// `/dev/` directory.{n}
+        /// To use this option, you must specify the `-c` option.{n}
+        /// When the device is a BonDriver-based device,
+        /// the name of the DLL comes here.{n}
+        /// When the device is a Unix chardev-based device,
+        /// the canonical path of the device comes here.
+        #[clap(short = 'i', long, value_name = "CANONICAL_PATH", requires = "channel")]
+        device: Option<String>,
+
+        /// The channel name.{n}
+        /// The channel name is a string that is defined in the
+        /// `channels` module.
+        #[clap(short, long, conflicts_with = "source")]
+        channel: Option<String>,
+
+        /// Override the transport stream ID(TSID) to obtain the stream (especially in ISDB-S w/ V4L-DVB).
+        #[clap(long, value_parser=maybe_hex::<u32>)]
+        tsid: Option<u32>,
+
+        /// LNB voltage.
+        /// If none, the LNB voltage is assumed unset.{n}
+        #[clap(value_enum, long = "lnb")]
+        lnb: Option<Voltage>,
+
+        /// The source file name.{n}
+        /// If '-' is specified, the stream is read from stdin.{n}
+        /// The whole file is inspected.
+        #[clap(long = "input", value_name = "file")]
+        source: Option<String>,
+
+        /// The duration of the inspection of a tuner in seconds.
+        #[clap(short, long, value_name = "seconds", default_value = "10")]
+        time: f64,
+
+        /// The output format.
+        #[clap(value_enum, long, default_value = "tree")]
+        format: InspectFormat,
+
+        /// The location of the output.{n}
+        /// If not specified or '-' is specified, the results will be written to stdout.
+        output: Option<String>,
+    },
+    /// Serve the B-CAS card to other hosts.{n}
+    /// This subcommand exposes the card in the local PC/SC reader over TCP,
+    /// to be used by `tune` and `decode` with '--card-server'.{n}
+    /// The clients and each of their messages are authenticated with the
+    /// token in '--token-file'. The messages are not encrypted, so the
+    /// scramble keys can be read by anyone on the network between them.
+    #[clap(name = "card-server")]
+    CardServer {
+        /// The address to listen on.
+        #[clap(long, value_name = "HOST:PORT", default_value = "127.0.0.1:40774")]
+        listen: String,
+        /// The file containing the token shared with the clients.
+        #[clap(long = "token-file", value_name = "PATH", required = true)]
+        token_file: String,
+    },
     #[cfg(windows)]
     Enumerate {
         #[clap(short = 'i', long, value_name = "CANONICAL_PATH", required = true)]
@@ -203,3 +662,22 @@ pub(crate) enum Commands {
         space: u32,
     },
 }
+
+/// Parses a ratio from 0.0 to 1.0.
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(format!("{} is not between 0.0 and 1.0", s))
+    }
+}
+
+fn parse_margin(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=86400.0).contains(&secs) {
+        Ok(secs)
+    } else {
+        Err(format!("{} is not between 0 and 86400 seconds", s))
+    }
+}
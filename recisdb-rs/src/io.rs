@@ -1,46 +1,492 @@
-use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::io;
 use std::io::Write;
+use std::ops::Range;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
 use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
-use futures_util::io::{AllowStdIo, BufReader};
-use futures_util::{AsyncBufRead, AsyncWrite};
+use futures_util::{AsyncBufRead, AsyncRead, AsyncWrite};
 use log::{error, info, warn};
 use pin_project_lite::pin_project;
 
-use b25_sys::{DecoderOptions, StreamDecoder};
+use b25_sys::{AsyncStreamDecoder, DecoderError, DecoderOptions, LatencyStats, ProgramStats};
+
+use self::stage::SourceOffsets;
+pub(crate) use self::stage::{PacketStage, Pipeline};
+pub(crate) use self::writer::OutputCounter;
+use self::writer::ThreadedWriter;
+use crate::ts::packet::TS_PACKET_SIZE;
+
+mod stage;
+mod writer;
+
+/// Limits on the decryption statistics of each program, checked when the
+/// decoder is released.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DecodeThresholds {
+    pub max_undecrypted: Option<u64>,
+    /// The ratio of the undecrypted packets to all the packets of a program.
+    pub max_undecrypted_ratio: Option<f64>,
+    pub max_unpurchased_ecm: Option<u32>,
+}
+
+impl DecodeThresholds {
+    /// Returns the limits the program exceeded.
+    fn check(&self, stats: &ProgramStats) -> Vec<String> {
+        let mut exceeded = Vec::new();
+        if let Some(max) = self.max_undecrypted {
+            if stats.undecrypted_packets > max {
+                exceeded.push(format!("more than {} undecrypted packets", max));
+            }
+        }
+        if let Some(max) = self.max_undecrypted_ratio {
+            let ratio = stats.undecrypted_packets as f64 / stats.total_packets.max(1) as f64;
+            if ratio > max {
+                exceeded.push(format!("undecrypted ratio {:.4} above {}", ratio, max));
+            }
+        }
+        if let Some(max) = self.max_unpurchased_ecm {
+            if stats.unpurchased_ecm_count > max {
+                exceeded.push(format!("more than {} unpurchased ECMs", max));
+            }
+        }
+        exceeded
+    }
+}
+
+/// The error of a run whose decryption statistics exceeded the thresholds.
+#[derive(Debug)]
+pub(crate) struct ThresholdsExceeded;
+
+impl Display for ThresholdsExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the decryption statistics exceeded the thresholds")
+    }
+}
+
+impl std::error::Error for ThresholdsExceeded {}
+
+impl From<ThresholdsExceeded> for io::Error {
+    fn from(e: ThresholdsExceeded) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl ThresholdsExceeded {
+    /// Whether the run failed for its decryption statistics.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().map_or(false, |e| e.is::<Self>())
+    }
+}
+
+/// Adds the statistics of a decoder to those of the decoders before it.
+fn merge_program_stats(total: &mut Vec<ProgramStats>, stats: Vec<ProgramStats>) {
+    for program in stats {
+        match total
+            .iter_mut()
+            .find(|p| p.program_number == program.program_number)
+        {
+            Some(p) => {
+                p.total_packets += program.total_packets;
+                p.undecrypted_packets += program.undecrypted_packets;
+                p.unpurchased_ecm_count += program.unpurchased_ecm_count;
+                if program.last_ecm_error.is_some() {
+                    p.last_ecm_error = program.last_ecm_error;
+                }
+            }
+            None => total.push(program),
+        }
+    }
+}
+
+/// Prints the statistics of each program, returning whether any of them
+/// exceeded the thresholds.
+///
+/// The packets written undecoded while the decoder was failing count as
+/// undecrypted in each program, as any of them may have been among those.
+fn report_program_stats(
+    stats: &[ProgramStats],
+    bypassed: u64,
+    thresholds: &DecodeThresholds,
+) -> bool {
+    let mut failed = false;
+    for program in stats {
+        let last_error = program
+            .last_ecm_error
+            .map_or("none".to_string(), |code| format!("{:#06x}", code));
+        info!(
+            "Program {}: {} packets, {} undecrypted, {} unpurchased ECMs, last ECM error: {}",
+            program.program_number,
+            program.total_packets,
+            program.undecrypted_packets,
+            program.unpurchased_ecm_count,
+            last_error
+        );
+        let exceeded = thresholds.check(&ProgramStats {
+            total_packets: program.total_packets + bypassed,
+            undecrypted_packets: program.undecrypted_packets + bypassed,
+            ..program.clone()
+        });
+        if !exceeded.is_empty() {
+            error!(
+                "Program {}: {}.",
+                program.program_number,
+                exceeded.join(", ")
+            );
+            failed = true;
+        }
+    }
+    if bypassed > 0 {
+        warn!("{} packets were written undecoded.", bypassed);
+        // Checked on their own if no decoder has seen a program
+        let exceeded = thresholds.check(&ProgramStats {
+            program_number: 0,
+            total_packets: bypassed,
+            undecrypted_packets: bypassed,
+            unpurchased_ecm_count: 0,
+            last_ecm_error: None,
+        });
+        if stats.is_empty() && !exceeded.is_empty() {
+            error!("The undecoded packets: {}.", exceeded.join(", "));
+            failed = true;
+        }
+    }
+    failed
+}
+
+/// Whether the run can go on without the decoder after its error, which is
+/// only when the card has failed.
+fn is_card_fault(e: &io::Error) -> bool {
+    DecoderError::from_io(e).map_or(false, |e| e.is_card_fault())
+}
+
+/// A part of the stream written undecoded while the decoder was failing.
+///
+/// The input is told in the bytes read from the source, rounded outwards as
+/// `SourceOffsets` tells it, and the output in the bytes written to the files,
+/// over all of them if the output is split. The packets a writer holds back,
+/// as the M2TS conversion does until the next PCR, are told where they are
+/// written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Undecoded {
+    input: Range<u64>,
+    output: Range<u64>,
+}
+
+impl Display for Undecoded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "input bytes {:?}, output bytes {:?}",
+            self.input, self.output
+        )
+    }
+}
+
+/// Rebuilds the decoder after it has failed, so that the decoding resumes
+/// when the card responds again.
+///
+/// The decoder is initialized on another thread, as the card may take long to
+/// answer, and retried with a backoff doubled on each failure.
+struct Recovery {
+    /// The options of a new decoder, without which it is not rebuilt.
+    options: Option<Box<dyn FnMut() -> DecoderOptions>>,
+    backoff: Duration,
+    retry_at: Instant,
+    /// The decoder being initialized.
+    pending: Option<Receiver<io::Result<AsyncStreamDecoder>>>,
+    /// The offset of the source since which the stream has been written
+    /// undecoded, and the mark of the output once the decoded stream before
+    /// it has been queued.
+    since: Option<(u64, Option<u64>)>,
+    /// The start of the range once a new decoder has taken over, which
+    /// passes the stream undecoded until it has the PAT, the PMT and the ECM.
+    warming: Option<(u64, u64)>,
+    /// The ranges with the marks of the output, told in its offsets at the
+    /// end of the run.
+    undecoded: Vec<Undecoded>,
+}
+
+impl Recovery {
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    fn new() -> Self {
+        Self {
+            options: None,
+            backoff: Self::MIN_BACKOFF,
+            retry_at: Instant::now(),
+            pending: None,
+            since: None,
+            warming: None,
+            undecoded: Vec::new(),
+        }
+    }
+
+    /// Starts writing the stream undecoded at the offset of the source, or
+    /// goes on with the range of a new decoder not decoding yet.
+    fn fail(&mut self, input: u64, now: Instant) {
+        if let Some((input, output)) = self.warming.take() {
+            self.since = Some((input, Some(output)));
+        }
+        self.since.get_or_insert((input, None));
+        self.backoff = Self::MIN_BACKOFF;
+        self.retry_at = now + self.backoff;
+    }
+
+    /// Marks the output where the undecoded stream starts.
+    fn start_output(&mut self, mark: impl FnOnce() -> u64) {
+        if let Some((_, output @ None)) = self.since.as_mut() {
+            *output = Some(mark());
+        }
+    }
+
+    /// Whether the stream is being written undecoded.
+    fn is_failing(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// Schedules the next attempt after one has failed.
+    fn retry_later(&mut self, now: Instant) {
+        self.retry_at = now + self.backoff;
+        self.backoff = (self.backoff * 2).min(Self::MAX_BACKOFF);
+    }
+
+    /// Hands the undecoded range over to a new decoder, whose output starts
+    /// at the mark.
+    fn resume(&mut self, output: u64) {
+        if let Some((input, output_start)) = self.since.take() {
+            self.warming = Some((input, output_start.unwrap_or(output)));
+        }
+    }
+
+    /// Whether the new decoder has not been seen decoding yet.
+    fn is_warming(&self) -> bool {
+        self.warming.is_some()
+    }
+
+    /// Records the undecoded range ending at the offset of the source and
+    /// the mark of the output, once the new decoder is decoding.
+    fn decoding(&mut self, input: u64, output: u64) {
+        if let Some((input_start, output_start)) = self.warming.take() {
+            let input = input_start..input;
+            warn!("Undecoded: input bytes {:?}", input);
+            self.undecoded.push(Undecoded {
+                input,
+                output: output_start..output,
+            });
+        }
+    }
+
+    /// Returns the new decoder once it is ready, starting an attempt when it
+    /// is time to.
+    fn poll(&mut self, cx: &Context<'_>) -> Option<AsyncStreamDecoder> {
+        let now = Instant::now();
+        match self.pending.as_ref().map(Receiver::try_recv) {
+            Some(Ok(Ok(dec))) => {
+                self.pending = None;
+                info!("The decoder has been reinitialized. Resuming decoding...");
+                return Some(dec);
+            }
+            Some(Ok(Err(e))) => {
+                self.pending = None;
+                warn!(
+                    "Failed to reinitialize the decoder ({}). Retrying in {}s...",
+                    e,
+                    self.backoff.as_secs()
+                );
+                self.retry_later(now);
+            }
+            Some(Err(TryRecvError::Disconnected)) => {
+                self.pending = None;
+                self.retry_later(now);
+            }
+            Some(Err(TryRecvError::Empty)) => {}
+            None if self.since.is_some() && now >= self.retry_at => {
+                if let Some(options) = self.options.as_mut() {
+                    let options = options();
+                    let (tx, rx) = std::sync::mpsc::channel();
+                    let waker = cx.waker().clone();
+                    std::thread::spawn(move || {
+                        let _ = tx.send(AsyncStreamDecoder::new(options));
+                        waker.wake();
+                    });
+                    self.pending = Some(rx);
+                }
+            }
+            None => {}
+        }
+        None
+    }
+
+    /// Logs the undecoded ranges at the end of the run, telling the marks of
+    /// the output with `offset_at`.
+    fn finish(&mut self, input: u64, output: u64, offset_at: impl Fn(u64) -> u64) {
+        self.resume(output);
+        self.decoding(input, output);
+        for range in self.undecoded.iter_mut() {
+            range.output = offset_at(range.output.start)..offset_at(range.output.end);
+        }
+        if !self.undecoded.is_empty() {
+            warn!(
+                "{} ranges of the stream are left undecoded:",
+                self.undecoded.len()
+            );
+            for range in &self.undecoded {
+                warn!("  {}", range);
+            }
+        }
+    }
+}
+
+/// Latencies of the stages around the decoder worker.
+#[derive(Debug, Default)]
+struct StageStats {
+    /// How long the source waited for room in the queue to the decoder.
+    source: LatencyStats,
+    /// When the source started waiting for the decoder.
+    blocked_since: Option<Instant>,
+}
 
 pin_project! {
+    /// Connects the source, the decoder and the output.
+    ///
+    /// The decoder and the output run on threads of their own, with bounded
+    /// queues between them, so that the round trips to the card hold up
+    /// neither the reads from the source nor the writes to the output, and a
+    /// slow output holds up neither of them until its queue fills up.
     pub(crate) struct AsyncInOutTriple {
         #[pin]
         i: Box<dyn AsyncBufRead + Unpin + 'static>,
-        o: AllowStdIo<Box<dyn Write>>,
-        dec: RefCell<Option<BufReader<AllowStdIo<StreamDecoder>>>>,
+        o: ThreadedWriter,
+        dec: Option<AsyncStreamDecoder>,
+        // The decoded stream read from the worker, and the part of it not yet
+        // queued for the output
+        buf: Vec<u8>,
+        unwritten: Range<usize>,
+        // The input the failed decoder has not decoded, queued after the rest
+        // of its output
+        bypass: Vec<u8>,
         amt: u64,
-        abandon_decoder: Option<bool>,
+        // Where the input of the decoder lies in the source
+        source: SourceOffsets,
+        // Set if the run goes on without the decoder when it fails
+        recovery: Option<Recovery>,
         abort: Arc<AtomicBool>,
+        // Set once the source has ended or the user has stopped the recording
+        closing: bool,
         progress_tx: std::sync::mpsc::Sender<u64>,
+        thresholds: DecodeThresholds,
+        // The statistics of the decoders released so far, and the bytes
+        // written undecoded since a decoder failed
+        programs: Vec<ProgramStats>,
+        bypassed: u64,
+        // When to end the run, as if the source had ended
+        deadline: Option<Instant>,
+        timer_started: bool,
+        stats: StageStats,
+    }
+    impl PinnedDrop for AsyncInOutTriple {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            // The statistics are left to the result if the run has completed
+            this.o.join();
+            report(
+                this.dec.take(),
+                this.recovery.take(),
+                std::mem::take(this.programs),
+                std::mem::take(this.bypassed),
+                this.thresholds,
+                this.stats,
+                this.o,
+                this.source.end(*this.amt),
+            );
+        }
     }
 }
 
+/// Logs the statistics of the run, returning whether the decryption
+/// statistics exceeded the thresholds. `input` is the end of the source read.
+#[allow(clippy::too_many_arguments)]
+fn report(
+    dec: Option<AsyncStreamDecoder>,
+    recovery: Option<Recovery>,
+    mut programs: Vec<ProgramStats>,
+    bypassed: u64,
+    thresholds: &DecodeThresholds,
+    stats: &StageStats,
+    writer: &ThreadedWriter,
+    input: u64,
+) -> bool {
+    if let Some(dec) = dec.as_ref() {
+        merge_program_stats(&mut programs, dec.program_stats());
+    }
+    let exceeded = report_program_stats(&programs, bypassed / TS_PACKET_SIZE as u64, thresholds);
+    if let Some(dec) = dec {
+        for emm in dec.emm_stats() {
+            info!("{}", emm);
+        }
+        info!(
+            "Latency of the source waiting for the decoder: {}",
+            stats.source
+        );
+        info!("Latency of the decoder: {}", dec.latency());
+        info!("Latency of the output: {}", writer.latency());
+    }
+    if let Some(mut recovery) = recovery {
+        recovery.finish(input, writer.mark(), |mark| writer.offset_at(mark));
+    }
+    exceeded
+}
+
+/// Releases the failed decoder, queueing the rest of its output after the
+/// decoded stream left in `buf`, and its input not decoded yet in `bypass`.
+/// Returns the offset of the input where the undecoded stream starts.
+fn release(
+    dec: AsyncStreamDecoder,
+    buf: &mut Vec<u8>,
+    unwritten: &mut Range<usize>,
+    bypass: &mut Vec<u8>,
+    programs: &mut Vec<ProgramStats>,
+    amt: u64,
+) -> u64 {
+    merge_program_stats(programs, dec.program_stats());
+    let (output, input) = dec.into_unprocessed();
+    buf.truncate(unwritten.end);
+    buf.drain(..unwritten.start);
+    buf.extend_from_slice(&output);
+    *unwritten = 0..buf.len();
+    let start = amt - input.len() as u64;
+    *bypass = input;
+    start
+}
+
 impl AsyncInOutTriple {
     const CAP: usize = 1600000;
     pub fn new(
         i: Box<dyn AsyncBufRead + Unpin>,
-        o: Box<dyn Write>,
+        o: Box<dyn Write + Send>,
         config: Option<DecoderOptions>,
         continue_on_error: bool,
+        pipeline: Pipeline,
     ) -> (Self, std::sync::mpsc::Receiver<u64>) {
-        let raw = config.and_then(|op| match StreamDecoder::new(op) {
-            Ok(raw) => Some(raw),
+        let mut recovery = continue_on_error.then(Recovery::new);
+        let decoding = config.is_some();
+        let dec = config.and_then(|op| match AsyncStreamDecoder::new(op) {
+            Ok(dec) => Some(dec),
             Err(e) if continue_on_error => {
                 error!("Failed to initialize the decoder. ({})", e);
                 info!("Disabling decoding and continue...");
                 // As a fallback, disable decoding and continue processing
+                // until the decoder is recovered
+                if let Some(recovery) = recovery.as_mut() {
+                    recovery.fail(0, Instant::now());
+                }
                 None
             }
             Err(e) => {
@@ -50,15 +496,8 @@ impl AsyncInOutTriple {
             }
         });
 
-        let dec = {
-            let buffered_decoder = raw
-                .map(AllowStdIo::new)
-                .map(|raw| BufReader::with_capacity(Self::CAP, raw));
-
-            RefCell::new(buffered_decoder)
-        };
-
-        let o = AllowStdIo::new(o);
+        let (i, o, source) = pipeline.attach(i, o);
+        let o = ThreadedWriter::new(o).expect("Error starting the output thread");
 
         let abort: Arc<AtomicBool> = Default::default();
         let weak = Arc::downgrade(&abort);
@@ -74,15 +513,59 @@ impl AsyncInOutTriple {
             Self {
                 i,
                 o,
+                buf: if decoding {
+                    vec![0; Self::CAP]
+                } else {
+                    Vec::new()
+                },
+                unwritten: 0..0,
+                bypass: Vec::new(),
                 dec,
                 amt: 0,
+                source,
                 abort,
+                closing: false,
                 progress_tx,
-                abandon_decoder: if continue_on_error { Some(false) } else { None },
+                recovery,
+                thresholds: DecodeThresholds::default(),
+                programs: Vec::new(),
+                bypassed: 0,
+                deadline: None,
+                timer_started: false,
+                stats: StageStats::default(),
             },
             progress_rx,
         )
     }
+
+    /// Fails the run when the decryption statistics exceed the thresholds.
+    pub fn with_thresholds(mut self, thresholds: DecodeThresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Ends the run after the duration, flushing the decoder as when the
+    /// source ends.
+    pub fn with_duration(mut self, duration: Option<Duration>) -> Self {
+        self.deadline = duration.map(|duration| Instant::now() + duration);
+        self
+    }
+
+    /// Tells the output in the bytes written to its files, counted by
+    /// `counter`, rather than in those written to it.
+    pub fn with_output_counter(self, counter: OutputCounter) -> Self {
+        self.o.count_with(counter);
+        self
+    }
+
+    /// Rebuilds the decoder with the options when it fails, if the run goes
+    /// on without it.
+    pub fn with_recovery(mut self, options: Box<dyn FnMut() -> DecoderOptions>) -> Self {
+        if let Some(recovery) = self.recovery.as_mut() {
+            recovery.options = Some(options);
+        }
+        self
+    }
 }
 
 impl Future for AsyncInOutTriple {
@@ -93,100 +576,248 @@ impl Future for AsyncInOutTriple {
 
         let _ = this.progress_tx.send(*this.amt);
 
-        match this.dec.get_mut() {
-            Some(ref mut dec) if !this.abandon_decoder.unwrap_or(false) => {
+        let timed_out = this
+            .deadline
+            .map_or(false, |deadline| Instant::now() >= deadline);
+        if let Some(deadline) = this.deadline.filter(|_| !*this.timer_started) {
+            // Wakes the run up at the deadline even if the source stalls
+            *this.timer_started = true;
+            let waker = cx.waker().clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                waker.wake();
+            });
+        }
+
+        match this.dec {
+            Some(ref mut dec) => {
                 //    A.         B.
                 // In -> Decoder -> Out
-                if !this.abort.load(Ordering::Relaxed) {
-                    // A(source)
-                    let buffer = ready!(this.i.as_mut().poll_fill_buf(cx))?;
-                    if buffer.is_empty() {
-                        // go to finalization
-                        this.abort.store(true, Ordering::Relaxed);
-                        cx.waker().wake_by_ref();
-                        return Poll::Pending;
-                    } else {
-                        // A(sink)
-                        match ready!(Pin::new(&mut *dec).poll_write(cx, buffer)) {
-                            Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
-                            Ok(i) => {
-                                *this.amt += i as u64;
-                                this.i.as_mut().consume(i);
+                // Each stage is woken up by the others, so the loop goes on
+                // until none of them makes progress.
+                let e = loop {
+                    let mut progress = false;
+
+                    if !*this.closing {
+                        // A(source)
+                        match this.i.as_mut().poll_fill_buf(cx) {
+                            _ if this.abort.load(Ordering::Relaxed) => *this.closing = true,
+                            _ if timed_out => {
+                                info!("The recording duration has passed.");
+                                *this.closing = true;
                             }
-                            Err(e) if this.abandon_decoder.is_some() => {
-                                // Enable bypassing a decoder
-                                *this.abandon_decoder = Some(true);
-                                error!("Unexpected failure in the decoder({}).", e);
-                                warn!("Falling back to decoder-less mode...")
+                            Poll::Ready(Ok(buffer)) if buffer.is_empty() => {
+                                // go to finalization
+                                *this.closing = true;
                             }
-                            Err(e) => return Poll::Ready(Err(e)),
+                            // A(sink)
+                            Poll::Ready(Ok(buffer)) => {
+                                match Pin::new(&mut *dec).poll_write(cx, buffer) {
+                                    Poll::Ready(Ok(0)) => {
+                                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+                                    }
+                                    Poll::Ready(Ok(i)) => {
+                                        if let Some(since) = this.stats.blocked_since.take() {
+                                            this.stats.source.record(since.elapsed());
+                                        }
+                                        *this.amt += i as u64;
+                                        this.i.as_mut().consume(i);
+                                        progress = true;
+                                    }
+                                    Poll::Ready(Err(e))
+                                        if this.recovery.is_some() && is_card_fault(&e) =>
+                                    {
+                                        break e
+                                    }
+                                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                                    Poll::Pending => {
+                                        this.stats.blocked_since.get_or_insert_with(Instant::now);
+                                    }
+                                }
+                            }
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => {}
                         }
-                    }
 
-                    // B(source)
-                    let buffer = ready!(Pin::new(&mut *dec).poll_fill_buf(cx))?;
-                    if !buffer.is_empty() {
-                        // B(sink)
-                        let j = ready!(Pin::new(&mut this.o).poll_write(cx, buffer))?;
-                        if j == 0 {
-                            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                        if *this.closing && !timed_out {
+                            // Finalize
+                            for _ in 1..1000000 {
+                                match this.progress_tx.send(u64::MAX) {
+                                    Ok(_) => {}
+                                    Err(_) => {
+                                        // Most likely due to pressing Ctrl+C
+                                        return Poll::Ready(Err(io::Error::new(
+                                            io::ErrorKind::Interrupted,
+                                            "Ctrl+C pressed",
+                                        )));
+                                    }
+                                }
+                            }
+                            info!("Flushing the buffer…");
+                        }
+                    }
+                    if *this.closing {
+                        // The worker flushes the decoder and ends the stream
+                        if let Poll::Ready(Err(e)) = Pin::new(&mut *dec).poll_close(cx) {
+                            return Poll::Ready(Err(e));
                         }
-                        Pin::new(&mut *dec).consume(j);
                     }
 
-                    cx.waker().wake_by_ref();
-                    return Poll::Pending;
-                }
+                    // B(sink)
+                    while !(*this.unwritten).is_empty() {
+                        let data = &this.buf[this.unwritten.clone()];
+                        match Pin::new(&mut *this.o).poll_write(cx, data) {
+                            Poll::Ready(Ok(0)) => {
+                                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+                            }
+                            Poll::Ready(Ok(j)) => {
+                                this.unwritten.start += j;
+                                progress = true;
+                            }
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => break,
+                        }
+                    }
+                    if !(*this.unwritten).is_empty() {
+                        if !progress {
+                            return Poll::Pending;
+                        }
+                        continue;
+                    }
 
-                // Finalize
-                for _ in 1..1000000 {
-                    match this.progress_tx.send(u64::MAX) {
-                        Ok(_) => {}
-                        Err(_) => {
-                            // Most likely due to pressing Ctrl+C
-                            return Poll::Ready(Err(io::Error::new(
-                                io::ErrorKind::Interrupted,
-                                "Ctrl+C pressed",
-                            )));
+                    // The new decoder passes the stream undecoded until it
+                    // has the PAT, the PMT and the ECM
+                    if let Some(recovery) = this.recovery.as_mut().filter(|r| r.is_warming()) {
+                        let stats = dec.program_stats();
+                        if stats
+                            .iter()
+                            .any(|p| p.total_packets > p.undecrypted_packets)
+                        {
+                            recovery.decoding(this.source.end(*this.amt), this.o.mark());
                         }
                     }
-                }
-                info!("Flushing the buffer…");
-
-                // A(sink)
-                ready!(Pin::new(&mut *dec).poll_flush(cx))?;
-                // B(source)
-                loop {
-                    match Pin::new(&mut *dec).poll_fill_buf(cx) {
-                        Poll::Ready(Ok(buffer)) if buffer.is_empty() => {
+
+                    // B(source)
+                    match Pin::new(&mut *dec).poll_read(cx, this.buf) {
+                        Poll::Ready(Ok(0)) => {
+                            ready!(Pin::new(&mut *this.o).poll_close(cx))?;
+                            let exceeded = report(
+                                this.dec.take(),
+                                this.recovery.take(),
+                                std::mem::take(this.programs),
+                                std::mem::take(this.bypassed),
+                                this.thresholds,
+                                this.stats,
+                                this.o,
+                                this.source.end(*this.amt),
+                            );
+                            if exceeded {
+                                return Poll::Ready(Err(ThresholdsExceeded.into()));
+                            }
                             return Poll::Ready(Ok(*this.amt));
                         }
-                        Poll::Ready(Ok(buffer)) => {
-                            // B(sink)
-                            let j = ready!(Pin::new(&mut this.o).poll_write(cx, buffer))?;
-                            if j == 0 {
-                                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
-                            }
-                            Pin::new(&mut *dec).consume(j);
+                        Poll::Ready(Ok(j)) => {
+                            *this.unwritten = 0..j;
+                            progress = true;
+                        }
+                        Poll::Ready(Err(e)) if this.recovery.is_some() && is_card_fault(&e) => {
+                            break e
                         }
                         Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                        Poll::Pending => continue,
+                        Poll::Pending => {}
                     }
+
+                    if !progress {
+                        return Poll::Pending;
+                    }
+                };
+
+                // Enable bypassing a decoder
+                error!("Unexpected failure in the decoder({}).", e);
+                warn!("Falling back to decoder-less mode...");
+                if let (Some(dec), Some(recovery)) = (this.dec.take(), this.recovery.as_mut()) {
+                    let start = release(
+                        dec,
+                        this.buf,
+                        this.unwritten,
+                        this.bypass,
+                        this.programs,
+                        *this.amt,
+                    );
+                    if !this.bypass.is_empty() {
+                        warn!(
+                            "The input bytes {:?} left in the decoder are written undecoded.",
+                            this.source.start(start)..this.source.end(*this.amt)
+                        );
+                    }
+                    recovery.fail(this.source.start(start), Instant::now());
                 }
+                cx.waker().wake_by_ref();
+                Poll::Pending
             }
-            _ => {
+            None => {
+                // The decoded stream left when the decoder failed
+                while !(*this.unwritten).is_empty() {
+                    let data = &this.buf[this.unwritten.clone()];
+                    match ready!(Pin::new(&mut *this.o).poll_write(cx, data))? {
+                        0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                        j => this.unwritten.start += j,
+                    }
+                }
+                if let Some(recovery) = this.recovery.as_mut() {
+                    recovery.start_output(|| this.o.mark());
+                }
+                // Then the input it has not decoded
+                while !this.bypass.is_empty() {
+                    match ready!(Pin::new(&mut *this.o).poll_write(cx, this.bypass))? {
+                        0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                        j => {
+                            this.bypass.drain(..j);
+                            *this.bypassed += j as u64;
+                        }
+                    }
+                }
+
+                if let Some(recovery) = this.recovery.as_mut().filter(|_| !*this.closing) {
+                    if let Some(dec) = recovery.poll(cx) {
+                        recovery.resume(this.o.mark());
+                        // The rest of the failed decoder has resized the buffer
+                        this.buf.resize(Self::CAP, 0);
+                        *this.dec = Some(dec);
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                }
+
                 // pass through
                 let buffer = ready!(this.i.as_mut().poll_fill_buf(cx))?;
-                if buffer.is_empty() || this.abort.load(Ordering::Relaxed) {
-                    ready!(Pin::new(&mut this.o).poll_flush(cx))?;
+                if buffer.is_empty() || this.abort.load(Ordering::Relaxed) || timed_out {
+                    *this.closing = true;
+                    ready!(Pin::new(&mut *this.o).poll_close(cx))?;
+                    let exceeded = report(
+                        None,
+                        this.recovery.take(),
+                        std::mem::take(this.programs),
+                        std::mem::take(this.bypassed),
+                        this.thresholds,
+                        this.stats,
+                        this.o,
+                        this.source.end(*this.amt),
+                    );
+                    if exceeded {
+                        return Poll::Ready(Err(ThresholdsExceeded.into()));
+                    }
                     return Poll::Ready(Ok(*this.amt));
                 }
 
-                let i = ready!(Pin::new(&mut this.o).poll_write(cx, buffer))?;
+                let i = ready!(Pin::new(&mut *this.o).poll_write(cx, buffer))?;
                 if i == 0 {
                     return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
                 }
                 *this.amt += i as u64;
+                if this.recovery.as_ref().map_or(false, Recovery::is_failing) {
+                    *this.bypassed += i as u64;
+                }
                 this.i.as_mut().consume(i);
 
                 cx.waker().wake_by_ref();
@@ -195,3 +826,178 @@ impl Future for AsyncInOutTriple {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_thresholds() {
+        let stats = ProgramStats {
+            program_number: 0x400,
+            total_packets: 1000,
+            undecrypted_packets: 20,
+            unpurchased_ecm_count: 1,
+            last_ecm_error: Some(0xA102),
+        };
+        assert!(DecodeThresholds::default().check(&stats).is_empty());
+
+        let thresholds = DecodeThresholds {
+            max_undecrypted: Some(20),
+            max_undecrypted_ratio: Some(0.01),
+            max_unpurchased_ecm: Some(0),
+        };
+        assert_eq!(
+            thresholds.check(&stats),
+            [
+                "undecrypted ratio 0.0200 above 0.01",
+                "more than 0 unpurchased ECMs"
+            ]
+        );
+
+        // A program without packets is never over the ratio
+        let empty = ProgramStats {
+            total_packets: 0,
+            undecrypted_packets: 0,
+            ..stats
+        };
+        assert_eq!(thresholds.check(&empty).len(), 1);
+
+        assert!(report_program_stats(
+            std::slice::from_ref(&stats),
+            0,
+            &thresholds
+        ));
+        assert!(!report_program_stats(
+            std::slice::from_ref(&stats),
+            0,
+            &DecodeThresholds::default()
+        ));
+
+        // The packets written undecoded count as undecrypted
+        let thresholds = DecodeThresholds {
+            max_undecrypted: Some(50),
+            ..DecodeThresholds::default()
+        };
+        assert!(!report_program_stats(
+            std::slice::from_ref(&stats),
+            30,
+            &thresholds
+        ));
+        assert!(report_program_stats(
+            std::slice::from_ref(&stats),
+            31,
+            &thresholds
+        ));
+        assert!(!report_program_stats(&[], 50, &thresholds));
+        assert!(report_program_stats(&[], 51, &thresholds));
+        assert!(ThresholdsExceeded::is(&ThresholdsExceeded.into()));
+        assert!(!ThresholdsExceeded::is(&io::ErrorKind::InvalidData.into()));
+    }
+
+    #[test]
+    fn test_recovery_backoff() {
+        let now = Instant::now();
+        let mut recovery = Recovery::new();
+        recovery.fail(1000, now);
+        assert_eq!(recovery.retry_at, now + Duration::from_secs(1));
+        for secs in [1, 2, 4, 8, 16, 32, 60, 60] {
+            recovery.retry_later(now);
+            assert_eq!(recovery.retry_at, now + Duration::from_secs(secs));
+        }
+        // Reset when the new decoder has failed again
+        recovery.resume(4900);
+        recovery.fail(6000, now);
+        assert_eq!(recovery.backoff, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_recovery_ranges() {
+        let now = Instant::now();
+        let mut recovery = Recovery::new();
+        recovery.fail(1000, now);
+        // Failing again keeps the start
+        recovery.fail(2000, now);
+        recovery.start_output(|| 900);
+        recovery.start_output(|| unreachable!());
+        recovery.resume(4900);
+        assert!(recovery.undecoded.is_empty());
+        recovery.decoding(5000, 4900);
+        recovery.fail(8000, now);
+        recovery.start_output(|| 7000);
+        // The marks are told in the offsets of the files
+        recovery.finish(9000, 8000, |mark| mark * 192 / 188);
+        assert_eq!(
+            recovery.undecoded,
+            [
+                Undecoded {
+                    input: 1000..5000,
+                    output: 919..5004,
+                },
+                Undecoded {
+                    input: 8000..9000,
+                    output: 7148..8170,
+                },
+            ]
+        );
+        assert_eq!(
+            recovery.undecoded[0].to_string(),
+            "input bytes 1000..5000, output bytes 919..5004"
+        );
+
+        // Nothing to record while decoding
+        recovery.resume(9000);
+        recovery.decoding(10000, 9000);
+        assert_eq!(recovery.undecoded.len(), 2);
+
+        // Resumed before the decoded stream was queued
+        let mut recovery = Recovery::new();
+        recovery.fail(0, now);
+        recovery.resume(800);
+        recovery.decoding(1000, 900);
+        assert_eq!(recovery.undecoded[0].output, 800..900);
+
+        // The new decoder failing before decoding goes on with the range
+        let mut recovery = Recovery::new();
+        recovery.fail(0, now);
+        recovery.start_output(|| 100);
+        recovery.resume(200);
+        recovery.fail(3000, now);
+        recovery.start_output(|| unreachable!());
+        recovery.resume(400);
+        recovery.finish(5000, 500, |mark| mark);
+        assert_eq!(
+            recovery.undecoded,
+            [Undecoded {
+                input: 0..5000,
+                output: 100..500,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_merge_program_stats() {
+        let program = |program_number, last_ecm_error| ProgramStats {
+            program_number,
+            total_packets: 100,
+            undecrypted_packets: 10,
+            unpurchased_ecm_count: 1,
+            last_ecm_error,
+        };
+        let mut total = Vec::new();
+        merge_program_stats(&mut total, vec![program(1, Some(0xA102))]);
+        merge_program_stats(&mut total, vec![program(2, None), program(1, None)]);
+        assert_eq!(
+            total,
+            [
+                ProgramStats {
+                    total_packets: 200,
+                    undecrypted_packets: 20,
+                    unpurchased_ecm_count: 2,
+                    ..program(1, Some(0xA102))
+                },
+                program(2, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use futures_util::{AsyncBufRead, AsyncRead};
+use log::error;
+
+use crate::ts::packet::{Framer, TS_PACKET_SIZE};
+
+/// A processing step applied to each TS packet flowing through
+/// `AsyncInOutTriple`.
+pub(crate) trait PacketStage: Send {
+    /// Process a 188-byte packet starting with the sync byte, appending the
+    /// packets to pass on to `out`. A stage may drop, modify or insert packets,
+    /// but must only emit whole 188-byte packets.
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>);
+
+    /// Called when the synchronization of the input was lost `count` times
+    /// and `skipped_bytes` bytes were discarded to recover it.
+    fn sync_lost(&mut self, _count: u64, _skipped_bytes: usize) {}
+
+    /// Called once at the end of the stream to emit any pending packets.
+    fn finish(&mut self, _out: &mut Vec<u8>) {}
+
+    /// Whether the stage wants the stream to end. Only the stages before the
+    /// decoder, and those added with `Pipeline::after_decoder_ending`, can end
+    /// the stream, as if the source reached its end.
+    fn is_done(&self) -> bool {
+        false
+    }
+}
+
+/// A stage shared with the command, which takes its results once the stream
+/// has ended.
+impl<T: PacketStage> PacketStage for Arc<Mutex<T>> {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        self.lock().unwrap().process(packet, out)
+    }
+
+    fn sync_lost(&mut self, count: u64, skipped_bytes: usize) {
+        self.lock().unwrap().sync_lost(count, skipped_bytes)
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        self.lock().unwrap().finish(out)
+    }
+
+    fn is_done(&self) -> bool {
+        self.lock().unwrap().is_done()
+    }
+}
+
+/// Packet stages to run before and after the decoder.
+#[derive(Default)]
+pub(crate) struct Pipeline {
+    pre: Vec<Box<dyn PacketStage>>,
+    post: Vec<Box<dyn PacketStage>>,
+}
+
+impl Pipeline {
+    /// Add a stage that sees the stream as received from the source.
+    pub fn before_decoder(&mut self, stage: impl PacketStage + 'static) {
+        self.pre.push(Box::new(stage));
+    }
+
+    /// Add a stage that sees the stream as written to the output.
+    pub fn after_decoder(&mut self, stage: impl PacketStage + 'static) {
+        self.post.push(Box::new(stage));
+    }
+
+    /// Add a stage after the decoder, whose end ends the stream read from the
+    /// source. The stream read until then still goes through the decoder.
+    pub fn after_decoder_ending(&mut self, stage: impl PacketStage + 'static) {
+        let stage = Arc::new(Mutex::new(stage));
+        self.pre.push(Box::new(EndWith(stage.clone())));
+        self.post.push(Box::new(stage));
+    }
+
+    /// Also returns where the stream read from the stages lies in the source.
+    pub(crate) fn attach(
+        self,
+        i: Box<dyn AsyncBufRead + Unpin>,
+        o: Box<dyn Write + Send>,
+    ) -> (
+        Box<dyn AsyncBufRead + Unpin>,
+        Box<dyn Write + Send>,
+        SourceOffsets,
+    ) {
+        let mut offsets = SourceOffsets::default();
+        let i = if self.pre.is_empty() {
+            i
+        } else {
+            offsets = SourceOffsets::mapped();
+            Box::new(StageReader::new(
+                i,
+                StageChain::new(self.pre),
+                offsets.clone(),
+            ))
+        };
+        let o = if self.post.is_empty() {
+            o
+        } else {
+            Box::new(StageWriter::new(o, StageChain::new(self.post)))
+        };
+        (i, o, offsets)
+    }
+}
+
+/// Passes the stream on, ending it once the stage after the decoder is done.
+struct EndWith<T>(Arc<Mutex<T>>);
+
+impl<T: PacketStage> PacketStage for EndWith<T> {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(packet);
+    }
+
+    fn is_done(&self) -> bool {
+        self.0.is_done()
+    }
+}
+
+/// Tells the offsets in the source of the stream the stages before the
+/// decoder emit, which may drop or insert packets.
+///
+/// An offset is told to the chunks read from the source around it, rounded
+/// outwards, as the framer carries the bytes after the last packet over to
+/// the next chunks, and only for the last chunks. A stage holding packets
+/// back moves them to a later chunk.
+/// Without the stages, the offsets are the same.
+#[derive(Clone, Default)]
+pub(crate) struct SourceOffsets(Option<Arc<Mutex<Chunks>>>);
+
+/// The ends of the chunks in the stream and in the source.
+type Chunks = VecDeque<(u64, u64)>;
+
+impl SourceOffsets {
+    /// The number of the chunks kept.
+    const MAX_CHUNKS: usize = 1 << 16;
+
+    fn mapped() -> Self {
+        Self(Some(Arc::new(Mutex::new(VecDeque::from([(0, 0)])))))
+    }
+
+    /// Records a chunk of the source turned into `emitted` bytes.
+    fn record(&self, emitted: usize, read: usize) {
+        if let Some(chunks) = self.0.as_ref() {
+            let mut chunks = chunks.lock().unwrap();
+            let (stream, source) = chunks.back().copied().unwrap_or_default();
+            chunks.push_back((stream + emitted as u64, source + read as u64));
+            if chunks.len() > Self::MAX_CHUNKS {
+                chunks.pop_front();
+            }
+        }
+    }
+
+    /// The offset in the source before which the byte at `offset` was not
+    /// read, that is the start of the last chunk emitting packets before it.
+    pub fn start(&self, offset: u64) -> u64 {
+        match self.0.as_ref() {
+            None => offset,
+            Some(chunks) => {
+                let chunks = chunks.lock().unwrap();
+                let first = chunks.front().map_or(0, |&(_, source)| source);
+                (1..chunks.len())
+                    .rev()
+                    .find(|&i| chunks[i - 1].0 < chunks[i].0 && chunks[i].0 < offset)
+                    .map_or(first, |i| chunks[i - 1].1)
+            }
+        }
+    }
+
+    /// The offset in the source where the bytes before `offset` end.
+    pub fn end(&self, offset: u64) -> u64 {
+        match self.0.as_ref() {
+            None => offset,
+            Some(chunks) => {
+                let chunks = chunks.lock().unwrap();
+                let first = chunks.iter().position(|&(stream, _)| stream >= offset);
+                let last = chunks.back().map_or(0, |&(_, source)| source);
+                first.map_or(last, |i| chunks[i].1)
+            }
+        }
+    }
+}
+
+/// A series of stages fed by a `Framer`.
+struct StageChain {
+    framer: Framer,
+    stages: Vec<Box<dyn PacketStage>>,
+    packets: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+impl StageChain {
+    fn new(stages: Vec<Box<dyn PacketStage>>) -> Self {
+        Self {
+            framer: Framer::new(),
+            stages,
+            packets: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Run `data` through the stages, appending the result to `out`.
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let losses = self.framer.sync_losses();
+        let skipped = self.framer.feed(data, &mut self.packets);
+        self.notify(losses, skipped);
+        self.run(0, out);
+    }
+
+    /// Flush the framer and all the stages at the end of the stream.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        let losses = self.framer.sync_losses();
+        let skipped = self.framer.finish(&mut self.packets);
+        self.notify(losses, skipped);
+        self.run(0, out);
+        for i in 0..self.stages.len() {
+            self.stages[i].finish(&mut self.packets);
+            self.run(i + 1, out);
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.stages.iter().any(|stage| stage.is_done())
+    }
+
+    fn notify(&mut self, losses: u64, skipped: usize) {
+        let count = self.framer.sync_losses() - losses;
+        if count > 0 || skipped > 0 {
+            for stage in self.stages.iter_mut() {
+                stage.sync_lost(count, skipped);
+            }
+        }
+    }
+
+    /// Pass the packets in `self.packets` through the stages from `first` on.
+    fn run(&mut self, first: usize, out: &mut Vec<u8>) {
+        let mut current = mem::take(&mut self.packets);
+        let mut next = mem::take(&mut self.scratch);
+        for stage in self.stages[first..].iter_mut() {
+            next.clear();
+            for packet in current.chunks_exact(TS_PACKET_SIZE) {
+                stage.process(packet, &mut next);
+            }
+            mem::swap(&mut current, &mut next);
+        }
+        out.extend_from_slice(&current);
+        current.clear();
+        self.packets = current;
+        self.scratch = next;
+    }
+}
+
+/// Applies the stages to the data read from the source.
+struct StageReader {
+    inner: Box<dyn AsyncBufRead + Unpin>,
+    chain: StageChain,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    offsets: SourceOffsets,
+}
+
+impl StageReader {
+    fn new(
+        inner: Box<dyn AsyncBufRead + Unpin>,
+        chain: StageChain,
+        offsets: SourceOffsets,
+    ) -> Self {
+        Self {
+            inner,
+            chain,
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+            offsets,
+        }
+    }
+}
+
+impl AsyncRead for StageReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncBufRead for StageReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        // A chunk of input may produce no packets at all
+        while this.pos >= this.buf.len() && !this.eof {
+            this.buf.clear();
+            this.pos = 0;
+            let data = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+            if data.is_empty() {
+                this.chain.finish(&mut this.buf);
+                this.eof = true;
+                this.offsets.record(this.buf.len(), 0);
+            } else {
+                let n = data.len();
+                this.chain.push(data, &mut this.buf);
+                Pin::new(&mut this.inner).consume(n);
+                if this.chain.is_done() {
+                    this.chain.finish(&mut this.buf);
+                    this.eof = true;
+                }
+                this.offsets.record(this.buf.len(), n);
+            }
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = (this.pos + amt).min(this.buf.len());
+    }
+}
+
+/// Applies the stages to the data written to the output.
+struct StageWriter {
+    inner: Box<dyn Write + Send>,
+    chain: StageChain,
+    buf: Vec<u8>,
+}
+
+impl StageWriter {
+    fn new(inner: Box<dyn Write + Send>, chain: StageChain) -> Self {
+        Self {
+            inner,
+            chain,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for StageWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.chain.push(data, &mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Drop for StageWriter {
+    fn drop(&mut self) {
+        self.buf.clear();
+        self.chain.finish(&mut self.buf);
+        if let Err(e) = self
+            .inner
+            .write_all(&self.buf)
+            .and_then(|_| self.inner.flush())
+        {
+            error!("Failed to write the last packets: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::io::{AllowStdIo, BufReader};
+    use futures_util::AsyncReadExt;
+
+    /// Drops every other packet.
+    struct Halve(bool);
+
+    impl PacketStage for Halve {
+        fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+            self.0 = !self.0;
+            if self.0 {
+                out.extend_from_slice(packet);
+            }
+        }
+    }
+
+    fn stream(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| {
+                let mut p = vec![i as u8; TS_PACKET_SIZE];
+                p[0] = 0x47;
+                p
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reader_and_writer() {
+        let data = stream(100);
+        let expected: Vec<u8> = data
+            .chunks(TS_PACKET_SIZE)
+            .step_by(4)
+            .flatten()
+            .copied()
+            .collect();
+
+        let mut pipeline = Pipeline::default();
+        pipeline.before_decoder(Halve(false));
+        pipeline.after_decoder(Halve(false));
+
+        let input = BufReader::with_capacity(1000, AllowStdIo::new(io::Cursor::new(data)));
+        let sink = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        struct Sink(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (mut i, mut o, offsets) =
+            pipeline.attach(Box::new(input), Box::new(Sink(sink.clone())));
+        let mut read = Vec::new();
+        futures_executor::block_on(i.read_to_end(&mut read)).unwrap();
+        for piece in read.chunks(777) {
+            o.write_all(piece).unwrap();
+        }
+        drop(o);
+        assert_eq!(*sink.lock().unwrap(), expected);
+
+        // Every other packet of the source is kept, and each is told within
+        // the offsets of the source around it
+        assert_eq!((offsets.start(0), offsets.end(0)), (0, 0));
+        assert_eq!(offsets.end(read.len() as u64), 18800);
+        for n in 0..read.len() as u64 / 188 {
+            let start = n * 2 * 188;
+            assert!(offsets.start(n * 188) <= start);
+            assert!(offsets.end((n + 1) * 188) >= start + 188);
+        }
+    }
+
+    /// Passes the first packets, and is done after them.
+    struct Take(usize);
+
+    impl PacketStage for Take {
+        fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+            if self.0 > 0 {
+                self.0 -= 1;
+                out.extend_from_slice(packet);
+            }
+        }
+
+        fn is_done(&self) -> bool {
+            self.0 == 0
+        }
+    }
+
+    #[test]
+    fn test_after_decoder_ending() {
+        let data = stream(100);
+        let mut pipeline = Pipeline::default();
+        pipeline.after_decoder_ending(Take(3));
+        let input = BufReader::with_capacity(1000, AllowStdIo::new(io::Cursor::new(data.clone())));
+        let (mut i, mut o, _) = pipeline.attach(Box::new(input), Box::new(Vec::new()));
+
+        // The source is read until the stage after the decoder is done
+        let mut read = Vec::new();
+        let mut buf = [0; 1000];
+        loop {
+            let n = futures_executor::block_on(i.read(&mut buf)).unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+            o.write_all(&buf[..n]).unwrap();
+        }
+        assert_eq!(read, data[..read.len()]);
+        assert!(read.len() >= 3 * TS_PACKET_SIZE);
+        assert!(read.len() < data.len());
+    }
+
+    #[test]
+    fn test_source_offsets() {
+        let offsets = SourceOffsets::default();
+        assert_eq!((offsets.start(1000), offsets.end(1000)), (1000, 1000));
+
+        let offsets = SourceOffsets::mapped();
+        offsets.record(376, 1000);
+        // A chunk without packets
+        offsets.record(0, 1000);
+        offsets.record(564, 1000);
+        assert_eq!(offsets.start(376), 0);
+        // The rest of the first chunk may have been carried over
+        assert_eq!(offsets.start(377), 0);
+        assert_eq!(offsets.start(941), 2000);
+        assert_eq!(offsets.end(376), 1000);
+        assert_eq!(offsets.end(377), 3000);
+        assert_eq!(offsets.end(2000), 3000);
+    }
+}
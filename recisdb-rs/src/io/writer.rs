@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use futures_util::AsyncWrite;
+use log::debug;
+
+use b25_sys::LatencyStats;
+
+/// The amount of the output queued for the thread before `poll_write` waits.
+const QUEUE_CAP: usize = 1600000;
+
+/// Counts the bytes written to the files of the output, which the converting
+/// and the splitting writers open.
+#[derive(Clone, Default)]
+pub(crate) struct OutputCounter(Arc<AtomicU64>);
+
+impl OutputCounter {
+    /// Counts the bytes written to `file`.
+    pub fn wrap(&self, file: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        Box::new(Counted {
+            inner: file,
+            count: self.clone(),
+        })
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct Counted {
+    inner: Box<dyn Write + Send>,
+    count: OutputCounter,
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.0.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: Vec<u8>,
+    /// The bytes queued so far, and those taken by the thread.
+    queued: u64,
+    taken: u64,
+    /// The marks not written yet, and the offsets of the output at those
+    /// written.
+    marks: VecDeque<u64>,
+    offsets: Vec<(u64, u64)>,
+    /// Counts the files of the output, or else the bytes written by the thread.
+    counter: Option<OutputCounter>,
+    closed: bool,
+    /// Set when the thread has dropped the output and exited.
+    finished: bool,
+    /// The error of the output, returned once, and whether it has failed.
+    error: Option<io::Error>,
+    failed: bool,
+    waker: Option<Waker>,
+    /// Each write to the output.
+    latency: LatencyStats,
+}
+
+impl State {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        match self.error.take() {
+            Some(e) => Some(e),
+            None if self.failed => Some(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The output has failed.",
+            )),
+            None => None,
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notifies the thread of new data or the close.
+    work: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Writes the output on a thread of its own, so that a slow disk or a slow
+/// reader of stdout holds up neither the source nor the decoder.
+///
+/// The data written is queued for the thread, and the task is woken up when
+/// the bounded queue has room again. `poll_close` waits until the thread has
+/// written out the queue, flushed the output and dropped it, which finishes
+/// the stages after the decoder. `poll_flush` only reports the errors of the
+/// output.
+///
+/// A mark placed between two writes is told in the offsets of the output once
+/// the thread has written the data before it.
+pub(crate) struct ThreadedWriter {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedWriter {
+    pub fn new(output: Box<dyn Write + Send>) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            work: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("output".to_string())
+                .spawn(move || work(output, &shared))?
+        };
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the latencies of the writes to the output.
+    pub fn latency(&self) -> LatencyStats {
+        self.shared.lock().latency
+    }
+
+    /// Tells the marks in the bytes counted by `counter` rather than in those
+    /// written to the output.
+    pub fn count_with(&self, counter: OutputCounter) {
+        self.shared.lock().counter = Some(counter);
+    }
+
+    /// Marks the end of the data written so far.
+    pub fn mark(&self) -> u64 {
+        let mut state = self.shared.lock();
+        let mark = state.queued;
+        if state.marks.back() != Some(&mark) {
+            state.marks.push_back(mark);
+        }
+        mark
+    }
+
+    /// The offset of the output at the mark, or the current one if the data
+    /// before the mark has not been written.
+    pub fn offset_at(&self, mark: u64) -> u64 {
+        let state = self.shared.lock();
+        match state.offsets.iter().find(|&&(m, _)| m == mark) {
+            Some(&(_, offset)) => offset,
+            None => state.counter.as_ref().map_or(mark, OutputCounter::get),
+        }
+    }
+
+    /// Writes out the queue and drops the output, as when the writer is
+    /// dropped.
+    pub fn join(&mut self) {
+        {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            self.shared.work.notify_one();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes `chunk`, which starts at `start`, recording the offsets of the
+/// output at the marks in it.
+fn write_marked(
+    output: &mut dyn Write,
+    chunk: &[u8],
+    start: u64,
+    marks: &[u64],
+    counter: Option<&OutputCounter>,
+) -> io::Result<Vec<(u64, u64)>> {
+    let mut offsets = Vec::with_capacity(marks.len());
+    let mut done = 0;
+    for &mark in marks {
+        let end = (mark - start) as usize;
+        output.write_all(&chunk[done..end])?;
+        done = end;
+        // Flushed, so that the bytes before the mark reach the files
+        output.flush()?;
+        offsets.push((mark, counter.map_or(mark, OutputCounter::get)));
+    }
+    output.write_all(&chunk[done..])?;
+    Ok(offsets)
+}
+
+fn work(mut output: Box<dyn Write + Send>, shared: &Shared) {
+    let mut chunk = Vec::new();
+    loop {
+        let (closed, start, marks, counter) = {
+            let mut state = shared.lock();
+            while state.queue.is_empty() && !state.closed {
+                state = shared.work.wait(state).unwrap();
+            }
+            std::mem::swap(&mut chunk, &mut state.queue);
+            let start = state.taken;
+            state.taken += chunk.len() as u64;
+            let end = state.taken;
+            let count = state.marks.iter().take_while(|&&m| m <= end).count();
+            let marks: Vec<u64> = state.marks.drain(..count).collect();
+            state.wake();
+            (state.closed, start, marks, state.counter.clone())
+        };
+
+        let started = Instant::now();
+        let result = write_marked(&mut output, &chunk, start, &marks, counter.as_ref()).and_then(
+            |offsets| {
+                if closed {
+                    output.flush()?;
+                }
+                Ok(offsets)
+            },
+        );
+        chunk.clear();
+
+        let mut state = shared.lock();
+        match result {
+            Ok(offsets) => {
+                state.offsets.extend(offsets);
+                state.latency.record(started.elapsed())
+            }
+            Err(e) => {
+                state.error = Some(e);
+                state.failed = true;
+            }
+        }
+        if state.failed || (closed && state.queue.is_empty()) {
+            drop(state);
+            // The stages after the decoder finish when the output is dropped
+            drop(output);
+            let mut state = shared.lock();
+            state.finished = true;
+            state.wake();
+            debug!("The output thread has exited.");
+            return;
+        }
+        state.wake();
+    }
+}
+
+impl AsyncWrite for ThreadedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.shared.lock();
+        if let Some(e) = state.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = QUEUE_CAP.saturating_sub(state.queue.len());
+        if room == 0 {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        state.queue.extend_from_slice(&buf[..n]);
+        state.queued += n as u64;
+        self.shared.work.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.shared.lock().take_error() {
+            Some(e) => Poll::Ready(Err(e)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.lock();
+        if !state.closed {
+            state.closed = true;
+            self.shared.work.notify_one();
+        }
+        if let Some(e) = state.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        if state.finished {
+            Poll::Ready(Ok(()))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ThreadedWriter {
+    fn drop(&mut self) {
+        // The queue is still written out, as the run has produced it
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_executor::block_on;
+    use futures_util::AsyncWriteExt;
+
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_threaded_writer() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = ThreadedWriter::new(Box::new(Sink(data.clone()))).unwrap();
+        let input: Vec<u8> = (0..QUEUE_CAP * 3).map(|i| i as u8).collect();
+        block_on(async {
+            writer.write_all(&input).await.unwrap();
+            writer.close().await.unwrap();
+        });
+        assert_eq!(*data.lock().unwrap(), input);
+        assert!(writer.latency().count > 0);
+    }
+
+    /// Writes each byte twice, as a converting writer before the files.
+    struct Doubler(Box<dyn Write + Send>);
+
+    impl Write for Doubler {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for b in buf {
+                self.0.write_all(&[*b, *b])?;
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn test_marks() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let counter = OutputCounter::default();
+        let file = counter.wrap(Box::new(Sink(data.clone())));
+        let mut writer = ThreadedWriter::new(Box::new(Doubler(file))).unwrap();
+        writer.count_with(counter);
+        let start = writer.mark();
+        let marks = block_on(async {
+            writer.write_all(&[1; 100]).await.unwrap();
+            let first = writer.mark();
+            writer.write_all(&[2; 50]).await.unwrap();
+            let second = writer.mark();
+            writer.close().await.unwrap();
+            [first, second]
+        });
+        assert_eq!(marks, [100, 150]);
+        assert_eq!(writer.offset_at(start), 0);
+        assert_eq!(writer.offset_at(100), 200);
+        assert_eq!(writer.offset_at(150), 300);
+        // Past the data written
+        assert_eq!(writer.offset_at(writer.mark()), 300);
+        assert_eq!(data.lock().unwrap().len(), 300);
+
+        // The bytes written to the output without a counter
+        let mut writer = ThreadedWriter::new(Box::new(Sink(Arc::default()))).unwrap();
+        block_on(writer.write_all(&[1; 100])).unwrap();
+        let mark = writer.mark();
+        writer.join();
+        assert_eq!(writer.offset_at(mark), 100);
+    }
+
+    #[test]
+    fn test_threaded_writer_error() {
+        let mut writer = ThreadedWriter::new(Box::new(Broken)).unwrap();
+        let e = block_on(async {
+            let _ = writer.write_all(&[0; 188]).await;
+            writer.close().await.unwrap_err()
+        });
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -2,12 +2,13 @@ use crate::utils::StreamExitType;
 use clap::Parser;
 use futures_executor::block_on;
 use futures_time::future::FutureExt;
-use log::{debug, info};
+use log::{debug, error, info};
 
 mod channels;
 mod commands;
 mod context;
 mod io;
+mod ts;
 mod tuner;
 mod utils;
 
@@ -18,7 +19,7 @@ fn main() {
     utils::initialize_logger();
 
     // Get Future
-    let (fut, timeout_option, progress) = commands::process_command(arg);
+    let (fut, timeout_option, progress, finish) = commands::process_command(arg);
 
     let result = {
         // Common code for handling progress
@@ -55,9 +56,17 @@ fn main() {
         }
     };
 
+    // Write out what the command collected, even if the stream timed out
+    if let Some(Err(e)) = finish.map(|finish| finish()) {
+        error!("Failed to write the output: {}", e);
+        std::process::exit(1);
+    }
+
     match result {
         StreamExitType::Success(_) => {}
         StreamExitType::Timeout => {}
+        // The programs over the thresholds have been logged
+        StreamExitType::Error(e) if io::ThresholdsExceeded::is(&e) => std::process::exit(1),
         StreamExitType::Error(_) => {}
         StreamExitType::UnexpectedEofInTuner => {}
     }
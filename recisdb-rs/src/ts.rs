@@ -0,0 +1,31 @@
+pub(crate) use self::analyze::{AnalysisFormat, PcrAnalyzer};
+pub(crate) use self::caption::{CaptionExtractor, CaptionFormat};
+pub(crate) use self::clock::{BroadcastClock, ClockReader};
+pub(crate) use self::epg::{EpgCollector, EpgFormat};
+pub(crate) use self::event::EventGate;
+pub(crate) use self::filter::{PacketFilter, PidList};
+pub(crate) use self::inspect::{InspectFormat, Inspector};
+pub(crate) use self::m2ts::{M2tsWriter, OutputFormat};
+pub(crate) use self::monitor::PacketMonitor;
+pub(crate) use self::partial::PartialTs;
+pub(crate) use self::service::{ServiceFilter, ServiceSelection};
+pub(crate) use self::split::{
+    FileTemplate, Schedule, SplitTrigger, SplitWriter, DEFAULT_SPLIT_TEMPLATE,
+};
+
+mod analyze;
+mod aribstr;
+mod caption;
+mod clock;
+mod epg;
+mod event;
+mod filter;
+mod inspect;
+mod m2ts;
+mod monitor;
+pub(crate) mod packet;
+mod partial;
+pub(crate) mod psi;
+mod service;
+pub(crate) mod si;
+mod split;
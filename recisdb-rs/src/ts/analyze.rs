@@ -0,0 +1,564 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::packet::{Packet, TS_PACKET_SIZE};
+use crate::ts::psi::{Pat, Pmt, Section, SectionBuffer, PAT_PID};
+
+/// The frequency of the PCR.
+const CLOCK_HZ: f64 = 27_000_000.0;
+/// The PCR wraps around at 2^33 * 300.
+const PCR_MODULO: i64 = (1 << 33) * 300;
+/// The maximum PCR interval allowed by ISO/IEC 13818-1 (100 ms).
+const MAX_PCR_INTERVAL: i64 = 2_700_000;
+/// PCRs farther apart than this are taken as a discontinuity.
+const DISCONTINUITY_THRESHOLD: i64 = 27_000_000;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum AnalysisFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+/// Statistics of the PCR on a PID.
+#[derive(Debug, Default)]
+struct PcrStats {
+    count: u64,
+    /// The last PCR and the byte position of its packet.
+    last: Option<(i64, u64)>,
+    intervals: u64,
+    interval_sum: i64,
+    interval_min: Option<i64>,
+    interval_max: i64,
+    /// Intervals longer than 100 ms.
+    late: u64,
+    discontinuities: u64,
+    jitters: u64,
+    /// Sum and maximum of the absolute jitter in 27 MHz ticks.
+    jitter_sum: f64,
+    jitter_max: f64,
+}
+
+impl PcrStats {
+    /// Record a PCR found at byte `pos`. Returns the time passed since the
+    /// last PCR, unless there is a discontinuity.
+    fn update(
+        &mut self,
+        pcr: i64,
+        pos: u64,
+        discontinuity: bool,
+        ticks_per_byte: Option<f64>,
+    ) -> Option<i64> {
+        self.count += 1;
+        let last = self.last.replace((pcr, pos));
+        let (last_pcr, last_pos) = last?;
+        let delta = (pcr - last_pcr).rem_euclid(PCR_MODULO);
+        if discontinuity || delta > DISCONTINUITY_THRESHOLD {
+            self.discontinuities += 1;
+            return None;
+        }
+        self.intervals += 1;
+        self.interval_sum += delta;
+        self.interval_min = Some(self.interval_min.map_or(delta, |min| min.min(delta)));
+        self.interval_max = self.interval_max.max(delta);
+        if delta > MAX_PCR_INTERVAL {
+            self.late += 1;
+        }
+        // The deviation from the time the packet should arrive at the
+        // average multiplex rate
+        if let Some(ticks_per_byte) = ticks_per_byte {
+            let jitter = (delta as f64 - (pos - last_pos) as f64 * ticks_per_byte).abs();
+            self.jitters += 1;
+            self.jitter_sum += jitter;
+            self.jitter_max = self.jitter_max.max(jitter);
+        }
+        Some(delta)
+    }
+
+    fn interval_ms(&self) -> (f64, f64, f64) {
+        let ms = |ticks: i64| ticks as f64 / 27_000.0;
+        let avg = if self.intervals > 0 {
+            self.interval_sum as f64 / self.intervals as f64
+        } else {
+            0.0
+        };
+        (
+            ms(self.interval_min.unwrap_or(0)),
+            avg / 27_000.0,
+            ms(self.interval_max),
+        )
+    }
+
+    fn jitter_us(&self) -> (f64, f64) {
+        let avg = if self.jitters > 0 {
+            self.jitter_sum / self.jitters as f64
+        } else {
+            0.0
+        };
+        (avg / 27.0, self.jitter_max / 27.0)
+    }
+}
+
+/// Bitrates over an interval of the timeline.
+#[derive(Debug)]
+struct Sample {
+    /// Seconds since the first PCR.
+    time: f64,
+    /// The multiplex rate in bits per second.
+    mux_rate: f64,
+    /// Bitrates of the services in bits per second.
+    services: BTreeMap<u16, f64>,
+}
+
+/// Measures the multiplex rate and the bitrate of each service over time on
+/// the clock of the PCR, along with the interval, jitter and discontinuities
+/// of the PCR on each PID. The results are written out on `finish` at the
+/// end of the stream.
+///
+/// The time base is the first PID found to carry a PCR. The jitter is the
+/// deviation of each PCR from the time its packet should arrive at the
+/// average multiplex rate.
+///
+/// The stage passes every packet through unchanged.
+pub(crate) struct PcrAnalyzer {
+    interval: i64,
+    format: AnalysisFormat,
+    /// `None` for stdout.
+    output: Option<PathBuf>,
+    pat_buffer: SectionBuffer,
+    pat: Option<Pat>,
+    pmt_buffers: HashMap<u16, SectionBuffer>,
+    /// Latest PMT of each service.
+    pmts: BTreeMap<u16, Pmt>,
+    /// Bytes received so far.
+    pos: u64,
+    pcr: BTreeMap<u16, PcrStats>,
+    reference: Option<u16>,
+    /// Byte position of the first PCR on the reference PID.
+    first_pos: u64,
+    /// Ticks passed on the reference PID since its first PCR.
+    clock: i64,
+    /// Byte position of the last PCR on the reference PID.
+    clock_pos: u64,
+    sample_start: (i64, u64),
+    sample_bytes: HashMap<u16, u64>,
+    timeline: Vec<Sample>,
+    total_bytes: HashMap<u16, u64>,
+}
+
+impl PcrAnalyzer {
+    pub fn new(interval_secs: f64, format: AnalysisFormat, output: Option<PathBuf>) -> Self {
+        Self {
+            interval: ((interval_secs * CLOCK_HZ) as i64).max(1),
+            format,
+            output,
+            pat_buffer: SectionBuffer::default(),
+            pat: None,
+            pmt_buffers: HashMap::new(),
+            pmts: BTreeMap::new(),
+            pos: 0,
+            pcr: BTreeMap::new(),
+            reference: None,
+            first_pos: 0,
+            clock: 0,
+            clock_pos: 0,
+            sample_start: (0, 0),
+            sample_bytes: HashMap::new(),
+            timeline: Vec::new(),
+            total_bytes: HashMap::new(),
+        }
+    }
+
+    /// 27 MHz ticks per byte at the average multiplex rate so far.
+    fn ticks_per_byte(&self) -> Option<f64> {
+        let bytes = self.clock_pos - self.first_pos;
+        (self.clock > 0 && bytes > 0).then(|| self.clock as f64 / bytes as f64)
+    }
+
+    /// PIDs of each service: the PMT, the ES, the ECM and the PCR.
+    fn service_pids(&self) -> BTreeMap<u16, BTreeSet<u16>> {
+        let pmt_pids: HashMap<u16, u16> = self.pat.iter().flat_map(|pat| pat.services()).collect();
+        self.pmts
+            .iter()
+            .map(|(&sid, pmt)| {
+                let pids = pmt
+                    .streams
+                    .iter()
+                    .map(|s| s.pid)
+                    .chain(pmt.ecm_pids())
+                    .chain([pmt.pcr_pid])
+                    .chain(pmt_pids.get(&sid).copied())
+                    .collect();
+                (sid, pids)
+            })
+            .collect()
+    }
+
+    fn on_section(&mut self, pid: u16, bytes: &[u8]) {
+        let section = match Section::parse(bytes) {
+            Some(section) if section.current_next() => section,
+            _ => return,
+        };
+        if pid == PAT_PID {
+            if let Some(pat) = Pat::parse(&section) {
+                if self.pat.as_ref() != Some(&pat) {
+                    self.pmts
+                        .retain(|sid, _| pat.services().any(|(s, _)| s == *sid));
+                    self.pat = Some(pat);
+                }
+            }
+        } else if let Some(pmt) = Pmt::parse(&section) {
+            self.pmts.insert(pmt.program_number, pmt);
+        }
+    }
+
+    fn close_sample(&mut self) {
+        let (start, start_pos) = self.sample_start;
+        let elapsed = (self.clock - start) as f64 / CLOCK_HZ;
+        if elapsed <= 0.0 {
+            return;
+        }
+        let bits = |bytes: u64| bytes as f64 * 8.0 / elapsed;
+        let services = self
+            .service_pids()
+            .into_iter()
+            .map(|(sid, pids)| {
+                let bytes = pids
+                    .iter()
+                    .filter_map(|pid| self.sample_bytes.get(pid))
+                    .sum();
+                (sid, bits(bytes))
+            })
+            .collect();
+        self.timeline.push(Sample {
+            time: start as f64 / CLOCK_HZ,
+            mux_rate: bits(self.clock_pos - start_pos),
+            services,
+        });
+        self.sample_start = (self.clock, self.clock_pos);
+        self.sample_bytes.clear();
+    }
+
+    fn on_pcr(&mut self, pid: u16, pcr: i64, discontinuity: bool) {
+        let pos = self.pos;
+        let ticks_per_byte = self.ticks_per_byte();
+        let reference = *self.reference.get_or_insert(pid);
+        let stats = self.pcr.entry(pid).or_default();
+        let first = stats.last.is_none();
+        let delta = stats.update(pcr, pos, discontinuity, ticks_per_byte);
+        if pid != reference {
+            return;
+        }
+        if first {
+            self.first_pos = pos;
+            self.clock_pos = pos;
+            self.sample_start = (0, pos);
+            self.sample_bytes.clear();
+            return;
+        }
+        // Across a discontinuity, advance the clock at the average rate
+        let delta = delta.unwrap_or_else(|| {
+            ticks_per_byte.map_or(0, |t| ((pos - self.clock_pos) as f64 * t) as i64)
+        });
+        self.clock += delta;
+        self.clock_pos = pos;
+        if self.clock - self.sample_start.0 >= self.interval {
+            self.close_sample();
+        }
+    }
+
+    fn duration(&self) -> f64 {
+        self.clock as f64 / CLOCK_HZ
+    }
+
+    fn mux_rate(&self) -> f64 {
+        if self.clock > 0 {
+            (self.clock_pos - self.first_pos) as f64 * 8.0 / self.duration()
+        } else {
+            0.0
+        }
+    }
+
+    /// Average bitrates of the services over the whole stream.
+    fn service_rates(&self) -> Vec<(u16, u16, f64)> {
+        let duration = self.duration();
+        self.service_pids()
+            .into_iter()
+            .map(|(sid, pids)| {
+                let bytes: u64 = pids
+                    .iter()
+                    .filter_map(|pid| self.total_bytes.get(pid))
+                    .sum();
+                let rate = if duration > 0.0 {
+                    bytes as f64 * 8.0 / duration
+                } else {
+                    0.0
+                };
+                (sid, self.pmts[&sid].pcr_pid, rate)
+            })
+            .collect()
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "Duration: {:.3} s, Packets: {}, Multiplex rate: {:.3} Mbps",
+            self.duration(),
+            self.pos / TS_PACKET_SIZE as u64,
+            self.mux_rate() / 1e6
+        );
+        text.push_str(
+            "\nPCR PID     Count  Interval min/avg/max (ms)  Jitter avg/max (us)   Late  Discont.\n",
+        );
+        for (pid, s) in &self.pcr {
+            let (min, avg, max) = s.interval_ms();
+            let (jitter_avg, jitter_max) = s.jitter_us();
+            let _ = writeln!(
+                text,
+                "{:#06x}  {:>10}  {:>7.2} {:>7.2} {:>7.2}    {:>8.2} {:>9.2}  {:>5}  {:>8}",
+                pid, s.count, min, avg, max, jitter_avg, jitter_max, s.late, s.discontinuities
+            );
+        }
+        text.push_str("\nService  PCR PID  Bitrate (Mbps)\n");
+        for (sid, pcr_pid, rate) in self.service_rates() {
+            let _ = writeln!(text, "{:>7}  {:#06x}  {:>14.3}", sid, pcr_pid, rate / 1e6);
+        }
+        text.push('\n');
+        text.push_str(&self.to_csv());
+        text
+    }
+
+    fn to_csv(&self) -> String {
+        let sids: BTreeSet<u16> = self
+            .timeline
+            .iter()
+            .flat_map(|s| s.services.keys().copied())
+            .collect();
+        let mut csv = String::from("time,mux_rate");
+        for sid in &sids {
+            let _ = write!(csv, ",{}", sid);
+        }
+        csv.push('\n');
+        for sample in &self.timeline {
+            let _ = write!(csv, "{:.3},{:.0}", sample.time, sample.mux_rate);
+            for sid in &sids {
+                match sample.services.get(sid) {
+                    Some(rate) => {
+                        let _ = write!(csv, ",{:.0}", rate);
+                    }
+                    None => csv.push(','),
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    fn to_json(&self) -> String {
+        let pcr = self
+            .pcr
+            .iter()
+            .map(|(pid, s)| {
+                let (min, avg, max) = s.interval_ms();
+                let (jitter_avg, jitter_max) = s.jitter_us();
+                format!(
+                    r#"{{"pid":{},"count":{},"interval_min_ms":{:.3},"interval_avg_ms":{:.3},"interval_max_ms":{:.3},"jitter_avg_us":{:.3},"jitter_max_us":{:.3},"late":{},"discontinuities":{}}}"#,
+                    pid, s.count, min, avg, max, jitter_avg, jitter_max, s.late, s.discontinuities
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let services = self
+            .service_rates()
+            .iter()
+            .map(|(sid, pcr_pid, rate)| {
+                format!(
+                    r#"{{"service_id":{},"pcr_pid":{},"bitrate":{:.0}}}"#,
+                    sid, pcr_pid, rate
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let timeline = self
+            .timeline
+            .iter()
+            .map(|sample| {
+                let services = sample
+                    .services
+                    .iter()
+                    .map(|(sid, rate)| format!(r#""{}":{:.0}"#, sid, rate))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(
+                    r#"{{"time":{:.3},"mux_rate":{:.0},"services":{{{}}}}}"#,
+                    sample.time, sample.mux_rate, services
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"duration":{:.3},"packets":{},"mux_rate":{:.0},"pcr":[{}],"services":[{}],"timeline":[{}]}}"#,
+            self.duration(),
+            self.pos / TS_PACKET_SIZE as u64,
+            self.mux_rate(),
+            pcr,
+            services,
+            timeline
+        )
+    }
+
+    /// Closes the last sample and writes out the results.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.close_sample();
+        if self.pcr.is_empty() {
+            warn!("No PCR was found in the stream.");
+        }
+        let discontinuities: u64 = self.pcr.values().map(|s| s.discontinuities).sum();
+        info!(
+            "Duration: {:.3} s, Multiplex rate: {:.3} Mbps, PCR discontinuities: {}",
+            self.duration(),
+            self.mux_rate() / 1e6,
+            discontinuities
+        );
+        self.write()
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let document = match self.format {
+            AnalysisFormat::Text => self.to_text(),
+            AnalysisFormat::Csv => self.to_csv(),
+            AnalysisFormat::Json => self.to_json() + "\n",
+        };
+        match &self.output {
+            Some(path) => fs::write(path, document),
+            None => io::stdout().lock().write_all(document.as_bytes()),
+        }
+    }
+}
+
+impl PacketStage for PcrAnalyzer {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let p = Packet::new(packet);
+        let pid = p.pid();
+        *self.sample_bytes.entry(pid).or_default() += TS_PACKET_SIZE as u64;
+        *self.total_bytes.entry(pid).or_default() += TS_PACKET_SIZE as u64;
+
+        let is_pmt = self.pat.as_ref().map_or(false, |pat| {
+            pat.services().any(|(_, pmt_pid)| pmt_pid == pid)
+        });
+        if pid == PAT_PID || is_pmt {
+            let mut sections = Vec::new();
+            let buffer = if pid == PAT_PID {
+                &mut self.pat_buffer
+            } else {
+                self.pmt_buffers.entry(pid).or_default()
+            };
+            buffer.push(p, |s| sections.push(s.to_vec()));
+            for section in sections {
+                self.on_section(pid, &section);
+            }
+        }
+
+        if let Some(pcr) = p.pcr() {
+            self.on_pcr(pid, pcr as i64, p.discontinuity_indicator());
+        }
+        self.pos += TS_PACKET_SIZE as u64;
+        out.extend_from_slice(packet);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::psi::{build_section, packetize, TABLE_PMT};
+
+    fn pcr_packet(pid: u16, pcr: u64, discontinuity: bool) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x20]);
+        p[4] = 183;
+        p[5] = 0x10 | if discontinuity { 0x80 } else { 0 };
+        let (base, ext) = (pcr / 300, pcr % 300);
+        p[6..10].copy_from_slice(&((base >> 1) as u32).to_be_bytes());
+        p[10] = ((base & 1) << 7) as u8 | 0x7E | (ext >> 8) as u8;
+        p[11] = ext as u8;
+        p
+    }
+
+    fn es_packet(pid: u16) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10]);
+        p
+    }
+
+    fn pmt(sid: u16, pcr_pid: u16, es: u16) -> Vec<u8> {
+        let mut body = vec![0xE0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xF0, 0x00];
+        body.extend_from_slice(&[0x02, 0xE0 | (es >> 8) as u8, es as u8, 0xF0, 0x00]);
+        build_section(TABLE_PMT, sid, 0, &body)
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mut stream = Vec::new();
+        let mut cc = 0;
+        let pat = Pat {
+            transport_stream_id: 1,
+            version: 0,
+            programs: vec![(0x400, 0x1F0)],
+        };
+        packetize(PAT_PID, &pat.to_section(), &mut cc, &mut stream);
+        let mut cc = 0;
+        packetize(0x1F0, &pmt(0x400, 0x1FF, 0x111), &mut cc, &mut stream);
+
+        // 10 packets every 10 ms: 1880 bytes in 10 ms is 1.504 Mbps
+        for i in 0..300u64 {
+            let pcr = 1_000_000 + i * 270_000;
+            // A discontinuity halfway, where the PCR jumps
+            let pcr = if i >= 150 { pcr + 100_000_000 } else { pcr };
+            stream.extend(pcr_packet(0x1FF, pcr, i == 150));
+            for _ in 0..9 {
+                stream.extend(es_packet(0x111));
+            }
+        }
+        let mut analyzer = PcrAnalyzer::new(1.0, AnalysisFormat::Json, None);
+        let mut out = Vec::new();
+        for packet in stream.chunks(TS_PACKET_SIZE) {
+            analyzer.process(packet, &mut out);
+        }
+        // A second PID with the PCR 200 ms apart
+        for i in 0..2u64 {
+            analyzer.process(&pcr_packet(0x200, i * 5_400_000, false), &mut out);
+        }
+        assert_eq!(out.len(), stream.len() + 2 * TS_PACKET_SIZE);
+
+        let stats = &analyzer.pcr[&0x1FF];
+        assert_eq!(
+            (stats.count, stats.discontinuities, stats.late),
+            (300, 1, 0)
+        );
+        assert_eq!(stats.interval_ms(), (10.0, 10.0, 10.0));
+        assert!(stats.jitter_us().1 < 1.0);
+        assert_eq!(analyzer.pcr[&0x200].late, 1);
+
+        // 2.99 seconds in 1 second samples
+        assert_eq!(analyzer.timeline.len(), 2);
+        assert!((analyzer.duration() - 2.99).abs() < 1e-9);
+        assert!((analyzer.mux_rate() - 1_504_000.0).abs() < 1.0);
+        let sample = &analyzer.timeline[1];
+        assert_eq!(sample.time, 1.0);
+        assert!((sample.mux_rate - 1_504_000.0).abs() < 1.0);
+        assert!((sample.services[&0x400] - 1_504_000.0).abs() < 1.0);
+
+        let csv = analyzer.to_csv();
+        assert!(csv.starts_with("time,mux_rate,1024\n0.000,"));
+        assert!(analyzer
+            .to_json()
+            .contains(r#""services":[{"service_id":1024,"pcr_pid":511,"#));
+    }
+}
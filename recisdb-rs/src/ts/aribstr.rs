@@ -0,0 +1,382 @@
+use encoding_rs::EUC_JP;
+
+/// Replacement for characters that cannot be represented (GETA MARK).
+const GETA: char = '\u{3013}';
+
+/// Additional symbols of ARIB STD-B24 in row 90, starting at column 0x50.
+const ADDITIONAL_SYMBOLS_90: [&str; 35] = [
+    "【HV】",
+    "【SD】",
+    "【Ｐ】",
+    "【Ｗ】",
+    "【MV】",
+    "【手】",
+    "【字】",
+    "【双】",
+    "【デ】",
+    "【Ｓ】",
+    "【二】",
+    "【多】",
+    "【解】",
+    "【SS】",
+    "【Ｂ】",
+    "【Ｎ】",
+    "■",
+    "●",
+    "【天】",
+    "【交】",
+    "【映】",
+    "【無】",
+    "【料】",
+    "【鍵】",
+    "【前】",
+    "【後】",
+    "【再】",
+    "【新】",
+    "【初】",
+    "【終】",
+    "【生】",
+    "【販】",
+    "【声】",
+    "【吹】",
+    "【PPV】",
+];
+
+/// Graphic sets of ARIB STD-B24 8-unit code.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Charset {
+    Kanji,
+    Alphanumeric,
+    Hiragana,
+    Katakana,
+    JisKatakana,
+    AdditionalSymbols,
+    /// Dynamically redefinable character sets, whose glyphs are sent as
+    /// bitmaps.
+    Drcs(usize),
+    /// Sets without a Unicode mapping: mosaics and JIS X 0213 plane 2.
+    Unsupported(usize),
+}
+
+impl Charset {
+    fn from_final_1byte(f: u8) -> Self {
+        match f {
+            0x4A | 0x36 => Charset::Alphanumeric,
+            0x30 | 0x37 => Charset::Hiragana,
+            0x31 | 0x38 => Charset::Katakana,
+            0x49 => Charset::JisKatakana,
+            _ => Charset::Unsupported(1),
+        }
+    }
+
+    fn from_final_2byte(f: u8) -> Self {
+        match f {
+            0x42 | 0x39 => Charset::Kanji,
+            0x3B => Charset::AdditionalSymbols,
+            _ => Charset::Unsupported(2),
+        }
+    }
+
+    fn width(self) -> usize {
+        match self {
+            Charset::Kanji | Charset::AdditionalSymbols => 2,
+            Charset::Drcs(width) | Charset::Unsupported(width) => width,
+            _ => 1,
+        }
+    }
+}
+
+struct Decoder {
+    g: [Charset; 4],
+    gl: usize,
+    gr: usize,
+    single_shift: Option<usize>,
+    /// Whether the middle size (MSZ) is selected. Alphanumerics are
+    /// rendered as half-width characters in the middle size.
+    middle: bool,
+    /// Whether the small size (SSZ) is selected.
+    small: bool,
+    /// Whether the string is a caption statement, where the screen is
+    /// cleared and the active position is moved by control functions, and
+    /// the small size is used for ruby.
+    caption: bool,
+    /// Number of DRCS characters replaced.
+    drcs: usize,
+    /// JIS X 0208 characters waiting to be converted, in EUC-JP.
+    euc: Vec<u8>,
+    out: String,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Self {
+            g: [
+                Charset::Kanji,
+                Charset::Alphanumeric,
+                Charset::Hiragana,
+                Charset::Katakana,
+            ],
+            gl: 0,
+            gr: 2,
+            single_shift: None,
+            middle: false,
+            small: false,
+            caption: false,
+            drcs: 0,
+            euc: Vec::new(),
+            out: String::new(),
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        self.flush();
+        self.out.push(c);
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.flush();
+        self.out.push_str(s);
+    }
+
+    fn flush(&mut self) {
+        if self.euc.is_empty() {
+            return;
+        }
+        let (decoded, _) = EUC_JP.decode_without_bom_handling(&self.euc);
+        self.out.extend(
+            decoded
+                .chars()
+                .map(|c| if c == '\u{FFFD}' { GETA } else { c }),
+        );
+        self.euc.clear();
+    }
+
+    /// Decode a character of `set`, with `c1` and `c2` in the range of GL.
+    fn graphic(&mut self, set: Charset, c1: u8, c2: u8) {
+        if self.caption && self.small {
+            // Ruby
+            return;
+        }
+        match set {
+            Charset::Kanji if c1 < 0x7A => self.euc.extend_from_slice(&[c1 | 0x80, c2 | 0x80]),
+            Charset::Kanji | Charset::AdditionalSymbols => self.additional_symbol(c1, c2),
+            Charset::Alphanumeric => {
+                let c = match (c1, self.middle) {
+                    (0x5C, true) => '\u{00A5}',
+                    (0x7E, true) => '\u{203E}',
+                    (_, true) => c1 as char,
+                    (0x5C, false) => '\u{FFE5}',
+                    (0x7E, false) => '\u{FFE3}',
+                    (_, false) => char::from_u32(0xFF01 + (c1 - 0x21) as u32).unwrap(),
+                };
+                self.push(c);
+            }
+            Charset::Hiragana | Charset::Katakana => {
+                let c = match c1 {
+                    0x21..=0x73 if set == Charset::Hiragana => {
+                        char::from_u32(0x3041 + (c1 - 0x21) as u32).unwrap()
+                    }
+                    0x21..=0x76 if set == Charset::Katakana => {
+                        char::from_u32(0x30A1 + (c1 - 0x21) as u32).unwrap()
+                    }
+                    0x77 if set == Charset::Hiragana => 'ゝ',
+                    0x78 if set == Charset::Hiragana => 'ゞ',
+                    0x77 => 'ヽ',
+                    0x78 => 'ヾ',
+                    0x79 => 'ー',
+                    0x7A => '。',
+                    0x7B => '「',
+                    0x7C => '」',
+                    0x7D => '、',
+                    0x7E => '・',
+                    _ => GETA,
+                };
+                self.push(c);
+            }
+            Charset::JisKatakana => {
+                let c = match c1 {
+                    0x21..=0x5F => char::from_u32(0xFF61 + (c1 - 0x21) as u32).unwrap(),
+                    _ => GETA,
+                };
+                self.push(c);
+            }
+            Charset::Drcs(_) => {
+                self.drcs += 1;
+                self.push(GETA);
+            }
+            Charset::Unsupported(_) => self.push(GETA),
+        }
+    }
+
+    fn additional_symbol(&mut self, c1: u8, c2: u8) {
+        match (c1, c2) {
+            (0x7A, 0x50..=0x72) => self.push_str(ADDITIONAL_SYMBOLS_90[(c2 - 0x50) as usize]),
+            _ => self.push(GETA),
+        }
+    }
+
+    /// Handle an escape sequence starting at `bytes[0]` (after ESC).
+    /// Returns the number of bytes consumed.
+    fn escape(&mut self, bytes: &[u8]) -> usize {
+        let at = |i: usize| bytes.get(i).copied().unwrap_or(0);
+        match at(0) {
+            0x6E => self.gl = 2,
+            0x6F => self.gl = 3,
+            0x7E => self.gr = 1,
+            0x7D => self.gr = 2,
+            0x7C => self.gr = 3,
+            i @ 0x28..=0x2B => {
+                let g = (i - 0x28) as usize;
+                return if at(1) == 0x20 {
+                    // 1-byte DRCS
+                    self.g[g] = Charset::Drcs(1);
+                    3
+                } else {
+                    self.g[g] = Charset::from_final_1byte(at(1));
+                    2
+                };
+            }
+            0x24 => {
+                return match at(1) {
+                    i @ 0x28..=0x2B => {
+                        let g = (i - 0x28) as usize;
+                        if at(2) == 0x20 {
+                            // 2-byte DRCS
+                            self.g[g] = Charset::Drcs(2);
+                            4
+                        } else {
+                            self.g[g] = Charset::from_final_2byte(at(2));
+                            3
+                        }
+                    }
+                    f => {
+                        self.g[0] = Charset::from_final_2byte(f);
+                        2
+                    }
+                };
+            }
+            _ => {}
+        }
+        1
+    }
+
+    /// Start a new line in a caption, unless at the beginning of a line.
+    fn new_line(&mut self) {
+        self.flush();
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+    }
+
+    fn decode(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            let b = bytes[i];
+            i += 1;
+            match b {
+                0x21..=0x7E | 0xA1..=0xFE => {
+                    let set = if b < 0x80 {
+                        self.g[self.single_shift.take().unwrap_or(self.gl)]
+                    } else {
+                        self.g[self.gr]
+                    };
+                    let c2 = if set.width() == 2 {
+                        let c2 = bytes.get(i).copied().unwrap_or(0x21) & 0x7F;
+                        i += 1;
+                        c2
+                    } else {
+                        0
+                    };
+                    self.graphic(set, b & 0x7F, c2);
+                }
+                0x20 => self.push(if self.middle { ' ' } else { '\u{3000}' }),
+                // APD, APR
+                0x0A | 0x0D if self.caption => self.new_line(),
+                0x0A | 0x0D => self.push('\n'),
+                // CS
+                0x0C if self.caption => {
+                    self.euc.clear();
+                    self.out.clear();
+                }
+                // APS
+                0x1C if self.caption => {
+                    self.new_line();
+                    i += 2;
+                }
+                0x0E => self.gl = 1,
+                0x0F => self.gl = 0,
+                0x19 => self.single_shift = Some(2),
+                0x1D => self.single_shift = Some(3),
+                0x1B => i += self.escape(&bytes[i..]),
+                0x88 => (self.small, self.middle) = (true, true),
+                0x89 => (self.small, self.middle) = (false, true),
+                0x8A => (self.small, self.middle) = (false, false),
+                // Control functions with parameters
+                0x16 | 0x8B | 0x91 | 0x93 | 0x94 | 0x95 | 0x97 | 0x98 => i += 1,
+                0x1C | 0x9D => i += 2,
+                0x90 | 0x92 => i += if bytes.get(i) == Some(&0x20) { 2 } else { 1 },
+                0x9B => {
+                    // CSI: skip up to and including the final byte
+                    while i < bytes.len() && !(0x40..=0x6F).contains(&bytes[i]) {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                _ => {}
+            }
+        }
+        self.flush();
+    }
+}
+
+/// Decode a string in ARIB STD-B24 8-unit code into UTF-8.
+pub(crate) fn decode(bytes: &[u8]) -> String {
+    let mut decoder = Decoder::new();
+    decoder.decode(bytes);
+    decoder.out
+}
+
+/// Decode the text of a caption statement into UTF-8, one line for each
+/// row on the screen. Ruby is removed, and DRCS characters are replaced
+/// with GETA MARK. Returns the text and the number of DRCS characters.
+pub(crate) fn decode_caption(bytes: &[u8]) -> (String, usize) {
+    let mut decoder = Decoder::new();
+    decoder.caption = true;
+    decoder.decode(bytes);
+    let text = decoder.out.trim_end_matches('\n').to_string();
+    (text, decoder.drcs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // Kanji in G0 (GL), hiragana in G2 (GR)
+        assert_eq!(decode(&[0x46, 0x7C, 0x4B, 0x5C, 0xCE]), "日本の");
+        // Alphanumerics are full-width unless the middle size is selected
+        assert_eq!(decode(&[0x0E, 0x4E, 0x48, 0x4B]), "ＮＨＫ");
+        assert_eq!(
+            decode(&[0x89, 0x0E, 0x4E, 0x48, 0x4B, 0x20, 0x31, 0x8A, 0x0F, 0x20]),
+            "NHK 1\u{3000}"
+        );
+        // Katakana designated to G1 via ESC
+        assert_eq!(decode(&[0x1B, 0x29, 0x31, 0x0E, 0x46, 0x22]), "テア");
+        // Additional symbols and a line break
+        assert_eq!(decode(&[0x7A, 0x56, 0x0D, 0x7A, 0x6A]), "【字】\n【再】");
+    }
+
+    #[test]
+    fn test_decode_caption() {
+        // CS, APS to row 9, kanji with ruby in the small size, APS to row 10,
+        // and a 1-byte DRCS designated to G3 and invoked by SS3
+        let statement = [
+            0x0C, 0x1C, 0x49, 0x41, 0x46, 0x7C, 0x88, 0xCB, 0xDB, 0x8A, 0xCE, 0x1C, 0x4A, 0x41,
+            0x1B, 0x2B, 0x20, 0x41, 0x1D, 0x21,
+        ];
+        assert_eq!(decode_caption(&statement), ("日の\n〓".to_string(), 1));
+        // Text before CS is cleared
+        assert_eq!(decode_caption(&[0xCE, 0x0C, 0xCE]), ("の".to_string(), 0));
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::aribstr;
+use crate::ts::packet::Packet;
+use crate::ts::psi::{Pat, Pmt, Section, SectionBuffer, PAT_PID};
+
+/// The stream type of the PES private data, which carries the captions.
+const STREAM_TYPE_PRIVATE_PES: u8 = 0x06;
+/// Component tags of the caption streams in ARIB TR-B14.
+const CAPTION_COMPONENT_TAGS: std::ops::RangeInclusive<u8> = 0x30..=0x37;
+/// The PTS wraps around at 2^33.
+const PTS_MODULO: u64 = 1 << 33;
+/// The data unit of the statement body.
+const DATA_UNIT_STATEMENT_BODY: u8 = 0x20;
+/// How long the last caption is shown when the end of the stream comes first.
+const LAST_CUE_DURATION: f64 = 5.0;
+
+/// The format of the caption file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum CaptionFormat {
+    /// SubRip.
+    Srt,
+    /// Advanced SubStation Alpha.
+    Ass,
+    /// WebVTT.
+    Vtt,
+}
+
+impl CaptionFormat {
+    /// The format given by the extension of `path`, SubRip by default.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("ass") => CaptionFormat::Ass,
+            Some(e) if e.eq_ignore_ascii_case("vtt") => CaptionFormat::Vtt,
+            _ => CaptionFormat::Srt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cue {
+    /// Seconds since the first PCR of the service.
+    start: f64,
+    end: f64,
+    text: String,
+}
+
+/// Format `seconds` as hours, minutes, seconds and the fraction with
+/// `digits` digits after `separator`.
+fn timestamp(seconds: f64, separator: char, digits: u32) -> String {
+    let scale = 10u64.pow(digits);
+    let units = (seconds.max(0.0) * scale as f64).round() as u64;
+    let (secs, frac) = (units / scale, units % scale);
+    format!(
+        "{:02}:{:02}:{:02}{}{:0width$}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        separator,
+        frac,
+        width = digits as usize
+    )
+}
+
+fn to_srt(cues: &[Cue]) -> String {
+    let mut srt = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        let _ = write!(
+            srt,
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            timestamp(cue.start, ',', 3),
+            timestamp(cue.end, ',', 3),
+            cue.text
+        );
+    }
+    srt
+}
+
+fn to_vtt(cues: &[Cue]) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for cue in cues {
+        let text = cue
+            .text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        let _ = write!(
+            vtt,
+            "{} --> {}\n{}\n\n",
+            timestamp(cue.start, '.', 3),
+            timestamp(cue.end, '.', 3),
+            text
+        );
+    }
+    vtt
+}
+
+fn to_ass(cues: &[Cue]) -> String {
+    let mut ass = String::from(
+        "[Script Info]\n\
+         ScriptType: v4.00+\n\
+         PlayResX: 1920\n\
+         PlayResY: 1080\n\
+         \n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Default,sans-serif,72,&H00FFFFFF,&H000000FF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,3,0,2,60,60,60,128\n\
+         \n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+    );
+    for cue in cues {
+        // ASS takes hours in a single digit and centiseconds
+        let (start, end) = (timestamp(cue.start, '.', 2), timestamp(cue.end, '.', 2));
+        let text = cue.text.replace('{', "\\{").replace('\n', "\\N");
+        let _ = writeln!(
+            ass,
+            "Dialogue: 0,{},{},Default,,0,0,0,,{}",
+            start.strip_prefix('0').unwrap_or(&start),
+            end.strip_prefix('0').unwrap_or(&end),
+            text
+        );
+    }
+    ass
+}
+
+/// The PTS of a PES packet in 90 kHz ticks, and its payload.
+fn parse_pes(pes: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    if pes.len() < 9 || pes[..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+    let header_len = pes[8] as usize;
+    let payload = pes.get(9 + header_len..)?;
+    let pts = (pes[7] & 0x80 != 0 && header_len >= 5).then(|| {
+        (((pes[9] >> 1) & 0x07) as u64) << 30
+            | (pes[10] as u64) << 22
+            | ((pes[11] >> 1) as u64) << 15
+            | (pes[12] as u64) << 7
+            | (pes[13] >> 1) as u64
+    });
+    // Trim the stuffing after the PES packet
+    let len = u16::from_be_bytes([pes[4], pes[5]]) as usize;
+    let payload = match len {
+        0 => payload,
+        len => payload.get(..(len + 6).saturating_sub(9 + header_len))?,
+    };
+    Some((pts, payload))
+}
+
+/// The text in a caption statement of the first language, from the data
+/// group in the PES payload of the independent PES format of ARIB STD-B24.
+fn parse_statement(payload: &[u8]) -> Option<Vec<u8>> {
+    // data_identifier, private_stream_id, PES_data_packet_header_length
+    if payload.len() < 3 || !matches!(payload[0], 0x80 | 0x81) {
+        return None;
+    }
+    let group = payload.get(3 + (payload[2] & 0x0F) as usize..)?;
+    if group.len() < 5 {
+        return None;
+    }
+    // Data groups 0x01 and 0x21 hold the statements of the first language
+    if (group[0] >> 2) & 0x0F != 1 {
+        return None;
+    }
+    let size = u16::from_be_bytes([group[3], group[4]]) as usize;
+    let data = group.get(5..5 + size)?;
+
+    // Skip the STM if the time control mode is real time or offset time
+    let tmd = data.first()? >> 6;
+    let mut units = data.get(if tmd == 1 || tmd == 2 { 6 } else { 1 }..)?;
+    let loop_len = u32::from_be_bytes([0, *units.first()?, *units.get(1)?, *units.get(2)?]);
+    units = units.get(3..3 + loop_len as usize)?;
+
+    let mut text = Vec::new();
+    while units.len() >= 5 && units[0] == 0x1F {
+        let parameter = units[1];
+        let size = u32::from_be_bytes([0, units[2], units[3], units[4]]) as usize;
+        let unit = units.get(5..5 + size)?;
+        if parameter == DATA_UNIT_STATEMENT_BODY {
+            text.extend_from_slice(unit);
+        }
+        units = &units[5 + size..];
+    }
+    Some(text)
+}
+
+/// Extracts the captions of a service into a SubRip, ASS or WebVTT file,
+/// which is written when the stage is dropped at the end of the stream.
+///
+/// The caption stream is the first stream of the service with a component
+/// tag of captions in the PMT. Each caption statement of the first language
+/// is shown from its PTS until the next statement, relative to the first PCR
+/// of the service. DRCS characters are replaced with GETA MARK, since their
+/// glyphs are only sent as bitmaps.
+///
+/// The service is the first one in the PAT if `service_id` is not given.
+/// The stage passes every packet through unchanged, so it must be placed
+/// after the decoder to read the captions of scrambled services.
+pub(crate) struct CaptionExtractor {
+    service_id: Option<u16>,
+    format: CaptionFormat,
+    output: PathBuf,
+    pat_buffer: SectionBuffer,
+    pmt_pid: Option<u16>,
+    pmt_buffers: HashMap<u16, SectionBuffer>,
+    pcr_pid: Option<u16>,
+    caption_pid: Option<u16>,
+    /// The first PCR of the service in 90 kHz ticks, and the last one in
+    /// seconds since the first.
+    base: Option<u64>,
+    last: f64,
+    pes: Vec<u8>,
+    /// The caption on the screen, and when it appeared.
+    current: Option<(f64, String)>,
+    cues: Vec<Cue>,
+    drcs: usize,
+}
+
+impl CaptionExtractor {
+    pub fn new(service_id: Option<u16>, format: CaptionFormat, output: PathBuf) -> Self {
+        Self {
+            service_id,
+            format,
+            output,
+            pat_buffer: SectionBuffer::default(),
+            pmt_pid: None,
+            pmt_buffers: HashMap::new(),
+            pcr_pid: None,
+            caption_pid: None,
+            base: None,
+            last: 0.0,
+            pes: Vec::new(),
+            current: None,
+            cues: Vec::new(),
+            drcs: 0,
+        }
+    }
+
+    fn on_pat(&mut self, pat: &Pat) {
+        let found = pat
+            .services()
+            .find(|&(sid, _)| self.service_id.map_or(true, |id| id == sid));
+        match found {
+            Some((sid, pmt_pid)) if self.pmt_pid != Some(pmt_pid) => {
+                info!("Extracting the captions of service {}", sid);
+                self.service_id = Some(sid);
+                self.pmt_pid = Some(pmt_pid);
+            }
+            Some(_) => {}
+            None => debug!("The service of the captions is not in the PAT."),
+        }
+    }
+
+    fn on_pmt(&mut self, pmt: &Pmt) {
+        if Some(pmt.program_number) != self.service_id {
+            return;
+        }
+        let caption_pid = pmt
+            .streams
+            .iter()
+            .filter(|s| s.stream_type == STREAM_TYPE_PRIVATE_PES)
+            .find(|s| {
+                s.component_tag()
+                    .map_or(false, |tag| CAPTION_COMPONENT_TAGS.contains(&tag))
+            })
+            .map(|s| s.pid);
+        if caption_pid != self.caption_pid {
+            match caption_pid {
+                Some(pid) => info!("Caption stream: pid={:#06x}", pid),
+                None => warn!("Service {} has no caption stream.", pmt.program_number),
+            }
+            self.caption_pid = caption_pid;
+            self.pes.clear();
+        }
+        self.pcr_pid = Some(pmt.pcr_pid);
+    }
+
+    /// Seconds since the first PCR of the service.
+    fn time(&mut self, pts: u64) -> f64 {
+        let base = *self.base.get_or_insert(pts);
+        let ticks = (pts + PTS_MODULO - base) % PTS_MODULO;
+        // A PTS slightly before the first PCR
+        if ticks > PTS_MODULO / 2 {
+            return 0.0;
+        }
+        ticks as f64 / 90_000.0
+    }
+
+    fn on_pes(&mut self) {
+        let pes = std::mem::take(&mut self.pes);
+        let (pts, payload) = match parse_pes(&pes) {
+            Some((Some(pts), payload)) => (pts, payload),
+            _ => return,
+        };
+        let text = match parse_statement(payload) {
+            Some(text) => text,
+            None => return,
+        };
+        let time = self.time(pts);
+        let (text, drcs) = aribstr::decode_caption(&text);
+        self.drcs += drcs;
+        self.show(time, text);
+    }
+
+    /// Replace the caption on the screen with `text` at `time`.
+    fn show(&mut self, time: f64, text: String) {
+        if let Some((start, shown)) = self.current.take() {
+            self.cues.push(Cue {
+                start,
+                end: time,
+                text: shown,
+            });
+        }
+        if !text.is_empty() {
+            self.current = Some((time, text));
+        }
+    }
+
+    fn write(&self) -> std::io::Result<()> {
+        let document = match self.format {
+            CaptionFormat::Srt => to_srt(&self.cues),
+            CaptionFormat::Ass => to_ass(&self.cues),
+            CaptionFormat::Vtt => to_vtt(&self.cues),
+        };
+        fs::write(&self.output, document)
+    }
+}
+
+impl PacketStage for CaptionExtractor {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(packet);
+        let p = Packet::new(packet);
+        let pid = p.pid();
+
+        if Some(pid) == self.pcr_pid {
+            if let Some(pcr) = p.pcr() {
+                let pts = pcr / 300;
+                self.base.get_or_insert(pts);
+                self.last = self.time(pts);
+            }
+        }
+        if Some(pid) == self.caption_pid {
+            if p.payload_unit_start() && !self.pes.is_empty() {
+                self.on_pes();
+            }
+            if let Some(payload) = p.payload() {
+                if p.payload_unit_start() || !self.pes.is_empty() {
+                    self.pes.extend_from_slice(payload);
+                }
+            }
+        } else if pid == PAT_PID || Some(pid) == self.pmt_pid {
+            let mut sections = Vec::new();
+            let buffer = if pid == PAT_PID {
+                &mut self.pat_buffer
+            } else {
+                self.pmt_buffers.entry(pid).or_default()
+            };
+            buffer.push(p, |s| sections.push(s.to_vec()));
+            for bytes in sections {
+                let section = match Section::parse(&bytes).filter(|s| s.current_next()) {
+                    Some(section) => section,
+                    None => continue,
+                };
+                if let Some(pat) = Pat::parse(&section).filter(|_| pid == PAT_PID) {
+                    self.on_pat(&pat);
+                } else if let Some(pmt) = Pmt::parse(&section) {
+                    self.on_pmt(&pmt);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CaptionExtractor {
+    fn drop(&mut self) {
+        if !self.pes.is_empty() {
+            self.on_pes();
+        }
+        if let Some((start, _)) = self.current {
+            let end = if self.last > start {
+                self.last
+            } else {
+                start + LAST_CUE_DURATION
+            };
+            self.show(end, String::new());
+        }
+        if self.drcs > 0 {
+            warn!(
+                "{} DRCS characters in the captions were replaced with '\u{3013}'.",
+                self.drcs
+            );
+        }
+        match self.write() {
+            Ok(()) => info!(
+                "{} captions were written to {}",
+                self.cues.len(),
+                self.output.display()
+            ),
+            Err(e) => error!("Failed to write the captions: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::TS_PACKET_SIZE;
+    use crate::ts::psi::{build_section, packetize, TABLE_PMT};
+
+    /// A PES packet of a caption statement with `text` in the statement body.
+    fn caption_pes(pts: u64, text: &[u8]) -> Vec<u8> {
+        let mut unit = vec![0x1F, DATA_UNIT_STATEMENT_BODY];
+        unit.extend_from_slice(&(text.len() as u32).to_be_bytes()[1..]);
+        unit.extend_from_slice(text);
+        // TMD = free, then the data units
+        let mut data = vec![0x3F];
+        data.extend_from_slice(&(unit.len() as u32).to_be_bytes()[1..]);
+        data.extend_from_slice(&unit);
+        let mut group = vec![0x01 << 2, 0, 0];
+        group.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        group.extend_from_slice(&data);
+        // CRC_16 is not checked
+        group.extend_from_slice(&[0, 0]);
+
+        let mut pes = vec![0x00, 0x00, 0x01, 0xBD, 0, 0, 0x80, 0x80, 5];
+        pes.push(0x21 | ((pts >> 29) & 0x0E) as u8);
+        pes.extend_from_slice(&(((pts >> 14) & 0xFFFE) as u16 | 1).to_be_bytes());
+        pes.extend_from_slice(&(((pts << 1) & 0xFFFE) as u16 | 1).to_be_bytes());
+        pes.extend_from_slice(&[0x80, 0xFF, 0xF0]);
+        pes.extend_from_slice(&group);
+        let len = (pes.len() - 6) as u16;
+        pes[4..6].copy_from_slice(&len.to_be_bytes());
+        pes
+    }
+
+    fn pes_packet(pid: u16, pes: &[u8]) -> Vec<u8> {
+        // Stuffing in the adaptation field
+        let stuffing = TS_PACKET_SIZE - 4 - pes.len();
+        let mut p = vec![
+            0x47,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x30,
+            stuffing as u8 - 1,
+        ];
+        if stuffing > 1 {
+            p.push(0x00);
+            p.resize(4 + stuffing, 0xFF);
+        }
+        p.extend_from_slice(pes);
+        p
+    }
+
+    fn pcr_packet(pid: u16, pcr: u64) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..6].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x20, 183, 0x10]);
+        let base = pcr / 300;
+        p[6..10].copy_from_slice(&((base >> 1) as u32).to_be_bytes());
+        p[10] = ((base & 1) << 7) as u8 | 0x7E;
+        p[11] = 0;
+        p
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(3723.4567, ',', 3), "01:02:03,457");
+        assert_eq!(timestamp(59.999, '.', 2), "00:01:00.00");
+        assert_eq!(
+            CaptionFormat::from_path(Path::new("a.VTT")),
+            CaptionFormat::Vtt
+        );
+    }
+
+    #[test]
+    fn test_extract_captions() {
+        let mut stream = Vec::new();
+        let pat = Pat {
+            transport_stream_id: 1,
+            version: 0,
+            programs: vec![(0x400, 0x1F0)],
+        };
+        packetize(PAT_PID, &pat.to_section(), &mut 0, &mut stream);
+        // The PCR on 0x1FF, and the captions on 0x130 with component tag 0x30
+        let body = [
+            0xE1, 0xFF, 0xF0, 0x00, 0x06, 0xE1, 0x30, 0xF0, 0x03, 0x52, 0x01, 0x30,
+        ];
+        let pmt = build_section(TABLE_PMT, 0x400, 0, &body);
+        packetize(0x1F0, &pmt, &mut 0, &mut stream);
+
+        let base = 1_000 * 90_000;
+        stream.extend(pcr_packet(0x1FF, base * 300));
+        // "日の" with ruby, then "の<>" on a new row, then a clear screen
+        let first = [0x0C, 0x46, 0x7C, 0x88, 0xCB, 0x8A, 0xCE];
+        let second = [0x0C, 0xCE, 0x0D, 0x89, 0x0E, 0x3C, 0x3E];
+        stream.extend(pes_packet(0x130, &caption_pes(base + 90_000, &first)));
+        stream.extend(pes_packet(0x130, &caption_pes(base + 270_000, &second)));
+        stream.extend(pes_packet(0x130, &caption_pes(base + 360_000, &[0x0C])));
+        stream.extend(pcr_packet(0x1FF, (base + 900_000) * 300));
+
+        let mut extractor =
+            CaptionExtractor::new(None, CaptionFormat::Srt, PathBuf::from("unused"));
+        let mut out = Vec::new();
+        for packet in stream.chunks(TS_PACKET_SIZE) {
+            extractor.process(packet, &mut out);
+        }
+        assert_eq!(out, stream);
+        assert_eq!(extractor.caption_pid, Some(0x130));
+        extractor.on_pes();
+        assert_eq!(
+            extractor.cues,
+            vec![
+                Cue {
+                    start: 1.0,
+                    end: 3.0,
+                    text: "日の".to_string()
+                },
+                Cue {
+                    start: 3.0,
+                    end: 4.0,
+                    text: "の\n<>".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            to_srt(&extractor.cues),
+            "1\n00:00:01,000 --> 00:00:03,000\n日の\n\n2\n00:00:03,000 --> 00:00:04,000\nの\n<>\n\n"
+        );
+        assert!(
+            to_vtt(&extractor.cues).ends_with("00:00:03.000 --> 00:00:04.000\nの\n&lt;&gt;\n\n")
+        );
+        assert!(to_ass(&extractor.cues)
+            .ends_with("Dialogue: 0,0:00:03.00,0:00:04.00,Default,,0,0,0,,の\\N<>\n"));
+
+        // The file is written when the stage is dropped
+        extractor.output = std::env::temp_dir().join("recisdb-test-captions.srt");
+        drop(extractor);
+        let _ = fs::remove_file(std::env::temp_dir().join("recisdb-test-captions.srt"));
+    }
+}
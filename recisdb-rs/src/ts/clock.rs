@@ -0,0 +1,216 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset};
+use log::{debug, info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::packet::Packet;
+use crate::ts::psi::{crc32, Section, SectionBuffer, TOT_PID};
+use crate::ts::si::{self, parse_jst};
+
+const TABLE_TDT: u8 = 0x70;
+const TABLE_TOT: u8 = 0x73;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// The time in the TOT/TDT.
+    time: DateTime<FixedOffset>,
+    /// The system time when the table was received.
+    system: DateTime<FixedOffset>,
+    received: Instant,
+}
+
+/// The broadcast time read from the TOT/TDT, shared with the stages and the
+/// commands that need it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BroadcastClock(Arc<Mutex<Option<Sample>>>);
+
+impl BroadcastClock {
+    /// The current broadcast time, advanced from the latest TOT/TDT by the
+    /// monotonic clock. `None` until a TOT/TDT is received.
+    pub fn now(&self) -> Option<DateTime<FixedOffset>> {
+        let sample = (*self.0.lock().unwrap())?;
+        Some(sample.time + chrono::Duration::from_std(sample.received.elapsed()).ok()?)
+    }
+
+    /// How far the broadcast time is ahead of the system clock.
+    pub fn drift(&self) -> Option<chrono::Duration> {
+        let sample = (*self.0.lock().unwrap())?;
+        Some(sample.time - sample.system)
+    }
+
+    fn update(&self, time: DateTime<FixedOffset>, received: Instant) {
+        let system = chrono::Local::now().with_timezone(&si::jst());
+        *self.0.lock().unwrap() = Some(Sample {
+            time,
+            system,
+            received,
+        });
+    }
+}
+
+/// Reads the broadcast time from the TOT/TDT into a `BroadcastClock`, and
+/// reports the drift of the system clock from it.
+///
+/// If `stop_after` is given, the stage ends the stream once that much
+/// broadcast time has passed since the stage was created. Until the first
+/// TOT/TDT arrives, the monotonic clock is used instead.
+///
+/// The stage passes every packet through unchanged.
+pub(crate) struct ClockReader {
+    clock: BroadcastClock,
+    buffer: SectionBuffer,
+    created: Instant,
+    /// The first broadcast time received and when it was received.
+    first: Option<(DateTime<FixedOffset>, Instant)>,
+    stop_after: Option<Duration>,
+    /// The drift last reported, in seconds.
+    reported: Option<i64>,
+}
+
+impl ClockReader {
+    pub fn new(clock: BroadcastClock, stop_after: Option<Duration>) -> Self {
+        Self {
+            clock,
+            buffer: SectionBuffer::default(),
+            created: Instant::now(),
+            first: None,
+            stop_after,
+            reported: None,
+        }
+    }
+
+    fn on_section(&mut self, bytes: &[u8]) {
+        let section = match Section::parse(bytes) {
+            Some(section) => section,
+            None => return,
+        };
+        let time = match section.table_id() {
+            TABLE_TDT => section.body(),
+            // The TOT is a short section, but with the CRC
+            TABLE_TOT if crc32(bytes) == 0 => section.body(),
+            _ => return,
+        };
+        let time = match parse_jst(time) {
+            Some(time) => time,
+            None => return,
+        };
+        let received = Instant::now();
+        self.clock.update(time, received);
+        self.first.get_or_insert((time, received));
+        self.report();
+    }
+
+    /// Log the drift whenever it changes by a second or more.
+    fn report(&mut self) {
+        let drift = match self.clock.drift() {
+            Some(drift) => drift,
+            None => return,
+        };
+        let seconds = drift.num_milliseconds() as f64 / 1000.0;
+        debug!("Broadcast clock drift: {:+.3} seconds", seconds);
+        let rounded = seconds.round() as i64;
+        if self.reported.map_or(true, |r| (r - rounded).abs() >= 1) {
+            info!(
+                "Broadcast time: {} (the system clock is {:.0} seconds {})",
+                self.clock.now().unwrap().format("%Y/%m/%d %H:%M:%S"),
+                seconds.abs(),
+                if seconds > 0.0 { "behind" } else { "ahead" }
+            );
+            self.reported = Some(rounded);
+        }
+    }
+
+    /// The broadcast time passed since the stage was created.
+    fn elapsed(&self) -> Duration {
+        match (self.first, self.clock.now()) {
+            (Some((first, received)), Some(now)) => {
+                (now - first).to_std().unwrap_or_default() + (received - self.created)
+            }
+            _ => self.created.elapsed(),
+        }
+    }
+}
+
+impl PacketStage for ClockReader {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let p = Packet::new(packet);
+        if p.pid() == TOT_PID {
+            let mut sections = Vec::new();
+            self.buffer.push(p, |s| sections.push(s.to_vec()));
+            for section in sections {
+                self.on_section(&section);
+            }
+        }
+        out.extend_from_slice(packet);
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.stop_after, Some(duration) if self.elapsed() >= duration)
+    }
+}
+
+impl Drop for ClockReader {
+    fn drop(&mut self) {
+        match self.clock.drift() {
+            Some(drift) => info!(
+                "Broadcast clock drift: {:+.3} seconds",
+                drift.num_milliseconds() as f64 / 1000.0
+            ),
+            None if self.stop_after.is_some() => {
+                warn!("No TOT/TDT was received. The duration was measured on the host clock.")
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::psi::packetize;
+
+    #[test]
+    fn test_tot() {
+        // 2024-01-01 12:34:56 JST, with an empty descriptor loop
+        let mut tot = vec![0x73, 0x70, 0x0B, 0xEB, 0x96, 0x12, 0x34, 0x56, 0xF0, 0x00];
+        let crc = crc32(&tot);
+        tot.extend_from_slice(&crc.to_be_bytes());
+        let mut broken = tot.clone();
+        broken[7] = 0x57;
+        let tdt = vec![0x70, 0x70, 0x05, 0xEB, 0x96, 0x12, 0x35, 0x00];
+
+        let clock = BroadcastClock::default();
+        let mut reader = ClockReader::new(clock.clone(), None);
+        let mut cc = 0;
+        let mut run = |reader: &mut ClockReader, section: &[u8]| {
+            let mut packets = Vec::new();
+            packetize(TOT_PID, section, &mut cc, &mut packets);
+            let mut out = Vec::new();
+            reader.process(&packets, &mut out);
+            assert_eq!(out, packets);
+        };
+
+        run(&mut reader, &broken);
+        assert!(clock.now().is_none());
+        run(&mut reader, &tot);
+        let now = clock.now().unwrap();
+        assert_eq!(now.timestamp(), parse_jst(&tot[3..8]).unwrap().timestamp());
+        run(&mut reader, &tdt);
+        assert_eq!(
+            clock.now().unwrap().timestamp(),
+            parse_jst(&tdt[3..8]).unwrap().timestamp()
+        );
+        assert!(!reader.is_done());
+        let expected =
+            parse_jst(&tdt[3..8]).unwrap() - chrono::Local::now().with_timezone(&si::jst());
+        assert!((clock.drift().unwrap() - expected).num_seconds().abs() < 1);
+    }
+
+    #[test]
+    fn test_stop_after() {
+        let reader = ClockReader::new(BroadcastClock::default(), Some(Duration::ZERO));
+        assert!(reader.is_done());
+    }
+}
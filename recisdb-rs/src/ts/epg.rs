@@ -0,0 +1,468 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset};
+use log::info;
+
+use crate::io::PacketStage;
+use crate::ts::aribstr;
+use crate::ts::packet::Packet;
+use crate::ts::psi::{descriptors, Section, SectionBuffer, EIT_PIDS, SDT_PID};
+use crate::ts::si::{Eit, EitEvent, Sdt};
+use crate::utils::json_string;
+
+const SHORT_EVENT_DESCRIPTOR: u8 = 0x4D;
+const EXTENDED_EVENT_DESCRIPTOR: u8 = 0x4E;
+const CONTENT_DESCRIPTOR: u8 = 0x54;
+
+/// Genres of ARIB STD-B10 Annex H, by content_nibble_level_1.
+const GENRES: [&str; 16] = [
+    "ニュース／報道",
+    "スポーツ",
+    "情報／ワイドショー",
+    "ドラマ",
+    "音楽",
+    "バラエティ",
+    "映画",
+    "アニメ／特撮",
+    "ドキュメンタリー／教養",
+    "劇場／公演",
+    "趣味／教育",
+    "福祉",
+    "予備",
+    "予備",
+    "拡張",
+    "その他",
+];
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum EpgFormat {
+    Json,
+    Xmltv,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Event {
+    start: Option<DateTime<FixedOffset>>,
+    duration: Option<Duration>,
+    free_ca: bool,
+    name: Option<String>,
+    text: Option<String>,
+    /// Pairs of item description and item.
+    extended: Vec<(String, String)>,
+    /// content_nibble_level_1 and content_nibble_level_2 in a byte.
+    genres: Vec<u8>,
+}
+
+impl Event {
+    /// Merge the fields present in `event`. The EIT schedule splits the
+    /// description of an event between the basic and extended tables.
+    fn update(&mut self, event: &EitEvent) {
+        if event.start.is_some() {
+            self.start = event.start;
+        }
+        if event.duration.is_some() {
+            self.duration = event.duration;
+        }
+        self.free_ca = event.free_ca;
+
+        // Items are continued across descriptors with an empty description,
+        // and a character may even be split between them.
+        let mut items: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut genres = Vec::new();
+        for (tag, data) in descriptors(event.descriptors) {
+            match tag {
+                SHORT_EVENT_DESCRIPTOR if data.len() >= 4 => {
+                    let name_len = data[3] as usize;
+                    if let Some(name) = data.get(4..4 + name_len) {
+                        self.name = Some(aribstr::decode(name));
+                        let text = data
+                            .get(4 + name_len)
+                            .and_then(|&n| data.get(5 + name_len..5 + name_len + n as usize));
+                        self.text = text.map(aribstr::decode);
+                    }
+                }
+                EXTENDED_EVENT_DESCRIPTOR if data.len() >= 5 => {
+                    let items_len = data[4] as usize;
+                    let mut rest = data.get(5..5 + items_len).unwrap_or_default();
+                    while let Some(&desc_len) = rest.first() {
+                        let desc_len = desc_len as usize;
+                        let (desc, item) = match rest
+                            .get(1 + desc_len)
+                            .and_then(|&n| rest.get(2 + desc_len..2 + desc_len + n as usize))
+                        {
+                            Some(item) => (&rest[1..1 + desc_len], item),
+                            None => break,
+                        };
+                        match items.last_mut() {
+                            Some(last) if desc.is_empty() => last.1.extend_from_slice(item),
+                            _ => items.push((desc.to_vec(), item.to_vec())),
+                        }
+                        rest = &rest[2 + desc_len + item.len()..];
+                    }
+                }
+                CONTENT_DESCRIPTOR => {
+                    genres.extend(data.chunks_exact(2).map(|nibbles| nibbles[0]));
+                }
+                _ => {}
+            }
+        }
+        if !items.is_empty() {
+            self.extended = items
+                .iter()
+                .map(|(desc, item)| (aribstr::decode(desc), aribstr::decode(item)))
+                .collect();
+        }
+        if !genres.is_empty() {
+            self.genres = genres;
+        }
+    }
+
+    /// The text and the extended items, as a single description.
+    fn description(&self) -> String {
+        let mut description = self.text.clone().unwrap_or_default();
+        for (desc, item) in &self.extended {
+            if !description.is_empty() {
+                description.push('\n');
+            }
+            let _ = write!(description, "{}\n{}", desc, item);
+        }
+        description
+    }
+
+    fn to_json(&self, event_id: u16) -> String {
+        let opt = |s: &Option<String>| s.as_deref().map_or("null".to_string(), json_string);
+        let extended = self
+            .extended
+            .iter()
+            .map(|(desc, item)| {
+                format!(
+                    r#"{{"description":{},"item":{}}}"#,
+                    json_string(desc),
+                    json_string(item)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let genres = self
+            .genres
+            .iter()
+            .map(|&nibbles| {
+                format!(
+                    r#"{{"level1":{},"level2":{},"name":{}}}"#,
+                    nibbles >> 4,
+                    nibbles & 0x0F,
+                    json_string(GENRES[(nibbles >> 4) as usize])
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"event_id":{},"start":{},"duration":{},"free_ca":{},"name":{},"text":{},"extended":[{}],"genres":[{}]}}"#,
+            event_id,
+            self.start
+                .map_or("null".to_string(), |t| json_string(&t.to_rfc3339())),
+            self.duration
+                .map_or("null".to_string(), |d| d.as_secs().to_string()),
+            self.free_ca,
+            opt(&self.name),
+            opt(&self.text),
+            extended,
+            genres
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Service {
+    name: Option<String>,
+    events: BTreeMap<u16, Event>,
+}
+
+/// Collects the events in the EIT and the service names in the SDT,
+/// and writes them out in JSON or XMLTV on `finish` at the end of the
+/// stream.
+///
+/// The stage passes every packet through unchanged.
+pub(crate) struct EpgCollector {
+    buffers: HashMap<u16, SectionBuffer>,
+    /// Sections already handled, to skip the repetitions.
+    seen: HashSet<(u8, u16, [u8; 4], u8, u8)>,
+    /// Services by original_network_id, transport_stream_id and service_id.
+    services: BTreeMap<(u16, u16, u16), Service>,
+    format: EpgFormat,
+    /// `None` for stdout.
+    output: Option<PathBuf>,
+}
+
+impl EpgCollector {
+    pub fn new(format: EpgFormat, output: Option<PathBuf>) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            seen: HashSet::new(),
+            services: BTreeMap::new(),
+            format,
+            output,
+        }
+    }
+
+    fn handle_section(&mut self, bytes: &[u8]) {
+        let section = match Section::parse(bytes) {
+            Some(section) if section.is_long() && section.current_next() => section,
+            _ => return,
+        };
+        // The table_id_extension and the first bytes of the body identify
+        // the service and the stream in both the EIT and the SDT.
+        let mut ids = [0; 4];
+        let body = section.body();
+        let n = body.len().min(4);
+        ids[..n].copy_from_slice(&body[..n]);
+        let key = (
+            section.table_id(),
+            section.table_id_extension(),
+            ids,
+            section.section_number(),
+            section.version(),
+        );
+        if !self.seen.insert(key) {
+            return;
+        }
+
+        if let Some(eit) = Eit::parse(&section) {
+            let service = self
+                .services
+                .entry((
+                    eit.original_network_id,
+                    eit.transport_stream_id,
+                    eit.service_id,
+                ))
+                .or_default();
+            for event in &eit.events {
+                service
+                    .events
+                    .entry(event.event_id)
+                    .or_default()
+                    .update(event);
+            }
+        } else if let Some(sdt) = Sdt::parse(&section) {
+            for s in sdt.services {
+                let key = (
+                    sdt.original_network_id,
+                    sdt.transport_stream_id,
+                    s.service_id,
+                );
+                self.services.entry(key).or_default().name = Some(s.name);
+            }
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let services = self
+            .services
+            .iter()
+            .map(|(&(onid, tsid, sid), service)| {
+                let events = service
+                    .events
+                    .iter()
+                    .map(|(&id, event)| event.to_json(id))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(
+                    r#"{{"original_network_id":{},"transport_stream_id":{},"service_id":{},"name":{},"events":[{}]}}"#,
+                    onid,
+                    tsid,
+                    sid,
+                    service
+                        .name
+                        .as_deref()
+                        .map_or("null".to_string(), json_string),
+                    events
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(r#"{{"services":[{}]}}"#, services)
+    }
+
+    fn to_xmltv(&self) -> String {
+        const TIME_FORMAT: &str = "%Y%m%d%H%M%S %z";
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<!DOCTYPE tv SYSTEM \"xmltv.dtd\">\n",
+            "<tv generator-info-name=\"recisdb\">\n"
+        ));
+        for (&(onid, _, sid), service) in &self.services {
+            let _ = writeln!(xml, "  <channel id=\"{}.{}\">", onid, sid);
+            let name = service.name.clone().unwrap_or_else(|| sid.to_string());
+            let _ = writeln!(
+                xml,
+                "    <display-name>{}</display-name>",
+                xml_escape(&name)
+            );
+            xml.push_str("  </channel>\n");
+        }
+        for (&(onid, _, sid), service) in &self.services {
+            for event in service.events.values() {
+                let start = match event.start {
+                    Some(start) => start,
+                    None => continue,
+                };
+                let _ = write!(xml, "  <programme start=\"{}\"", start.format(TIME_FORMAT));
+                if let Some(duration) = event.duration {
+                    let stop = start + chrono::Duration::seconds(duration.as_secs() as i64);
+                    let _ = write!(xml, " stop=\"{}\"", stop.format(TIME_FORMAT));
+                }
+                let _ = writeln!(xml, " channel=\"{}.{}\">", onid, sid);
+                let _ = writeln!(
+                    xml,
+                    "    <title lang=\"ja\">{}</title>",
+                    xml_escape(event.name.as_deref().unwrap_or_default())
+                );
+                let description = event.description();
+                if !description.is_empty() {
+                    let _ = writeln!(
+                        xml,
+                        "    <desc lang=\"ja\">{}</desc>",
+                        xml_escape(&description)
+                    );
+                }
+                for &nibbles in &event.genres {
+                    let _ = writeln!(
+                        xml,
+                        "    <category lang=\"ja\">{}</category>",
+                        GENRES[(nibbles >> 4) as usize]
+                    );
+                }
+                xml.push_str("  </programme>\n");
+            }
+        }
+        xml.push_str("</tv>\n");
+        xml
+    }
+
+    /// Writes out the events collected so far.
+    pub fn finish(&self) -> io::Result<()> {
+        let events: usize = self.services.values().map(|s| s.events.len()).sum();
+        info!("EPG: {} events in {} services", events, self.services.len());
+        let document = match self.format {
+            EpgFormat::Json => self.to_json() + "\n",
+            EpgFormat::Xmltv => self.to_xmltv(),
+        };
+        match &self.output {
+            Some(path) => fs::write(path, document),
+            None => io::stdout().lock().write_all(document.as_bytes()),
+        }
+    }
+}
+
+impl PacketStage for EpgCollector {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let packet = Packet::new(packet);
+        let pid = packet.pid();
+        if pid == SDT_PID || EIT_PIDS.contains(&pid) {
+            let mut sections = Vec::new();
+            self.buffers
+                .entry(pid)
+                .or_default()
+                .push(packet, |s| sections.push(s.to_vec()));
+            for section in sections {
+                self.handle_section(&section);
+            }
+        }
+        out.extend_from_slice(packet.as_bytes());
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::psi::{build_section, packetize};
+    use crate::ts::si::TABLE_EIT_SCHEDULE;
+
+    fn eit_section(table_id: u8, event_descriptors: &[u8]) -> Vec<u8> {
+        // transport_stream_id, original_network_id, segment_last_section_number, last_table_id
+        let mut body = vec![0x7F, 0xE0, 0x7F, 0xE0, 0x00, table_id];
+        // event_id 0x1234, 2024-01-01 21:00:00, 00:30:00
+        body.extend_from_slice(&[0x12, 0x34, 0xEB, 0x96, 0x21, 0x00, 0x00, 0x00, 0x30, 0x00]);
+        body.push(0x80 | (event_descriptors.len() >> 8) as u8);
+        body.push(event_descriptors.len() as u8);
+        body.extend_from_slice(event_descriptors);
+        build_section(table_id, 0x0400, 1, &body)
+    }
+
+    fn collect(sections: &[Vec<u8>]) -> EpgCollector {
+        let mut collector = EpgCollector::new(EpgFormat::Json, None);
+        let mut packets = Vec::new();
+        let mut cc = 0;
+        for section in sections {
+            packetize(0x12, section, &mut cc, &mut packets);
+        }
+        let mut out = Vec::new();
+        for packet in packets.chunks(188) {
+            collector.process(packet, &mut out);
+        }
+        assert_eq!(out, packets);
+        collector
+    }
+
+    #[test]
+    fn test_merge_schedule() {
+        // Short event "ニュース" / "<速報>" and a content descriptor of news
+        let short = [
+            0x4D, 0x16, b'j', b'p', b'n', 0x08, 0x25, 0x4B, 0x25, 0x65, 0x21, 0x3C, 0x25, 0x39,
+            0x09, 0x0E, 0x3C, 0x0F, 0x42, 0x2E, 0x4A, 0x73, 0x0E, 0x3E, 0x54, 0x02, 0x00, 0xFF,
+        ];
+        // Extended event with an item continued in a second descriptor
+        let extended = [
+            0x4E, 0x0E, 0x01, b'j', b'p', b'n', 0x08, 0x04, 0x3D, 0x50, 0x31, 0x69, 0x02, 0x3C,
+            0x54, 0x00, 0x4E, 0x0A, 0x11, b'j', b'p', b'n', 0x04, 0x00, 0x02, 0x3C, 0x54, 0x00,
+        ];
+        let basic = eit_section(*TABLE_EIT_SCHEDULE.start(), &short);
+        let collector = collect(&[basic.clone(), eit_section(0x58, &extended), basic]);
+        assert_eq!(collector.seen.len(), 2);
+
+        let service = &collector.services[&(0x7FE0, 0x7FE0, 0x0400)];
+        let event = &service.events[&0x1234];
+        assert_eq!(event.name.as_deref(), Some("ニュース"));
+        assert_eq!(event.text.as_deref(), Some("＜速報＞"));
+        assert_eq!(
+            event.extended,
+            vec![("出演".to_string(), "者者".to_string())]
+        );
+        assert_eq!(event.genres, vec![0x00]);
+        assert_eq!(event.duration, Some(Duration::from_secs(1800)));
+
+        let xml = collector.to_xmltv();
+        assert!(xml.contains(
+            r#"<programme start="20240101210000 +0900" stop="20240101213000 +0900" channel="32736.1024">"#
+        ));
+        assert!(xml.contains("<category lang=\"ja\">ニュース／報道</category>"));
+        assert!(collector
+            .to_json()
+            .contains(r#""event_id":4660,"start":"2024-01-01T21:00:00+09:00","duration":1800,"#));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(xml_escape("<A&B>\""), "&lt;A&amp;B&gt;&quot;");
+        assert_eq!(json_string("a\"b\\\n\u{1}"), r#""a\"b\\\n\u0001""#);
+    }
+}
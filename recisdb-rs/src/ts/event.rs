@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset};
+use log::{info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::packet::Packet;
+use crate::ts::psi::{Section, SectionBuffer, EIT_PIDS};
+use crate::ts::si::{self, Eit, TABLE_EIT_PF_ACTUAL, TABLE_EIT_SCHEDULE_ACTUAL};
+use crate::ts::BroadcastClock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Waiting,
+    Recording,
+    Done,
+}
+
+/// Passes the stream through only while an event is on air, as signalled in
+/// the EIT of the service, and ends the stream when the event is over.
+///
+/// The recording starts when the EIT present/following lists the event as
+/// present. The start time in the EIT is only used without the
+/// present/following, or to start `margin_before` ahead of it, so that an
+/// overrun of the previous program is not recorded in its place. It stops
+/// `margin_after` past the end of the event, which is when the event is no
+/// longer present. The scheduled end is only trusted while the event has not
+/// been seen as present, so that delays and extensions are followed.
+///
+/// The times are compared with the broadcast time if `clock` is given and
+/// has been set, or the system clock otherwise.
+pub(crate) struct EventGate {
+    service_id: u16,
+    event_id: u16,
+    margin_before: Duration,
+    margin_after: Duration,
+    eit_buffer: SectionBuffer,
+    start: Option<DateTime<FixedOffset>>,
+    duration: Option<Duration>,
+    /// Whether the schedule is taken from the EIT present/following, which
+    /// is preferred to the EIT schedule.
+    from_pf: bool,
+    /// Whether the present event of the service has been received.
+    pf_seen: bool,
+    /// Whether the event is the present one.
+    present: bool,
+    /// Whether the event has ever been the present one.
+    was_present: bool,
+    stop_at: Option<DateTime<FixedOffset>>,
+    state: State,
+    clock: Option<BroadcastClock>,
+}
+
+impl EventGate {
+    pub fn new(
+        service_id: u16,
+        event_id: u16,
+        margin_before: Duration,
+        margin_after: Duration,
+        clock: Option<BroadcastClock>,
+    ) -> Self {
+        Self {
+            service_id,
+            event_id,
+            margin_before,
+            margin_after,
+            eit_buffer: SectionBuffer::default(),
+            start: None,
+            duration: None,
+            from_pf: false,
+            pf_seen: false,
+            present: false,
+            was_present: false,
+            stop_at: None,
+            state: State::Waiting,
+            clock,
+        }
+    }
+
+    fn on_section(&mut self, section: &Section) {
+        let table_id = section.table_id();
+        let is_pf = table_id == TABLE_EIT_PF_ACTUAL;
+        if !is_pf && (self.from_pf || !TABLE_EIT_SCHEDULE_ACTUAL.contains(&table_id)) {
+            return;
+        }
+        let eit = match Eit::parse(section) {
+            Some(eit) if eit.service_id == self.service_id => eit,
+            _ => return,
+        };
+        let event = eit.events.iter().find(|e| e.event_id == self.event_id);
+
+        // Section 0 of the EIT present/following holds the present event
+        if is_pf && section.section_number() == 0 {
+            self.pf_seen = true;
+            self.present = event.is_some();
+            self.was_present |= self.present;
+        }
+        if let Some(event) = event {
+            if (event.start, event.duration) != (self.start, self.duration) {
+                info!(
+                    "Event {:#06x}: {} for {}",
+                    self.event_id,
+                    event
+                        .start
+                        .map_or("undetermined".to_string(), |t| t.to_rfc3339()),
+                    event
+                        .duration
+                        .map_or("undetermined".to_string(), |d| format!(
+                            "{} seconds",
+                            d.as_secs()
+                        ))
+                );
+            }
+            self.start = event.start;
+            self.duration = event.duration;
+            self.from_pf |= is_pf;
+        }
+    }
+
+    fn update(&mut self, now: DateTime<FixedOffset>) {
+        let scheduled_end = self
+            .start
+            .zip(self.duration)
+            .map(|(start, duration)| start + chrono::Duration::from_std(duration).unwrap());
+        match self.state {
+            State::Waiting => {
+                let scheduled = !self.pf_seen || !self.margin_before.is_zero();
+                let starting = scheduled
+                    && self.start.map_or(false, |start| {
+                        now + chrono::Duration::from_std(self.margin_before).unwrap() >= start
+                    });
+                if self.present || starting {
+                    info!("Event {:#06x} started. Recording...", self.event_id);
+                    self.state = State::Recording;
+                }
+            }
+            State::Recording if self.stop_at.is_none() => {
+                let ended = !self.present
+                    && (self.was_present || scheduled_end.map_or(false, |end| now >= end));
+                if ended {
+                    info!("Event {:#06x} ended.", self.event_id);
+                    self.stop_at =
+                        Some(now + chrono::Duration::from_std(self.margin_after).unwrap());
+                }
+            }
+            State::Recording => {}
+            State::Done => return,
+        }
+        if matches!(self.stop_at, Some(stop_at) if now >= stop_at) {
+            self.state = State::Done;
+        }
+    }
+
+    fn process_at(&mut self, packet: &[u8], out: &mut Vec<u8>, now: DateTime<FixedOffset>) {
+        let p = Packet::new(packet);
+        if p.pid() == EIT_PIDS[0] {
+            let mut sections = Vec::new();
+            self.eit_buffer.push(p, |s| sections.push(s.to_vec()));
+            for section in sections {
+                if let Some(section) = Section::parse(&section).filter(|s| s.current_next()) {
+                    self.on_section(&section);
+                }
+            }
+        }
+        self.update(now);
+        if self.state == State::Recording {
+            out.extend_from_slice(packet);
+        }
+    }
+}
+
+impl PacketStage for EventGate {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let now = self
+            .clock
+            .as_ref()
+            .and_then(BroadcastClock::now)
+            .unwrap_or_else(|| chrono::Local::now().with_timezone(&si::jst()));
+        self.process_at(packet, out, now);
+    }
+
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+}
+
+impl Drop for EventGate {
+    fn drop(&mut self) {
+        if self.state == State::Waiting {
+            warn!(
+                "Event {:#06x} of service {} did not start.",
+                self.event_id, self.service_id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::psi::{build_section, crc32, packetize};
+    use crate::ts::si::parse_jst;
+
+    /// An EIT p/f section listing `event_id` starting at 21:00 for 30 minutes.
+    fn pf(section_number: u8, event_id: Option<u16>) -> Vec<u8> {
+        let mut body = vec![0x7F, 0xE0, 0x7F, 0xE0, 0x01, TABLE_EIT_PF_ACTUAL];
+        if let Some(id) = event_id {
+            let [hi, lo] = id.to_be_bytes();
+            body.extend_from_slice(&[hi, lo, 0xEB, 0x96, 0x21, 0x00, 0x00, 0x00, 0x30, 0x00]);
+            body.extend_from_slice(&[0x80, 0x00]);
+        }
+        let mut section = build_section(TABLE_EIT_PF_ACTUAL, 0x0400, 0, &body);
+        section[6] = section_number;
+        let crc = crc32(&section[..section.len() - 4]);
+        let len = section.len();
+        section[len - 4..].copy_from_slice(&crc.to_be_bytes());
+        section
+    }
+
+    fn es() -> Vec<u8> {
+        let mut p = vec![0xFFu8; 188];
+        p[..4].copy_from_slice(&[0x47, 0x01, 0x11, 0x10]);
+        p
+    }
+
+    #[test]
+    fn test_follow_event() {
+        let at =
+            |hhmmss: [u8; 3]| parse_jst(&[0xEB, 0x96, hhmmss[0], hhmmss[1], hhmmss[2]]).unwrap();
+        let mut gate = EventGate::new(
+            0x0400,
+            0x1234,
+            Duration::from_secs(0),
+            Duration::from_secs(10),
+            None,
+        );
+        let mut cc = 0;
+        let mut feed = |gate: &mut EventGate, sections: &[Vec<u8>], now| {
+            let mut packets = Vec::new();
+            for section in sections {
+                packetize(EIT_PIDS[0], section, &mut cc, &mut packets);
+            }
+            packets.extend(es());
+            let mut out = Vec::new();
+            for packet in packets.chunks(188) {
+                gate.process_at(packet, &mut out, now);
+            }
+            out.len() / 188
+        };
+
+        // The event follows, and the previous program overruns
+        assert_eq!(
+            feed(
+                &mut gate,
+                &[pf(0, Some(0x1233)), pf(1, Some(0x1234))],
+                at([0x20, 0x59, 0x00])
+            ),
+            0
+        );
+        assert_eq!(gate.start, Some(at([0x21, 0x00, 0x00])));
+        assert_eq!(
+            feed(&mut gate, &[pf(0, Some(0x1233))], at([0x20, 0x59, 0x59])),
+            0
+        );
+        // Not started by the schedule while the previous program is present
+        assert_eq!(feed(&mut gate, &[], at([0x21, 0x00, 0x00])), 0);
+        assert_eq!(gate.state, State::Waiting);
+        assert_eq!(
+            feed(&mut gate, &[pf(0, Some(0x1234))], at([0x21, 0x05, 0x00])),
+            2
+        );
+        assert_eq!(gate.state, State::Recording);
+        // Past the scheduled end, but still present
+        assert_eq!(feed(&mut gate, &[], at([0x21, 0x31, 0x00])), 1);
+        assert!(gate.stop_at.is_none());
+        // Ended, kept for the margin
+        assert_eq!(
+            feed(&mut gate, &[pf(0, Some(0x1235))], at([0x21, 0x32, 0x00])),
+            2
+        );
+        assert_eq!(feed(&mut gate, &[], at([0x21, 0x32, 0x09])), 1);
+        assert!(!gate.is_done());
+        assert_eq!(feed(&mut gate, &[], at([0x21, 0x32, 0x10])), 0);
+        assert!(gate.is_done());
+    }
+
+    #[test]
+    fn test_margin_before() {
+        let at =
+            |hhmmss: [u8; 3]| parse_jst(&[0xEB, 0x96, hhmmss[0], hhmmss[1], hhmmss[2]]).unwrap();
+        let mut gate = EventGate::new(
+            0x0400,
+            0x1234,
+            Duration::from_secs(60),
+            Duration::from_secs(0),
+            None,
+        );
+        let mut cc = 0;
+        let mut packets = Vec::new();
+        for section in [pf(0, Some(0x1233)), pf(1, Some(0x1234))] {
+            packetize(EIT_PIDS[0], &section, &mut cc, &mut packets);
+        }
+        let mut out = Vec::new();
+        for packet in packets.chunks(188) {
+            gate.process_at(packet, &mut out, at([0x20, 0x58, 0x59]));
+        }
+        assert_eq!(gate.state, State::Waiting);
+
+        // Started ahead of the schedule as requested, even if the previous
+        // program is still present
+        gate.process_at(&es(), &mut out, at([0x20, 0x59, 0x00]));
+        assert_eq!(gate.state, State::Recording);
+        assert_eq!(out.len(), 188);
+    }
+}
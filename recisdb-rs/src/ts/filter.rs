@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use clap_num::maybe_hex;
+use log::info;
+
+use crate::io::PacketStage;
+use crate::ts::packet::{Packet, NULL_PID};
+
+/// A comma-separated list of PIDs in decimal or hexadecimal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PidList(Vec<u16>);
+
+impl FromStr for PidList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pids = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            match maybe_hex::<u16>(item) {
+                Ok(pid) if pid <= NULL_PID => pids.push(pid),
+                _ => return Err(format!("invalid PID '{}'", item)),
+            }
+        }
+        if pids.is_empty() {
+            return Err("no PID is specified".to_string());
+        }
+        Ok(PidList(pids))
+    }
+}
+
+impl fmt::Display for PidList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pids: Vec<String> = self.0.iter().map(|pid| format!("{:#06x}", pid)).collect();
+        write!(f, "{}", pids.join(","))
+    }
+}
+
+/// Drops packets from the output: null packets, packets of PIDs not in the
+/// allow list or in the deny list, and packets still scrambled after
+/// decoding. The number of packets dropped is logged when the stage is
+/// dropped at the end of the stream.
+pub(crate) struct PacketFilter {
+    drop_null: bool,
+    drop_scrambled: bool,
+    keep: Box<[bool; 0x2000]>,
+    null_dropped: u64,
+    pid_dropped: u64,
+    /// Packets dropped for being scrambled, per PID.
+    scrambled_dropped: BTreeMap<u16, u64>,
+}
+
+impl PacketFilter {
+    /// Returns `None` if there is nothing to drop.
+    pub fn new(
+        drop_null: bool,
+        allow: Option<PidList>,
+        deny: Option<PidList>,
+        drop_scrambled: bool,
+    ) -> Option<Self> {
+        if !drop_null && allow.is_none() && deny.is_none() && !drop_scrambled {
+            return None;
+        }
+        let mut keep = Box::new([allow.is_none(); 0x2000]);
+        if let Some(allow) = allow {
+            info!("PIDs to keep: {}", allow);
+            for &pid in &allow.0 {
+                keep[pid as usize] = true;
+            }
+        }
+        if let Some(deny) = deny {
+            info!("PIDs to drop: {}", deny);
+            for &pid in &deny.0 {
+                keep[pid as usize] = false;
+            }
+        }
+        Some(Self {
+            drop_null,
+            drop_scrambled,
+            keep,
+            null_dropped: 0,
+            pid_dropped: 0,
+            scrambled_dropped: BTreeMap::new(),
+        })
+    }
+}
+
+impl PacketStage for PacketFilter {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let p = Packet::new(packet);
+        let pid = p.pid();
+        if pid == NULL_PID && self.drop_null {
+            self.null_dropped += 1;
+        } else if !self.keep[pid as usize] {
+            self.pid_dropped += 1;
+        } else if self.drop_scrambled && p.is_scrambled() {
+            *self.scrambled_dropped.entry(pid).or_default() += 1;
+        } else {
+            out.extend_from_slice(packet);
+        }
+    }
+}
+
+impl Drop for PacketFilter {
+    fn drop(&mut self) {
+        for (pid, count) in &self.scrambled_dropped {
+            info!("pid={:#06x}, scrambled packets dropped={:>10}", pid, count);
+        }
+        info!(
+            "Dropped: {} null packets, {} packets by PID, {} scrambled packets",
+            self.null_dropped,
+            self.pid_dropped,
+            self.scrambled_dropped.values().sum::<u64>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::TS_PACKET_SIZE;
+
+    fn packet(pid: u16, scrambled: bool) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[0] = 0x47;
+        p[1] = (pid >> 8) as u8;
+        p[2] = pid as u8;
+        p[3] = 0x10 | if scrambled { 0x80 } else { 0 };
+        p
+    }
+
+    fn run(filter: &mut PacketFilter, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for p in packets {
+            filter.process(p, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_pid_list() {
+        let list: PidList = "0x100, 273,0x1FFF".parse().unwrap();
+        assert_eq!(list, PidList(vec![0x100, 0x111, 0x1FFF]));
+        assert_eq!(list.to_string(), "0x0100,0x0111,0x1fff");
+        assert!("0x2000".parse::<PidList>().is_err());
+        assert!("foo".parse::<PidList>().is_err());
+        assert!(",".parse::<PidList>().is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        assert!(PacketFilter::new(false, None, None, false).is_none());
+
+        let packets = [
+            packet(0x100, false),
+            packet(NULL_PID, false),
+            packet(0x111, true),
+            packet(0x111, false),
+            packet(0x200, false),
+        ];
+        let mut filter = PacketFilter::new(true, None, "0x200".parse().ok(), true).unwrap();
+        assert_eq!(
+            run(&mut filter, &packets),
+            [&packets[0][..], &packets[3]].concat()
+        );
+        assert_eq!(
+            (
+                filter.null_dropped,
+                filter.pid_dropped,
+                filter.scrambled_dropped[&0x111]
+            ),
+            (1, 1, 1)
+        );
+
+        let mut filter =
+            PacketFilter::new(false, "0x111,0x1FFF".parse().ok(), None, false).unwrap();
+        assert_eq!(run(&mut filter, &packets), packets[1..4].concat());
+        assert_eq!(filter.pid_dropped, 2);
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use log::warn;
+
+use crate::io::PacketStage;
+use crate::ts::packet::{Packet, NULL_PID};
+use crate::ts::psi::{
+    ca_descriptors, Pat, Pmt, Section, SectionBuffer, CAT_PID, NIT_PID, PAT_PID, SDT_PID, TABLE_CAT,
+};
+use crate::ts::si::{Nit, Sdt, TABLE_SDT_ACTUAL};
+use crate::utils::json_string;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum InspectFormat {
+    Tree,
+    Json,
+}
+
+/// A readable name of a stream type in the PMT.
+fn stream_type_name(stream_type: u8) -> &'static str {
+    match stream_type {
+        0x01 => "MPEG-1 Video",
+        0x02 => "MPEG-2 Video",
+        0x03 => "MPEG-1 Audio",
+        0x04 => "MPEG-2 Audio",
+        0x05 => "Private sections",
+        0x06 => "PES private data",
+        0x0B => "DSM-CC U-N messages",
+        0x0C => "DSM-CC stream descriptors",
+        0x0D => "DSM-CC sections",
+        0x0F => "AAC",
+        0x11 => "LATM AAC",
+        0x1B => "H.264",
+        0x24 => "H.265",
+        _ => "Unknown",
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PidCount {
+    packets: u64,
+    scrambled: u64,
+}
+
+#[derive(Debug)]
+struct SdtEntry {
+    name: String,
+    service_type: Option<u8>,
+}
+
+/// Collects the PAT, the PMTs, the CAT, the NIT and the SDT of the actual
+/// stream, and counts the packets and the scrambled packets of each PID.
+/// The summary is written out as a text tree or as JSON on `finish` at the
+/// end of the stream.
+///
+/// The stage passes every packet through unchanged.
+pub(crate) struct Inspector {
+    format: InspectFormat,
+    /// `None` for stdout.
+    output: Option<PathBuf>,
+    buffers: BTreeMap<u16, SectionBuffer>,
+    pat: Option<Pat>,
+    /// Latest PMT of each service.
+    pmts: BTreeMap<u16, Pmt>,
+    /// Pairs of CA system ID and EMM PID.
+    emm: Vec<(u16, u16)>,
+    nit: Option<Nit>,
+    original_network_id: Option<u16>,
+    sdt: BTreeMap<u16, SdtEntry>,
+    pids: BTreeMap<u16, PidCount>,
+}
+
+impl Inspector {
+    pub fn new(format: InspectFormat, output: Option<PathBuf>) -> Self {
+        Self {
+            format,
+            output,
+            buffers: BTreeMap::new(),
+            pat: None,
+            pmts: BTreeMap::new(),
+            emm: Vec::new(),
+            nit: None,
+            original_network_id: None,
+            sdt: BTreeMap::new(),
+            pids: BTreeMap::new(),
+        }
+    }
+
+    fn is_psi(&self, pid: u16) -> bool {
+        matches!(pid, PAT_PID | CAT_PID | NIT_PID | SDT_PID)
+            || self.pat.as_ref().map_or(false, |pat| {
+                pat.services().any(|(_, pmt_pid)| pmt_pid == pid)
+            })
+    }
+
+    fn on_section(&mut self, pid: u16, section: &Section) {
+        match pid {
+            PAT_PID => self.pat = Pat::parse(section).or_else(|| self.pat.take()),
+            CAT_PID if section.table_id() == TABLE_CAT => {
+                self.emm = ca_descriptors(section.body()).collect();
+            }
+            NIT_PID => {
+                if let Some(nit) = Nit::parse(section) {
+                    self.nit = Some(nit);
+                }
+            }
+            SDT_PID if section.table_id() == TABLE_SDT_ACTUAL => {
+                if let Some(sdt) = Sdt::parse(section) {
+                    self.original_network_id = Some(sdt.original_network_id);
+                    for s in sdt.services {
+                        self.sdt.insert(
+                            s.service_id,
+                            SdtEntry {
+                                name: s.name,
+                                service_type: s.service_descriptor.get(2).copied(),
+                            },
+                        );
+                    }
+                }
+            }
+            _ => {
+                if let Some(pmt) = Pmt::parse(section) {
+                    self.pmts.insert(pmt.program_number, pmt);
+                }
+            }
+        }
+    }
+
+    /// PMT PIDs of the services in the PAT.
+    fn services(&self) -> Vec<(u16, u16)> {
+        self.pat
+            .as_ref()
+            .map_or_else(Vec::new, |pat| pat.services().collect())
+    }
+
+    fn count(&self, pid: u16) -> PidCount {
+        self.pids.get(&pid).copied().unwrap_or_default()
+    }
+
+    fn pid_status(&self, pid: u16) -> String {
+        let count = self.count(pid);
+        match count {
+            PidCount { packets: 0, .. } => "no packets".to_string(),
+            PidCount { scrambled: 0, .. } => format!("{} packets", count.packets),
+            _ => format!("{} packets, {} scrambled", count.packets, count.scrambled),
+        }
+    }
+
+    fn ca_text(ca: &[(u16, u16)]) -> String {
+        let ca: Vec<String> = ca
+            .iter()
+            .map(|(system, pid)| format!("pid={:#06x} (CA system {:#06x})", pid, system))
+            .collect();
+        ca.join(", ")
+    }
+
+    fn to_tree(&self) -> String {
+        let mut text = String::new();
+        match &self.pat {
+            Some(pat) => {
+                let _ = write!(
+                    text,
+                    "Transport stream: id={:#06x}",
+                    pat.transport_stream_id
+                );
+                if let Some(onid) = self.original_network_id {
+                    let _ = write!(text, ", original network id={:#06x}", onid);
+                }
+                text.push('\n');
+            }
+            None => text.push_str("No PAT was found.\n"),
+        }
+        if let Some(nit) = &self.nit {
+            let _ = writeln!(
+                text,
+                "Network: id={:#06x}, name={}, {} transport streams",
+                nit.network_id,
+                nit.name.as_deref().unwrap_or("(none)"),
+                nit.transport_streams.len()
+            );
+        }
+        if !self.emm.is_empty() {
+            let _ = writeln!(text, "EMM: {}", Self::ca_text(&self.emm));
+        }
+
+        for (sid, pmt_pid) in self.services() {
+            let sdt = self.sdt.get(&sid);
+            let _ = write!(text, "Service {} ({:#06x})", sid, sid);
+            if let Some(sdt) = sdt {
+                let _ = write!(text, " \"{}\"", sdt.name);
+                if let Some(service_type) = sdt.service_type {
+                    let _ = write!(text, " type={:#04x}", service_type);
+                }
+            }
+            let _ = writeln!(text, ", PMT pid={:#06x}", pmt_pid);
+            let pmt = match self.pmts.get(&sid) {
+                Some(pmt) => pmt,
+                None => {
+                    text.push_str("  (no PMT)\n");
+                    continue;
+                }
+            };
+            let _ = writeln!(
+                text,
+                "  PCR pid={:#06x} ({})",
+                pmt.pcr_pid,
+                self.pid_status(pmt.pcr_pid)
+            );
+            let ecm: Vec<(u16, u16)> = ca_descriptors(&pmt.descriptors).collect();
+            if !ecm.is_empty() {
+                let _ = writeln!(text, "  ECM: {}", Self::ca_text(&ecm));
+            }
+            for (i, stream) in pmt.streams.iter().enumerate() {
+                let branch = if i + 1 == pmt.streams.len() {
+                    "└─"
+                } else {
+                    "├─"
+                };
+                let _ = write!(
+                    text,
+                    "  {} pid={:#06x} {} ({:#04x})",
+                    branch,
+                    stream.pid,
+                    stream_type_name(stream.stream_type),
+                    stream.stream_type
+                );
+                if let Some(tag) = stream.component_tag() {
+                    let _ = write!(text, " tag={:#04x}", tag);
+                }
+                let ecm: Vec<(u16, u16)> = ca_descriptors(&stream.descriptors).collect();
+                if !ecm.is_empty() {
+                    let _ = write!(text, " ECM {}", Self::ca_text(&ecm));
+                }
+                let _ = writeln!(text, ": {}", self.pid_status(stream.pid));
+            }
+        }
+
+        text.push_str("PIDs:\n");
+        for (pid, count) in &self.pids {
+            let _ = write!(text, "  {:#06x} {:>10} packets", pid, count.packets);
+            if count.scrambled > 0 {
+                let _ = write!(text, ", {} scrambled", count.scrambled);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn ca_json(ca: impl Iterator<Item = (u16, u16)>) -> String {
+        let ca: Vec<String> = ca
+            .map(|(system, pid)| format!(r#"{{"ca_system_id":{},"pid":{}}}"#, system, pid))
+            .collect();
+        format!("[{}]", ca.join(","))
+    }
+
+    fn to_json(&self) -> String {
+        let opt = |v: Option<u16>| v.map_or("null".to_string(), |v| v.to_string());
+        let network = match &self.nit {
+            Some(nit) => {
+                let ts: Vec<String> = nit
+                    .transport_streams
+                    .iter()
+                    .map(|(tsid, onid)| {
+                        format!(
+                            r#"{{"transport_stream_id":{},"original_network_id":{}}}"#,
+                            tsid, onid
+                        )
+                    })
+                    .collect();
+                format!(
+                    r#"{{"network_id":{},"name":{},"transport_streams":[{}]}}"#,
+                    nit.network_id,
+                    nit.name.as_deref().map_or("null".to_string(), json_string),
+                    ts.join(",")
+                )
+            }
+            None => "null".to_string(),
+        };
+
+        let services: Vec<String> = self
+            .services()
+            .into_iter()
+            .map(|(sid, pmt_pid)| {
+                let sdt = self.sdt.get(&sid);
+                let name = sdt.map_or("null".to_string(), |s| json_string(&s.name));
+                let service_type = sdt
+                    .and_then(|s| s.service_type)
+                    .map_or("null".to_string(), |t| t.to_string());
+                let pmt = self.pmts.get(&sid);
+                let streams: Vec<String> = pmt
+                    .iter()
+                    .flat_map(|pmt| &pmt.streams)
+                    .map(|s| {
+                        let count = self.count(s.pid);
+                        format!(
+                            r#"{{"pid":{},"stream_type":{},"stream_type_name":{},"component_tag":{},"ecm":{},"packets":{},"scrambled":{}}}"#,
+                            s.pid,
+                            s.stream_type,
+                            json_string(stream_type_name(s.stream_type)),
+                            s.component_tag().map_or("null".to_string(), |t| t.to_string()),
+                            Self::ca_json(ca_descriptors(&s.descriptors)),
+                            count.packets,
+                            count.scrambled
+                        )
+                    })
+                    .collect();
+                format!(
+                    r#"{{"service_id":{},"name":{},"service_type":{},"pmt_pid":{},"pcr_pid":{},"ecm":{},"streams":[{}]}}"#,
+                    sid,
+                    name,
+                    service_type,
+                    pmt_pid,
+                    opt(pmt.map(|p| p.pcr_pid)),
+                    Self::ca_json(pmt.into_iter().flat_map(|p| ca_descriptors(&p.descriptors))),
+                    streams.join(",")
+                )
+            })
+            .collect();
+
+        let pids: Vec<String> = self
+            .pids
+            .iter()
+            .map(|(pid, count)| {
+                format!(
+                    r#"{{"pid":{},"packets":{},"scrambled":{}}}"#,
+                    pid, count.packets, count.scrambled
+                )
+            })
+            .collect();
+
+        format!(
+            r#"{{"transport_stream_id":{},"original_network_id":{},"network":{},"emm":{},"services":[{}],"pids":[{}]}}"#,
+            opt(self.pat.as_ref().map(|p| p.transport_stream_id)),
+            opt(self.original_network_id),
+            network,
+            Self::ca_json(self.emm.iter().copied()),
+            services.join(","),
+            pids.join(",")
+        )
+    }
+
+    /// Writes out the summary of the stream received so far.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pat.is_none() {
+            warn!("No PAT was found in the stream.");
+        }
+        self.write()
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let document = match self.format {
+            InspectFormat::Tree => self.to_tree(),
+            InspectFormat::Json => self.to_json() + "\n",
+        };
+        match &self.output {
+            Some(path) => fs::write(path, document),
+            None => io::stdout().lock().write_all(document.as_bytes()),
+        }
+    }
+}
+
+impl PacketStage for Inspector {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(packet);
+        let p = Packet::new(packet);
+        let pid = p.pid();
+        if pid == NULL_PID {
+            return;
+        }
+        let count = self.pids.entry(pid).or_default();
+        count.packets += 1;
+        if p.is_scrambled() {
+            count.scrambled += 1;
+            return;
+        }
+
+        if self.is_psi(pid) {
+            let mut sections = Vec::new();
+            self.buffers
+                .entry(pid)
+                .or_default()
+                .push(p, |s| sections.push(s.to_vec()));
+            for bytes in sections {
+                if let Some(section) = Section::parse(&bytes).filter(|s| s.current_next()) {
+                    self.on_section(pid, &section);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::TS_PACKET_SIZE;
+    use crate::ts::psi::{build_section, packetize, TABLE_PMT};
+    use crate::ts::si::TABLE_NIT_ACTUAL;
+
+    fn es(pid: u16, scrambled: bool) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10]);
+        if scrambled {
+            p[3] |= 0x80;
+        }
+        p
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut stream = Vec::new();
+        let pat = Pat {
+            transport_stream_id: 0x7FE0,
+            version: 0,
+            programs: vec![(0, NIT_PID), (0x400, 0x1F0), (0x401, 0x1F1)],
+        };
+        packetize(PAT_PID, &pat.to_section(), &mut 0, &mut stream);
+        // The ECM on 0x901, video on 0x111 and audio with tag 0x10 on 0x112
+        let body = [
+            0xE1, 0x11, 0xF0, 0x06, 0x09, 0x04, 0x00, 0x05, 0xE9, 0x01, 0x02, 0xE1, 0x11, 0xF0,
+            0x00, 0x0F, 0xE1, 0x12, 0xF0, 0x03, 0x52, 0x01, 0x10,
+        ];
+        let pmt = build_section(TABLE_PMT, 0x400, 0, &body);
+        packetize(0x1F0, &pmt, &mut 0, &mut stream);
+        let cat = [
+            0x01, 0xB0, 0x0F, 0xFF, 0xFF, 0xC1, 0x00, 0x00, 0x09, 0x04, 0x00, 0x05, 0xE0, 0x40,
+        ];
+        let cat = [&cat[..], &crate::ts::psi::crc32(&cat).to_be_bytes()].concat();
+        packetize(CAT_PID, &cat, &mut 0, &mut stream);
+        // Network "の" with one transport stream
+        let nit = [
+            0xF0, 0x03, 0x40, 0x01, 0xCE, 0xF0, 0x06, 0x7F, 0xE0, 0x7F, 0xE0, 0xF0, 0x00,
+        ];
+        packetize(
+            NIT_PID,
+            &build_section(TABLE_NIT_ACTUAL, 0x7FE0, 0, &nit),
+            &mut 0,
+            &mut stream,
+        );
+        let sdt = [
+            0x7F, 0xE0, 0xFF, 0x04, 0x00, 0xFC, 0x80, 0x06, 0x48, 0x04, 0x01, 0x00, 0x01, 0xCE,
+        ];
+        packetize(
+            SDT_PID,
+            &build_section(TABLE_SDT_ACTUAL, 0x7FE0, 0, &sdt),
+            &mut 0,
+            &mut stream,
+        );
+        stream.extend(es(0x111, true));
+        stream.extend(es(0x111, false));
+        stream.extend(es(NULL_PID, false));
+
+        let mut inspector = Inspector::new(InspectFormat::Json, None);
+        let mut out = Vec::new();
+        for packet in stream.chunks(TS_PACKET_SIZE) {
+            inspector.process(packet, &mut out);
+        }
+        assert_eq!(out, stream);
+
+        let tree = inspector.to_tree();
+        assert!(tree.starts_with(
+            "Transport stream: id=0x7fe0, original network id=0x7fe0\n\
+             Network: id=0x7fe0, name=の, 1 transport streams\n\
+             EMM: pid=0x0040 (CA system 0x0005)\n\
+             Service 1024 (0x0400) \"の\" type=0x01, PMT pid=0x01f0\n  \
+             PCR pid=0x0111 (2 packets, 1 scrambled)\n  \
+             ECM: pid=0x0901 (CA system 0x0005)\n  \
+             ├─ pid=0x0111 MPEG-2 Video (0x02): 2 packets, 1 scrambled\n  \
+             └─ pid=0x0112 AAC (0x0f) tag=0x10: no packets\n\
+             Service 1025 (0x0401), PMT pid=0x01f1\n  (no PMT)\n"
+        ));
+        assert!(!tree.contains("0x1fff"));
+
+        let json = inspector.to_json();
+        assert!(json.starts_with(
+            r#"{"transport_stream_id":32736,"original_network_id":32736,"network":{"network_id":32736,"name":"の","transport_streams":[{"transport_stream_id":32736,"original_network_id":32736}]},"emm":[{"ca_system_id":5,"pid":64}],"services":[{"service_id":1024,"name":"の","service_type":1,"pmt_pid":496,"pcr_pid":273,"ecm":[{"ca_system_id":5,"pid":2305}],"streams":[{"pid":273,"#
+        ));
+        assert!(json.contains(r#"{"pid":274,"stream_type":15,"stream_type_name":"AAC","component_tag":16,"ecm":[],"packets":0,"scrambled":0}"#));
+    }
+
+    #[test]
+    fn test_finish_error() {
+        let dir = std::env::temp_dir().join("recisdb-inspect-missing");
+        let inspector = Inspector::new(InspectFormat::Json, Some(dir.join("summary.json")));
+        assert!(inspector.finish().is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::io::{self, Write};
+use std::time::Instant;
+
+use log::{debug, error, warn};
+
+use crate::ts::packet::{Framer, Packet, TS_PACKET_SIZE};
+
+/// The frequency of the PCR and the arrival time stamp.
+const CLOCK_HZ: i64 = 27_000_000;
+/// The PCR wraps around at 2^33 * 300.
+const PCR_MODULO: i64 = (1 << 33) * 300;
+/// PCRs farther apart than this are taken as a discontinuity.
+const MAX_PCR_INTERVAL: i64 = CLOCK_HZ;
+/// Packets held while waiting for a PCR, before falling back to extrapolation
+/// or to the arrival time.
+const MAX_PENDING: usize = 100_000;
+
+/// The container of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// MPEG-2 TS with 188-byte packets.
+    Ts,
+    /// BDAV MPEG-2 TS with 192-byte packets, each prefixed with an arrival
+    /// time stamp.
+    M2ts,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Ts => "ts",
+            OutputFormat::M2ts => "m2ts",
+        }
+    }
+}
+
+/// Writes the TS as BDAV M2TS, prefixing each packet with a TP_extra_header.
+///
+/// The arrival time stamps are interpolated between the PCRs of the first
+/// PID found to carry them, so packets are held until the next PCR arrives.
+/// Packets before the first PCR are stamped backwards with the bitrate of
+/// the first PCR interval. If no PCR arrives for a long time, the packets are
+/// stamped by extrapolating the last bitrate, or with the arrival time at
+/// recisdb if the bitrate is not known yet.
+pub(crate) struct M2tsWriter {
+    inner: Box<dyn Write + Send>,
+    framer: Framer,
+    packets: Vec<u8>,
+    /// Packets waiting for their time stamps.
+    pending: Vec<u8>,
+    /// Number of pending packets up to the one carrying the last PCR.
+    anchored: usize,
+    pcr_pid: Option<u16>,
+    /// The last PCR and the time stamp given to its packet.
+    last: Option<(i64, i64)>,
+    /// 27 MHz ticks per packet in the last PCR interval.
+    rate: Option<f64>,
+    started: Instant,
+    /// Whether packets have been stamped with the arrival time.
+    arrival_stamped: bool,
+    buf: Vec<u8>,
+}
+
+impl M2tsWriter {
+    pub fn new(inner: Box<dyn Write + Send>) -> Self {
+        Self {
+            inner,
+            framer: Framer::new(),
+            packets: Vec::new(),
+            pending: Vec::new(),
+            anchored: 0,
+            pcr_pid: None,
+            last: None,
+            rate: None,
+            started: Instant::now(),
+            arrival_stamped: false,
+            buf: Vec::new(),
+        }
+    }
+
+    fn arrival_time(&self) -> i64 {
+        (self.started.elapsed().as_nanos() as i64).saturating_mul(27) / 1000
+    }
+
+    fn emit(buf: &mut Vec<u8>, packet: &[u8], ats: i64) {
+        // copy_permission_indicator (2 bits) is left zero
+        buf.extend_from_slice(&((ats as u32) & 0x3FFF_FFFF).to_be_bytes());
+        buf.extend_from_slice(packet);
+    }
+
+    fn push(&mut self, packet: &[u8]) {
+        self.pending.extend_from_slice(packet);
+        let p = Packet::new(packet);
+        if let Some(pcr) = p.pcr() {
+            let pid = *self.pcr_pid.get_or_insert(p.pid());
+            if pid == p.pid() {
+                self.on_pcr(pcr as i64, p.discontinuity_indicator());
+            }
+        }
+        if self.pending.len() >= MAX_PENDING * TS_PACKET_SIZE {
+            warn!("No PCR for {} packets.", MAX_PENDING);
+            self.stamp_pending();
+            // Follow another PID if the PCR has moved
+            self.pcr_pid = None;
+        }
+    }
+
+    fn on_pcr(&mut self, pcr: i64, discontinuity: bool) {
+        let count = self.pending.len() / TS_PACKET_SIZE;
+        let (last_pcr, last_ats) = match self.last {
+            Some(last) => last,
+            None => {
+                // Continue from the time stamps given by the arrival time
+                let ats = if self.arrival_stamped {
+                    self.arrival_time()
+                } else {
+                    pcr
+                };
+                self.last = Some((pcr, ats));
+                self.anchored = count;
+                return;
+            }
+        };
+
+        let interval = count - self.anchored;
+        let mut delta = (pcr - last_pcr).rem_euclid(PCR_MODULO);
+        if discontinuity || delta > MAX_PCR_INTERVAL {
+            debug!("PCR discontinuity ({} -> {})", last_pcr, pcr);
+            delta = (self.rate.unwrap_or(0.0) * interval as f64) as i64;
+        } else if interval > 0 {
+            self.rate = Some(delta as f64 / interval as f64);
+        }
+        let rate = self.rate.unwrap_or(0.0);
+
+        let pending = std::mem::take(&mut self.pending);
+        let mut packets = pending.chunks_exact(TS_PACKET_SIZE);
+        for (i, packet) in packets.by_ref().take(self.anchored).enumerate() {
+            let behind = (self.anchored - 1 - i) as f64 * rate;
+            Self::emit(&mut self.buf, packet, last_ats - behind as i64);
+        }
+        for (k, packet) in packets.enumerate() {
+            let ats = last_ats + delta * (k as i64 + 1) / interval as i64;
+            Self::emit(&mut self.buf, packet, ats);
+        }
+        self.pending = pending;
+        self.pending.clear();
+        self.anchored = 0;
+        self.last = Some((pcr, last_ats + delta));
+    }
+
+    /// Stamp all the pending packets without waiting for the next PCR.
+    fn stamp_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        match (self.last, self.rate) {
+            (Some((last_pcr, last_ats)), Some(rate)) => {
+                let mut ats = last_ats;
+                for (i, packet) in pending.chunks_exact(TS_PACKET_SIZE).enumerate() {
+                    ats = if i < self.anchored {
+                        last_ats - ((self.anchored - 1 - i) as f64 * rate) as i64
+                    } else {
+                        last_ats + ((i + 1 - self.anchored) as f64 * rate) as i64
+                    };
+                    Self::emit(&mut self.buf, packet, ats);
+                }
+                self.last = Some((last_pcr + (ats - last_ats), ats));
+            }
+            _ => {
+                let ats = self.arrival_time();
+                for packet in pending.chunks_exact(TS_PACKET_SIZE) {
+                    Self::emit(&mut self.buf, packet, ats);
+                }
+                self.arrival_stamped = true;
+                self.last = None;
+            }
+        }
+        self.pending = pending;
+        self.pending.clear();
+        self.anchored = 0;
+    }
+
+    fn write_buf(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Write for M2tsWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.framer.feed(data, &mut self.packets);
+        let packets = std::mem::take(&mut self.packets);
+        for packet in packets.chunks_exact(TS_PACKET_SIZE) {
+            self.push(packet);
+        }
+        self.packets = packets;
+        self.packets.clear();
+        self.write_buf()?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Drop for M2tsWriter {
+    fn drop(&mut self) {
+        self.framer.finish(&mut self.packets);
+        let packets = std::mem::take(&mut self.packets);
+        for packet in packets.chunks_exact(TS_PACKET_SIZE) {
+            self.push(packet);
+        }
+        self.stamp_pending();
+        if let Err(e) = self.write_buf().and_then(|_| self.inner.flush()) {
+            error!("Failed to write the last packets: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A packet of PID 0x100, with a PCR if given.
+    fn packet(i: usize, pcr: Option<u64>) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, 0x01, 0x00, 0x30 | (i as u8 & 0x0F)]);
+        p[4] = 7;
+        p[5] = 0x00;
+        if let Some(pcr) = pcr {
+            let (base, ext) = (pcr / 300, pcr % 300);
+            p[5] = 0x10;
+            p[6..10].copy_from_slice(&((base >> 1) as u32).to_be_bytes());
+            p[10] = ((base & 1) << 7) as u8 | 0x7E | (ext >> 8) as u8;
+            p[11] = ext as u8;
+        }
+        p
+    }
+
+    #[test]
+    fn test_pcr() {
+        let p = packet(0, Some(0x1_2345_6789 * 300 + 299));
+        assert_eq!(Packet::new(&p).pcr(), Some(0x1_2345_6789 * 300 + 299));
+        assert_eq!(Packet::new(&packet(0, None)).pcr(), None);
+    }
+
+    #[test]
+    fn test_time_stamps() {
+        // A PCR every 10 packets, 2700 ticks apart, from the 5th packet on
+        let pcr_at = |i: usize| (i % 10 == 5).then(|| 1_000_000 + (i as u64 / 10) * 2700);
+        let input: Vec<u8> = (0..100).flat_map(|i| packet(i, pcr_at(i))).collect();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = M2tsWriter::new(Box::new(Sink(output.clone())));
+        for piece in input.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        drop(writer);
+
+        let output = output.lock().unwrap();
+        assert_eq!(output.len(), 100 * 192);
+        for (i, record) in output.chunks(192).enumerate() {
+            let ats = u32::from_be_bytes([record[0], record[1], record[2], record[3]]);
+            assert_eq!(
+                ats as i64,
+                1_000_000 - 5 * 270 + i as i64 * 270,
+                "packet {}",
+                i
+            );
+            assert_eq!(&record[4..], &input[i * 188..(i + 1) * 188]);
+        }
+
+        // The 192-byte packets are read back as they were
+        let mut framer = Framer::new();
+        let mut packets = Vec::new();
+        framer.feed(&output, &mut packets);
+        framer.finish(&mut packets);
+        assert_eq!(packets, input);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use log::{debug, error, info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::packet::{Packet, NULL_PID};
+
+/// Counters of a single PID.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PidStats {
+    pub packets: u64,
+    /// Packets missing according to the continuity counter.
+    pub drops: u64,
+    /// Packets with `transport_error_indicator` set.
+    pub errors: u64,
+    pub scrambled: u64,
+    last_cc: Option<u8>,
+    duplicated: bool,
+}
+
+impl PidStats {
+    fn count(&mut self, pid: u16, packet: Packet) {
+        self.packets += 1;
+        if packet.transport_error() {
+            // The rest of the header cannot be trusted
+            self.errors += 1;
+            return;
+        }
+        if packet.is_scrambled() {
+            self.scrambled += 1;
+        }
+        // The continuity counter only advances on packets with a payload
+        if pid == NULL_PID || !packet.has_payload() {
+            return;
+        }
+
+        let cc = packet.continuity_counter();
+        match self.last_cc {
+            Some(last) if !packet.discontinuity_indicator() => {
+                if cc == last {
+                    // A packet may be sent twice in a row
+                    if self.duplicated {
+                        self.drops += 1;
+                    }
+                    self.duplicated = true;
+                    return;
+                }
+                let missing = cc.wrapping_sub(last.wrapping_add(1)) & 0x0F;
+                if missing > 0 {
+                    debug!(
+                        "PID {:#06x}: {} packets dropped (CC {} -> {})",
+                        pid, missing, last, cc
+                    );
+                    self.drops += missing as u64;
+                }
+            }
+            _ => {}
+        }
+        self.last_cc = Some(cc);
+        self.duplicated = false;
+    }
+}
+
+/// Counts packets, drops, transport errors and scrambled packets per PID,
+/// and logs the report when the stage is dropped at the end of the stream.
+///
+/// The stage passes every packet through unchanged.
+pub(crate) struct PacketMonitor {
+    pids: BTreeMap<u16, PidStats>,
+    sync_losses: u64,
+    skipped_bytes: u64,
+    json: Option<PathBuf>,
+}
+
+impl PacketMonitor {
+    /// If `json` is given, the report is also written there in JSON.
+    pub fn new(json: Option<PathBuf>) -> Self {
+        Self {
+            pids: BTreeMap::new(),
+            sync_losses: 0,
+            skipped_bytes: 0,
+            json,
+        }
+    }
+
+    pub fn total(&self) -> PidStats {
+        self.pids.values().fold(PidStats::default(), |mut acc, s| {
+            acc.packets += s.packets;
+            acc.drops += s.drops;
+            acc.errors += s.errors;
+            acc.scrambled += s.scrambled;
+            acc
+        })
+    }
+
+    /// Log the report in the style of tsselect.
+    pub fn log_report(&self) {
+        for (pid, s) in &self.pids {
+            info!(
+                "pid={:#06x}, total={:>10}, d={:>6}, e={:>6}, scrambling={:>10}",
+                pid, s.packets, s.drops, s.errors, s.scrambled
+            );
+        }
+        let total = self.total();
+        let summary = format!(
+            "Packets: {}, Drops: {}, Errors: {}, Scrambled: {}, Sync losses: {}",
+            total.packets, total.drops, total.errors, total.scrambled, self.sync_losses
+        );
+        if total.drops > 0 || total.errors > 0 || self.sync_losses > 0 {
+            warn!("{}", summary);
+        } else {
+            info!("{}", summary);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let total = self.total();
+        let pids = self
+            .pids
+            .iter()
+            .map(|(pid, s)| {
+                format!(
+                    r#"{{"pid":{},"packets":{},"drops":{},"errors":{},"scrambled":{}}}"#,
+                    pid, s.packets, s.drops, s.errors, s.scrambled
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"packets":{},"drops":{},"errors":{},"scrambled":{},"sync_losses":{},"skipped_bytes":{},"pids":[{}]}}"#,
+            total.packets,
+            total.drops,
+            total.errors,
+            total.scrambled,
+            self.sync_losses,
+            self.skipped_bytes,
+            pids
+        )
+    }
+}
+
+impl PacketStage for PacketMonitor {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let packet = Packet::new(packet);
+        let pid = packet.pid();
+        self.pids.entry(pid).or_default().count(pid, packet);
+        out.extend_from_slice(packet.as_bytes());
+    }
+
+    fn sync_lost(&mut self, count: u64, skipped_bytes: usize) {
+        self.sync_losses += count;
+        self.skipped_bytes += skipped_bytes as u64;
+    }
+}
+
+impl Drop for PacketMonitor {
+    fn drop(&mut self) {
+        self.log_report();
+        if let Some(path) = &self.json {
+            if let Err(e) = fs::write(path, self.to_json() + "\n") {
+                error!("Failed to write the report to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::TS_PACKET_SIZE;
+
+    fn packet(pid: u16, cc: u8, flags: u8) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[0] = 0x47;
+        p[1] = (pid >> 8) as u8;
+        p[2] = pid as u8;
+        p[3] = 0x10 | (cc & 0x0F) | flags;
+        p
+    }
+
+    fn run(packets: &[Vec<u8>]) -> PacketMonitor {
+        let mut monitor = PacketMonitor::new(None);
+        let mut out = Vec::new();
+        for p in packets {
+            monitor.process(p, &mut out);
+        }
+        assert_eq!(out, packets.concat());
+        monitor
+    }
+
+    #[test]
+    fn test_drops() {
+        // 0, 1, 2, [3, 4 missing], 5, 5 (duplicate), 6, [7..=15, 0 missing], 1
+        let ccs = [0, 1, 2, 5, 5, 6, 1];
+        let packets: Vec<_> = ccs.iter().map(|&cc| packet(0x100, cc, 0)).collect();
+        let monitor = run(&packets);
+        let stats = &monitor.pids[&0x100];
+        assert_eq!(stats.packets, 7);
+        assert_eq!(stats.drops, 2 + 10);
+    }
+
+    #[test]
+    fn test_errors_and_scrambling() {
+        let mut packets = vec![packet(0x111, 0, 0), packet(0x111, 1, 0x80)];
+        // A transport error does not update the continuity counter
+        let mut broken = packet(0x111, 9, 0);
+        broken[1] |= 0x80;
+        packets.push(broken);
+        packets.push(packet(0x111, 2, 0xC0));
+        packets.push(packet(NULL_PID, 7, 0));
+        packets.push(packet(NULL_PID, 3, 0));
+
+        let monitor = run(&packets);
+        let stats = &monitor.pids[&0x111];
+        assert_eq!(
+            (stats.packets, stats.drops, stats.errors, stats.scrambled),
+            (4, 0, 1, 2)
+        );
+        assert_eq!(monitor.pids[&NULL_PID].drops, 0);
+        assert!(monitor
+            .to_json()
+            .starts_with(r#"{"packets":6,"drops":0,"errors":1,"scrambled":2,"#));
+    }
+}
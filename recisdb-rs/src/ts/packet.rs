@@ -0,0 +1,324 @@
+use log::debug;
+
+pub(crate) const SYNC_BYTE: u8 = 0x47;
+/// Size of a plain MPEG-TS packet.
+pub(crate) const TS_PACKET_SIZE: usize = 188;
+/// Size of a BDAV (.m2ts) packet: a 4-byte TP_extra_header followed by a TS packet.
+pub(crate) const M2TS_PACKET_SIZE: usize = 192;
+/// Size of a TS packet followed by 16 bytes of Reed-Solomon parity.
+pub(crate) const FEC_PACKET_SIZE: usize = 204;
+pub(crate) const NULL_PID: u16 = 0x1FFF;
+
+/// Number of consecutive sync bytes required to (re)acquire synchronization.
+const SYNC_CHECK_COUNT: usize = 8;
+/// Bytes needed to check `SYNC_CHECK_COUNT` packets of any supported size.
+const SYNC_LOOKAHEAD: usize = FEC_PACKET_SIZE * SYNC_CHECK_COUNT;
+
+/// A read-only view of a single 188-byte TS packet.
+#[derive(Clone, Copy)]
+pub(crate) struct Packet<'a>(&'a [u8]);
+
+impl<'a> Packet<'a> {
+    /// `bytes` must hold exactly one packet, starting with the sync byte.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        debug_assert_eq!(bytes.len(), TS_PACKET_SIZE);
+        debug_assert_eq!(bytes[0], SYNC_BYTE);
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn transport_error(&self) -> bool {
+        self.0[1] & 0x80 != 0
+    }
+
+    pub fn payload_unit_start(&self) -> bool {
+        self.0[1] & 0x40 != 0
+    }
+
+    pub fn pid(&self) -> u16 {
+        u16::from_be_bytes([self.0[1] & 0x1F, self.0[2]])
+    }
+
+    pub fn scrambling_control(&self) -> u8 {
+        self.0[3] >> 6
+    }
+
+    pub fn is_scrambled(&self) -> bool {
+        self.scrambling_control() != 0
+    }
+
+    pub fn has_adaptation_field(&self) -> bool {
+        self.0[3] & 0x20 != 0
+    }
+
+    pub fn has_payload(&self) -> bool {
+        self.0[3] & 0x10 != 0
+    }
+
+    pub fn continuity_counter(&self) -> u8 {
+        self.0[3] & 0x0F
+    }
+
+    /// The adaptation field without its length byte.
+    pub fn adaptation_field(&self) -> Option<&'a [u8]> {
+        if !self.has_adaptation_field() {
+            return None;
+        }
+        let len = self.0[4] as usize;
+        self.0.get(5..5 + len)
+    }
+
+    /// The payload following the header and the adaptation field.
+    pub fn payload(&self) -> Option<&'a [u8]> {
+        if !self.has_payload() {
+            return None;
+        }
+        let start = if self.has_adaptation_field() {
+            5 + self.0[4] as usize
+        } else {
+            4
+        };
+        self.0.get(start..).filter(|p| !p.is_empty())
+    }
+
+    pub fn discontinuity_indicator(&self) -> bool {
+        matches!(self.adaptation_field(), Some(af) if !af.is_empty() && af[0] & 0x80 != 0)
+    }
+
+    /// The PCR in the adaptation field, in 27 MHz ticks.
+    pub fn pcr(&self) -> Option<u64> {
+        let af = self.adaptation_field().filter(|af| af.len() >= 7)?;
+        if af[0] & 0x10 == 0 {
+            return None;
+        }
+        let base = u64::from_be_bytes([0, 0, 0, af[1], af[2], af[3], af[4], af[5]]) >> 7;
+        let ext = u64::from(af[5] & 0x01) << 8 | u64::from(af[6]);
+        Some(base * 300 + ext)
+    }
+}
+
+/// Splits an arbitrary byte stream into aligned 188-byte TS packets.
+///
+/// The packet size (188, 192 or 204 bytes) is detected from the spacing of
+/// the sync bytes. For 192-byte packets the TP_extra_header in front of the
+/// sync byte is dropped, and for 204-byte packets the trailing parity is
+/// dropped, so that the output always consists of plain TS packets.
+///
+/// When a sync byte is missing where one is expected, the framer searches
+/// for the next position followed by `SYNC_CHECK_COUNT` regularly spaced
+/// sync bytes and skips everything in between.
+#[derive(Default)]
+pub(crate) struct Framer {
+    buf: Vec<u8>,
+    /// Bytes to skip at the start of the next input, when the last packet
+    /// ended past the data received so far (e.g. the parity of a 204-byte
+    /// packet).
+    skip: usize,
+    size: Option<usize>,
+    synced: bool,
+    /// Whether synchronization has been acquired at least once.
+    locked: bool,
+    sync_losses: u64,
+    skipped_bytes: u64,
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of times the synchronization was lost after being acquired.
+    pub fn sync_losses(&self) -> u64 {
+        self.sync_losses
+    }
+
+    /// Feed `data`, appending the complete packets found to `packets`.
+    /// Returns the number of bytes skipped to recover synchronization.
+    pub fn feed(&mut self, data: &[u8], packets: &mut Vec<u8>) -> usize {
+        let skip = self.skip.min(data.len());
+        self.skip -= skip;
+        self.buf.extend_from_slice(&data[skip..]);
+        self.process(packets, false)
+    }
+
+    /// Flush the packets still buffered at the end of the stream.
+    /// Returns the number of trailing bytes that did not form a packet.
+    pub fn finish(&mut self, packets: &mut Vec<u8>) -> usize {
+        let skipped = self.process(packets, true);
+        let rest = self.buf.len();
+        self.buf.clear();
+        if rest > 0 {
+            debug!("Discarded {} trailing bytes.", rest);
+        }
+        skipped + rest
+    }
+
+    fn process(&mut self, packets: &mut Vec<u8>, eof: bool) -> usize {
+        let mut pos = 0;
+        let mut skipped = 0;
+        loop {
+            if !self.synced {
+                match self.find_sync(pos, eof) {
+                    Some((start, size)) => {
+                        skipped += start - pos;
+                        pos = start;
+                        if self.size != Some(size) {
+                            debug!("Detected {}-byte packets.", size);
+                        }
+                        self.size = Some(size);
+                        self.synced = true;
+                        self.locked = true;
+                    }
+                    None => {
+                        // Keep enough bytes to resume the search later
+                        let keep = if eof {
+                            0
+                        } else {
+                            (self.buf.len() - pos).min(SYNC_LOOKAHEAD)
+                        };
+                        skipped += self.buf.len() - pos - keep;
+                        pos = self.buf.len() - keep;
+                        break;
+                    }
+                }
+            }
+
+            let size = self.size.unwrap();
+            while pos + TS_PACKET_SIZE <= self.buf.len() {
+                if self.buf[pos] != SYNC_BYTE {
+                    self.synced = false;
+                    self.sync_losses += 1;
+                    debug!("Lost synchronization of the TS packets.");
+                    break;
+                }
+                packets.extend_from_slice(&self.buf[pos..pos + TS_PACKET_SIZE]);
+                pos += size;
+            }
+            if self.synced {
+                break;
+            }
+        }
+
+        if pos > self.buf.len() {
+            self.skip = pos - self.buf.len();
+            pos = self.buf.len();
+        }
+        self.buf.drain(..pos);
+
+        if skipped > 0 {
+            if self.locked && self.synced {
+                debug!("Skipped {} bytes to recover synchronization.", skipped);
+            }
+            self.skipped_bytes += skipped as u64;
+        }
+        skipped
+    }
+
+    /// Find the first sync byte at or after `from` that is followed by
+    /// regularly spaced sync bytes, along with the packet size.
+    fn find_sync(&self, from: usize, eof: bool) -> Option<(usize, usize)> {
+        let buf = &self.buf;
+        let candidates =
+            self.size
+                .into_iter()
+                .chain([TS_PACKET_SIZE, M2TS_PACKET_SIZE, FEC_PACKET_SIZE]);
+        for start in from..buf.len() {
+            if !eof && buf.len() - start < SYNC_LOOKAHEAD {
+                return None;
+            }
+            if buf[start] != SYNC_BYTE {
+                continue;
+            }
+            for size in candidates.clone() {
+                // Near the end of the stream, accept whatever packets remain
+                let count = if eof {
+                    ((buf.len() - start) / size).clamp(1, SYNC_CHECK_COUNT)
+                } else {
+                    SYNC_CHECK_COUNT
+                };
+                let aligned = (0..count)
+                    .map(|k| start + k * size)
+                    .all(|p| buf.get(p) == Some(&SYNC_BYTE));
+                if aligned && start + (count - 1) * size + TS_PACKET_SIZE <= buf.len() {
+                    return Some((start, size));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a stream of `count` packets of `size` bytes, each carrying its
+    /// index in the continuity counter field.
+    fn stream(size: usize, count: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..count {
+            let mut record = vec![0xFFu8; size];
+            let offset = if size == M2TS_PACKET_SIZE { 4 } else { 0 };
+            record[offset] = SYNC_BYTE;
+            record[offset + 1] = 0x01;
+            record[offset + 2] = 0x00;
+            record[offset + 3] = 0x10 | (i as u8 & 0x0F);
+            data.extend_from_slice(&record);
+        }
+        data
+    }
+
+    fn frame(framer: &mut Framer, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut packets = Vec::new();
+        for piece in data.chunks(chunk) {
+            framer.feed(piece, &mut packets);
+        }
+        framer.finish(&mut packets);
+        packets
+    }
+
+    #[test]
+    fn test_packet_sizes() {
+        for size in [TS_PACKET_SIZE, M2TS_PACKET_SIZE, FEC_PACKET_SIZE] {
+            let mut framer = Framer::new();
+            let packets = frame(&mut framer, &stream(size, 100), 1000);
+            assert_eq!(framer.size, Some(size));
+            assert_eq!(packets.len(), 100 * TS_PACKET_SIZE, "size {}", size);
+            for (i, p) in packets.chunks(TS_PACKET_SIZE).enumerate() {
+                let p = Packet::new(p);
+                assert_eq!(p.pid(), 0x100);
+                assert_eq!(p.continuity_counter(), i as u8 & 0x0F);
+            }
+            assert_eq!(framer.sync_losses(), 0);
+        }
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut data = stream(TS_PACKET_SIZE, 50);
+        // Corrupt the sync byte of packet 20 and insert some garbage
+        data[20 * TS_PACKET_SIZE] = 0x00;
+        data.splice(30 * TS_PACKET_SIZE..30 * TS_PACKET_SIZE, [0u8; 7]);
+
+        let mut framer = Framer::new();
+        let packets = frame(&mut framer, &data, 333);
+        assert_eq!(framer.sync_losses(), 2);
+        // Only packet 20 is lost; the garbage is skipped
+        assert_eq!(packets.len(), 49 * TS_PACKET_SIZE);
+        assert!(packets.chunks(TS_PACKET_SIZE).all(|p| p[0] == SYNC_BYTE));
+    }
+
+    #[test]
+    fn test_leading_garbage() {
+        let mut data = vec![0x47, 0x00, 0x12];
+        data.extend(stream(TS_PACKET_SIZE, 20));
+        let mut framer = Framer::new();
+        let packets = frame(&mut framer, &data, 64);
+        assert_eq!(packets.len(), 20 * TS_PACKET_SIZE);
+        assert_eq!(framer.skipped_bytes, 3);
+        assert_eq!(framer.sync_losses(), 0);
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+
+use log::{debug, info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::packet::Packet;
+use crate::ts::psi::{
+    self, Pat, Section, SectionBuffer, CAT_PID, DIT_PID, NIT_PID, PAT_PID, SDT_PID, SIT_PID,
+    TOT_PID,
+};
+use crate::ts::si::{Sdt, TABLE_NIT_ACTUAL, TABLE_SDT_ACTUAL};
+
+const TABLE_SIT: u8 = 0x7F;
+const NETWORK_IDENTIFICATION_DESCRIPTOR: u8 = 0xC2;
+
+/// The media type in the network identification descriptor of ARIB STD-B10,
+/// derived from the network ID.
+fn media_type(network_id: u16) -> &'static [u8; 2] {
+    match network_id {
+        0x0004 => b"BS",
+        0x0006 | 0x0007 => b"CS",
+        _ => b"TB",
+    }
+}
+
+/// A service in the SIT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SitService {
+    running_status: u8,
+    /// The service descriptor copied from the SDT.
+    descriptor: Vec<u8>,
+}
+
+/// Turns the output of `ServiceFilter` into a partial TS as defined in ARIB
+/// STD-B21.
+///
+/// The NIT entry is removed from the PAT, and a Selection Information Table
+/// (SIT) is sent along with every PAT in place of the NIT and the SDT. The
+/// SIT carries the network ID read from the NIT, and the service descriptor
+/// and the running status of each service in the PAT read from the SDT. Its
+/// version is incremented whenever its contents change.
+///
+/// The NIT, SDT, TOT and CAT are removed, as well as any DIT and SIT already
+/// in the stream. The other packets are passed through unchanged.
+pub(crate) struct PartialTs {
+    pat_buffer: SectionBuffer,
+    nit_buffer: SectionBuffer,
+    sdt_buffer: SectionBuffer,
+    network_id: Option<u16>,
+    /// Services in the actual SDT by service ID.
+    sdt: BTreeMap<u16, SitService>,
+    pat_cc: u8,
+    sit_cc: u8,
+    /// The body of the last SIT and its version.
+    sit: Option<(Vec<u8>, u8)>,
+    removed: u64,
+}
+
+impl PartialTs {
+    pub fn new() -> Self {
+        Self {
+            pat_buffer: SectionBuffer::default(),
+            nit_buffer: SectionBuffer::default(),
+            sdt_buffer: SectionBuffer::default(),
+            network_id: None,
+            sdt: BTreeMap::new(),
+            pat_cc: 0,
+            sit_cc: 0,
+            sit: None,
+            removed: 0,
+        }
+    }
+
+    fn sit_body(&self, pat: &Pat) -> Vec<u8> {
+        let mut info = Vec::new();
+        if let Some(network_id) = self.network_id {
+            info.extend_from_slice(&[NETWORK_IDENTIFICATION_DESCRIPTOR, 7]);
+            info.extend_from_slice(b"JPN");
+            info.extend_from_slice(media_type(network_id));
+            info.extend_from_slice(&network_id.to_be_bytes());
+        }
+        let mut body = vec![0xF0 | (info.len() >> 8) as u8, info.len() as u8];
+        body.extend_from_slice(&info);
+        for (sid, _) in pat.services() {
+            let service = self.sdt.get(&sid);
+            let descriptor = service.map_or(&[][..], |s| &s.descriptor);
+            let running_status = service.map_or(0, |s| s.running_status);
+            body.extend_from_slice(&sid.to_be_bytes());
+            body.push(0x80 | running_status << 4 | (descriptor.len() >> 8) as u8);
+            body.push(descriptor.len() as u8);
+            body.extend_from_slice(descriptor);
+        }
+        body
+    }
+
+    fn on_pat(&mut self, pat: Pat, out: &mut Vec<u8>) {
+        let pat = Pat {
+            programs: pat.programs.into_iter().filter(|p| p.0 != 0).collect(),
+            ..pat
+        };
+        psi::packetize(PAT_PID, &pat.to_section(), &mut self.pat_cc, out);
+
+        let body = self.sit_body(&pat);
+        let version = match &self.sit {
+            Some((last, version)) if *last == body => *version,
+            Some((_, version)) => {
+                debug!("The SIT was updated (version {}).", (version + 1) & 0x1F);
+                (version + 1) & 0x1F
+            }
+            None => 0,
+        };
+        let section = psi::build_section(TABLE_SIT, 0xFFFF, version, &body);
+        psi::packetize(SIT_PID, &section, &mut self.sit_cc, out);
+        self.sit = Some((body, version));
+    }
+
+    fn on_nit(&mut self, section: &Section) {
+        if section.table_id() == TABLE_NIT_ACTUAL {
+            self.network_id = Some(section.table_id_extension());
+        }
+    }
+
+    fn on_sdt(&mut self, section: &Section) {
+        if section.table_id() != TABLE_SDT_ACTUAL {
+            return;
+        }
+        if let Some(sdt) = Sdt::parse(section) {
+            for s in sdt.services {
+                self.sdt.insert(
+                    s.service_id,
+                    SitService {
+                        running_status: s.running_status,
+                        descriptor: s.service_descriptor,
+                    },
+                );
+            }
+        }
+    }
+}
+
+impl PacketStage for PartialTs {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let p = Packet::new(packet);
+        let pid = p.pid();
+        let buffer = match pid {
+            PAT_PID => &mut self.pat_buffer,
+            NIT_PID => &mut self.nit_buffer,
+            SDT_PID => &mut self.sdt_buffer,
+            CAT_PID | TOT_PID | DIT_PID | SIT_PID => {
+                self.removed += 1;
+                return;
+            }
+            _ => {
+                out.extend_from_slice(packet);
+                return;
+            }
+        };
+        let mut sections = Vec::new();
+        buffer.push(p, |s| sections.push(s.to_vec()));
+        if pid != PAT_PID {
+            self.removed += 1;
+        }
+
+        for bytes in sections {
+            let section = match Section::parse(&bytes).filter(|s| s.current_next()) {
+                Some(section) => section,
+                None => continue,
+            };
+            match pid {
+                PAT_PID => {
+                    if let Some(pat) = Pat::parse(&section) {
+                        self.on_pat(pat, out);
+                    }
+                }
+                NIT_PID => self.on_nit(&section),
+                _ => self.on_sdt(&section),
+            }
+        }
+    }
+}
+
+impl Drop for PartialTs {
+    fn drop(&mut self) {
+        match &self.sit {
+            Some((_, version)) => info!(
+                "Partial TS: SIT version {}, {} packets of the NIT, SDT, TOT and CAT removed.",
+                version, self.removed
+            ),
+            None => warn!("Partial TS: no PAT was found, so no SIT was sent."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::TS_PACKET_SIZE;
+
+    fn packets(pid: u16, section: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        psi::packetize(pid, section, &mut 0, &mut out);
+        out
+    }
+
+    fn pat(cc: &mut u8) -> Vec<u8> {
+        let pat = Pat {
+            transport_stream_id: 0x7FE0,
+            version: 0,
+            programs: vec![(0, NIT_PID), (0x400, 0x1F0)],
+        };
+        let mut out = Vec::new();
+        psi::packetize(PAT_PID, &pat.to_section(), cc, &mut out);
+        out
+    }
+
+    fn sdt(running_status: u8, cc: &mut u8) -> Vec<u8> {
+        // service_type 0x01, no provider name, service name "A"
+        let descriptor = [0x48, 4, 0x01, 0, 1, 0x41];
+        let mut body = vec![0x7F, 0xE0, 0xFF];
+        body.extend_from_slice(&[0x04, 0x00, 0xFC]);
+        body.extend_from_slice(&[running_status << 5, descriptor.len() as u8]);
+        body.extend_from_slice(&descriptor);
+        let mut out = Vec::new();
+        let section = psi::build_section(TABLE_SDT_ACTUAL, 0x7FE0, 0, &body);
+        psi::packetize(SDT_PID, &section, cc, &mut out);
+        out
+    }
+
+    fn es(pid: u16) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10]);
+        p
+    }
+
+    fn sections(out: &[u8], pid: u16) -> Vec<Vec<u8>> {
+        let mut buffer = SectionBuffer::default();
+        let mut sections = Vec::new();
+        for p in out.chunks(TS_PACKET_SIZE).map(Packet::new) {
+            if p.pid() == pid {
+                buffer.push(p, |s| sections.push(s.to_vec()));
+            }
+        }
+        sections
+    }
+
+    #[test]
+    fn test_partial_ts() {
+        let nit = packets(
+            NIT_PID,
+            &psi::build_section(TABLE_NIT_ACTUAL, 0x7FE0, 0, &[0xF0, 0x00, 0xF0, 0x00]),
+        );
+        let (mut pat_cc, mut sdt_cc) = (0, 0);
+        let mut stage = PartialTs::new();
+        let mut out = Vec::new();
+        for p in [
+            pat(&mut pat_cc),
+            nit,
+            es(0x111),
+            sdt(4, &mut sdt_cc),
+            es(CAT_PID),
+            es(TOT_PID),
+            pat(&mut pat_cc),
+            sdt(1, &mut sdt_cc),
+            pat(&mut pat_cc),
+        ] {
+            stage.process(&p, &mut out);
+        }
+        let pids: Vec<u16> = out
+            .chunks(TS_PACKET_SIZE)
+            .map(|p| Packet::new(p).pid())
+            .collect();
+        assert_eq!(
+            pids,
+            [PAT_PID, SIT_PID, 0x111, PAT_PID, SIT_PID, PAT_PID, SIT_PID]
+        );
+
+        // The NIT is no longer listed in the PAT
+        let pat = sections(&out, PAT_PID).remove(0);
+        let pat = Pat::parse(&Section::parse(&pat).unwrap()).unwrap();
+        assert_eq!(pat.programs, vec![(0x400, 0x1F0)]);
+
+        let sits = sections(&out, SIT_PID);
+        let versions: Vec<u8> = sits
+            .iter()
+            .map(|s| Section::parse(s).unwrap().version())
+            .collect();
+        assert_eq!(versions, [0, 1, 2]);
+        assert_eq!(psi::crc32(&sits[1]), 0);
+        let sit = Section::parse(&sits[1]).unwrap();
+        assert_eq!(sit.table_id(), TABLE_SIT);
+        assert_eq!(
+            sit.body(),
+            [
+                &[0xF0, 9, 0xC2, 7][..],
+                b"JPNTB",
+                &[0x7F, 0xE0],
+                &[0x04, 0x00, 0xC0, 6],
+                &[0x48, 4, 0x01, 0, 1, 0x41],
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use crate::ts::packet::{Packet, SYNC_BYTE, TS_PACKET_SIZE};
+
+pub(crate) const PAT_PID: u16 = 0x0000;
+pub(crate) const CAT_PID: u16 = 0x0001;
+pub(crate) const NIT_PID: u16 = 0x0010;
+pub(crate) const SDT_PID: u16 = 0x0011;
+pub(crate) const TOT_PID: u16 = 0x0014;
+pub(crate) const DIT_PID: u16 = 0x001E;
+pub(crate) const SIT_PID: u16 = 0x001F;
+/// PIDs of the EIT: the standard one and the ARIB L-EIT/H-EIT for one-seg.
+pub(crate) const EIT_PIDS: [u16; 3] = [0x0012, 0x0026, 0x0027];
+
+pub(crate) const TABLE_PAT: u8 = 0x00;
+pub(crate) const TABLE_CAT: u8 = 0x01;
+pub(crate) const TABLE_PMT: u8 = 0x02;
+
+const CA_DESCRIPTOR: u8 = 0x09;
+const STREAM_IDENTIFIER_DESCRIPTOR: u8 = 0x52;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 as used in PSI/SI sections (ISO/IEC 13818-1 Annex A).
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+/// Reassembles the sections carried on a single PID.
+#[derive(Default)]
+pub(crate) struct SectionBuffer {
+    buf: Vec<u8>,
+    last_cc: Option<u8>,
+}
+
+impl SectionBuffer {
+    /// Feed a packet of the PID, calling `on_section` for every section
+    /// completed by it. Incomplete sections are discarded on discontinuities.
+    pub fn push(&mut self, packet: Packet, mut on_section: impl FnMut(&[u8])) {
+        if packet.transport_error() {
+            self.buf.clear();
+            self.last_cc = None;
+            return;
+        }
+        let payload = match packet.payload() {
+            Some(payload) => payload,
+            None => return,
+        };
+        let cc = packet.continuity_counter();
+        if self.last_cc == Some(cc) {
+            // Duplicate packet
+            return;
+        }
+        let continuous = matches!(self.last_cc, Some(last) if cc == (last + 1) & 0x0F);
+        self.last_cc = Some(cc);
+
+        if packet.payload_unit_start() {
+            let pointer = payload[0] as usize;
+            let payload = &payload[1..];
+            if pointer > payload.len() {
+                self.buf.clear();
+                return;
+            }
+            if continuous && !self.buf.is_empty() {
+                self.buf.extend_from_slice(&payload[..pointer]);
+                self.drain(&mut on_section);
+            }
+            self.buf.clear();
+            self.buf.extend_from_slice(&payload[pointer..]);
+            self.drain(&mut on_section);
+        } else if continuous && !self.buf.is_empty() {
+            self.buf.extend_from_slice(payload);
+            self.drain(&mut on_section);
+        } else {
+            self.buf.clear();
+        }
+    }
+
+    fn drain(&mut self, on_section: &mut impl FnMut(&[u8])) {
+        let mut pos = 0;
+        loop {
+            let rest = &self.buf[pos..];
+            // The rest of the packet is stuffing
+            if rest.first().map_or(true, |&b| b == 0xFF) {
+                self.buf.clear();
+                return;
+            }
+            if rest.len() < 3 {
+                break;
+            }
+            let len = 3 + (((rest[1] & 0x0F) as usize) << 8 | rest[2] as usize);
+            if rest.len() < len {
+                break;
+            }
+            on_section(&rest[..len]);
+            pos += len;
+        }
+        self.buf.drain(..pos);
+    }
+
+    /// Whether a section has been started and not completed yet.
+    pub fn is_pending(&self) -> bool {
+        !self.buf.is_empty()
+    }
+}
+
+/// A complete PSI/SI section.
+#[derive(Clone, Copy)]
+pub(crate) struct Section<'a>(&'a [u8]);
+
+impl<'a> Section<'a> {
+    /// Returns `None` if the section is truncated or fails the CRC check.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < 3 {
+            return None;
+        }
+        let section = Self(bytes);
+        if section.is_long() && (bytes.len() < 12 || crc32(bytes) != 0) {
+            return None;
+        }
+        Some(section)
+    }
+
+    pub fn table_id(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// Whether the section has the extended header and the CRC.
+    pub fn is_long(&self) -> bool {
+        self.0[1] & 0x80 != 0
+    }
+
+    /// A byte of the extended header, which `parse` has checked to be there
+    /// for a long section, or 0 without it.
+    fn header(&self, index: usize) -> u8 {
+        if self.is_long() {
+            self.0[index]
+        } else {
+            0
+        }
+    }
+
+    pub fn table_id_extension(&self) -> u16 {
+        u16::from_be_bytes([self.header(3), self.header(4)])
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.header(5) >> 1) & 0x1F
+    }
+
+    pub fn section_number(&self) -> u8 {
+        self.header(6)
+    }
+
+    /// Always false without the extended header, so that a section cut short
+    /// is never taken for a table.
+    pub fn current_next(&self) -> bool {
+        self.header(5) & 0x01 != 0
+    }
+
+    /// The data following the header, without the CRC.
+    pub fn body(&self) -> &'a [u8] {
+        if self.is_long() {
+            &self.0[8..self.0.len() - 4]
+        } else {
+            &self.0[3..]
+        }
+    }
+}
+
+/// Build a long section (with `section_syntax_indicator` set) around `body`.
+pub(crate) fn build_section(
+    table_id: u8,
+    table_id_extension: u16,
+    version: u8,
+    body: &[u8],
+) -> Vec<u8> {
+    let len = 5 + body.len() + 4;
+    let mut section = Vec::with_capacity(3 + len);
+    section.push(table_id);
+    section.push(0xB0 | (len >> 8) as u8);
+    section.push(len as u8);
+    section.extend_from_slice(&table_id_extension.to_be_bytes());
+    section.push(0xC1 | (version & 0x1F) << 1);
+    // section_number, last_section_number
+    section.extend_from_slice(&[0, 0]);
+    section.extend_from_slice(body);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// Split `section` into packets on `pid`, appending them to `out`.
+pub(crate) fn packetize(pid: u16, section: &[u8], cc: &mut u8, out: &mut Vec<u8>) {
+    let mut rest = section;
+    let mut first = true;
+    while first || !rest.is_empty() {
+        let mut packet = [0xFFu8; TS_PACKET_SIZE];
+        packet[0] = SYNC_BYTE;
+        packet[1] = (pid >> 8) as u8 & 0x1F | if first { 0x40 } else { 0 };
+        packet[2] = pid as u8;
+        packet[3] = 0x10 | *cc;
+        *cc = (*cc + 1) & 0x0F;
+
+        let mut pos = 4;
+        if first {
+            // pointer_field
+            packet[pos] = 0;
+            pos += 1;
+        }
+        let n = rest.len().min(TS_PACKET_SIZE - pos);
+        packet[pos..pos + n].copy_from_slice(&rest[..n]);
+        rest = &rest[n..];
+        first = false;
+        out.extend_from_slice(&packet);
+    }
+}
+
+/// Iterate over the (tag, data) pairs of a descriptor loop.
+pub(crate) fn descriptors(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 2 || data.len() < 2 + data[1] as usize {
+            return None;
+        }
+        let (descriptor, rest) = data.split_at(2 + data[1] as usize);
+        data = rest;
+        Some((descriptor[0], &descriptor[2..]))
+    })
+}
+
+/// Iterate over the CA system IDs and the PIDs in the CA descriptors of a
+/// descriptor loop.
+pub(crate) fn ca_descriptors(data: &[u8]) -> impl Iterator<Item = (u16, u16)> + '_ {
+    descriptors(data)
+        .filter(|&(tag, data)| tag == CA_DESCRIPTOR && data.len() >= 4)
+        .map(|(_, data)| (u16::from_be_bytes([data[0], data[1]]), read_pid(&data[2..])))
+}
+
+/// Read a 2-byte field whose lower 13 bits hold a PID.
+fn read_pid(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0] & 0x1F, bytes[1]])
+}
+
+/// Read a 2-byte field whose lower 12 bits hold a length.
+fn read_len(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0] & 0x0F, bytes[1]]) as usize
+}
+
+/// Program Association Table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Pat {
+    pub transport_stream_id: u16,
+    pub version: u8,
+    /// Pairs of program_number and PID. Program 0 points to the NIT.
+    pub programs: Vec<(u16, u16)>,
+}
+
+impl Pat {
+    pub fn parse(section: &Section) -> Option<Self> {
+        if section.table_id() != TABLE_PAT || !section.is_long() {
+            return None;
+        }
+        let programs = section
+            .body()
+            .chunks_exact(4)
+            .map(|entry| {
+                (
+                    u16::from_be_bytes([entry[0], entry[1]]),
+                    read_pid(&entry[2..]),
+                )
+            })
+            .collect();
+        Some(Self {
+            transport_stream_id: section.table_id_extension(),
+            version: section.version(),
+            programs,
+        })
+    }
+
+    /// Services listed in the table, excluding the NIT entry.
+    pub fn services(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.programs
+            .iter()
+            .copied()
+            .filter(|&(number, _)| number != 0)
+    }
+
+    pub fn to_section(&self) -> Vec<u8> {
+        let body: Vec<u8> = self
+            .programs
+            .iter()
+            .flat_map(|&(number, pid)| {
+                let [hi, lo] = number.to_be_bytes();
+                [hi, lo, 0xE0 | (pid >> 8) as u8, pid as u8]
+            })
+            .collect();
+        build_section(TABLE_PAT, self.transport_stream_id, self.version, &body)
+    }
+}
+
+/// An elementary stream listed in a PMT.
+#[derive(Debug, Clone)]
+pub(crate) struct PmtStream {
+    pub stream_type: u8,
+    pub pid: u16,
+    pub descriptors: Vec<u8>,
+}
+
+impl PmtStream {
+    /// The component tag in the stream identifier descriptor.
+    pub fn component_tag(&self) -> Option<u8> {
+        descriptors(&self.descriptors)
+            .find(|&(tag, data)| tag == STREAM_IDENTIFIER_DESCRIPTOR && !data.is_empty())
+            .map(|(_, data)| data[0])
+    }
+}
+
+/// Program Map Table.
+#[derive(Debug, Clone)]
+pub(crate) struct Pmt {
+    pub program_number: u16,
+    pub version: u8,
+    pub pcr_pid: u16,
+    pub descriptors: Vec<u8>,
+    pub streams: Vec<PmtStream>,
+}
+
+impl Pmt {
+    pub fn parse(section: &Section) -> Option<Self> {
+        if section.table_id() != TABLE_PMT || !section.is_long() {
+            return None;
+        }
+        let body = section.body();
+        if body.len() < 4 {
+            return None;
+        }
+        let info_len = read_len(&body[2..]);
+        let descriptors = body.get(4..4 + info_len)?.to_vec();
+
+        let mut streams = Vec::new();
+        let mut rest = &body[4 + info_len..];
+        while rest.len() >= 5 {
+            let es_info_len = read_len(&rest[3..]);
+            let es_descriptors = rest.get(5..5 + es_info_len)?;
+            streams.push(PmtStream {
+                stream_type: rest[0],
+                pid: read_pid(&rest[1..]),
+                descriptors: es_descriptors.to_vec(),
+            });
+            rest = &rest[5 + es_info_len..];
+        }
+
+        Some(Self {
+            program_number: section.table_id_extension(),
+            version: section.version(),
+            pcr_pid: read_pid(body),
+            descriptors,
+            streams,
+        })
+    }
+
+    /// PIDs of the ECM, from the CA descriptors of the program and its streams.
+    pub fn ecm_pids(&self) -> Vec<u16> {
+        std::iter::once(&self.descriptors[..])
+            .chain(self.streams.iter().map(|s| &s.descriptors[..]))
+            .flat_map(ca_descriptors)
+            .map(|(_, pid)| pid)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0x0376_E6E7);
+        let section = build_section(TABLE_PAT, 1, 0, &[0, 0, 0xE0, 0x10]);
+        assert_eq!(crc32(&section), 0);
+    }
+
+    #[test]
+    fn test_pat_round_trip() {
+        let pat = Pat {
+            transport_stream_id: 0x7FE0,
+            version: 3,
+            programs: vec![(0, 0x10), (0x400, 0x1F0), (0x5A8, 0x1FC8)],
+        };
+        let mut packets = Vec::new();
+        let mut cc = 0;
+        packetize(PAT_PID, &pat.to_section(), &mut cc, &mut packets);
+        assert_eq!(packets.len(), TS_PACKET_SIZE);
+
+        let mut buffer = SectionBuffer::default();
+        let mut parsed = None;
+        buffer.push(Packet::new(&packets), |s| {
+            parsed = Section::parse(s).and_then(|s| Pat::parse(&s));
+        });
+        assert_eq!(parsed, Some(pat));
+    }
+
+    #[test]
+    fn test_sections_across_packets() {
+        // Two sections, the first of which spans three packets
+        let long = build_section(0x42, 1, 0, &[0xAB; 400]);
+        let short = build_section(0x42, 2, 0, &[0xCD; 10]);
+        let mut packets = Vec::new();
+        let mut cc = 5;
+        packetize(
+            SDT_PID,
+            &[long.clone(), short.clone()].concat(),
+            &mut cc,
+            &mut packets,
+        );
+        assert_eq!(packets.len(), 3 * TS_PACKET_SIZE);
+
+        let mut buffer = SectionBuffer::default();
+        let mut sections = Vec::new();
+        for packet in packets.chunks(TS_PACKET_SIZE) {
+            buffer.push(Packet::new(packet), |s| sections.push(s.to_vec()));
+        }
+        assert_eq!(sections, vec![long, short]);
+        assert!(sections.iter().all(|s| Section::parse(s).is_some()));
+    }
+
+    #[test]
+    fn test_short_section() {
+        // A corrupted PAT of 3 bytes, without the extended header
+        let mut packet = vec![SYNC_BYTE, 0x40, 0x00, 0x10, 0x00, TABLE_PAT, 0x00, 0x00];
+        packet.resize(TS_PACKET_SIZE, 0xFF);
+        let mut buffer = SectionBuffer::default();
+        let mut sections = Vec::new();
+        buffer.push(Packet::new(&packet), |s| sections.push(s.to_vec()));
+        assert_eq!(sections, [vec![TABLE_PAT, 0x00, 0x00]]);
+
+        let section = Section::parse(&sections[0]).unwrap();
+        assert!(!section.is_long());
+        assert!(!section.current_next());
+        assert_eq!(section.table_id_extension(), 0);
+        assert_eq!(section.version(), 0);
+        assert_eq!(section.section_number(), 0);
+        assert!(section.body().is_empty());
+        assert_eq!(Pat::parse(&section), None);
+
+        // Cut short with the extended header
+        let mut pat = build_section(TABLE_PAT, 1, 0, &[]);
+        pat.truncate(8);
+        pat[2] = 5;
+        assert!(Section::parse(&pat).is_none());
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use log::{info, warn};
+
+use crate::io::PacketStage;
+use crate::ts::packet::Packet;
+use crate::ts::psi::{
+    self, ca_descriptors, Pat, Pmt, Section, SectionBuffer, CAT_PID, EIT_PIDS, NIT_PID, PAT_PID,
+    SDT_PID, TABLE_CAT, TOT_PID,
+};
+
+/// PMT PIDs reserved for the partial reception (one-seg) service in ARIB TR-B14.
+const ONESEG_PMT_PIDS: std::ops::RangeInclusive<u16> = 0x1FC8..=0x1FCF;
+
+/// A service to extract, as given to `--sid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ServiceSelector {
+    /// A service ID.
+    Id(u16),
+    /// The n-th service in the PAT, counted from 1. `hd` is the same as `sd1`.
+    Nth(usize),
+    /// The partial reception (one-seg) service.
+    OneSeg,
+    /// All services in the PAT.
+    All,
+}
+
+/// The services and tables to keep, parsed from a comma-separated list of
+/// service IDs and the keywords `hd`, `sd1`, `sd2`, `sd3`, `1seg`, `all`
+/// and `epg`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServiceSelection {
+    pub services: Vec<ServiceSelector>,
+    /// Keep the EIT as well.
+    pub epg: bool,
+}
+
+impl FromStr for ServiceSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut selection = ServiceSelection {
+            services: Vec::new(),
+            epg: false,
+        };
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let selector = match item.to_ascii_lowercase().as_str() {
+                "epg" => {
+                    selection.epg = true;
+                    continue;
+                }
+                "hd" | "sd1" => ServiceSelector::Nth(1),
+                "sd2" => ServiceSelector::Nth(2),
+                "sd3" => ServiceSelector::Nth(3),
+                "1seg" => ServiceSelector::OneSeg,
+                "all" => ServiceSelector::All,
+                other => match other.parse() {
+                    Ok(sid) => ServiceSelector::Id(sid),
+                    Err(_) => return Err(format!("invalid service '{}'", item)),
+                },
+            };
+            selection.services.push(selector);
+        }
+        if selection.services.is_empty() && !selection.epg {
+            return Err("no service is specified".to_string());
+        }
+        Ok(selection)
+    }
+}
+
+impl fmt::Display for ServiceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceSelector::Id(sid) => write!(f, "{}", sid),
+            ServiceSelector::Nth(n) => write!(f, "sd{}", n),
+            ServiceSelector::OneSeg => write!(f, "1seg"),
+            ServiceSelector::All => write!(f, "all"),
+        }
+    }
+}
+
+impl fmt::Display for ServiceSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items: Vec<String> = self.services.iter().map(|s| s.to_string()).collect();
+        if self.epg {
+            items.push("epg".to_string());
+        }
+        write!(f, "{}", items.join(","))
+    }
+}
+
+impl ServiceSelection {
+    /// The service ID, if the selection is a single service ID.
+    pub fn single_id(&self) -> Option<u16> {
+        match self.services[..] {
+            [ServiceSelector::Id(sid)] => Some(sid),
+            _ => None,
+        }
+    }
+
+    /// Resolve the selection against the services of `pat`, returning the
+    /// pairs of service ID and PMT PID.
+    fn resolve(&self, pat: &Pat) -> Vec<(u16, u16)> {
+        let services: Vec<(u16, u16)> = pat.services().collect();
+        let mut selected: Vec<(u16, u16)> = Vec::new();
+        for selector in &self.services {
+            let found: Vec<(u16, u16)> = match *selector {
+                ServiceSelector::Id(sid) => {
+                    services.iter().copied().filter(|s| s.0 == sid).collect()
+                }
+                ServiceSelector::Nth(n) => services.get(n - 1).copied().into_iter().collect(),
+                ServiceSelector::OneSeg => services
+                    .iter()
+                    .copied()
+                    .filter(|s| ONESEG_PMT_PIDS.contains(&s.1))
+                    .collect(),
+                ServiceSelector::All => services.clone(),
+            };
+            if found.is_empty() {
+                warn!("No service in the PAT matches '{}'.", selector);
+            }
+            for service in found {
+                if !selected.contains(&service) {
+                    selected.push(service);
+                }
+            }
+        }
+        selected
+    }
+}
+
+/// Extracts the selected services from the stream.
+///
+/// The stage keeps a rewritten PAT listing only the selected services, their
+/// PMTs together with the ES, ECM and PCR PIDs listed there, the CAT with the
+/// EMM PIDs listed there, the NIT, SDT and TOT, and the EIT if requested. The
+/// PAT, the PMTs and the CAT are followed during the stream, so that the PIDs
+/// kept are updated whenever they change. Packets preceding the first PAT are
+/// discarded.
+pub(crate) struct ServiceFilter {
+    selection: ServiceSelection,
+    pat_buffer: SectionBuffer,
+    pat: Option<Pat>,
+    /// Selected services as pairs of service ID and PMT PID.
+    selected: Vec<(u16, u16)>,
+    pmt_buffers: HashMap<u16, SectionBuffer>,
+    /// Latest PMT of each selected service, keyed by the PMT PID.
+    pmts: HashMap<u16, Pmt>,
+    cat_buffer: SectionBuffer,
+    /// The EMM PIDs in the latest CAT, for the decoder to pass the EMMs.
+    emm_pids: Vec<u16>,
+    keep: Box<[bool; 0x2000]>,
+    pat_cc: u8,
+    kept: u64,
+    dropped: u64,
+}
+
+impl ServiceFilter {
+    pub fn new(selection: ServiceSelection) -> Self {
+        let mut filter = Self {
+            selection,
+            pat_buffer: SectionBuffer::default(),
+            pat: None,
+            selected: Vec::new(),
+            pmt_buffers: HashMap::new(),
+            pmts: HashMap::new(),
+            cat_buffer: SectionBuffer::default(),
+            emm_pids: Vec::new(),
+            keep: Box::new([false; 0x2000]),
+            pat_cc: 0,
+            kept: 0,
+            dropped: 0,
+        };
+        filter.update_pids();
+        filter
+    }
+
+    fn on_pat(&mut self, pat: Pat, out: &mut Vec<u8>) {
+        if self.pat.as_ref() != Some(&pat) {
+            let selected = self.selection.resolve(&pat);
+            if selected != self.selected {
+                let sids: Vec<String> = selected.iter().map(|s| s.0.to_string()).collect();
+                info!("Extracting services: [{}]", sids.join(", "));
+                self.pmt_buffers
+                    .retain(|pid, _| selected.iter().any(|s| s.1 == *pid));
+                self.pmts
+                    .retain(|pid, _| selected.iter().any(|s| s.1 == *pid));
+                self.selected = selected;
+            }
+            self.pat = Some(pat);
+            self.update_pids();
+        }
+
+        let pat = self.pat.as_ref().unwrap();
+        let rewritten = Pat {
+            programs: pat
+                .programs
+                .iter()
+                .copied()
+                .filter(|p| p.0 == 0 || self.selected.contains(p))
+                .collect(),
+            ..pat.clone()
+        };
+        psi::packetize(PAT_PID, &rewritten.to_section(), &mut self.pat_cc, out);
+    }
+
+    fn on_pmt(&mut self, pid: u16, pmt: Pmt) {
+        // The PID may carry the PMTs of other services as well
+        if !self.selected.contains(&(pmt.program_number, pid)) {
+            return;
+        }
+        let changed = match self.pmts.get(&pid) {
+            Some(current) => current.version != pmt.version,
+            None => true,
+        };
+        if changed {
+            if self.pmts.contains_key(&pid) {
+                info!(
+                    "The PMT of service {} was updated (version {}).",
+                    pmt.program_number, pmt.version
+                );
+            }
+            self.pmts.insert(pid, pmt);
+            self.update_pids();
+        }
+    }
+
+    fn on_cat(&mut self, emm_pids: Vec<u16>) {
+        if emm_pids != self.emm_pids {
+            self.emm_pids = emm_pids;
+            self.update_pids();
+        }
+    }
+
+    fn update_pids(&mut self) {
+        self.keep.fill(false);
+        for pid in [CAT_PID, NIT_PID, SDT_PID, TOT_PID] {
+            self.keep[pid as usize] = true;
+        }
+        for &pid in &self.emm_pids {
+            self.keep[pid as usize & 0x1FFF] = true;
+        }
+        if self.selection.epg {
+            for pid in EIT_PIDS {
+                self.keep[pid as usize] = true;
+            }
+        }
+        for &(_, pmt_pid) in &self.selected {
+            self.keep[pmt_pid as usize] = true;
+        }
+        for pmt in self.pmts.values() {
+            let pids = pmt
+                .streams
+                .iter()
+                .map(|s| s.pid)
+                .chain(pmt.ecm_pids())
+                .chain(std::iter::once(pmt.pcr_pid));
+            for pid in pids {
+                self.keep[pid as usize & 0x1FFF] = true;
+            }
+        }
+    }
+}
+
+impl PacketStage for ServiceFilter {
+    fn process(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let packet = Packet::new(packet);
+        let pid = packet.pid();
+
+        if pid == PAT_PID {
+            let mut pats = Vec::new();
+            self.pat_buffer.push(packet, |s| {
+                if let Some(pat) = Section::parse(s)
+                    .filter(|s| s.current_next())
+                    .and_then(|s| Pat::parse(&s))
+                {
+                    pats.push(pat);
+                }
+            });
+            for pat in pats {
+                self.on_pat(pat, out);
+            }
+            return;
+        }
+
+        if !self.keep[pid as usize] {
+            self.dropped += 1;
+            return;
+        }
+        if pid == CAT_PID {
+            let mut cats = Vec::new();
+            self.cat_buffer.push(packet, |s| {
+                if let Some(section) =
+                    Section::parse(s).filter(|s| s.current_next() && s.table_id() == TABLE_CAT)
+                {
+                    cats.push(ca_descriptors(section.body()).map(|(_, pid)| pid).collect());
+                }
+            });
+            for emm_pids in cats {
+                self.on_cat(emm_pids);
+            }
+        } else if self.selected.iter().any(|s| s.1 == pid) {
+            let mut pmts = Vec::new();
+            self.pmt_buffers.entry(pid).or_default().push(packet, |s| {
+                if let Some(pmt) = Section::parse(s)
+                    .filter(|s| s.current_next())
+                    .and_then(|s| Pmt::parse(&s))
+                {
+                    pmts.push(pmt);
+                }
+            });
+            for pmt in pmts {
+                self.on_pmt(pid, pmt);
+            }
+        }
+        self.kept += 1;
+        out.extend_from_slice(packet.as_bytes());
+    }
+}
+
+impl Drop for ServiceFilter {
+    fn drop(&mut self) {
+        info!(
+            "Service extraction ({}): kept {} packets, dropped {} packets.",
+            self.selection, self.kept, self.dropped
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::packet::TS_PACKET_SIZE;
+    use crate::ts::psi::TABLE_PMT;
+
+    fn pat(version: u8, services: &[(u16, u16)]) -> Vec<u8> {
+        let pat = Pat {
+            transport_stream_id: 0x7FE0,
+            version,
+            programs: [(0, NIT_PID)].iter().chain(services).copied().collect(),
+        };
+        let mut out = Vec::new();
+        psi::packetize(PAT_PID, &pat.to_section(), &mut 0, &mut out);
+        out
+    }
+
+    /// A PMT with an ECM on `ecm` and the PCR on the first of `pids`.
+    fn pmt(sid: u16, pmt_pid: u16, version: u8, ecm: u16, pids: &[u16]) -> Vec<u8> {
+        let ca = [0x09, 4, 0x00, 0x05, 0xE0 | (ecm >> 8) as u8, ecm as u8];
+        let mut body = vec![
+            0xE0 | (pids[0] >> 8) as u8,
+            pids[0] as u8,
+            0xF0,
+            ca.len() as u8,
+        ];
+        body.extend_from_slice(&ca);
+        for &pid in pids {
+            body.extend_from_slice(&[0x02, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0]);
+        }
+        let mut out = Vec::new();
+        let section = psi::build_section(TABLE_PMT, sid, version, &body);
+        // Successive versions need successive continuity counters
+        psi::packetize(pmt_pid, &section, &mut version.clone(), &mut out);
+        out
+    }
+
+    fn cat(version: u8, emm: u16) -> Vec<u8> {
+        let ca = [0x09, 4, 0x00, 0x05, 0xE0 | (emm >> 8) as u8, emm as u8];
+        let mut out = Vec::new();
+        let section = psi::build_section(TABLE_CAT, 0xFFFF, version, &ca);
+        psi::packetize(CAT_PID, &section, &mut version.clone(), &mut out);
+        out
+    }
+
+    fn es(pid: u16) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10]);
+        p
+    }
+
+    fn pids(out: &[u8]) -> Vec<u16> {
+        out.chunks(TS_PACKET_SIZE)
+            .map(|p| Packet::new(p).pid())
+            .collect()
+    }
+
+    fn run(filter: &mut ServiceFilter, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for p in packets {
+            filter.process(p, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        let selection: ServiceSelection = "hd, 1024 ,1seg,epg".parse().unwrap();
+        assert_eq!(
+            selection.services,
+            vec![
+                ServiceSelector::Nth(1),
+                ServiceSelector::Id(1024),
+                ServiceSelector::OneSeg
+            ]
+        );
+        assert!(selection.epg);
+        assert_eq!(selection.to_string(), "sd1,1024,1seg,epg");
+        assert!("foo".parse::<ServiceSelection>().is_err());
+        assert!("".parse::<ServiceSelection>().is_err());
+    }
+
+    #[test]
+    fn test_extract_service() {
+        let services = [(0x400, 0x1F0), (0x401, 0x1F1), (0x5A8, 0x1FC8)];
+        let mut filter = ServiceFilter::new("sd2".parse().unwrap());
+        let out = run(
+            &mut filter,
+            &[
+                es(0x111),
+                pat(0, &services),
+                pmt(0x400, 0x1F0, 0, 0x901, &[0x111, 0x112]),
+                pmt(0x401, 0x1F1, 0, 0x901, &[0x121, 0x122]),
+                es(0x111),
+                es(0x121),
+                es(0x122),
+                es(0x901),
+                es(EIT_PIDS[0]),
+                es(SDT_PID),
+            ],
+        );
+        assert_eq!(
+            pids(&out),
+            vec![PAT_PID, 0x1F1, 0x121, 0x122, 0x901, SDT_PID]
+        );
+
+        // The PAT only lists the NIT and the selected service
+        let mut rewritten = None;
+        SectionBuffer::default().push(Packet::new(&out[..TS_PACKET_SIZE]), |s| {
+            rewritten = Section::parse(s).and_then(|s| Pat::parse(&s));
+        });
+        assert_eq!(
+            rewritten.unwrap().programs,
+            vec![(0, NIT_PID), (0x401, 0x1F1)]
+        );
+    }
+
+    #[test]
+    fn test_follow_pmt_update() {
+        let services = [(0x400, 0x1F0)];
+        let mut filter = ServiceFilter::new("1024,epg".parse().unwrap());
+        let out = run(
+            &mut filter,
+            &[
+                pat(0, &services),
+                pmt(0x400, 0x1F0, 0, 0x901, &[0x111]),
+                es(0x111),
+                es(0x112),
+                es(EIT_PIDS[0]),
+                pmt(0x400, 0x1F0, 1, 0x901, &[0x112]),
+                es(0x111),
+                es(0x112),
+            ],
+        );
+        assert_eq!(
+            pids(&out),
+            vec![PAT_PID, 0x1F0, 0x111, EIT_PIDS[0], 0x1F0, 0x112]
+        );
+    }
+
+    #[test]
+    fn test_keep_emm_pids() {
+        let services = [(0x400, 0x1F0)];
+        let mut filter = ServiceFilter::new("1024".parse().unwrap());
+        let out = run(
+            &mut filter,
+            &[
+                pat(0, &services),
+                es(0x902),
+                cat(0, 0x902),
+                es(0x902),
+                cat(1, 0x903),
+                es(0x902),
+                es(0x903),
+            ],
+        );
+        assert_eq!(pids(&out), vec![PAT_PID, CAT_PID, 0x902, CAT_PID, 0x903]);
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone};
+
+use crate::ts::aribstr;
+use crate::ts::psi::{descriptors, Section};
+
+pub(crate) const TABLE_NIT_ACTUAL: u8 = 0x40;
+pub(crate) const TABLE_SDT_ACTUAL: u8 = 0x42;
+pub(crate) const TABLE_SDT_OTHER: u8 = 0x46;
+pub(crate) const TABLE_EIT_PF_ACTUAL: u8 = 0x4E;
+pub(crate) const TABLE_EIT_PF_OTHER: u8 = 0x4F;
+/// EIT schedule tables, both actual (0x50-0x5F) and other (0x60-0x6F).
+pub(crate) const TABLE_EIT_SCHEDULE: std::ops::RangeInclusive<u8> = 0x50..=0x6F;
+pub(crate) const TABLE_EIT_SCHEDULE_ACTUAL: std::ops::RangeInclusive<u8> = 0x50..=0x5F;
+
+const NETWORK_NAME_DESCRIPTOR: u8 = 0x40;
+const SERVICE_DESCRIPTOR: u8 = 0x48;
+
+/// Japan Standard Time, in which ARIB streams carry all dates.
+pub(crate) fn jst() -> FixedOffset {
+    FixedOffset::east_opt(9 * 3600).unwrap()
+}
+
+fn bcd(b: u8) -> Option<u32> {
+    let (hi, lo) = (b >> 4, b & 0x0F);
+    if hi > 9 || lo > 9 {
+        return None;
+    }
+    Some((hi * 10 + lo) as u32)
+}
+
+/// Decode a 40-bit field of an MJD date and a BCD time in JST.
+/// Returns `None` if the field is undefined (all ones) or malformed.
+pub(crate) fn parse_jst(bytes: &[u8]) -> Option<DateTime<FixedOffset>> {
+    if bytes.len() < 5 || bytes[..5].iter().all(|&b| b == 0xFF) {
+        return None;
+    }
+    let mjd = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let date = NaiveDate::from_ymd_opt(1858, 11, 17)?
+        .checked_add_signed(chrono::Duration::days(mjd as i64))?;
+    let time = date.and_hms_opt(bcd(bytes[2])?, bcd(bytes[3])?, bcd(bytes[4])?)?;
+    jst().from_local_datetime(&time).single()
+}
+
+/// Decode a 24-bit BCD duration (hhmmss).
+/// Returns `None` if the field is undefined (all ones) or malformed.
+pub(crate) fn parse_bcd_duration(bytes: &[u8]) -> Option<Duration> {
+    if bytes.len() < 3 || bytes[..3].iter().all(|&b| b == 0xFF) {
+        return None;
+    }
+    let secs = bcd(bytes[0])? * 3600 + bcd(bytes[1])? * 60 + bcd(bytes[2])?;
+    Some(Duration::from_secs(secs as u64))
+}
+
+/// An event listed in an EIT section.
+pub(crate) struct EitEvent<'a> {
+    pub event_id: u16,
+    pub start: Option<DateTime<FixedOffset>>,
+    pub duration: Option<Duration>,
+    pub free_ca: bool,
+    pub descriptors: &'a [u8],
+}
+
+/// Event Information Table (a single section).
+pub(crate) struct Eit<'a> {
+    pub service_id: u16,
+    pub transport_stream_id: u16,
+    pub original_network_id: u16,
+    pub events: Vec<EitEvent<'a>>,
+}
+
+impl<'a> Eit<'a> {
+    pub fn parse(section: &Section<'a>) -> Option<Self> {
+        let table_id = section.table_id();
+        let is_eit = matches!(table_id, TABLE_EIT_PF_ACTUAL | TABLE_EIT_PF_OTHER)
+            || TABLE_EIT_SCHEDULE.contains(&table_id);
+        let body = section.body();
+        if !is_eit || !section.is_long() || body.len() < 6 {
+            return None;
+        }
+
+        let mut events = Vec::new();
+        let mut rest = &body[6..];
+        while rest.len() >= 12 {
+            let len = u16::from_be_bytes([rest[10] & 0x0F, rest[11]]) as usize;
+            let descriptors = rest.get(12..12 + len)?;
+            events.push(EitEvent {
+                event_id: u16::from_be_bytes([rest[0], rest[1]]),
+                start: parse_jst(&rest[2..7]),
+                duration: parse_bcd_duration(&rest[7..10]),
+                free_ca: rest[10] & 0x10 != 0,
+                descriptors,
+            });
+            rest = &rest[12 + len..];
+        }
+
+        Some(Self {
+            service_id: section.table_id_extension(),
+            transport_stream_id: u16::from_be_bytes([body[0], body[1]]),
+            original_network_id: u16::from_be_bytes([body[2], body[3]]),
+            events,
+        })
+    }
+}
+
+/// A service listed in the SDT.
+pub(crate) struct SdtService {
+    pub service_id: u16,
+    pub running_status: u8,
+    pub name: String,
+    /// The whole service descriptor, including the tag and the length.
+    pub service_descriptor: Vec<u8>,
+}
+
+/// Service Description Table (a single section).
+pub(crate) struct Sdt {
+    pub transport_stream_id: u16,
+    pub original_network_id: u16,
+    pub services: Vec<SdtService>,
+}
+
+impl Sdt {
+    pub fn parse(section: &Section) -> Option<Self> {
+        let table_id = section.table_id();
+        let body = section.body();
+        if !matches!(table_id, TABLE_SDT_ACTUAL | TABLE_SDT_OTHER)
+            || !section.is_long()
+            || body.len() < 3
+        {
+            return None;
+        }
+
+        let mut services = Vec::new();
+        let mut rest = &body[3..];
+        while rest.len() >= 5 {
+            let len = u16::from_be_bytes([rest[3] & 0x0F, rest[4]]) as usize;
+            let service_descriptor = descriptors(rest.get(5..5 + len)?)
+                .find(|&(tag, data)| tag == SERVICE_DESCRIPTOR && data.len() >= 2);
+            if let Some((_, data)) = service_descriptor {
+                // service_type, provider name and service name
+                let provider_len = data[1] as usize;
+                let name = data
+                    .get(2 + provider_len)
+                    .and_then(|&n| data.get(3 + provider_len..3 + provider_len + n as usize))
+                    .map(aribstr::decode)
+                    .unwrap_or_default();
+                services.push(SdtService {
+                    service_id: u16::from_be_bytes([rest[0], rest[1]]),
+                    running_status: rest[3] >> 5,
+                    name,
+                    service_descriptor: [&[SERVICE_DESCRIPTOR, data.len() as u8], data].concat(),
+                });
+            }
+            rest = &rest[5 + len..];
+        }
+
+        Some(Self {
+            transport_stream_id: section.table_id_extension(),
+            original_network_id: u16::from_be_bytes([body[0], body[1]]),
+            services,
+        })
+    }
+}
+
+/// Network Information Table of the actual network (a single section).
+pub(crate) struct Nit {
+    pub network_id: u16,
+    pub name: Option<String>,
+    /// Pairs of transport stream ID and original network ID.
+    pub transport_streams: Vec<(u16, u16)>,
+}
+
+impl Nit {
+    pub fn parse(section: &Section) -> Option<Self> {
+        let body = section.body();
+        if section.table_id() != TABLE_NIT_ACTUAL || !section.is_long() || body.len() < 2 {
+            return None;
+        }
+        let len = u16::from_be_bytes([body[0] & 0x0F, body[1]]) as usize;
+        let name = descriptors(body.get(2..2 + len)?)
+            .find(|&(tag, _)| tag == NETWORK_NAME_DESCRIPTOR)
+            .map(|(_, data)| aribstr::decode(data));
+
+        let mut transport_streams = Vec::new();
+        let mut rest = body.get(2 + len + 2..)?;
+        while rest.len() >= 6 {
+            transport_streams.push((
+                u16::from_be_bytes([rest[0], rest[1]]),
+                u16::from_be_bytes([rest[2], rest[3]]),
+            ));
+            let len = u16::from_be_bytes([rest[4] & 0x0F, rest[5]]) as usize;
+            rest = rest.get(6 + len..)?;
+        }
+
+        Some(Self {
+            network_id: section.table_id_extension(),
+            name,
+            transport_streams,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_jst() {
+        // 2024-01-01 12:34:56 JST
+        let t = parse_jst(&[0xEB, 0x96, 0x12, 0x34, 0x56]).unwrap();
+        assert_eq!(t.to_rfc3339(), "2024-01-01T12:34:56+09:00");
+        assert!(parse_jst(&[0xFF; 5]).is_none());
+        assert_eq!(
+            parse_bcd_duration(&[0x01, 0x30, 0x00]),
+            Some(Duration::from_secs(5400))
+        );
+        assert!(parse_bcd_duration(&[0xFF; 3]).is_none());
+    }
+}
@@ -0,0 +1,734 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike};
+use log::{error, info};
+
+use crate::io::OutputCounter;
+use crate::ts::packet::{Framer, Packet, TS_PACKET_SIZE};
+use crate::ts::psi::{self, Pat, Pmt, Section, SectionBuffer, EIT_PIDS, PAT_PID};
+use crate::ts::si::{Eit, TABLE_EIT_PF_ACTUAL};
+use crate::ts::{BroadcastClock, M2tsWriter, OutputFormat};
+
+/// Packets held before the first file, while waiting for the PAT and the PMT.
+const MAX_PENDING: usize = 100_000;
+/// Packets held while a PMT section is incomplete.
+const MAX_HELD: usize = 1_000;
+
+/// The template used when the output of a split recording is a directory.
+pub(crate) const DEFAULT_SPLIT_TEMPLATE: &str = "%Y%m%d-%H%M%S_{n}.{ext}";
+
+/// An event in the stream that starts a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum SplitTrigger {
+    /// The present event in the EIT changes.
+    Event,
+    /// The version or the ES composition of the PMT changes.
+    Pmt,
+}
+
+/// A cron-like schedule of five fields: minute, hour, day of month, month
+/// and day of week (0 or 7 is Sunday). Each field is `*` or a
+/// comma-separated list of values and ranges, optionally with a step such as
+/// `*/30` or `0-30/10`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Schedule {
+    fields: [u64; 5],
+    /// Whether the day of month and the day of week are `*`.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const SCHEDULE_RANGES: [(u32, u32); 5] = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 7)];
+
+fn parse_field(field: &str, (min, max): (u32, u32)) -> Result<u64, String> {
+    let mut mask = 0u64;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| format!("invalid step '{}'", step))?,
+            ),
+            None => (item, 1),
+        };
+        let value = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("'{}' is out of {}-{}", s, min, max))
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        for v in (from..=to).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let items: Vec<&str> = s.split_whitespace().collect();
+        if items.len() != 5 {
+            return Err("a schedule has 5 fields: minute hour day month weekday".to_string());
+        }
+        let mut fields = [0; 5];
+        for (i, item) in items.iter().enumerate() {
+            fields[i] = parse_field(item, SCHEDULE_RANGES[i])?;
+        }
+        // Sunday is either 0 or 7
+        if fields[4] & 1 << 7 != 0 {
+            fields[4] |= 1;
+        }
+        Ok(Self {
+            fields,
+            any_day: items[2] == "*",
+            any_weekday: items[4] == "*",
+        })
+    }
+}
+
+impl Schedule {
+    fn matches(&self, t: &DateTime<FixedOffset>) -> bool {
+        let has = |i: usize, v: u32| self.fields[i] & 1 << v != 0;
+        let day = has(2, t.day());
+        let weekday = has(4, t.weekday().num_days_from_sunday());
+        // As in cron, either day matches if both are restricted
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(0, t.minute()) && has(1, t.hour()) && has(3, t.month()) && day_matches
+    }
+}
+
+/// A template of the file names of a split recording.
+///
+/// The template is formatted with `strftime` on the time the file is
+/// created, after `{n}`, `{sid}`, `{event_id}` and `{ext}` are replaced with
+/// the sequence number of the file from 1, the service ID, the ID of the
+/// present event and the extension of the output format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileTemplate(String);
+
+impl FromStr for FileTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if StrftimeItems::new(s).any(|item| item == Item::Error) {
+            return Err(format!("invalid format in '{}'", s));
+        }
+        Ok(FileTemplate(s.to_string()))
+    }
+}
+
+impl fmt::Display for FileTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FileTemplate {
+    fn render(
+        &self,
+        time: &DateTime<FixedOffset>,
+        n: u32,
+        service_id: Option<u16>,
+        event_id: Option<u16>,
+        ext: &str,
+    ) -> PathBuf {
+        let id = |id: Option<u16>| id.map_or("none".to_string(), |id| id.to_string());
+        let s = self
+            .0
+            .replace("{n}", &n.to_string())
+            .replace("{sid}", &id(service_id))
+            .replace("{event_id}", &id(event_id))
+            .replace("{ext}", ext);
+        PathBuf::from(time.format(&s).to_string())
+    }
+}
+
+type Opener = Box<dyn FnMut(&Path) -> io::Result<Box<dyn Write + Send>> + Send>;
+
+/// Writes the output into a series of files, starting a new file when the
+/// present event in the EIT changes, when the version or the ES composition
+/// of the PMT changes, or on a schedule. Each new file starts with the PAT
+/// and the PMT, so that it can be played on its own. The first file is
+/// opened once both have been received, holding back the packets before.
+///
+/// The service followed is the one given, or the first one in the PAT. The
+/// times in the schedule and in the file names are on the broadcast time if
+/// `clock` is given and has been set, or the local time otherwise.
+pub(crate) struct SplitWriter {
+    template: FileTemplate,
+    format: OutputFormat,
+    triggers: Vec<SplitTrigger>,
+    schedule: Option<Schedule>,
+    clock: Option<BroadcastClock>,
+    open: Opener,
+    inner: Option<Box<dyn Write + Send>>,
+    files: u32,
+    framer: Framer,
+    packets: Vec<u8>,
+    /// The packets received before the first file.
+    pending: Vec<u8>,
+    /// The packets since a PMT section has started, and the reason to split
+    /// before them, decided once it is complete.
+    held: Vec<u8>,
+    split: Option<&'static str>,
+
+    service_id: Option<u16>,
+    pat_buffer: SectionBuffer,
+    pat: Option<Vec<u8>>,
+    pmt_pid: Option<u16>,
+    pmt_buffer: SectionBuffer,
+    pmt: Option<Vec<u8>>,
+    /// The version and the ES composition of the PMT.
+    pmt_key: Option<(u8, Vec<(u8, u16)>)>,
+    eit_buffer: SectionBuffer,
+    event_id: Option<u16>,
+    /// The last continuity counters of the PAT and the PMT.
+    cc: HashMap<u16, u8>,
+    /// The minute of the schedule last checked.
+    minute: Option<i64>,
+}
+
+impl SplitWriter {
+    pub fn new(
+        template: FileTemplate,
+        format: OutputFormat,
+        triggers: Vec<SplitTrigger>,
+        schedule: Option<Schedule>,
+        service_id: Option<u16>,
+        clock: Option<BroadcastClock>,
+        counter: OutputCounter,
+    ) -> Self {
+        Self {
+            template,
+            format,
+            triggers,
+            schedule,
+            clock,
+            open: Box::new(move |path| {
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent)?;
+                }
+                let file = counter.wrap(Box::new(fs::File::create(path)?));
+                Ok(match format {
+                    OutputFormat::Ts => file,
+                    OutputFormat::M2ts => Box::new(M2tsWriter::new(file)),
+                })
+            }),
+            inner: None,
+            files: 0,
+            framer: Framer::new(),
+            packets: Vec::new(),
+            pending: Vec::new(),
+            held: Vec::new(),
+            split: None,
+            service_id,
+            pat_buffer: SectionBuffer::default(),
+            pat: None,
+            pmt_pid: None,
+            pmt_buffer: SectionBuffer::default(),
+            pmt: None,
+            pmt_key: None,
+            eit_buffer: SectionBuffer::default(),
+            event_id: None,
+            cc: HashMap::new(),
+            minute: None,
+        }
+    }
+
+    fn now(&self) -> DateTime<FixedOffset> {
+        self.clock
+            .as_ref()
+            .and_then(BroadcastClock::now)
+            .unwrap_or_else(|| {
+                let now = chrono::Local::now();
+                now.with_timezone(now.offset())
+            })
+    }
+
+    /// Close the current file, and open the next one.
+    fn next_file(&mut self, reason: &str) -> io::Result<()> {
+        if let Some(mut inner) = self.inner.take() {
+            inner.flush()?;
+        }
+        self.files += 1;
+        let path = self.template.render(
+            &self.now(),
+            self.files,
+            self.service_id,
+            self.event_id,
+            self.format.extension(),
+        );
+        // Never overwrite the files of the recording
+        let mut unique = path.clone();
+        let mut k = 1;
+        while unique.exists() {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match path.extension() {
+                Some(ext) => format!("{}_{}.{}", stem, k, ext.to_string_lossy()),
+                None => format!("{}_{}", stem, k),
+            };
+            unique = path.with_file_name(name);
+            k += 1;
+        }
+        info!("Output ({}): {}", reason, unique.display());
+        let mut inner = (self.open)(&unique)?;
+
+        // Start with the PAT and the PMT, with the continuity counters
+        // leading to the next packets
+        let mut head = Vec::new();
+        for (pid, section) in [(Some(PAT_PID), &self.pat), (self.pmt_pid, &self.pmt)] {
+            if let (Some(pid), Some(section), Some(&last)) =
+                (pid, section, pid.and_then(|pid| self.cc.get(&pid)))
+            {
+                let count = (section.len() + 1 + TS_PACKET_SIZE - 5) / (TS_PACKET_SIZE - 4);
+                let mut cc = last.wrapping_sub(count as u8 - 1) & 0x0F;
+                psi::packetize(pid, section, &mut cc, &mut head);
+            }
+        }
+        inner.write_all(&head)?;
+        self.inner = Some(inner);
+        Ok(())
+    }
+
+    /// Read the PSI/SI in `packet`, returning the reason to split before it.
+    fn inspect(&mut self, packet: &[u8]) -> Option<&'static str> {
+        let p = Packet::new(packet);
+        let pid = p.pid();
+        let mut reason = None;
+
+        let mut sections = Vec::new();
+        if pid == PAT_PID {
+            self.pat_buffer.push(p, |s| sections.push(s.to_vec()));
+        } else if Some(pid) == self.pmt_pid {
+            self.pmt_buffer.push(p, |s| sections.push(s.to_vec()));
+        } else if pid == EIT_PIDS[0] && self.triggers.contains(&SplitTrigger::Event) {
+            self.eit_buffer.push(p, |s| sections.push(s.to_vec()));
+        }
+        for bytes in sections {
+            let section = match Section::parse(&bytes).filter(|s| s.current_next()) {
+                Some(section) => section,
+                None => continue,
+            };
+            if pid == PAT_PID {
+                if let Some(pat) = Pat::parse(&section) {
+                    let found = pat
+                        .services()
+                        .find(|&(sid, _)| self.service_id.map_or(true, |id| id == sid));
+                    if let Some((sid, pmt_pid)) = found {
+                        self.service_id = Some(sid);
+                        self.pmt_pid = Some(pmt_pid);
+                    }
+                    self.pat = Some(bytes.clone());
+                }
+            } else if Some(pid) == self.pmt_pid {
+                let pmt = match Pmt::parse(&section) {
+                    Some(pmt) if Some(pmt.program_number) == self.service_id => pmt,
+                    _ => continue,
+                };
+                let key = (
+                    pmt.version,
+                    pmt.streams.iter().map(|s| (s.stream_type, s.pid)).collect(),
+                );
+                if matches!(&self.pmt_key, Some(last) if *last != key)
+                    && self.triggers.contains(&SplitTrigger::Pmt)
+                {
+                    reason = Some("PMT changed");
+                }
+                self.pmt_key = Some(key);
+                self.pmt = Some(bytes.clone());
+            } else if section.table_id() == TABLE_EIT_PF_ACTUAL && section.section_number() == 0 {
+                let eit = match Eit::parse(&section) {
+                    Some(eit) if Some(eit.service_id) == self.service_id => eit,
+                    _ => continue,
+                };
+                let event_id = eit.events.first().map(|e| e.event_id);
+                if event_id.is_some() && event_id != self.event_id {
+                    if self.event_id.is_some() {
+                        reason = Some("event changed");
+                    }
+                    self.event_id = event_id;
+                }
+            }
+        }
+
+        if let Some(schedule) = &self.schedule {
+            let now = self.now();
+            let minute = now.timestamp().div_euclid(60);
+            if self.minute.map_or(false, |m| m != minute) && schedule.matches(&now) {
+                reason = reason.or(Some("schedule"));
+            }
+            self.minute = Some(minute);
+        }
+        reason
+    }
+
+    fn push(&mut self, packet: &[u8]) -> io::Result<()> {
+        let reason = self.inspect(packet);
+        if self.inner.is_none() {
+            self.pending.extend_from_slice(packet);
+            let ready = self.pat.is_some() && self.pmt.is_some();
+            if ready || self.pending.len() >= MAX_PENDING * TS_PACKET_SIZE {
+                self.start()?;
+            }
+            return Ok(());
+        }
+        self.split = self.split.or(reason);
+        self.held.extend_from_slice(packet);
+        // The first packets of a PMT go to the file it may start
+        if self.pmt_buffer.is_pending() && self.held.len() < MAX_HELD * TS_PACKET_SIZE {
+            return Ok(());
+        }
+        self.release()
+    }
+
+    /// Write the held packets, to the next file if they are to split.
+    fn release(&mut self) -> io::Result<()> {
+        let held = std::mem::take(&mut self.held);
+        match self.split.take() {
+            Some(reason) => self.open_with(reason, &held),
+            None => {
+                held.chunks_exact(TS_PACKET_SIZE)
+                    .for_each(|packet| self.track_cc(packet));
+                self.inner.as_mut().unwrap().write_all(&held)
+            }
+        }
+    }
+
+    /// Open the first file with the packets held so far.
+    fn start(&mut self) -> io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.open_with("start", &pending)
+    }
+
+    /// Open the next file with `packets`, whose PAT and PMT are replaced by
+    /// those the file starts with.
+    fn open_with(&mut self, reason: &str, packets: &[u8]) -> io::Result<()> {
+        packets
+            .chunks_exact(TS_PACKET_SIZE)
+            .for_each(|packet| self.track_cc(packet));
+        self.next_file(reason)?;
+        let mut rest = Vec::with_capacity(packets.len());
+        for packet in packets.chunks_exact(TS_PACKET_SIZE) {
+            let pid = Packet::new(packet).pid();
+            let in_head = (pid == PAT_PID && self.pat.is_some())
+                || (Some(pid) == self.pmt_pid && self.pmt.is_some());
+            if !in_head {
+                rest.extend_from_slice(packet);
+            }
+        }
+        self.inner.as_mut().unwrap().write_all(&rest)
+    }
+
+    fn track_cc(&mut self, packet: &[u8]) {
+        let p = Packet::new(packet);
+        if p.pid() == PAT_PID || Some(p.pid()) == self.pmt_pid {
+            self.cc.insert(p.pid(), p.continuity_counter());
+        }
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.framer.feed(data, &mut self.packets);
+        let packets = std::mem::take(&mut self.packets);
+        let result = packets
+            .chunks_exact(TS_PACKET_SIZE)
+            .try_for_each(|packet| self.push(packet));
+        self.packets = packets;
+        self.packets.clear();
+        result.map(|_| data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SplitWriter {
+    fn drop(&mut self) {
+        self.framer.finish(&mut self.packets);
+        let packets = std::mem::take(&mut self.packets);
+        let result = packets
+            .chunks_exact(TS_PACKET_SIZE)
+            .try_for_each(|packet| self.push(packet))
+            .and_then(|_| match self.inner {
+                None if !self.pending.is_empty() => self.start(),
+                Some(_) if !self.held.is_empty() => self.release(),
+                _ => Ok(()),
+            })
+            .and_then(|_| self.flush());
+        if let Err(e) = result {
+            error!("Failed to write the last packets: {}", e);
+        }
+        info!("The recording was split into {} files.", self.files);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::psi::{build_section, TABLE_PMT};
+    use std::sync::{Arc, Mutex};
+
+    type Files = Arc<Mutex<Vec<(PathBuf, Arc<Mutex<Vec<u8>>>)>>>;
+
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn writer(triggers: Vec<SplitTrigger>) -> (SplitWriter, Files) {
+        let files: Files = Arc::default();
+        let mut writer = SplitWriter::new(
+            "rec/{n}_{sid}_{event_id}.{ext}".parse().unwrap(),
+            OutputFormat::Ts,
+            triggers,
+            None,
+            None,
+            None,
+            OutputCounter::default(),
+        );
+        let opened = files.clone();
+        writer.open = Box::new(move |path| {
+            let data = Arc::new(Mutex::new(Vec::new()));
+            opened
+                .lock()
+                .unwrap()
+                .push((path.to_path_buf(), data.clone()));
+            Ok(Box::new(Sink(data)))
+        });
+        (writer, files)
+    }
+
+    fn pmt(version: u8, pids: &[u16], cc: &mut u8) -> Vec<u8> {
+        let mut body = vec![0xE1, 0x11, 0xF0, 0x00];
+        for &pid in pids {
+            body.extend_from_slice(&[0x02, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0]);
+        }
+        let mut out = Vec::new();
+        psi::packetize(
+            0x1F0,
+            &build_section(TABLE_PMT, 0x400, version, &body),
+            cc,
+            &mut out,
+        );
+        out
+    }
+
+    /// An EIT present/following section 0 with `event_id` as present.
+    fn eit(event_id: u16, cc: &mut u8) -> Vec<u8> {
+        let mut body = vec![0x7F, 0xE0, 0x7F, 0xE0, 0x01, TABLE_EIT_PF_ACTUAL];
+        body.extend_from_slice(&event_id.to_be_bytes());
+        body.extend_from_slice(&[0xEB, 0x96, 0x21, 0x00, 0x00, 0x00, 0x30, 0x00, 0x80, 0x00]);
+        let mut out = Vec::new();
+        let section = build_section(TABLE_EIT_PF_ACTUAL, 0x400, 0, &body);
+        psi::packetize(EIT_PIDS[0], &section, cc, &mut out);
+        out
+    }
+
+    fn es(pid: u16, cc: u8) -> Vec<u8> {
+        let mut p = vec![0xFFu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10 | cc]);
+        p
+    }
+
+    fn pids(data: &[u8]) -> Vec<(u16, u8)> {
+        data.chunks(TS_PACKET_SIZE)
+            .map(Packet::new)
+            .map(|p| (p.pid(), p.continuity_counter()))
+            .collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let pat = Pat {
+            transport_stream_id: 1,
+            version: 0,
+            programs: vec![(0x400, 0x1F0)],
+        };
+        let (mut pat_cc, mut pmt_cc, mut eit_cc) = (0, 0, 0);
+        let mut stream = Vec::new();
+        psi::packetize(PAT_PID, &pat.to_section(), &mut pat_cc, &mut stream);
+        stream.extend(pmt(0, &[0x111], &mut pmt_cc));
+        stream.extend(eit(0x1234, &mut eit_cc));
+        stream.extend(es(0x111, 0));
+        // The same event, and the next event
+        stream.extend(eit(0x1234, &mut eit_cc));
+        stream.extend(es(0x111, 1));
+        stream.extend(eit(0x1235, &mut eit_cc));
+        stream.extend(es(0x111, 2));
+        // A stream is added to the PMT
+        stream.extend(pmt(1, &[0x111, 0x112], &mut pmt_cc));
+        stream.extend(es(0x112, 0));
+
+        let (mut writer, files) = writer(vec![SplitTrigger::Event, SplitTrigger::Pmt]);
+        for piece in stream.chunks(100) {
+            writer.write_all(piece).unwrap();
+        }
+        drop(writer);
+
+        let files = files.lock().unwrap();
+        let names: Vec<&Path> = files.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(
+            names,
+            [
+                Path::new("rec/1_1024_none.ts"),
+                Path::new("rec/2_1024_4661.ts"),
+                Path::new("rec/3_1024_4661.ts")
+            ]
+        );
+        let contents: Vec<Vec<(u16, u8)>> = files
+            .iter()
+            .map(|(_, data)| pids(&data.lock().unwrap()))
+            .collect();
+        assert_eq!(
+            contents[0],
+            [
+                (0, 0),
+                (0x1F0, 0),
+                (0x12, 0),
+                (0x111, 0),
+                (0x12, 1),
+                (0x111, 1)
+            ]
+        );
+        // Each file starts with the PAT and the PMT, continuous with the
+        // following packets
+        assert_eq!(contents[1], [(0, 0), (0x1F0, 0), (0x12, 2), (0x111, 2)]);
+        assert_eq!(contents[2], [(0, 0), (0x1F0, 1), (0x112, 0)]);
+    }
+
+    #[test]
+    fn test_split_long_pmt() {
+        let pat = Pat {
+            transport_stream_id: 1,
+            version: 0,
+            programs: vec![(0x400, 0x1F0)],
+        };
+        let (mut pat_cc, mut pmt_cc) = (0, 0);
+        let mut stream = Vec::new();
+        psi::packetize(PAT_PID, &pat.to_section(), &mut pat_cc, &mut stream);
+        stream.extend(pmt(0, &[0x111], &mut pmt_cc));
+        stream.extend(es(0x111, 0));
+        // A PMT of two packets, with a packet of another PID between them
+        let streams: Vec<u16> = (0x111..0x140).collect();
+        let long = pmt(1, &streams, &mut pmt_cc);
+        assert_eq!(long.len(), 2 * TS_PACKET_SIZE);
+        stream.extend(&long[..TS_PACKET_SIZE]);
+        stream.extend(es(0x111, 1));
+        stream.extend(&long[TS_PACKET_SIZE..]);
+        stream.extend(es(0x112, 0));
+
+        let (mut writer, files) = writer(vec![SplitTrigger::Pmt]);
+        writer.write_all(&stream).unwrap();
+        drop(writer);
+
+        let files = files.lock().unwrap();
+        assert_eq!(files.len(), 2);
+        let contents: Vec<Vec<(u16, u8)>> = files
+            .iter()
+            .map(|(_, data)| pids(&data.lock().unwrap()))
+            .collect();
+        assert_eq!(contents[0], [(0, 0), (0x1F0, 0), (0x111, 0)]);
+        // The whole new PMT goes to the file it starts
+        assert_eq!(
+            contents[1],
+            [(0, 0), (0x1F0, 1), (0x1F0, 2), (0x111, 1), (0x112, 0)]
+        );
+    }
+
+    #[test]
+    fn test_wait_for_pmt() {
+        let pat = Pat {
+            transport_stream_id: 1,
+            version: 0,
+            programs: vec![(0x400, 0x1F0)],
+        };
+        let (mut pat_cc, mut pmt_cc) = (0, 0);
+        let mut stream = es(0x111, 0);
+        psi::packetize(PAT_PID, &pat.to_section(), &mut pat_cc, &mut stream);
+        stream.extend(es(0x111, 1));
+
+        let (mut writer, files) = writer(vec![SplitTrigger::Pmt]);
+        writer.write_all(&stream).unwrap();
+        assert!(files.lock().unwrap().is_empty());
+
+        let mut rest = pmt(0, &[0x111], &mut pmt_cc);
+        rest.extend(es(0x111, 2));
+        writer.write_all(&rest).unwrap();
+        drop(writer);
+
+        let files = files.lock().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, Path::new("rec/1_1024_none.ts"));
+        // The file starts with the PAT and the PMT, before the packets
+        // held while waiting for them
+        assert_eq!(
+            pids(&files[0].1.lock().unwrap()),
+            [(0, 0), (0x1F0, 0), (0x111, 0), (0x111, 1), (0x111, 2)]
+        );
+    }
+
+    #[test]
+    fn test_no_pmt() {
+        let (mut writer, files) = writer(vec![SplitTrigger::Pmt]);
+        writer.write_all(&es(0x111, 0)).unwrap();
+        drop(writer);
+
+        // Written out at the end, without the PMT
+        let files = files.lock().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(pids(&files[0].1.lock().unwrap()), [(0x111, 0)]);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule: Schedule = "*/30 21-23 * * 1-5".parse().unwrap();
+        // 2024-01-01 is a Monday
+        let at = |hh: u8, mm: u8| crate::ts::si::parse_jst(&[0xEB, 0x96, hh, mm, 0x00]).unwrap();
+        assert!(schedule.matches(&at(0x21, 0x00)));
+        assert!(schedule.matches(&at(0x23, 0x30)));
+        assert!(!schedule.matches(&at(0x21, 0x15)));
+        assert!(!schedule.matches(&at(0x20, 0x30)));
+
+        let sunday: Schedule = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sunday.fields[4], 1 | 1 << 7);
+        let either: Schedule = "0 0 15 * 1".parse().unwrap();
+        assert!(either.matches(&at(0x00, 0x00)));
+
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("%Q".parse::<FileTemplate>().is_err());
+    }
+}
@@ -1,9 +1,11 @@
 use std::fs::File;
+use std::io::Write;
 use std::os::fd::{AsRawFd, RawFd};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
-use futures_util::io::{AllowStdIo, BufReader};
+use futures_util::io::BufReader;
 use futures_util::{AsyncBufRead, AsyncRead};
 use log::warn;
 
@@ -71,7 +73,7 @@ impl UnTunedTuner {
             // is dropped last, which terminates the reader thread.
             _lnb_capab: lnb_capab,
             ioctl_file,
-            inner: BufReader::with_capacity(self.buf_sz, AllowStdIo::new(reader)),
+            inner: BufReader::with_capacity(self.buf_sz, reader),
             channel: ch,
         })
     }
@@ -90,11 +92,19 @@ pub struct Tuner {
     // and can be used from the main thread concurrently with the reader
     // thread's read operations.
     ioctl_file: File,
-    inner: BufReader<AllowStdIo<ThreadedReader>>,
+    inner: BufReader<ThreadedReader>,
     channel: Channel,
 }
 
 impl Tuner {
+    pub fn set_arrival_index(&mut self, index: Box<dyn Write + Send>) -> std::io::Result<()> {
+        self.inner.get_mut().set_arrival_index(index)
+    }
+
+    pub fn last_arrival(&self) -> Option<Instant> {
+        self.inner.get_ref().last_arrival()
+    }
+
     pub fn signal_quality(&self) -> f64 {
         let raw = {
             let mut raw = [0i64; 1];
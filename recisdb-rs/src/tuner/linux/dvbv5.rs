@@ -12,14 +12,15 @@ use dvbv5_sys::{
     DTV_ISDBT_LAYER_ENABLED, DTV_ISDBT_PARTIAL_RECEPTION, DTV_ISDBT_SOUND_BROADCASTING, DTV_STATUS,
     DTV_STAT_CNR, DTV_STREAM_ID, DTV_VOLTAGE, NO_STREAM_ID_FILTER,
 };
-use futures_util::io::{AllowStdIo, BufReader};
+use futures_util::io::BufReader;
 use futures_util::{AsyncBufRead, AsyncRead};
 use log::{error, info, warn};
 use std::ffi::c_uint;
 use std::fs::File;
-use std::io::Error;
+use std::io::{Error, Write};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use super::threaded_reader::ThreadedReader;
 
@@ -192,7 +193,7 @@ impl UnTunedTuner {
         let dvr_file = File::open(format!("/dev/dvb/adapter{}/dvr{}", self.id.0, self.id.1))?;
         let reader = ThreadedReader::with_defaults(dvr_file)?;
         Ok(Tuner {
-            stream: BufReader::with_capacity(self.buf_sz, AllowStdIo::new(reader)),
+            stream: BufReader::with_capacity(self.buf_sz, reader),
             inner: self,
             state: TunedDvbInternalState::Locked,
         })
@@ -208,7 +209,7 @@ pub struct Tuner {
     // empty ring buffer, causing join() to deadlock. By dropping `stream`
     // first, the reader thread can exit (shutdown flag + receiver dropped)
     // while the device is still actively supplying data.
-    stream: BufReader<AllowStdIo<ThreadedReader>>,
+    stream: BufReader<ThreadedReader>,
     state: TunedDvbInternalState,
     inner: UnTunedTuner,
 }
@@ -219,6 +220,14 @@ pub enum TunedDvbInternalState {
 }
 
 impl Tuner {
+    pub fn set_arrival_index(&mut self, index: Box<dyn Write + Send>) -> std::io::Result<()> {
+        self.stream.get_mut().set_arrival_index(index)
+    }
+
+    pub fn last_arrival(&self) -> Option<Instant> {
+        self.stream.get_ref().last_arrival()
+    }
+
     pub fn signal_quality(&self) -> f64 {
         let p = self.inner.frontend.get_c_ptr();
         unsafe {
@@ -8,10 +8,12 @@ use nom::IResult;
 use std::io::Error;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 mod character_device;
 #[cfg(feature = "dvb")]
 mod dvbv5;
+mod spill;
 mod threaded_reader;
 
 pub enum UnTunedTuner {
@@ -65,6 +67,27 @@ impl Tuner {
             Tuner::Character(inner) => inner.signal_quality(),
         }
     }
+
+    /// Record the arrival time of every chunk read from the device to `index`.
+    pub fn set_arrival_index(
+        &mut self,
+        index: Box<dyn std::io::Write + Send>,
+    ) -> Result<(), Error> {
+        match self {
+            #[cfg(feature = "dvb")]
+            Tuner::DvbV5(inner) => inner.set_arrival_index(index),
+            Tuner::Character(inner) => inner.set_arrival_index(index),
+        }
+    }
+
+    /// The time the latest chunk was read from the device.
+    pub fn last_arrival(&self) -> Option<Instant> {
+        match self {
+            #[cfg(feature = "dvb")]
+            Tuner::DvbV5(inner) => inner.last_arrival(),
+            Tuner::Character(inner) => inner.last_arrival(),
+        }
+    }
 }
 
 impl Tunable for UnTunedTuner {
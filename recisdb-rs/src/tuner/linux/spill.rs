@@ -0,0 +1,211 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use super::threaded_reader::Chunk;
+
+/// Size of the header stored in front of each spilled chunk:
+/// the payload length and the arrival time.
+const RECORD_HEADER_SIZE: u64 = 12;
+
+/// Sequence number to keep spill file names unique within a process.
+static SPILL_FILE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Settings of the spill-to-disk overflow mode of `ThreadedReader`.
+#[derive(Debug, Clone)]
+pub(crate) struct SpillConfig {
+    /// Directory in which the temporary spill file is created.
+    pub dir: PathBuf,
+    /// Number of chunks waiting in the in-memory queue above which new
+    /// chunks are written to the spill file instead.
+    pub threshold: usize,
+    /// Maximum size of the spill file in bytes. Once reached, the reader
+    /// thread falls back to blocking on the in-memory queue.
+    pub max_bytes: u64,
+}
+
+/// A FIFO of chunks backed by a temporary file.
+///
+/// Each chunk is stored as a little-endian `u32` length and a `u64` arrival
+/// time in nanoseconds since `base`, followed by the payload. Chunks are
+/// appended at `write_pos` and read back in the same order from `read_pos`.
+/// The file is created lazily on the first spill, truncated whenever it has
+/// been fully drained, and removed on drop.
+pub(crate) struct Spill {
+    config: SpillConfig,
+    /// Reference point of the arrival times stored in the file.
+    base: Instant,
+    file: Option<(File, PathBuf)>,
+    read_pos: u64,
+    write_pos: u64,
+    chunks: usize,
+    /// Start of the current spill episode, for logging.
+    since: Option<Instant>,
+    /// Total bytes spilled during the current episode.
+    spilled: u64,
+    /// Whether the size cap has already been reported in this episode.
+    cap_reported: bool,
+}
+
+impl Spill {
+    pub fn new(config: SpillConfig, base: Instant) -> Self {
+        Self {
+            config,
+            base,
+            file: None,
+            read_pos: 0,
+            write_pos: 0,
+            chunks: 0,
+            since: None,
+            spilled: 0,
+            cap_reported: false,
+        }
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.config.threshold
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks == 0
+    }
+
+    /// Bytes currently waiting in the spill file.
+    pub fn backlog(&self) -> u64 {
+        self.write_pos - self.read_pos
+    }
+
+    /// Append a chunk to the spill file.
+    /// Returns `Ok(false)` without writing if the size cap would be exceeded.
+    pub fn push(&mut self, chunk: &Chunk) -> io::Result<bool> {
+        let record_size = RECORD_HEADER_SIZE + chunk.data.len() as u64;
+        if self.write_pos + record_size > self.config.max_bytes {
+            if !self.cap_reported {
+                warn!(
+                    "Spill file reached its size cap ({} bytes). Waiting for the downstream to catch up.",
+                    self.config.max_bytes
+                );
+                self.cap_reported = true;
+            }
+            return Ok(false);
+        }
+
+        if self.file.is_none() {
+            self.file = Some(self.create_file()?);
+        }
+        let (file, path) = self.file.as_ref().unwrap();
+        let arrival_ns = chunk
+            .arrival
+            .saturating_duration_since(self.base)
+            .as_nanos() as u64;
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+        header[4..].copy_from_slice(&arrival_ns.to_le_bytes());
+        file.write_all_at(&header, self.write_pos)?;
+        file.write_all_at(&chunk.data, self.write_pos + RECORD_HEADER_SIZE)?;
+
+        if self.since.is_none() {
+            info!(
+                "The downstream is stalling. Spilling tuner data to {}.",
+                path.display()
+            );
+            self.since = Some(Instant::now());
+        }
+        self.write_pos += record_size;
+        self.spilled += chunk.data.len() as u64;
+        self.chunks += 1;
+        Ok(true)
+    }
+
+    /// Take the oldest chunk out of the spill file.
+    pub fn pop(&mut self) -> io::Result<Option<Chunk>> {
+        if self.chunks == 0 {
+            return Ok(None);
+        }
+        let (file, _) = self.file.as_ref().unwrap();
+
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, self.read_pos)?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let arrival_ns = u64::from_le_bytes(header[4..].try_into().unwrap());
+        let mut data = vec![0u8; len];
+        file.read_exact_at(&mut data, self.read_pos + RECORD_HEADER_SIZE)?;
+        let chunk = Chunk {
+            data,
+            arrival: self.base + Duration::from_nanos(arrival_ns),
+        };
+
+        self.read_pos += RECORD_HEADER_SIZE + len as u64;
+        self.chunks -= 1;
+        if self.chunks == 0 {
+            if let Some(since) = self.since {
+                info!(
+                    "Spill drained: {} bytes were buffered on disk for {:.1} seconds.",
+                    self.spilled,
+                    since.elapsed().as_secs_f64()
+                );
+            }
+            self.reset()?;
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Drop everything that is still spilled. Used when the file has become
+    /// unreadable, so that the stream can continue with fresh data.
+    pub fn discard(&mut self) {
+        self.chunks = 0;
+        if let Err(e) = self.reset() {
+            warn!("Failed to truncate the spill file: {}", e);
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.read_pos = 0;
+        self.write_pos = 0;
+        self.since = None;
+        self.spilled = 0;
+        self.cap_reported = false;
+        match self.file.as_ref() {
+            Some((file, _)) => file.set_len(0),
+            None => Ok(()),
+        }
+    }
+
+    fn create_file(&self) -> io::Result<(File, PathBuf)> {
+        let path = self.config.dir.join(format!(
+            "recisdb-spill-{}-{}.tmp",
+            std::process::id(),
+            SPILL_FILE_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        debug!("Created spill file {}", path.display());
+        Ok((file, path))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Some((file, path)) = self.file.take() {
+            if self.chunks > 0 {
+                warn!(
+                    "{} spilled chunks ({} bytes) were never delivered.",
+                    self.chunks,
+                    self.backlog()
+                );
+            }
+            drop(file);
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to remove the spill file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
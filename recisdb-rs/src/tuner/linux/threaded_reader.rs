@@ -1,12 +1,19 @@
-use std::io::{self, ErrorKind, Read};
+use std::io::{self, ErrorKind, Read, Write};
 use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
 use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-use log::{debug, warn};
+use futures_util::task::AtomicWaker;
+use futures_util::{AsyncBufRead, AsyncRead};
+use log::{debug, error, warn};
+
+use super::spill::{Spill, SpillConfig};
 
 /// Default chunk size for each read from the source device (32 KiB).
 /// This matches recpt1's MAX_READ_SIZE, which has proven effective for
@@ -20,8 +27,15 @@ const DEFAULT_CHUNK_SIZE: usize = 32 * 1024;
 /// preventing kernel tuner buffer overflows and resultant data drops.
 const DEFAULT_QUEUE_CAPACITY: usize = 4096;
 
+/// Default size cap of the spill file (4 GiB).
+/// At typical ISDB-S bitrates this covers a stall of several minutes.
+const DEFAULT_SPILL_MAX_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
 const ENV_TUNER_CHUNK_SIZE_BYTES: &str = "RECISDB_TUNER_CHUNK_SIZE_BYTES";
 const ENV_TUNER_QUEUE_CAPACITY: &str = "RECISDB_TUNER_QUEUE_CAPACITY";
+const ENV_TUNER_SPILL_DIR: &str = "RECISDB_TUNER_SPILL_DIR";
+const ENV_TUNER_SPILL_THRESHOLD: &str = "RECISDB_TUNER_SPILL_THRESHOLD";
+const ENV_TUNER_SPILL_MAX_BYTES: &str = "RECISDB_TUNER_SPILL_MAX_BYTES";
 
 /// Timeout in milliseconds for poll() in the reader loop.
 /// The reader thread checks the shutdown flag after each timeout, so this
@@ -36,6 +50,17 @@ const POLL_TIMEOUT_MS: i32 = 100;
 /// DMX_STOP), the thread is detached to avoid deadlocking the drop chain.
 const DROP_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Inter-arrival time above which a delivery gap is reported.
+/// Tuners deliver data every few milliseconds while a signal is locked.
+const ARRIVAL_GAP_WARN: Duration = Duration::from_secs(1);
+
+/// A block of bytes read from the source, tagged with the monotonic time at
+/// which the read completed. An empty chunk signals end-of-stream.
+pub(crate) struct Chunk {
+    pub data: Vec<u8>,
+    pub arrival: Instant,
+}
+
 /// A buffered wrapper around any `Read` source that decouples the reading
 /// from the consuming thread by using a dedicated background thread.
 ///
@@ -49,6 +74,33 @@ const DROP_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
 /// TS packet drops even on high-bitrate CS channels with slow physical
 /// B-CAS card readers.
 ///
+/// # Spill-to-disk overflow
+///
+/// Optionally, once more than `SpillConfig::threshold` chunks are waiting in
+/// the queue, new chunks are appended to a temporary file instead of
+/// blocking the reader thread. The spilled chunks are moved back into the
+/// queue in order as soon as the consumer catches up, so that a short
+/// storage outage downstream does not overflow the kernel buffer.
+/// When the spill file reaches `SpillConfig::max_bytes`, the reader thread
+/// blocks on the queue as it would without spilling.
+///
+/// # Arrival timestamps
+///
+/// Every chunk is tagged with the `Instant` at which the reader thread
+/// received it from the source. The arrival time of the latest chunk is
+/// exposed by `last_arrival()`, gaps between the arrivals are reported, and
+/// `set_arrival_index()` writes a sidecar index of stream offset → arrival
+/// time, so that dropouts can later be correlated with signal logs and system
+/// events.
+///
+/// # Async integration
+///
+/// `ThreadedReader` implements `AsyncRead` and `AsyncBufRead` natively.
+/// When the queue is empty, the consumer task's `Waker` is stored and the
+/// reader thread wakes it after every chunk it sends, so polling never
+/// blocks the executor thread. The blocking `Read` implementation is kept
+/// for synchronous callers.
+///
 /// # Lifecycle
 ///
 /// The reader thread exits on EOF, fatal I/O error, or shutdown request.
@@ -57,13 +109,28 @@ const DROP_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
 /// no data is available.
 pub(crate) struct ThreadedReader {
     /// Receiver end of the bounded channel from the reader thread.
-    receiver: Option<Receiver<io::Result<Vec<u8>>>>,
+    receiver: Option<Receiver<io::Result<Chunk>>>,
     /// Buffer for partially-consumed data from the last received chunk.
     /// When a chunk from the channel is larger than the caller's read
     /// buffer, the remainder is stored here for subsequent read() calls.
     pending: Vec<u8>,
     /// Current read offset within `pending`.
     offset: usize,
+    /// Reference point of the arrival times written to the index.
+    base: Instant,
+    /// Arrival time of the most recently received chunk.
+    arrival: Option<Instant>,
+    /// Number of bytes received so far, i.e. the stream offset of the next
+    /// chunk.
+    received: u64,
+    /// Sidecar index of stream offset → arrival time, if requested.
+    index: Option<Box<dyn Write + Send>>,
+    /// Number of messages sent by the reader thread but not yet received.
+    queued: Arc<AtomicUsize>,
+    /// Waker of the task waiting for the next chunk.
+    /// Registered by the async consumer when the queue is empty and woken by
+    /// the reader thread after each `send()`.
+    waker: Arc<AtomicWaker>,
     /// Shutdown signal for the reader thread.
     /// Set to `true` in Drop to request the reader thread to exit.
     shutdown: Arc<AtomicBool>,
@@ -85,19 +152,41 @@ impl ThreadedReader {
     ///   can hold. When the queue is full, the reader thread blocks on
     ///   `send()` until the consumer drains some data. Total memory usage
     ///   is approximately `chunk_size * queue_capacity` bytes.
+    /// * `spill` - Enables the spill-to-disk overflow mode if given.
     pub fn new<R: Read + Send + AsRawFd + 'static>(
         source: R,
         chunk_size: usize,
         queue_capacity: usize,
+        spill: Option<SpillConfig>,
     ) -> io::Result<Self> {
         let (sender, receiver) = sync_channel(queue_capacity);
         let shutdown = Arc::new(AtomicBool::new(false));
         let shutdown_clone = Arc::clone(&shutdown);
+        let waker: Arc<AtomicWaker> = Default::default();
+        let queued: Arc<AtomicUsize> = Default::default();
+        let base = Instant::now();
+
+        if let Some(config) = spill.as_ref() {
+            debug!(
+                "Spill-to-disk enabled (dir: {}, threshold: {} chunks, cap: {} bytes)",
+                config.dir.display(),
+                config.threshold,
+                config.max_bytes,
+            );
+        }
+        let outlet = Outlet {
+            queue: Queue {
+                sender,
+                waker: Arc::clone(&waker),
+                queued: Arc::clone(&queued),
+            },
+            spill: spill.map(|config| Spill::new(config, base)),
+        };
 
         let reader_thread = thread::Builder::new()
             .name("tuner-reader".to_string())
             .spawn(move || {
-                Self::reader_loop(source, sender, chunk_size, shutdown_clone);
+                Self::reader_loop(source, outlet, chunk_size, shutdown_clone);
             })?;
 
         debug!(
@@ -111,6 +200,12 @@ impl ThreadedReader {
             receiver: Some(receiver),
             pending: Vec::new(),
             offset: 0,
+            base,
+            arrival: None,
+            received: 0,
+            index: None,
+            queued,
+            waker,
             shutdown,
             reader_thread: Some(reader_thread),
         })
@@ -122,16 +217,52 @@ impl ThreadedReader {
     /// Both values can be overridden by environment variables:
     /// - RECISDB_TUNER_CHUNK_SIZE_BYTES
     /// - RECISDB_TUNER_QUEUE_CAPACITY
+    ///
+    /// The spill-to-disk overflow mode is enabled by setting
+    /// RECISDB_TUNER_SPILL_DIR. Its threshold (in chunks, 3/4 of the queue
+    /// capacity by default) and size cap (4 GiB by default) are set by
+    /// RECISDB_TUNER_SPILL_THRESHOLD and RECISDB_TUNER_SPILL_MAX_BYTES.
     pub fn with_defaults<R: Read + Send + AsRawFd + 'static>(source: R) -> io::Result<Self> {
-        let chunk_size = Self::read_usize_env(ENV_TUNER_CHUNK_SIZE_BYTES, DEFAULT_CHUNK_SIZE);
-        let queue_capacity = Self::read_usize_env(ENV_TUNER_QUEUE_CAPACITY, DEFAULT_QUEUE_CAPACITY);
-        Self::new(source, chunk_size, queue_capacity)
+        let chunk_size = Self::read_env(ENV_TUNER_CHUNK_SIZE_BYTES, DEFAULT_CHUNK_SIZE);
+        let queue_capacity = Self::read_env(ENV_TUNER_QUEUE_CAPACITY, DEFAULT_QUEUE_CAPACITY);
+        let spill = std::env::var_os(ENV_TUNER_SPILL_DIR).map(|dir| SpillConfig {
+            dir: PathBuf::from(dir),
+            threshold: Self::read_env(ENV_TUNER_SPILL_THRESHOLD, (queue_capacity * 3 / 4).max(1))
+                .min(queue_capacity),
+            max_bytes: Self::read_env(ENV_TUNER_SPILL_MAX_BYTES, DEFAULT_SPILL_MAX_BYTES),
+        });
+        Self::new(source, chunk_size, queue_capacity, spill)
+    }
+
+    /// Arrival time of the most recently received chunk.
+    pub fn last_arrival(&self) -> Option<Instant> {
+        self.arrival
+    }
+
+    /// Write a sidecar index of the chunks received from now on.
+    ///
+    /// Each line holds the byte offset of a chunk in the tuner stream, its
+    /// arrival time in nanoseconds since the reader was created, and its
+    /// length, separated by tabs. A header comment records the wall-clock
+    /// time corresponding to the origin of the arrival times.
+    pub fn set_arrival_index(&mut self, mut index: Box<dyn Write + Send>) -> io::Result<()> {
+        let origin = chrono::Local::now()
+            - chrono::Duration::from_std(self.base.elapsed())
+                .unwrap_or_else(|_| chrono::Duration::zero());
+        writeln!(index, "# recisdb arrival index")?;
+        writeln!(index, "# origin: {}", origin.to_rfc3339())?;
+        writeln!(index, "# offset\tarrival_ns\tlength")?;
+        self.index = Some(index);
+        Ok(())
     }
 
-    fn read_usize_env(name: &str, fallback: usize) -> usize {
+    fn read_env<T>(name: &str, fallback: T) -> T
+    where
+        T: std::str::FromStr + std::fmt::Display + Default + PartialOrd,
+    {
         match std::env::var(name) {
-            Ok(raw) => match raw.parse::<usize>() {
-                Ok(value) if value > 0 => value,
+            Ok(raw) => match raw.parse::<T>() {
+                Ok(value) if value > T::default() => value,
                 _ => {
                     warn!(
                         "Invalid value for {}: {}. Falling back to {}.",
@@ -149,9 +280,10 @@ impl ThreadedReader {
     ///
     /// Uses `poll()` timeout to periodically observe `shutdown`, and bounded
     /// channel backpressure (`send()` blocks when queue is full).
+    /// Every message sent is followed by a wake-up of the registered consumer.
     fn reader_loop<R: Read + AsRawFd>(
         mut source: R,
-        sender: SyncSender<io::Result<Vec<u8>>>,
+        mut outlet: Outlet,
         chunk_size: usize,
         shutdown: Arc<AtomicBool>,
     ) {
@@ -163,6 +295,11 @@ impl ThreadedReader {
                 break;
             }
 
+            // Move spilled chunks back into the queue while it has room.
+            if !outlet.drain(false) {
+                break;
+            }
+
             // Wait for data to be available on the source fd, with timeout.
             // This allows the thread to periodically check the shutdown flag
             // even when the source device is not producing data.
@@ -180,7 +317,7 @@ impl ThreadedReader {
                 if poll_error.kind() == ErrorKind::Interrupted {
                     continue;
                 }
-                let _ = sender.send(Err(poll_error));
+                let _ = outlet.finish(Err(poll_error));
                 break;
             }
 
@@ -192,7 +329,7 @@ impl ThreadedReader {
 
             // Invalid fd indicates broken stream state.
             if pollfd.revents & libc::POLLNVAL != 0 {
-                let _ = sender.send(Err(io::Error::new(
+                let _ = outlet.finish(Err(io::Error::new(
                     ErrorKind::BrokenPipe,
                     "Tuner fd became invalid while polling.",
                 )));
@@ -203,14 +340,14 @@ impl ThreadedReader {
             // also set. This preserves any final bytes before stream teardown.
             if pollfd.revents & libc::POLLIN == 0 {
                 if pollfd.revents & libc::POLLERR != 0 {
-                    let _ = sender.send(Err(io::Error::new(
+                    let _ = outlet.finish(Err(io::Error::new(
                         ErrorKind::Other,
                         "Tuner poll reported device error (POLLERR).",
                     )));
                     break;
                 }
                 if pollfd.revents & libc::POLLHUP != 0 {
-                    let _ = sender.send(Err(io::Error::new(
+                    let _ = outlet.finish(Err(io::Error::new(
                         ErrorKind::UnexpectedEof,
                         "Tuner stream hang-up detected (POLLHUP).",
                     )));
@@ -220,17 +357,22 @@ impl ThreadedReader {
             }
 
             let mut buf = vec![0u8; chunk_size];
-            match source.read(&mut buf) {
-                // EOF: signal completion by sending an empty Vec, then exit
+            let result = source.read(&mut buf);
+            let arrival = Instant::now();
+            match result {
+                // EOF: signal completion by sending an empty chunk, then exit
                 Ok(0) => {
-                    let _ = sender.send(Ok(Vec::new()));
+                    let _ = outlet.finish(Ok(Chunk {
+                        data: Vec::new(),
+                        arrival,
+                    }));
                     break;
                 }
                 Ok(bytes_read) => {
                     buf.truncate(bytes_read);
                     // If send fails, the receiver has been dropped
                     // (consumer is done), so we exit the loop
-                    if sender.send(Ok(buf)).is_err() {
+                    if !outlet.deliver(Chunk { data: buf, arrival }) {
                         break;
                     }
                 }
@@ -245,15 +387,108 @@ impl ThreadedReader {
                 }
                 // Fatal I/O error: forward to consumer and exit
                 Err(io_error) => {
-                    let _ = sender.send(Err(io_error));
+                    let _ = outlet.finish(Err(io_error));
                     break;
                 }
             }
         }
+        // Disconnect the channel and wake the consumer once more, so that a
+        // task parked on an empty queue observes the end of the stream.
+        let waker = Arc::clone(&outlet.queue.waker);
+        drop(outlet);
+        waker.wake();
         debug!("Tuner reader thread exiting.");
     }
 }
 
+/// The reader thread's end of the in-memory queue.
+struct Queue {
+    sender: SyncSender<io::Result<Chunk>>,
+    waker: Arc<AtomicWaker>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Queue {
+    /// Send a message, blocking while the queue is full, and wake the
+    /// consumer task, if any.
+    /// Returns false when the receiver has been dropped.
+    fn send(&self, message: io::Result<Chunk>) -> bool {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        let sent = self.sender.send(message).is_ok();
+        self.waker.wake();
+        sent
+    }
+
+    fn len(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+/// The in-memory queue combined with the optional spill file.
+struct Outlet {
+    queue: Queue,
+    spill: Option<Spill>,
+}
+
+impl Outlet {
+    /// Hand a chunk over to the consumer, spilling it to disk if the queue
+    /// is above the threshold or earlier chunks are still on disk.
+    fn deliver(&mut self, chunk: Chunk) -> bool {
+        if let Some(spill) = self.spill.as_mut() {
+            if !spill.is_empty() || self.queue.len() >= spill.threshold() {
+                match spill.push(&chunk) {
+                    Ok(true) => return true,
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to write to the spill file: {}", e),
+                }
+                // The chunk could not be spilled. Deliver everything on disk
+                // first to keep the order, waiting for the consumer.
+                if !self.drain(true) {
+                    return false;
+                }
+            }
+        }
+        self.queue.send(Ok(chunk))
+    }
+
+    /// Send the last message (EOF or an error) after all spilled chunks.
+    fn finish(&mut self, message: io::Result<Chunk>) -> bool {
+        self.drain(true) && self.queue.send(message)
+    }
+
+    /// Move spilled chunks back into the queue. Unless `block` is set, this
+    /// stops as soon as the queue reaches the spill threshold again.
+    /// Returns false when the receiver has been dropped.
+    fn drain(&mut self, block: bool) -> bool {
+        let spill = match self.spill.as_mut() {
+            Some(spill) => spill,
+            None => return true,
+        };
+        while !spill.is_empty() {
+            if !block && self.queue.len() >= spill.threshold() {
+                break;
+            }
+            match spill.pop() {
+                Ok(Some(chunk)) => {
+                    if !self.queue.send(Ok(chunk)) {
+                        return false;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!(
+                        "Failed to read back the spill file: {}. {} bytes are lost.",
+                        e,
+                        spill.backlog()
+                    );
+                    spill.discard();
+                }
+            }
+        }
+        true
+    }
+}
+
 impl Drop for ThreadedReader {
     fn drop(&mut self) {
         // Signal the reader thread to exit.
@@ -294,6 +529,110 @@ impl Drop for ThreadedReader {
     }
 }
 
+impl ThreadedReader {
+    /// Take a message received from the reader thread into `pending`.
+    /// `None` means the sender has been dropped.
+    /// Returns `Ok(false)` at end-of-stream.
+    fn store(&mut self, received: Option<io::Result<Chunk>>) -> io::Result<bool> {
+        if received.is_some() {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+        }
+        match received {
+            // EOF signaled by the reader thread (empty chunk)
+            Some(Ok(chunk)) if chunk.data.is_empty() => Ok(false),
+            Some(Ok(chunk)) => {
+                self.record_arrival(&chunk);
+                self.received += chunk.data.len() as u64;
+                self.pending = chunk.data;
+                self.offset = 0;
+                Ok(true)
+            }
+            // I/O error forwarded from the reader thread
+            Some(Err(io_error)) => Err(io_error),
+            // Sender dropped unexpectedly; treat as EOF
+            None => Ok(false),
+        }
+    }
+
+    /// Update the arrival time, reporting delivery gaps and appending the
+    /// chunk to the sidecar index.
+    fn record_arrival(&mut self, chunk: &Chunk) {
+        if let Some(previous) = self.arrival {
+            let gap = chunk.arrival.saturating_duration_since(previous);
+            if gap >= ARRIVAL_GAP_WARN {
+                warn!(
+                    "No data arrived from the tuner for {:.2} seconds (stream offset {}).",
+                    gap.as_secs_f64(),
+                    self.received
+                );
+            }
+        }
+        self.arrival = Some(chunk.arrival);
+
+        if let Some(index) = self.index.as_mut() {
+            let arrival_ns = chunk
+                .arrival
+                .saturating_duration_since(self.base)
+                .as_nanos();
+            if let Err(e) = writeln!(
+                index,
+                "{}\t{}\t{}",
+                self.received,
+                arrival_ns,
+                chunk.data.len()
+            ) {
+                error!("Failed to write the arrival index: {}", e);
+                self.index = None;
+            }
+        }
+    }
+
+    /// Make sure `pending` has unread data without blocking.
+    /// If the queue is empty, the task's waker is registered so that the
+    /// reader thread can resume it once the next chunk has been sent.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        if self.offset < self.pending.len() {
+            return Poll::Ready(Ok(true));
+        }
+
+        let receiver = match self.receiver.as_ref() {
+            Some(receiver) => receiver,
+            None => return Poll::Ready(Ok(false)),
+        };
+        let received = match receiver.try_recv() {
+            Err(TryRecvError::Empty) => {
+                // Register first and check again, so that a chunk sent
+                // between the two try_recv() calls cannot be missed.
+                self.waker.register(cx.waker());
+                match receiver.try_recv() {
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    received => received,
+                }
+            }
+            received => received,
+        };
+        Poll::Ready(self.store(received.ok()))
+    }
+
+    /// Copy as much of `pending` as fits into `buf`.
+    fn copy_pending(&mut self, buf: &mut [u8]) -> usize {
+        let available = self.pending.len() - self.offset;
+        let copy_size = available.min(buf.len());
+        buf[..copy_size].copy_from_slice(&self.pending[self.offset..self.offset + copy_size]);
+        self.consume_pending(copy_size);
+        copy_size
+    }
+
+    fn consume_pending(&mut self, amt: usize) {
+        self.offset = (self.offset + amt).min(self.pending.len());
+        // If we've consumed all pending data, clear the buffer
+        if self.offset >= self.pending.len() {
+            self.pending.clear();
+            self.offset = 0;
+        }
+    }
+}
+
 impl Read for ThreadedReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Per the Read trait contract, a zero-length buffer must return
@@ -306,44 +645,50 @@ impl Read for ThreadedReader {
         // First, drain any remaining data from the previously received chunk.
         // This avoids calling recv() when we already have data available,
         // which would block unnecessarily.
-        if self.offset < self.pending.len() {
-            let available = self.pending.len() - self.offset;
-            let copy_size = available.min(buf.len());
-            buf[..copy_size].copy_from_slice(&self.pending[self.offset..self.offset + copy_size]);
-            self.offset += copy_size;
-            // If we've consumed all pending data, clear the buffer
-            if self.offset >= self.pending.len() {
-                self.pending.clear();
-                self.offset = 0;
+        // Otherwise, block until the reader thread sends the next chunk.
+        if self.offset >= self.pending.len() {
+            let received = match self.receiver.as_ref() {
+                Some(receiver) => receiver.recv().ok(),
+                None => return Ok(0),
+            };
+            if !self.store(received)? {
+                return Ok(0);
             }
-            return Ok(copy_size);
         }
+        Ok(self.copy_pending(buf))
+    }
+}
 
-        // No pending data; block until the reader thread sends the next chunk
-        let receiver = match self.receiver.as_ref() {
-            Some(receiver) => receiver,
-            None => return Ok(0),
-        };
-        match receiver.recv() {
-            // EOF signaled by the reader thread (empty Vec)
-            Ok(Ok(data)) if data.is_empty() => Ok(0),
-            Ok(Ok(data)) => {
-                let copy_size = data.len().min(buf.len());
-                buf[..copy_size].copy_from_slice(&data[..copy_size]);
-                // If the received chunk is larger than buf, save the
-                // remainder for subsequent read() calls
-                if data.len() > copy_size {
-                    self.pending = data;
-                    self.offset = copy_size;
-                }
-                Ok(copy_size)
-            }
-            // I/O error forwarded from the reader thread
-            Ok(Err(io_error)) => Err(io_error),
-            // Sender dropped unexpectedly; treat as EOF
-            Err(_) => Ok(0),
+impl AsyncRead for ThreadedReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if !ready!(this.poll_pending(cx))? {
+            return Poll::Ready(Ok(0));
+        }
+        Poll::Ready(Ok(this.copy_pending(buf)))
+    }
+}
+
+impl AsyncBufRead for ThreadedReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if ready!(this.poll_pending(cx))? {
+            Poll::Ready(Ok(&this.pending[this.offset..]))
+        } else {
+            Poll::Ready(Ok(&[]))
         }
     }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().consume_pending(amt)
+    }
 }
 
 #[cfg(test)]
@@ -378,7 +723,7 @@ mod tests {
         write_end.write_all(test_data).unwrap();
         drop(write_end);
 
-        let mut reader = ThreadedReader::new(read_end, 4096, 16).unwrap();
+        let mut reader = ThreadedReader::new(read_end, 4096, 16, None).unwrap();
         let mut buf = vec![0u8; test_data.len()];
         let n = reader.read(&mut buf).unwrap();
         assert_eq!(n, test_data.len());
@@ -393,7 +738,7 @@ mod tests {
         write_end.write_all(b"data").unwrap();
         drop(write_end);
 
-        let mut reader = ThreadedReader::new(read_end, 4096, 16).unwrap();
+        let mut reader = ThreadedReader::new(read_end, 4096, 16, None).unwrap();
         let mut buf = vec![0u8; 1024];
         // The reader thread signals completion via Ok(0) (empty Vec) or
         // via an error (POLLHUP → UnexpectedEof). Both indicate the
@@ -416,7 +761,7 @@ mod tests {
 
         let start = Instant::now();
         {
-            let mut reader = ThreadedReader::new(read_end, 4096, 16).unwrap();
+            let mut reader = ThreadedReader::new(read_end, 4096, 16, None).unwrap();
             // Drain so the reader thread processes EOF and exits before drop.
             let mut buf = vec![0u8; 1024];
             let _ = reader.read(&mut buf);
@@ -467,7 +812,7 @@ mod tests {
     fn test_drop_with_blocking_source() {
         let source = BlockingMockSource::new();
         // Give the reader thread time to enter the blocking read().
-        let reader = ThreadedReader::new(source, 4096, 16).unwrap();
+        let reader = ThreadedReader::new(source, 4096, 16, None).unwrap();
         thread::sleep(Duration::from_millis(500));
 
         let start = Instant::now();
@@ -483,4 +828,178 @@ mod tests {
             DROP_JOIN_TIMEOUT + Duration::from_secs(3)
         );
     }
+
+    /// Waker that counts how many times it has been woken.
+    struct CountingWaker(std::sync::atomic::AtomicUsize);
+
+    impl std::task::Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The async interface should relay the same data as the blocking one.
+    #[test]
+    fn test_async_read() {
+        let (read_end, mut write_end) = create_pipe();
+        let test_data = b"Hello, async ThreadedReader!";
+        write_end.write_all(test_data).unwrap();
+        drop(write_end);
+
+        let mut reader = ThreadedReader::new(read_end, 4096, 16, None).unwrap();
+        let mut buf = Vec::new();
+        // The stream may end with either EOF or POLLHUP (see test_eof).
+        let _ = futures_executor::block_on(futures_util::AsyncReadExt::read_to_end(
+            &mut reader,
+            &mut buf,
+        ));
+        assert_eq!(&buf[..], test_data);
+    }
+
+    /// Polling an empty queue must return Pending instead of blocking,
+    /// and the reader thread must wake the task once a chunk arrives.
+    #[test]
+    fn test_async_wakeup() {
+        let (read_end, mut write_end) = create_pipe();
+        let mut reader = ThreadedReader::new(read_end, 4096, 16, None).unwrap();
+
+        let counter = Arc::new(CountingWaker(Default::default()));
+        let waker = std::task::Waker::from(Arc::clone(&counter));
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut reader).poll_fill_buf(&mut cx).is_pending());
+
+        write_end.write_all(b"wake").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while counter.0.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "the waker was never woken");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        match Pin::new(&mut reader).poll_fill_buf(&mut cx) {
+            Poll::Ready(Ok(buf)) => assert_eq!(buf, b"wake"),
+            other => panic!(
+                "unexpected poll result: {:?}",
+                other.map(|r| r.map(<[u8]>::len))
+            ),
+        }
+        Pin::new(&mut reader).consume(4);
+        assert!(Pin::new(&mut reader).poll_fill_buf(&mut cx).is_pending());
+    }
+
+    /// Creates an empty directory for spill files under the system temp dir.
+    fn spill_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "recisdb-test-spill-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `len` bytes of a recognizable pattern to `write_end` from
+    /// another thread, then closes it. The thread returns the data written.
+    fn write_pattern(mut write_end: std::fs::File, len: usize) -> JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            write_end.write_all(&data).unwrap();
+            data
+        })
+    }
+
+    /// With a stalled consumer, chunks beyond the threshold should go to the
+    /// spill file and come back in order once the consumer resumes.
+    #[test]
+    fn test_spill_preserves_order() {
+        let dir = spill_dir("order");
+        let (read_end, write_end) = create_pipe();
+        let config = SpillConfig {
+            dir: dir.clone(),
+            threshold: 2,
+            max_bytes: 16 * 1024 * 1024,
+        };
+        let mut reader = ThreadedReader::new(read_end, 1024, 4, Some(config)).unwrap();
+
+        // The writer can only finish if the reader thread keeps draining the
+        // pipe while nobody consumes the queue.
+        let expected = write_pattern(write_end, 512 * 1024).join().unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut buf = Vec::new();
+        // The stream may end with either EOF or POLLHUP (see test_eof).
+        let _ = reader.read_to_end(&mut buf);
+        assert_eq!(buf, expected);
+
+        // The spill file is removed when the reader thread exits.
+        drop(reader);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Once the spill file reaches its cap, the reader thread should fall
+    /// back to waiting on the queue without losing or reordering data.
+    #[test]
+    fn test_spill_cap() {
+        let dir = spill_dir("cap");
+        let (read_end, write_end) = create_pipe();
+        let config = SpillConfig {
+            dir: dir.clone(),
+            threshold: 2,
+            max_bytes: 8 * 1024,
+        };
+        let mut reader = ThreadedReader::new(read_end, 1024, 4, Some(config)).unwrap();
+
+        let writer = write_pattern(write_end, 512 * 1024);
+        thread::sleep(Duration::from_millis(300));
+        assert!(!writer.is_finished(), "the spill cap was not enforced");
+
+        let mut buf = Vec::new();
+        let _ = reader.read_to_end(&mut buf);
+        assert_eq!(buf, writer.join().unwrap());
+
+        drop(reader);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// The arrival index should cover the whole stream with contiguous
+    /// offsets and non-decreasing arrival times, including chunks that went
+    /// through the spill file.
+    #[test]
+    fn test_arrival_index() {
+        let dir = spill_dir("arrival");
+        let (read_end, write_end) = create_pipe();
+        let config = SpillConfig {
+            dir: dir.clone(),
+            threshold: 2,
+            max_bytes: 16 * 1024 * 1024,
+        };
+        let mut reader = ThreadedReader::new(read_end, 1024, 4, Some(config)).unwrap();
+        let index_path = dir.join("arrival.idx");
+        reader
+            .set_arrival_index(Box::new(std::fs::File::create(&index_path).unwrap()))
+            .unwrap();
+        assert!(reader.last_arrival().is_none());
+
+        let expected = write_pattern(write_end, 64 * 1024).join().unwrap();
+        let mut buf = Vec::new();
+        let _ = reader.read_to_end(&mut buf);
+        assert_eq!(buf, expected);
+        assert!(reader.last_arrival().unwrap() <= Instant::now());
+        drop(reader);
+
+        let index = std::fs::read_to_string(&index_path).unwrap();
+        let (mut offset, mut last_ns) = (0u64, 0u128);
+        for line in index.lines().filter(|l| !l.starts_with('#')) {
+            let fields: Vec<u128> = line.split('\t').map(|f| f.parse().unwrap()).collect();
+            assert_eq!(fields.len(), 3);
+            assert_eq!(fields[0], offset as u128);
+            assert!(fields[1] >= last_ns);
+            offset += fields[2] as u64;
+            last_ns = fields[1];
+        }
+        assert_eq!(offset, expected.len() as u64);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -195,6 +195,18 @@ impl Tuner {
             .unwrap()
             .into()
     }
+
+    pub fn set_arrival_index(&mut self, _index: Box<dyn io::Write + Send>) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Arrival indexing is not supported with BonDriver.",
+        ))
+    }
+
+    /// The arrival times are not tracked with BonDriver.
+    pub fn last_arrival(&self) -> Option<std::time::Instant> {
+        None
+    }
 }
 
 impl AsyncRead for Tuner {
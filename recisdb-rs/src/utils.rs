@@ -50,6 +50,25 @@ pub(crate) fn initialize_logger() {
     info!("recisdb version {}", env!("CARGO_PKG_VERSION"));
 }
 
+/// Quote `s` as a JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 pub(crate) fn progress(bar: &ProgressBar, value: u64) {
     bar.set_position(value);
 }